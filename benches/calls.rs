@@ -0,0 +1,39 @@
+//! Measures function calls against environments with more and more global
+//! definitions. Run with `cargo bench`.
+//!
+//! A call only binds its parameters in a new scope, so the time per call
+//! should stay the same however many globals are defined. When calls copied
+//! the whole environment, it grew with the number of globals.
+
+use minilisp_rust::{Env, Expr, eval, parse, parse_program};
+use std::hint::black_box;
+use std::time::Instant;
+
+/// How deep `count-down` recurses in each run.
+const DEPTH: u32 = 100;
+/// How many times `count-down` is run for each environment.
+const RUNS: u32 = 200;
+
+fn main() {
+    let program =
+        parse_program("(define count-down (lambda (n) (if (= n 0) 0 (count-down (- n 1)))))")
+            .unwrap();
+    let call = parse(&format!("(count-down {})", DEPTH)).unwrap();
+
+    for globals in [10, 1_000, 100_000] {
+        let mut env = Env::new();
+        for i in 0..globals {
+            env.insert(format!("global-{}", i), Expr::Number(i as f64));
+        }
+        for form in &program {
+            eval(form, &mut env).unwrap();
+        }
+
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            black_box(eval(black_box(&call), &mut env).unwrap());
+        }
+        let per_call = start.elapsed() / (RUNS * (DEPTH + 1));
+        println!("{:>7} globals: {:?} per call", globals, per_call);
+    }
+}
@@ -0,0 +1,48 @@
+//! Measures workloads that pass large lists and strings around. Run with
+//! `cargo bench`.
+//!
+//! Looking up a variable or binding an argument copies only a handle on a
+//! list or string, so the time per call should not grow with its length.
+//! When values were copied whole, it grew with every item.
+
+use minilisp_rust::{Env, Expr, eval, parse, parse_program};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// How many times each workload is run for each length.
+const RUNS: u32 = 20;
+
+const PROGRAM: &str = "
+(define pass (lambda (xs n) (if (= n 0) xs (pass xs (- n 1)))))
+(define big? (lambda (x) (> x 500)))
+";
+
+fn main() {
+    let program = parse_program(PROGRAM).unwrap();
+    let workloads = [
+        ("pass list 100 times", "(pass items 100)"),
+        ("pass string 100 times", "(pass text 100)"),
+        ("count over list", "(count big? items)"),
+        ("list-tail", "(list-tail items 10)"),
+    ];
+
+    for len in [10, 1_000, 100_000] {
+        let mut env = Env::new();
+        for form in &program {
+            eval(form, &mut env).unwrap();
+        }
+        let items = (0..len).map(|i| Expr::Number(i as f64)).collect::<Vec<_>>();
+        env.insert("items".to_string(), Expr::from(items));
+        env.insert("text".to_string(), Expr::from("x".repeat(len)));
+
+        for (name, source) in workloads {
+            let expr = parse(source).unwrap();
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                black_box(eval(black_box(&expr), &mut env).unwrap());
+            }
+            let per_run: Duration = start.elapsed() / RUNS;
+            println!("{:>7} items, {:<22} {:?} per run", len, name, per_run);
+        }
+    }
+}
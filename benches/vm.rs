@@ -0,0 +1,35 @@
+//! Compares the tree-walking evaluator with compiled code on a recursive
+//! function. Run with `cargo bench`.
+
+use minilisp_rust::compile::{Vm, compile};
+use minilisp_rust::{Env, eval, parse, parse_program};
+use std::hint::black_box;
+use std::time::Instant;
+
+/// How many times `(fib 20)` is run for each implementation.
+const RUNS: u32 = 20;
+
+fn main() {
+    let program =
+        parse_program("(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))")
+            .unwrap();
+    let call = parse("(fib 20)").unwrap();
+    let mut env = Env::new();
+    for form in &program {
+        eval(form, &mut env).unwrap();
+    }
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(eval(black_box(&call), &mut env).unwrap());
+    }
+    println!("  evaluated: {:?} per run", start.elapsed() / RUNS);
+
+    let chunk = compile(&call);
+    let mut vm = Vm::new();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.run(black_box(&chunk), &mut env).unwrap());
+    }
+    println!("   compiled: {:?} per run", start.elapsed() / RUNS);
+}
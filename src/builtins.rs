@@ -0,0 +1,416 @@
+//! The registry of builtin functions.
+//!
+//! A call whose operator is a symbol first looks the symbol up in the
+//! current thread's [`Builtins`], and only falls back to the environment if
+//! no builtin has that name. The standard registry holds every builtin the
+//! language defines; embedders can add their own, override or remove
+//! standard ones, and strip whole [`Category`]s to sandbox untrusted code.
+//! An [`Interpreter`](crate::Interpreter) keeps its own registry and
+//! installs it with [`runtime::set_builtins`](crate::runtime::set_builtins)
+//! while it evaluates.
+
+use crate::data::{Env, Expr, NativeFn};
+use crate::error::EvalError;
+use crate::eval::apply_builtin_op;
+use crate::shared::{NativeFunction, Shared};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Groups of related builtins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Arithmetic, comparison and other operations on numbers.
+    Numbers,
+    /// Bitwise operations on integers.
+    Bits,
+    /// Type tests and equality.
+    Predicates,
+    /// Operations on lists.
+    Lists,
+    /// Operations on strings, characters and symbols.
+    Strings,
+    /// Maps and association lists.
+    Maps,
+    /// Printing to the output sink and reading from the input.
+    Io,
+    /// Reading and evaluating code at run time, and memoizing functions.
+    Evaluation,
+    /// Reading and writing files.
+    Filesystem,
+    /// Reading and setting environment variables.
+    Environment,
+    /// Running other programs and exiting.
+    Process,
+    /// Clocks and sleeping.
+    Time,
+    /// JSON, CSV, base64, hex, hashes and UUIDs.
+    Encoding,
+    /// Signalling and inspecting conditions.
+    Conditions,
+    /// `deftest` support.
+    Testing,
+    /// Builtins added by the embedding program.
+    Host,
+}
+
+/// How many arguments a builtin accepts.
+///
+/// Builtins check their own arguments, so this is for documentation and
+/// tools rather than enforced by the registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arity {
+    /// The fewest arguments accepted.
+    pub min: usize,
+    /// The most arguments accepted, or `None` if there is no limit.
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    /// Exactly `n` arguments.
+    pub const fn exact(n: usize) -> Self {
+        Arity {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    /// From `min` to `max` arguments, inclusive.
+    pub const fn range(min: usize, max: usize) -> Self {
+        Arity {
+            min,
+            max: Some(max),
+        }
+    }
+
+    /// At least `min` arguments.
+    pub const fn at_least(min: usize) -> Self {
+        Arity { min, max: None }
+    }
+
+    /// Returns whether a call with `n` arguments is accepted.
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Implementation {
+    /// One of the builtins of the language, under its standard name.
+    Standard(&'static str),
+    /// A builtin supplied by the host.
+    Native(NativeFn),
+}
+
+/// A builtin function and its description.
+#[derive(Clone, Debug)]
+pub struct Builtin {
+    category: Category,
+    arity: Arity,
+    doc: Cow<'static, str>,
+    implementation: Implementation,
+}
+
+impl Builtin {
+    /// Creates a builtin that runs `func` on the evaluated arguments of each
+    /// call. It is in the [`Category::Host`] category, accepts any number of
+    /// arguments and has no documentation until the `with_` methods say
+    /// otherwise.
+    pub fn new(func: impl NativeFunction) -> Self {
+        Builtin {
+            category: Category::Host,
+            arity: Arity::at_least(0),
+            doc: Cow::Borrowed(""),
+            implementation: Implementation::Native(NativeFn::new(func)),
+        }
+    }
+
+    /// Sets the category.
+    pub fn with_category(mut self, category: Category) -> Self {
+        self.category = category;
+        self
+    }
+
+    /// Sets the arity.
+    pub fn with_arity(mut self, arity: Arity) -> Self {
+        self.arity = arity;
+        self
+    }
+
+    /// Sets the documentation.
+    pub fn with_doc(mut self, doc: &str) -> Self {
+        self.doc = Cow::Owned(doc.to_string());
+        self
+    }
+
+    /// Returns the category.
+    pub fn category(&self) -> Category {
+        self.category
+    }
+
+    /// Returns how many arguments the builtin accepts.
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    /// Returns a one-line description of what the builtin does.
+    pub fn doc(&self) -> &str {
+        &self.doc
+    }
+
+    /// Returns whether this is the standard builtin called `name`, rather
+    /// than one added or overridden by the host.
+    pub fn is_standard(&self, name: &str) -> bool {
+        matches!(self.implementation, Implementation::Standard(op) if op == name)
+    }
+
+    /// Returns the name of the standard builtin this is, if it is one.
+    pub(crate) fn standard_name(&self) -> Option<&'static str> {
+        match self.implementation {
+            Implementation::Standard(op) => Some(op),
+            Implementation::Native(_) => None,
+        }
+    }
+
+    /// Calls the builtin with evaluated arguments. `env` is the environment
+    /// of the call, which builtins such as `eval` evaluate in.
+    pub fn call(&self, args: &[Expr], env: &mut Env) -> Result<Expr, EvalError> {
+        match &self.implementation {
+            Implementation::Standard(op) => apply_builtin_op(op, args, env),
+            Implementation::Native(func) => func.call(args),
+        }
+    }
+}
+
+/// A set of builtins by name.
+///
+/// Clones share their entries until one of them is changed, so a registry
+/// is cheap to copy into the runtime. With the `sync` feature a registry
+/// can be shared between threads, to give interpreters on each the same
+/// builtins.
+#[derive(Clone, Debug)]
+pub struct Builtins(Shared<HashMap<String, Builtin>>);
+
+impl Builtins {
+    /// Creates a registry with no builtins at all.
+    pub fn empty() -> Self {
+        Builtins(Shared::new(HashMap::new()))
+    }
+
+    /// Creates a registry with every builtin of the language.
+    pub fn standard() -> Self {
+        let table = STANDARD
+            .iter()
+            .map(|&(name, category, arity, doc)| {
+                let builtin = Builtin {
+                    category,
+                    arity,
+                    doc: Cow::Borrowed(doc),
+                    implementation: Implementation::Standard(name),
+                };
+                (name.to_string(), builtin)
+            })
+            .collect();
+        Builtins(Shared::new(table))
+    }
+
+    /// Returns the builtin called `name`.
+    pub fn get(&self, name: &str) -> Option<&Builtin> {
+        self.0.get(name)
+    }
+
+    /// Returns whether there is a builtin called `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// Adds a builtin called `name`, returning the one it replaces.
+    pub fn insert(&mut self, name: &str, builtin: Builtin) -> Option<Builtin> {
+        Shared::make_mut(&mut self.0).insert(name.to_string(), builtin)
+    }
+
+    /// Removes the builtin called `name` and returns it.
+    pub fn remove(&mut self, name: &str) -> Option<Builtin> {
+        Shared::make_mut(&mut self.0).remove(name)
+    }
+
+    /// Removes every builtin in `category`.
+    pub fn remove_category(&mut self, category: Category) {
+        Shared::make_mut(&mut self.0).retain(|_, builtin| builtin.category != category);
+    }
+
+    /// Returns the names of the builtins, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.0.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// The standard builtins.
+impl Default for Builtins {
+    fn default() -> Self {
+        Builtins::standard()
+    }
+}
+
+use Category::*;
+
+/// The builtins of the language, all implemented by `apply_builtin_op`.
+#[rustfmt::skip]
+const STANDARD: &[(&str, Category, Arity, &str)] = &[
+    ("+", Numbers, Arity::at_least(0), "Adds numbers."),
+    ("*", Numbers, Arity::at_least(0), "Multiplies numbers."),
+    ("-", Numbers, Arity::at_least(1), "Subtracts numbers from the first, or negates one."),
+    ("/", Numbers, Arity::at_least(1), "Divides the first number by the rest."),
+    (">", Numbers, Arity::at_least(2), "Tests that numbers are decreasing."),
+    ("<", Numbers, Arity::at_least(2), "Tests that numbers are increasing."),
+    (">=", Numbers, Arity::at_least(2), "Tests that numbers are non-increasing."),
+    ("<=", Numbers, Arity::at_least(2), "Tests that numbers are non-decreasing."),
+    ("=", Numbers, Arity::at_least(2), "Tests that numbers are equal."),
+    ("modulo", Numbers, Arity::exact(2), "Remainder with the sign of the divisor."),
+    ("remainder", Numbers, Arity::exact(2), "Remainder with the sign of the dividend."),
+    ("quotient", Numbers, Arity::exact(2), "Integer division, truncating."),
+    ("abs", Numbers, Arity::exact(1), "Absolute value."),
+    ("min", Numbers, Arity::at_least(1), "Smallest of numbers or of a list of numbers."),
+    ("max", Numbers, Arity::at_least(1), "Largest of numbers or of a list of numbers."),
+    ("floor", Numbers, Arity::exact(1), "Rounds down."),
+    ("ceiling", Numbers, Arity::exact(1), "Rounds up."),
+    ("round", Numbers, Arity::exact(1), "Rounds to the nearest integer."),
+    ("truncate", Numbers, Arity::exact(1), "Rounds towards zero."),
+    ("sqrt", Numbers, Arity::exact(1), "Square root."),
+    ("exp", Numbers, Arity::exact(1), "e raised to a power."),
+    ("expt", Numbers, Arity::exact(2), "A base raised to a power."),
+    ("log", Numbers, Arity::range(1, 2), "Natural logarithm, or logarithm in a base."),
+    ("sin", Numbers, Arity::exact(1), "Sine of an angle in radians."),
+    ("cos", Numbers, Arity::exact(1), "Cosine of an angle in radians."),
+    ("tan", Numbers, Arity::exact(1), "Tangent of an angle in radians."),
+    ("atan", Numbers, Arity::range(1, 2), "Arctangent of y, or of y/x by quadrant."),
+    ("random", Numbers, Arity::range(0, 1), "Random number in [0, 1), or integer below a bound."),
+    ("random-seed", Numbers, Arity::exact(1), "Reseeds the random source."),
+    ("gcd", Numbers, Arity::at_least(0), "Greatest common divisor."),
+    ("lcm", Numbers, Arity::at_least(0), "Least common multiple."),
+    ("bit-and", Bits, Arity::at_least(0), "Bitwise and."),
+    ("bit-or", Bits, Arity::at_least(0), "Bitwise or."),
+    ("bit-xor", Bits, Arity::at_least(0), "Bitwise exclusive or."),
+    ("bit-not", Bits, Arity::exact(1), "Bitwise complement."),
+    ("shift-left", Bits, Arity::exact(2), "Shifts bits left."),
+    ("shift-right", Bits, Arity::exact(2), "Shifts bits right, keeping the sign."),
+    ("eq?", Predicates, Arity::exact(2), "Tests that two values are the same."),
+    ("eqv?", Predicates, Arity::exact(2), "Tests that two values are the same."),
+    ("equal?", Predicates, Arity::exact(2), "Tests that two values are structurally equal."),
+    ("number?", Predicates, Arity::exact(1), "Tests for a number."),
+    ("string?", Predicates, Arity::exact(1), "Tests for a string."),
+    ("symbol?", Predicates, Arity::exact(1), "Tests for a symbol."),
+    ("list?", Predicates, Arity::exact(1), "Tests for a list."),
+    ("map?", Predicates, Arity::exact(1), "Tests for a map."),
+    ("procedure?", Predicates, Arity::exact(1), "Tests for a function."),
+    ("bool?", Predicates, Arity::exact(1), "Tests for a boolean."),
+    ("boolean?", Predicates, Arity::exact(1), "Tests for a boolean."),
+    ("condition?", Predicates, Arity::exact(1), "Tests for a condition."),
+    ("list", Lists, Arity::at_least(0), "Makes a list of the arguments."),
+    ("flatten", Lists, Arity::range(1, 2), "Flattens nested lists, optionally to a depth."),
+    ("flatmap", Lists, Arity::exact(2), "Maps a function over a list and appends the results."),
+    ("append-map", Lists, Arity::exact(2), "Maps a function over a list and appends the results."),
+    ("distinct", Lists, Arity::exact(1), "Removes later duplicates from a list."),
+    ("remove-duplicates", Lists, Arity::exact(1), "Removes later duplicates from a list."),
+    ("partition", Lists, Arity::exact(2), "Splits a list by a predicate."),
+    ("group-by", Lists, Arity::exact(2), "Groups list items into an association list, in first-seen key order."),
+    ("any?", Lists, Arity::exact(2), "Tests whether a predicate holds for some item."),
+    ("every?", Lists, Arity::exact(2), "Tests whether a predicate holds for every item."),
+    ("count", Lists, Arity::exact(2), "Counts the items a predicate holds for."),
+    ("find", Lists, Arity::exact(2), "First item a predicate holds for."),
+    ("position", Lists, Arity::exact(2), "Index of the first item a predicate holds for."),
+    ("butlast", Lists, Arity::exact(1), "A list without its last item."),
+    ("list-tail", Lists, Arity::exact(2), "A list without its first n items."),
+    ("split-at", Lists, Arity::exact(2), "Splits a list after n items."),
+    ("concat", Strings, Arity::at_least(0), "Joins strings."),
+    ("string-length", Strings, Arity::exact(1), "Number of characters in a string."),
+    ("substring", Strings, Arity::range(2, 3), "Characters of a string from start to end."),
+    ("string-ref", Strings, Arity::exact(2), "Character of a string at an index."),
+    ("string->list", Strings, Arity::exact(1), "The characters of a string."),
+    ("list->string", Strings, Arity::exact(1), "Joins a list of characters."),
+    ("string-split", Strings, Arity::range(1, 2), "Splits a string at a delimiter or whitespace."),
+    ("string-join", Strings, Arity::range(1, 2), "Joins a list of strings with a delimiter."),
+    ("upcase", Strings, Arity::exact(1), "Converts a string to upper case."),
+    ("downcase", Strings, Arity::exact(1), "Converts a string to lower case."),
+    ("trim", Strings, Arity::exact(1), "Removes surrounding whitespace."),
+    ("trim-left", Strings, Arity::exact(1), "Removes leading whitespace."),
+    ("trim-right", Strings, Arity::exact(1), "Removes trailing whitespace."),
+    ("string->number", Strings, Arity::range(1, 2), "Parses a number, optionally in a radix."),
+    ("number->string", Strings, Arity::range(1, 2), "Writes a number, optionally in a radix."),
+    ("format-number", Strings, Arity::range(2, 3), "Writes a number with fixed decimals and thousands separators."),
+    ("string->symbol", Strings, Arity::exact(1), "The symbol with a name."),
+    ("symbol->string", Strings, Arity::exact(1), "The name of a symbol."),
+    ("string-contains?", Strings, Arity::exact(2), "Tests whether a string contains another."),
+    ("starts-with?", Strings, Arity::exact(2), "Tests whether a string starts with another."),
+    ("ends-with?", Strings, Arity::exact(2), "Tests whether a string ends with another."),
+    ("string-replace", Strings, Arity::range(3, 4), "Replaces occurrences of a substring."),
+    ("format", Strings, Arity::at_least(1), "Fills in the directives of a format string."),
+    ("char->integer", Strings, Arity::exact(1), "Code point of a character."),
+    ("integer->char", Strings, Arity::exact(1), "Character with a code point."),
+    ("char-upcase", Strings, Arity::exact(1), "Converts a character to upper case."),
+    ("char-downcase", Strings, Arity::exact(1), "Converts a character to lower case."),
+    ("char-alphabetic?", Strings, Arity::exact(1), "Tests for a letter."),
+    ("char-numeric?", Strings, Arity::exact(1), "Tests for a digit."),
+    ("char-whitespace?", Strings, Arity::exact(1), "Tests for whitespace."),
+    ("hash-map", Maps, Arity::at_least(0), "Makes a map from alternating keys and values."),
+    ("map-get", Maps, Arity::range(2, 3), "Value of a key in a map, or a default."),
+    ("map-keys", Maps, Arity::exact(1), "The keys of a map."),
+    ("alist->map", Maps, Arity::exact(1), "Converts an association list to a map."),
+    ("map->alist", Maps, Arity::exact(1), "Converts a map to an association list."),
+    ("alist-get", Maps, Arity::range(2, 3), "Value of a key in an association list, or a default."),
+    ("alist-update", Maps, Arity::exact(3), "Sets a key in an association list."),
+    ("alist-remove", Maps, Arity::exact(2), "Removes a key from an association list."),
+    ("print", Io, Arity::exact(1), "Displays a value followed by a newline."),
+    ("display", Io, Arity::exact(1), "Writes a value, strings without quotes."),
+    ("write", Io, Arity::exact(1), "Writes a value as it would be read."),
+    ("newline", Io, Arity::exact(0), "Writes a newline."),
+    ("read-line", Io, Arity::exact(0), "Reads a line of input, or false at the end."),
+    ("read", Evaluation, Arity::exact(1), "Parses a value from a string."),
+    ("read-string", Evaluation, Arity::exact(1), "Parses a value from a string."),
+    ("eval", Evaluation, Arity::exact(1), "Evaluates a value as code."),
+    ("break", Evaluation, Arity::exact(0), "Pauses in the debugger, if one is installed."),
+    ("profile-start", Evaluation, Arity::exact(0), "Starts counting and timing calls."),
+    ("profile-stop", Evaluation, Arity::exact(0), "Stops counting and timing calls."),
+    ("profile-data", Evaluation, Arity::exact(0), "The calls profiled, as a list of maps."),
+    ("profile-report", Evaluation, Arity::exact(0), "Writes a table of the calls profiled."),
+    ("memoize", Evaluation, Arity::exact(1), "Caches the results of a function."),
+    ("read-file", Filesystem, Arity::exact(1), "Contents of a file."),
+    ("write-file", Filesystem, Arity::exact(2), "Replaces the contents of a file."),
+    ("append-file", Filesystem, Arity::exact(2), "Appends to a file."),
+    ("file-exists?", Filesystem, Arity::exact(1), "Tests whether a path exists."),
+    ("directory?", Filesystem, Arity::exact(1), "Tests whether a path is a directory."),
+    ("list-dir", Filesystem, Arity::exact(1), "Names of the entries in a directory."),
+    ("delete-file", Filesystem, Arity::exact(1), "Deletes a file."),
+    ("getenv", Environment, Arity::exact(1), "Value of an environment variable, or false."),
+    ("setenv", Environment, Arity::exact(2), "Sets an environment variable."),
+    ("shell", Process, Arity::exact(1), "Runs a shell command."),
+    ("exec", Process, Arity::at_least(1), "Runs a program with arguments."),
+    ("exit", Process, Arity::range(0, 1), "Ends the program with a status."),
+    ("current-time", Time, Arity::exact(0), "Seconds since the Unix epoch."),
+    ("monotonic-ms", Time, Arity::exact(0), "Milliseconds on a clock that never goes back."),
+    ("sleep", Time, Arity::exact(1), "Pauses for a number of seconds."),
+    ("json-parse", Encoding, Arity::exact(1), "Parses JSON text."),
+    ("json-stringify", Encoding, Arity::range(1, 2), "Writes a value as JSON, optionally indented."),
+    ("base64-encode", Encoding, Arity::exact(1), "Encodes bytes as base64."),
+    ("base64-decode", Encoding, Arity::exact(1), "Decodes base64 text to a UTF-8 string."),
+    ("base64-decode-bytes", Encoding, Arity::exact(1), "Decodes base64 text to a list of bytes."),
+    ("hex-encode", Encoding, Arity::exact(1), "Encodes bytes as hexadecimal."),
+    ("hex-decode", Encoding, Arity::exact(1), "Decodes hexadecimal text to a UTF-8 string."),
+    ("hex-decode-bytes", Encoding, Arity::exact(1), "Decodes hexadecimal text to a list of bytes."),
+    #[cfg(feature = "hash")]
+    ("sha256", Encoding, Arity::exact(1), "SHA-256 digest in hexadecimal."),
+    #[cfg(feature = "hash")]
+    ("sha1", Encoding, Arity::exact(1), "SHA-1 digest in hexadecimal."),
+    #[cfg(feature = "hash")]
+    ("md5", Encoding, Arity::exact(1), "MD5 digest in hexadecimal."),
+    ("uuid", Encoding, Arity::exact(0), "A random version 4 UUID."),
+    ("csv-parse", Encoding, Arity::range(1, 2), "Parses CSV text into rows or maps."),
+    ("csv-write", Encoding, Arity::exact(1), "Writes rows as CSV text."),
+    ("raise", Conditions, Arity::range(2, 3), "Signals a condition with a kind and message."),
+    ("condition-message", Conditions, Arity::exact(1), "Message of a condition."),
+    ("condition-kind", Conditions, Arity::exact(1), "Kind of a condition."),
+    ("condition-data", Conditions, Arity::exact(1), "Data of a condition."),
+    ("check-equal?", Testing, Arity::exact(2), "Fails unless two values are equal."),
+    ("run-tests", Testing, Arity::exact(0), "Runs the tests defined with deftest."),
+];
@@ -0,0 +1,75 @@
+//! Byte encodings used by the `base64-*` and `hex-*` builtins.
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes bytes as standard, padded base64.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64, with or without padding.
+pub fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let digits = text.trim_end_matches('=');
+    if text.len() - digits.len() > 2 || digits.len() % 4 == 1 {
+        return Err("Invalid base64: wrong length or padding.".to_string());
+    }
+    let mut out = Vec::with_capacity(digits.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in digits.bytes() {
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| format!("Invalid base64: unexpected character '{}'.", c as char))?;
+        acc = acc << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Encodes bytes as lowercase hexadecimal.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hexadecimal in either case.
+pub fn hex_decode(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err("Invalid hex: odd number of digits.".to_string());
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            // `from_str_radix` alone would also accept a sign such as "+f".
+            if !pair.iter().all(u8::is_ascii_hexdigit) {
+                return Err(format!(
+                    "Invalid hex: unexpected digits '{}'.",
+                    String::from_utf8_lossy(pair)
+                ));
+            }
+            let digits = std::str::from_utf8(pair).expect("hex digits are ASCII");
+            Ok(u8::from_str_radix(digits, 16).expect("checked hex digits"))
+        })
+        .collect()
+}
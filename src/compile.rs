@@ -0,0 +1,542 @@
+//! A bytecode compiler and the stack machine that runs its output.
+//!
+//! [`compile`] lowers an expression to a [`Chunk`] of [`Op`]s, and a [`Vm`]
+//! runs it. The machine walks a flat list of operations instead of the
+//! nested lists of the source, and compiles each function body once rather
+//! than re-examining it on every call, which makes it the faster choice for
+//! code that calls the same functions many times.
+//!
+//! The tree-walking [`eval`] remains the reference implementation, and the
+//! two give the same results and errors, including backtraces, tail calls,
+//! tracing and the limits set in [`runtime`]. The machine takes a step of
+//! fuel wherever the evaluator would, so a budget runs out at the same
+//! point under either. Forms the compiler does not lower, such as `try` and
+//! `check-error`, are handed to [`eval`] whole.
+
+use crate::data::{Env, EqualKey, Expr};
+use crate::error::EvalError;
+use crate::eval::{Cache, eval, eval_lambda, lookup, track_allocation};
+use crate::runtime;
+use crate::shared::{Shared, Weak};
+use crate::symbol::Symbol;
+use std::collections::HashMap;
+
+/// One operation of compiled code. Operations take their operands from, and
+/// leave their results on, the value stack of the machine.
+#[derive(Clone, Debug)]
+pub enum Op {
+    /// Takes an evaluation step, as the evaluator does before each
+    /// expression it evaluates, other than arguments that are not calls.
+    Step,
+    /// Pushes a value.
+    Const(Expr),
+    /// Pushes the value of a variable.
+    Load(Symbol),
+    /// Pops a value and binds it to a name in the current scope, pushing the
+    /// name as a symbol.
+    Define(Symbol),
+    /// Pushes a function closing over the current scope.
+    Lambda {
+        /// The names of the function's parameters.
+        params: Shared<[Symbol]>,
+        /// The body of the function.
+        body: Shared<Expr>,
+    },
+    /// Pops a boolean and jumps to an operation if it is false.
+    JumpIfFalse(usize),
+    /// Jumps to an operation.
+    Jump(usize),
+    /// Pops `argc` arguments and calls the builtin or function called
+    /// `name` with them.
+    CallNamed {
+        /// The name the function is called by.
+        name: Symbol,
+        /// How many arguments to pass.
+        argc: usize,
+        /// Whether the call is the last thing its function does, so that it
+        /// can replace the running call.
+        tail: bool,
+    },
+    /// Pops a function, then `argc` arguments, and calls the function.
+    Call {
+        /// The expression the function came from, for errors.
+        operator: Expr,
+        /// How many arguments to pass.
+        argc: usize,
+        /// Whether the call is in tail position.
+        tail: bool,
+    },
+    /// Evaluates an expression with [`eval`] and pushes its value.
+    Eval(Shared<Expr>),
+    /// Pops a value and returns it from the running code.
+    Return,
+}
+
+/// Compiled code, which is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Chunk(Shared<[Op]>);
+
+impl Chunk {
+    /// Returns the operations, in order.
+    pub fn ops(&self) -> &[Op] {
+        &self.0
+    }
+}
+
+/// Compiles an expression to be run by a [`Vm`].
+pub fn compile(expr: &Expr) -> Chunk {
+    compile_body(expr, false)
+}
+
+/// Compiles `expr` followed by a return. Function bodies are compiled with
+/// `tail` set, so that calls they end with replace the running call.
+fn compile_body(expr: &Expr, tail: bool) -> Chunk {
+    let mut ops = Vec::new();
+    compile_expr(expr, tail, &mut ops);
+    ops.push(Op::Return);
+    Chunk(ops.into())
+}
+
+/// Compiles an argument of a call. The evaluator takes no step for an
+/// argument that is not a call, so neither does the machine.
+fn compile_arg(arg: &Expr, ops: &mut Vec<Op>) {
+    match arg {
+        Expr::Symbol(s) => ops.push(Op::Load(s.clone())),
+        Expr::List(list) if !list.is_empty() => compile_expr(arg, false, ops),
+        arg => ops.push(Op::Const(arg.clone())),
+    }
+}
+
+fn compile_expr(expr: &Expr, tail: bool, ops: &mut Vec<Op>) {
+    let list = match expr {
+        Expr::List(list) if !list.is_empty() => list,
+        expr => {
+            ops.push(Op::Step);
+            return compile_arg(expr, ops);
+        }
+    };
+    let args = &list[1..];
+    let Expr::Symbol(s) = &list[0] else {
+        ops.push(Op::Step);
+        for arg in args {
+            compile_arg(arg, ops);
+        }
+        compile_expr(&list[0], false, ops);
+        return ops.push(Op::Call {
+            operator: list[0].clone(),
+            argc: args.len(),
+            tail,
+        });
+    };
+    match (s.as_str(), args) {
+        ("define", [Expr::Symbol(name), value]) => {
+            ops.push(Op::Step);
+            compile_expr(value, false, ops);
+            ops.push(Op::Define(name.clone()));
+        }
+        ("lambda", _) => match eval_lambda(args, &Env::new()) {
+            Ok(Expr::Func { params, body, .. }) => {
+                ops.push(Op::Step);
+                ops.push(Op::Lambda { params, body });
+            }
+            _ => ops.push(Op::Eval(Shared::new(expr.clone()))),
+        },
+        ("if", [condition, then, otherwise]) => {
+            ops.push(Op::Step);
+            compile_expr(condition, false, ops);
+            let jump_if_false = ops.len();
+            ops.push(Op::JumpIfFalse(0));
+            compile_expr(then, tail, ops);
+            let jump = ops.len();
+            ops.push(Op::Jump(0));
+            ops[jump_if_false] = Op::JumpIfFalse(ops.len());
+            compile_expr(otherwise, tail, ops);
+            ops[jump] = Op::Jump(ops.len());
+        }
+        // The remaining special forms, and malformed ones, are left to the
+        // evaluator, which also reports their errors.
+        ("define" | "if" | "try" | "trace" | "untrace" | "deftest" | "check-error", _) => {
+            ops.push(Op::Eval(Shared::new(expr.clone())));
+        }
+        _ => {
+            ops.push(Op::Step);
+            for arg in args {
+                compile_arg(arg, ops);
+            }
+            ops.push(Op::CallNamed {
+                name: s.clone(),
+                argc: args.len(),
+                tail,
+            });
+        }
+    }
+}
+
+/// A frame of the machine's call stack.
+enum Frame {
+    /// Code is running.
+    Code {
+        chunk: Chunk,
+        /// The next operation to run.
+        ip: usize,
+        env: Env,
+        /// Whether this is the body of a function call, rather than the code
+        /// given to [`Vm::run`].
+        call: bool,
+        /// The name the running function was called by, for backtraces.
+        caller: Option<Symbol>,
+        /// Whether the call is being profiled.
+        profiled: bool,
+    },
+    /// A traced call is running; its result is written indented by `indent`.
+    Traced { indent: String },
+    /// A memoized function is running; its result is cached under `key`.
+    Memoize { cache: Cache, key: EqualKey },
+}
+
+/// How many compiled bodies a machine keeps before it first drops those of
+/// functions that no longer exist.
+const MIN_BODIES: usize = 256;
+
+/// A machine that runs compiled code.
+///
+/// It keeps the compiled bodies of the functions it has called, so reusing
+/// one machine avoids compiling the same functions again. It does not keep
+/// the functions alive: the bodies of those that have been dropped are
+/// dropped in turn whenever the number kept has doubled.
+#[derive(Debug)]
+pub struct Vm {
+    /// Compiled function bodies by address. A weak handle on the body is
+    /// kept alongside, which holds on to the address so that another
+    /// expression cannot reuse it, and tells whether the body still exists.
+    bodies: HashMap<*const Expr, (Weak<Expr>, Chunk)>,
+    /// How many bodies may be kept before those of dropped functions are.
+    threshold: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm {
+            bodies: HashMap::new(),
+            threshold: MIN_BODIES,
+        }
+    }
+}
+
+impl Vm {
+    /// Creates a machine that has compiled no functions yet.
+    pub fn new() -> Self {
+        Vm::default()
+    }
+
+    /// Runs `chunk` in `env` and returns its value.
+    pub fn run(&mut self, chunk: &Chunk, env: &mut Env) -> Result<Expr, EvalError> {
+        let mut frames = vec![Frame::Code {
+            chunk: chunk.clone(),
+            ip: 0,
+            env: env.clone(),
+            call: false,
+            caller: None,
+            profiled: false,
+        }];
+        let mut values = Vec::new();
+        loop {
+            if let Some(result) = self.step(&mut frames, &mut values)
+                && let Some(result) = deliver(result, &mut frames, &mut values)
+            {
+                return result;
+            }
+        }
+    }
+
+    /// Runs the next operation of the innermost code. Returns the value or
+    /// error that the code produced, if it did not simply continue.
+    fn step(
+        &mut self,
+        frames: &mut Vec<Frame>,
+        values: &mut Vec<Expr>,
+    ) -> Option<Result<Expr, EvalError>> {
+        let Some(Frame::Code { chunk, ip, env, .. }) = frames.last_mut() else {
+            unreachable!("only code frames run");
+        };
+        let chunk = chunk.clone();
+        let op = &chunk.ops()[*ip];
+        *ip += 1;
+        match op {
+            Op::Step => {
+                if let Err(e) = runtime::take_step() {
+                    return Some(Err(e));
+                }
+            }
+            Op::Const(value) => values.push(value.clone()),
+            Op::Load(name) => match lookup(name, env) {
+                Ok(value) => values.push(value),
+                Err(e) => return Some(Err(e)),
+            },
+            Op::Define(name) => {
+                let value = values.pop().expect("define has a value");
+                env.insert(name.clone(), value);
+                values.push(Expr::Symbol(name.clone()));
+            }
+            Op::Lambda { params, body } => values.push(Expr::Func {
+                params: params.clone(),
+                body: body.clone(),
+                env: env.clone(),
+            }),
+            Op::JumpIfFalse(target) => match values.pop() {
+                Some(Expr::Bool(true)) => {}
+                Some(Expr::Bool(false)) => *ip = *target,
+                _ => {
+                    return Some(Err(
+                        "The condition for 'if' must evaluate to a boolean.".into()
+                    ));
+                }
+            },
+            Op::Jump(target) => *ip = *target,
+            Op::CallNamed { name, argc, tail } => {
+                let mut env = env.clone();
+                let start = values.len() - argc;
+                return self.call_named(name, values, start, *tail, &mut env, frames);
+            }
+            Op::Call {
+                operator,
+                argc,
+                tail,
+            } => {
+                let func = values.pop().expect("call has an operator");
+                let args = values.split_off(values.len() - argc);
+                return match func {
+                    func @ (Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. }) => {
+                        self.call(func, args, None, *tail, frames)
+                    }
+                    _ => Some(Err(EvalError::NotAFunction(operator.clone()))),
+                };
+            }
+            Op::Eval(expr) => return Some(eval(expr, &mut env.clone())),
+            Op::Return => {
+                let value = values.pop().expect("return has a value");
+                if let Some(Frame::Code {
+                    call: true,
+                    profiled,
+                    ..
+                }) = frames.pop()
+                {
+                    if profiled {
+                        runtime::profile_exit();
+                    }
+                    runtime::exit_call();
+                }
+                return Some(Ok(value));
+            }
+        }
+        None
+    }
+
+    /// Calls the builtin or function called `name` with the values from
+    /// `start` on, as the evaluator does: tracing the call if asked to, and
+    /// preferring a builtin to a variable.
+    fn call_named(
+        &mut self,
+        name: &Symbol,
+        values: &mut Vec<Expr>,
+        start: usize,
+        mut tail: bool,
+        env: &mut Env,
+        frames: &mut Vec<Frame>,
+    ) -> Option<Result<Expr, EvalError>> {
+        if runtime::is_traced(name) {
+            let mut call = vec![Expr::Symbol(name.clone())];
+            call.extend(values[start..].iter().cloned());
+            let indent = "  ".repeat(runtime::enter_trace());
+            if let Err(e) =
+                runtime::write_output(&format!("{}> {}\n", indent, Expr::List(call.into())))
+            {
+                runtime::exit_trace();
+                return Some(Err(e.into()));
+            }
+            frames.push(Frame::Traced { indent });
+            tail = false;
+        }
+        // Builtins borrow their arguments where they lie.
+        if let Some(builtin) = runtime::builtins().get(name) {
+            let profiled = runtime::profile_enter(name);
+            let result = builtin.call(&values[start..], env);
+            if profiled {
+                runtime::profile_exit();
+            }
+            values.truncate(start);
+            return Some(track_allocation(result));
+        }
+        let args = values.split_off(start);
+        match lookup(name, env) {
+            Ok(func @ (Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. })) => {
+                self.call(func, args, Some(name.clone()), tail, frames)
+            }
+            Ok(_) => Some(Err(EvalError::NotAFunction(Expr::Symbol(name.clone())))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Calls a function value. A call to a function defined in Lisp pushes
+    /// a frame for its body, or replaces the running one for a tail call,
+    /// and returns `None`; other calls return their result.
+    fn call(
+        &mut self,
+        func: Expr,
+        args: Vec<Expr>,
+        caller: Option<Symbol>,
+        tail: bool,
+        frames: &mut Vec<Frame>,
+    ) -> Option<Result<Expr, EvalError>> {
+        let (params, body, env) = match func {
+            Expr::Memoized { func, cache } => {
+                let key = EqualKey(Expr::List(args.clone().into()));
+                if let Some(result) = cache.borrow().get(&key) {
+                    return Some(Ok(result.clone()));
+                }
+                frames.push(Frame::Memoize { cache, key });
+                return self.call(*func, args, caller, false, frames);
+            }
+            Expr::NativeFn(func) => {
+                let profiled = caller.as_deref().is_some_and(runtime::profile_enter);
+                let result = runtime::take_step().and_then(|_| func.call(&args));
+                if profiled {
+                    runtime::profile_exit();
+                }
+                return Some(track_allocation(result));
+            }
+            Expr::Func { params, body, env } => (params, body, env),
+            func => return Some(Err(EvalError::NotAFunction(func))),
+        };
+        let in_call = |e: EvalError| match &caller {
+            Some(name) => e.in_call(name),
+            None => e,
+        };
+        if params.len() != args.len() {
+            let e = EvalError::ArityMismatch {
+                expected: params.len(),
+                got: args.len(),
+            };
+            return Some(Err(in_call(e)));
+        }
+        if let Err(e) = runtime::take_step().and_then(|_| runtime::check_interrupt()) {
+            return Some(Err(in_call(e)));
+        }
+        let mut func_env = env.child();
+        for (param_name, arg_value) in params.iter().zip(args) {
+            func_env.insert(param_name.clone(), arg_value);
+        }
+        let chunk = self.body(body);
+        match frames.last_mut() {
+            // A call in tail position takes over its caller's frame, ending
+            // the caller's profiled call.
+            Some(frame @ Frame::Code { call: true, .. }) if tail => {
+                if let Frame::Code { profiled: true, .. } = frame {
+                    runtime::profile_exit();
+                }
+                *frame = Frame::Code {
+                    chunk,
+                    ip: 0,
+                    env: func_env,
+                    call: true,
+                    profiled: caller.as_deref().is_some_and(runtime::profile_enter),
+                    caller,
+                };
+            }
+            _ => {
+                if let Err(e) = runtime::enter_call() {
+                    return Some(Err(in_call(e)));
+                }
+                frames.push(Frame::Code {
+                    chunk,
+                    ip: 0,
+                    env: func_env,
+                    call: true,
+                    profiled: caller.as_deref().is_some_and(runtime::profile_enter),
+                    caller,
+                });
+            }
+        }
+        None
+    }
+
+    /// Returns the compiled code of a function body, compiling it the first
+    /// time.
+    fn body(&mut self, body: Shared<Expr>) -> Chunk {
+        if let Some((_, chunk)) = self.bodies.get(&Shared::as_ptr(&body)) {
+            return chunk.clone();
+        }
+        if self.bodies.len() >= self.threshold {
+            self.bodies.retain(|_, (body, _)| body.strong_count() > 0);
+            self.threshold = MIN_BODIES.max(2 * self.bodies.len());
+        }
+        let chunk = compile_body(&body, true);
+        self.bodies.insert(
+            Shared::as_ptr(&body),
+            (Shared::downgrade(&body), chunk.clone()),
+        );
+        chunk
+    }
+
+    /// Returns how many compiled function bodies the machine keeps.
+    pub fn compiled_bodies(&self) -> usize {
+        self.bodies.len()
+    }
+}
+
+/// Hands a value or error to the innermost frame waiting for one, unwinding
+/// frames that an error passes through. Returns the final result once no
+/// frames are left.
+fn deliver(
+    mut result: Result<Expr, EvalError>,
+    frames: &mut Vec<Frame>,
+    values: &mut Vec<Expr>,
+) -> Option<Result<Expr, EvalError>> {
+    loop {
+        match frames.last() {
+            None => return Some(result),
+            Some(Frame::Traced { indent }) => {
+                runtime::exit_trace();
+                result = result.and_then(|result| {
+                    runtime::write_output(&format!("{}< {}\n", indent, result))?;
+                    Ok(result)
+                });
+            }
+            Some(Frame::Memoize { cache, key }) => {
+                if let Ok(result) = &result {
+                    cache.borrow_mut().insert(key.clone(), result.clone());
+                }
+            }
+            Some(Frame::Code {
+                call,
+                caller,
+                profiled,
+                ..
+            }) => match result {
+                Ok(value) => {
+                    values.push(value);
+                    return None;
+                }
+                Err(e) => {
+                    result = Err(match caller {
+                        Some(name) if *call => e.in_call(name),
+                        _ => e,
+                    });
+                    if *profiled {
+                        runtime::profile_exit();
+                    }
+                    if *call {
+                        runtime::exit_call();
+                    }
+                }
+            },
+        }
+        frames.pop();
+    }
+}
+
+/// Compiles and runs an expression on a new [`Vm`]. It gives the same result
+/// as [`eval`].
+pub fn eval_compiled(expr: &Expr, env: &mut Env) -> Result<Expr, EvalError> {
+    Vm::new().run(&compile(expr), env)
+}
@@ -0,0 +1,377 @@
+//! Conversions between Lisp values and Rust types, for writing native
+//! functions as plain Rust functions.
+//!
+//! [`native_fn!`](crate::native_fn) wraps a Rust function or closure whose
+//! parameter types implement [`FromExpr`] and whose return type implements
+//! [`IntoResult`], checking the number and types of the arguments so that
+//! the function itself only sees values of the types it asked for.
+//!
+//! [`Expr`] also converts from and, with [`TryFrom`], to the plain Rust
+//! types, so hosts can build arguments and take results apart without
+//! matching on variants.
+
+use crate::data::Expr;
+use crate::error::EvalError;
+use crate::shared::Shared;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Types that an argument of a native function can be converted to.
+pub trait FromExpr<'a>: Sized {
+    /// What the argument must be, such as `a number`, for error messages.
+    const EXPECTED: &'static str;
+
+    /// Converts `expr`, or returns `None` if it is of the wrong type.
+    fn from_expr(expr: &'a Expr) -> Option<Self>;
+}
+
+impl FromExpr<'_> for f64 {
+    const EXPECTED: &'static str = "a number";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FromExpr<'_> for i64 {
+    const EXPECTED: &'static str = "an integer";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            // `i64::MAX as f64` rounds up to 2^63, which is out of range.
+            Expr::Number(n)
+                if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 =>
+            {
+                Some(*n as i64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromExpr<'_> for usize {
+    const EXPECTED: &'static str = "a non-negative integer";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n < usize::MAX as f64 => {
+                Some(*n as usize)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromExpr<'_> for bool {
+    const EXPECTED: &'static str = "a boolean";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromExpr<'a> for &'a str {
+    const EXPECTED: &'static str = "a string";
+
+    fn from_expr(expr: &'a Expr) -> Option<Self> {
+        match expr {
+            Expr::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl FromExpr<'_> for String {
+    const EXPECTED: &'static str = "a string";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        <&str>::from_expr(expr).map(str::to_string)
+    }
+}
+
+impl<'a> FromExpr<'a> for &'a [Expr] {
+    const EXPECTED: &'static str = "a list";
+
+    fn from_expr(expr: &'a Expr) -> Option<Self> {
+        match expr {
+            Expr::List(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromExpr<'a> for &'a Expr {
+    const EXPECTED: &'static str = "a value";
+
+    fn from_expr(expr: &'a Expr) -> Option<Self> {
+        Some(expr)
+    }
+}
+
+impl FromExpr<'_> for Expr {
+    const EXPECTED: &'static str = "a value";
+
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        Some(expr.clone())
+    }
+}
+
+/// Types that a native function can return as a Lisp value: anything that
+/// converts into an [`Expr`].
+pub trait ToExpr {
+    /// Converts the value.
+    fn to_expr(self) -> Expr;
+}
+
+impl<T: Into<Expr>> ToExpr for T {
+    fn to_expr(self) -> Expr {
+        self.into()
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(n: f64) -> Self {
+        Expr::Number(n)
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(n: i64) -> Self {
+        Expr::Number(n as f64)
+    }
+}
+
+impl From<usize> for Expr {
+    fn from(n: usize) -> Self {
+        Expr::Number(n as f64)
+    }
+}
+
+impl From<bool> for Expr {
+    fn from(b: bool) -> Self {
+        Expr::Bool(b)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(s: String) -> Self {
+        Expr::String(s.into())
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(s: &str) -> Self {
+        Expr::String(s.into())
+    }
+}
+
+/// `()` becomes the empty list, which builtins return when they have no
+/// useful value.
+impl From<()> for Expr {
+    fn from(_: ()) -> Self {
+        Expr::empty_list()
+    }
+}
+
+impl<T: Into<Expr>> From<Vec<T>> for Expr {
+    fn from(items: Vec<T>) -> Self {
+        Expr::List(Shared::new(items.into_iter().map(Into::into).collect()))
+    }
+}
+
+/// `None` becomes `()`, as JSON `null` does.
+impl<T: Into<Expr>> From<Option<T>> for Expr {
+    fn from(value: Option<T>) -> Self {
+        value.map_or_else(Expr::empty_list, Into::into)
+    }
+}
+
+impl<T: Into<Expr>> From<HashMap<String, T>> for Expr {
+    fn from(map: HashMap<String, T>) -> Self {
+        Expr::Map(map.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+/// A value that is not of the Rust type it was converted to with
+/// [`TryFrom`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConversionError {
+    /// What the value had to be, such as `a number`.
+    pub expected: &'static str,
+    /// The value, or for a list or map the item that could not be converted.
+    pub got: Expr,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected {}, but got {}.", self.expected, self.got)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ConversionError> for EvalError {
+    fn from(e: ConversionError) -> Self {
+        EvalError::Other(e.to_string())
+    }
+}
+
+/// Implements `TryFrom<Expr>` for types that implement [`FromExpr`] for
+/// every lifetime.
+macro_rules! try_from_expr {
+    ($($ty:ty),*) => {$(
+        impl TryFrom<Expr> for $ty {
+            type Error = ConversionError;
+
+            fn try_from(expr: Expr) -> Result<Self, ConversionError> {
+                <$ty>::from_expr(&expr).ok_or(ConversionError {
+                    expected: <$ty as FromExpr>::EXPECTED,
+                    got: expr,
+                })
+            }
+        }
+    )*};
+}
+
+try_from_expr!(f64, i64, usize, bool, String);
+
+impl<T: TryFrom<Expr, Error = ConversionError>> TryFrom<Expr> for Vec<T> {
+    type Error = ConversionError;
+
+    fn try_from(expr: Expr) -> Result<Self, ConversionError> {
+        match expr {
+            Expr::List(items) => Shared::unwrap_or_clone(items)
+                .into_iter()
+                .map(T::try_from)
+                .collect(),
+            got => Err(ConversionError {
+                expected: "a list",
+                got,
+            }),
+        }
+    }
+}
+
+/// `()` becomes `None`, so an `Option<Vec<T>>` is never `Some` of an empty
+/// vector.
+impl<T: TryFrom<Expr, Error = ConversionError>> TryFrom<Expr> for Option<T> {
+    type Error = ConversionError;
+
+    fn try_from(expr: Expr) -> Result<Self, ConversionError> {
+        match expr {
+            Expr::List(items) if items.is_empty() => Ok(None),
+            expr => T::try_from(expr).map(Some),
+        }
+    }
+}
+
+impl<T: TryFrom<Expr, Error = ConversionError>> TryFrom<Expr> for HashMap<String, T> {
+    type Error = ConversionError;
+
+    fn try_from(expr: Expr) -> Result<Self, ConversionError> {
+        match expr {
+            Expr::Map(map) => map
+                .into_iter()
+                .map(|(k, v)| Ok((k, T::try_from(v)?)))
+                .collect(),
+            got => Err(ConversionError {
+                expected: "a map",
+                got,
+            }),
+        }
+    }
+}
+
+/// What a native function can return: a value, or a `Result` whose error
+/// becomes the error of the call.
+pub trait IntoResult {
+    /// Converts the returned value.
+    fn into_result(self) -> Result<Expr, EvalError>;
+}
+
+impl<T: ToExpr> IntoResult for T {
+    fn into_result(self) -> Result<Expr, EvalError> {
+        Ok(self.to_expr())
+    }
+}
+
+impl<T: ToExpr, E: Into<EvalError>> IntoResult for Result<T, E> {
+    fn into_result(self) -> Result<Expr, EvalError> {
+        self.map(ToExpr::to_expr).map_err(Into::into)
+    }
+}
+
+/// Converts the argument at `*index` for the native function `op`, moving
+/// `index` on to the next one. Used by [`native_fn!`](crate::native_fn).
+#[doc(hidden)]
+pub fn next_arg<'a, T: FromExpr<'a>>(
+    op: &str,
+    args: &'a [Expr],
+    index: &mut usize,
+) -> Result<T, EvalError> {
+    let arg = &args[*index];
+    *index += 1;
+    T::from_expr(arg).ok_or_else(|| EvalError::TypeMismatch {
+        op: op.to_string(),
+        expected: format!("{} as argument {}", T::EXPECTED, index),
+        got: arg.clone(),
+    })
+}
+
+/// Checks that a native function was given `expected` arguments. Used by
+/// [`native_fn!`](crate::native_fn).
+#[doc(hidden)]
+pub fn check_arity(args: &[Expr], expected: usize) -> Result<(), EvalError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(EvalError::ArityMismatch {
+            expected,
+            got: args.len(),
+        })
+    }
+}
+
+/// Wraps a Rust function as the body of a native function, for
+/// [`Interpreter::register`](crate::Interpreter::register) or
+/// [`NativeFn::new`](crate::NativeFn::new).
+///
+/// Either name a function and its parameter types, as
+/// `native_fn!(scale(f64, &str))`, or give a name for error messages and a
+/// closure with typed parameters, as
+/// `native_fn!("scale", |x: f64, unit: &str| x * 2.0)`. Parameter types must
+/// implement [`FromExpr`] and the result [`IntoResult`]. Calls with the
+/// wrong number of arguments fail with [`EvalError::ArityMismatch`], and
+/// arguments of the wrong type with [`EvalError::TypeMismatch`].
+#[macro_export]
+macro_rules! native_fn {
+    ($func:ident($($ty:ty),* $(,)?)) => {
+        move |args: &[$crate::Expr]| -> ::std::result::Result<$crate::Expr, $crate::EvalError> {
+            $crate::convert::check_arity(args, <[&str]>::len(&[$(stringify!($ty)),*]))?;
+            let mut index = 0;
+            let result = $func($(
+                $crate::convert::next_arg::<$ty>(stringify!($func), args, &mut index)?
+            ),*);
+            $crate::convert::IntoResult::into_result(result)
+        }
+    };
+    // `||` is a single token, so closures without parameters need a rule
+    // of their own.
+    ($name:expr, || $body:expr) => {
+        $crate::native_fn!($name, | | $body)
+    };
+    ($name:expr, |$($arg:ident: $ty:ty),* $(,)?| $body:expr) => {
+        move |args: &[$crate::Expr]| -> ::std::result::Result<$crate::Expr, $crate::EvalError> {
+            $crate::convert::check_arity(args, <[&str]>::len(&[$(stringify!($ty)),*]))?;
+            let mut index = 0;
+            $(let $arg: $ty = $crate::convert::next_arg($name, args, &mut index)?;)*
+            $crate::convert::IntoResult::into_result($body)
+        }
+    };
+}
@@ -0,0 +1,91 @@
+//! Reading and writing comma-separated values for the `csv-*` builtins.
+//!
+//! Fields follow RFC 4180: a field containing a comma, a double quote or a
+//! line break is enclosed in double quotes, with embedded quotes doubled.
+
+/// Splits CSV text into rows of fields.
+///
+/// Both `\n` and `\r\n` end a record, and a trailing line break does not
+/// produce an empty final row.
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    // Whether the current field was quoted, so that a final `""` still
+    // counts as a record.
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start_line = line;
+                quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(format!(
+                                "Invalid CSV: unterminated quoted field starting on line {}.",
+                                start_line
+                            ));
+                        }
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\n' | '\r')) {
+                    return Err(format!(
+                        "Invalid CSV: unexpected character after quoted field on line {}.",
+                        line
+                    ));
+                }
+            }
+            ',' => {
+                quoted = false;
+                row.push(std::mem::take(&mut field));
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                line += 1;
+                quoted = false;
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted || !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Joins rows of fields into CSV text, ending every row with `\n`.
+pub fn write(rows: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|field| quote(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
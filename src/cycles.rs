@@ -0,0 +1,270 @@
+//! Freeing scopes that only keep each other alive.
+//!
+//! A function holds on to the scope it was created in, and that scope often
+//! holds the function, as when a function is defined with `define`. Counting
+//! references never frees such a cycle, so every scope is registered here
+//! when it is created, and each thread now and then looks through the scopes
+//! it created for ones that nothing outside them refers to, and empties
+//! them. That frees the functions in them, and so the scopes themselves.
+//!
+//! This happens by itself as scopes are created. Call [`collect`] to do it
+//! at once, for example after dropping an environment.
+
+use crate::data::{EqualKey, Expr, Frame};
+use crate::shared::{Lock, Shared, Weak};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// How many scopes the registry holds before it is first looked through.
+const MIN_THRESHOLD: usize = 1024;
+
+type Scope = Shared<Lock<Frame>>;
+type Cache = Shared<Lock<HashMap<EqualKey, Expr>>>;
+
+/// The scopes created on one thread that may still be alive.
+struct Registry {
+    scopes: Vec<Weak<Lock<Frame>>>,
+    /// How many scopes may be registered before the registry is next looked
+    /// through.
+    threshold: usize,
+    /// How many scopes were alive after the last collection.
+    survivors: usize,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = const {
+        RefCell::new(Registry {
+            scopes: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            survivors: 0,
+        })
+    };
+}
+
+/// Registers a new scope, looking through the registry first if it is full.
+pub(crate) fn register(scope: &Scope) {
+    let full = REGISTRY
+        .try_with(|registry| {
+            let mut registry = registry.borrow_mut();
+            registry.scopes.push(Shared::downgrade(scope));
+            registry.scopes.len() >= registry.threshold
+        })
+        .unwrap_or(false);
+    if full {
+        sweep(false);
+    }
+}
+
+/// Frees the scopes created on the current thread that are only kept alive
+/// by cycles, returning how many there were.
+///
+/// This runs by itself once enough scopes have been created since it last
+/// did, so hosts only need it to free memory at a particular moment.
+pub fn collect() -> usize {
+    sweep(true)
+}
+
+/// Forgets the registered scopes that have been freed, then collects the
+/// cycles among the rest if `force` is set or they have doubled in number
+/// since the last collection.
+fn sweep(force: bool) -> usize {
+    let Ok(scopes) = REGISTRY.try_with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.scopes.retain(|scope| scope.strong_count() > 0);
+        let live = registry.scopes.len();
+        if force || live >= MIN_THRESHOLD.max(2 * registry.survivors) {
+            Some(registry.scopes.iter().filter_map(Weak::upgrade).collect())
+        } else {
+            registry.threshold = MIN_THRESHOLD.max(2 * live);
+            None
+        }
+    }) else {
+        return 0;
+    };
+    let Some(scopes) = scopes else {
+        return 0;
+    };
+    let freed = free_cycles(scopes).unwrap_or(0);
+    REGISTRY
+        .try_with(|registry| {
+            let mut registry = registry.borrow_mut();
+            registry.scopes.retain(|scope| scope.strong_count() > 0);
+            registry.survivors = registry.scopes.len();
+            registry.threshold = MIN_THRESHOLD.max(2 * registry.survivors);
+        })
+        .ok();
+    freed
+}
+
+/// Something a reference may point to: a registered scope, or a list or
+/// `memoize` cache found in one.
+enum Node {
+    Scope(usize),
+    List(Shared<Vec<Expr>>),
+    Cache(Cache),
+}
+
+/// The references found between the scopes being collected and the lists
+/// and caches in them.
+struct Graph {
+    nodes: Vec<Node>,
+    index: HashMap<*const (), usize>,
+    /// The references from each node to the others.
+    edges: Vec<Vec<usize>>,
+    /// How many references to each node come from the others.
+    internal: Vec<usize>,
+}
+
+impl Graph {
+    /// Records the references from node `from` held by `expr`.
+    fn visit(&mut self, from: usize, expr: &Expr) {
+        match expr {
+            Expr::List(items) => {
+                let to = self.node(Shared::as_ptr(items).cast(), || Node::List(items.clone()));
+                self.edge(from, to);
+            }
+            Expr::Map(map) => map.values().for_each(|value| self.visit(from, value)),
+            // The body is code, which rarely holds values that refer to
+            // scopes. Anything it does refer to counts as referred to from
+            // outside, so it is kept.
+            Expr::Func { env, .. } => {
+                if let Some(&to) = self.index.get(&Shared::as_ptr(&env.0).cast()) {
+                    self.edge(from, to);
+                }
+            }
+            Expr::Memoized { func, cache } => {
+                self.visit(from, func);
+                let to = self.node(Shared::as_ptr(cache).cast(), || Node::Cache(cache.clone()));
+                self.edge(from, to);
+            }
+            Expr::Condition { data, .. } => self.visit(from, data),
+            _ => {}
+        }
+    }
+
+    /// Returns the node at `ptr`, adding it with `node` if it is new.
+    fn node(&mut self, ptr: *const (), node: impl FnOnce() -> Node) -> usize {
+        *self.index.entry(ptr).or_insert_with(|| {
+            self.nodes.push(node());
+            self.edges.push(Vec::new());
+            self.internal.push(0);
+            self.nodes.len() - 1
+        })
+    }
+
+    fn edge(&mut self, from: usize, to: usize) {
+        self.edges[from].push(to);
+        self.internal[to] += 1;
+    }
+
+    /// Returns how many references to each node there are, not counting
+    /// the one held by the graph.
+    fn counts(&self, scopes: &[Scope]) -> Vec<usize> {
+        self.nodes
+            .iter()
+            .map(|node| match node {
+                Node::Scope(i) => Shared::strong_count(&scopes[*i]),
+                Node::List(list) => Shared::strong_count(list),
+                Node::Cache(cache) => Shared::strong_count(cache),
+            } - 1)
+            .collect()
+    }
+}
+
+/// Finds the scopes among `scopes` that are only referred to by each other,
+/// and by lists and caches that are, and empties them. Returns `None`,
+/// freeing nothing, if a scope or cache is being changed meanwhile.
+///
+/// The scopes are locked while their references are counted, so none of them
+/// change. Any count that changes before the end means that something else
+/// got hold of a reference, so nothing is freed.
+fn free_cycles(scopes: Vec<Scope>) -> Option<usize> {
+    let frames = scopes
+        .iter()
+        .map(|scope| scope.try_borrow().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let mut graph = Graph {
+        nodes: Vec::new(),
+        index: HashMap::new(),
+        edges: Vec::new(),
+        internal: Vec::new(),
+    };
+    for (i, scope) in scopes.iter().enumerate() {
+        graph.node(Shared::as_ptr(scope).cast(), || Node::Scope(i));
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        if let Some(parent) = &frame.parent
+            && let Some(&to) = graph.index.get(&Shared::as_ptr(&parent.0).cast())
+        {
+            graph.edge(i, to);
+        }
+        for value in frame.vars.values() {
+            graph.visit(i, value);
+        }
+    }
+    // Lists and caches are added as they are found, after the scopes.
+    let mut next = scopes.len();
+    while next < graph.nodes.len() {
+        match &graph.nodes[next] {
+            Node::List(list) => {
+                let list = list.clone();
+                list.iter().for_each(|item| graph.visit(next, item));
+            }
+            Node::Cache(cache) => {
+                let cache = cache.clone();
+                let entries = cache.try_borrow().ok()?;
+                for (key, value) in entries.iter() {
+                    graph.visit(next, &key.0);
+                    graph.visit(next, value);
+                }
+            }
+            Node::Scope(_) => unreachable!("scopes come first"),
+        }
+        next += 1;
+    }
+
+    // Whatever is referred to from outside is alive, and so is everything
+    // it refers to.
+    let counts = graph.counts(&scopes);
+    let mut alive = vec![false; graph.nodes.len()];
+    let mut pending: Vec<usize> = Vec::new();
+    for (node, (&count, &internal)) in counts.iter().zip(&graph.internal).enumerate() {
+        if count < internal {
+            return None;
+        }
+        if count > internal {
+            alive[node] = true;
+            pending.push(node);
+        }
+    }
+    while let Some(node) = pending.pop() {
+        for &to in &graph.edges[node] {
+            if !alive[to] {
+                alive[to] = true;
+                pending.push(to);
+            }
+        }
+    }
+    if graph.counts(&scopes) != counts {
+        return None;
+    }
+    drop(frames);
+
+    // Nothing can reach the rest, so emptying them is safe. Their contents
+    // are dropped only once every scope has been emptied.
+    let mut vars = Vec::new();
+    let mut entries = Vec::new();
+    for (node, _) in alive.iter().enumerate().filter(|(_, alive)| !**alive) {
+        match &graph.nodes[node] {
+            Node::Scope(i) => vars.push(std::mem::take(&mut scopes[*i].borrow_mut().vars)),
+            Node::Cache(cache) => entries.push(std::mem::take(&mut *cache.borrow_mut())),
+            Node::List(_) => {}
+        }
+    }
+    let freed = vars.len();
+    drop(graph);
+    drop(scopes);
+    drop(vars);
+    drop(entries);
+    Some(freed)
+}
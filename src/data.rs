@@ -1,44 +1,470 @@
-use std::collections::HashMap;
+use crate::cycles;
+use crate::error::EvalError;
+use crate::shared::{Lock, NativeFunction, Shared};
+use crate::symbol::Symbol;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
 /// Represents a Lisp expression.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     /// A symbol, which can represent a variable or a function name.
-    Symbol(String),
+    Symbol(Symbol),
     /// A floating-point number.
     Number(f64),
     /// A boolean value (`true` or `false`).
     Bool(bool),
     /// A string literal.
-    String(String),
+    ///
+    /// Copies of a string share its text.
+    String(Shared<str>),
     /// A list of expressions.
-    List(Vec<Expr>),
+    ///
+    /// Copies of a list share its items, so passing one around does not
+    /// copy them; [`Shared::make_mut`] copies them only if a list being changed
+    /// is shared.
+    List(Shared<Vec<Expr>>),
+    /// A map from string keys to values, kept in key order.
+    Map(BTreeMap<String, Expr>),
     /// A user-defined function (lambda).
+    ///
+    /// Copies of a function share its parameters and body, so looking one up
+    /// to call it does not copy its code.
     Func {
         /// The names of the function's parameters.
-        params: Vec<String>,
+        params: Shared<[Symbol]>,
         /// The body of the function, which is another expression.
-        body: Box<Expr>,
+        body: Shared<Expr>,
+        /// The environment the function was created in, which its body sees.
+        env: Env,
+    },
+    /// A function implemented in Rust, such as one supplied by the host.
+    NativeFn(NativeFn),
+    /// A function wrapped by `memoize`, which remembers its results.
+    Memoized {
+        /// The wrapped function.
+        func: Box<Expr>,
+        /// Results by argument list, shared by every copy of this value.
+        cache: Shared<Lock<HashMap<EqualKey, Expr>>>,
+    },
+    /// An error caught by `try`, describing what went wrong.
+    Condition {
+        /// The kind of error, such as `error` for errors raised by builtins
+        /// or the kind given to `raise`.
+        kind: String,
+        /// The error message.
+        message: String,
+        /// Extra information supplied to `raise`, or `()`.
+        data: Box<Expr>,
     },
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = match self {
-            Expr::Symbol(s) => s.clone(),
-            Expr::Number(n) => n.to_string(),
+            Expr::Symbol(s) if crate::parser::is_bare_symbol(s) => s.to_string(),
+            Expr::Symbol(s) => format!("|{}|", escape(s, '|')),
+            Expr::Number(n) => format_number(*n),
             Expr::Bool(b) => b.to_string(),
-            Expr::String(s) => format!("\"{}\"", s),
+            Expr::String(s) => format!("\"{}\"", escape(s, '"')),
             Expr::List(list) => {
                 let xs: Vec<String> = list.iter().map(|x| x.to_string()).collect();
                 format!("({})", xs.join(" "))
             }
-            Expr::Func { .. } => "<function>".to_string(),
+            Expr::Map(map) => {
+                let xs: Vec<String> = map
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", Expr::String(k.clone().into()), v))
+                    .collect();
+                format!("{{{}}}", xs.join(" "))
+            }
+            Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. } => {
+                "<function>".to_string()
+            }
+            Expr::Condition { kind, message, .. } => format!("<condition {}: {}>", kind, message),
         };
         write!(f, "{}", s)
     }
 }
 
-/// Represents the evaluation environment, mapping variable names to expressions.
-pub type Env = HashMap<String, Expr>;
+/// A function implemented in Rust, which takes the evaluated arguments of a
+/// call and returns its value.
+///
+/// Copies share the function, and are the only values equal to each other.
+#[derive(Clone)]
+pub struct NativeFn(Shared<dyn NativeFunction>);
+
+impl NativeFn {
+    /// Wraps `func` so that Lisp code can call it.
+    pub fn new(func: impl NativeFunction) -> Self {
+        NativeFn(Shared::new(func))
+    }
+
+    /// Calls the function with evaluated arguments.
+    pub fn call(&self, args: &[Expr]) -> Result<Expr, EvalError> {
+        (self.0)(args)
+    }
+}
+
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &Self) -> bool {
+        Shared::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeFn({:p})", Shared::as_ptr(&self.0))
+    }
+}
+
+/// Formats a number so that reading the text back gives the same number.
+///
+/// Very large and very small magnitudes use exponent notation, and the
+/// special values are written as the literals `+inf.0`, `-inf.0` and `nan.0`.
+pub(crate) fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan.0".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "+inf.0" } else { "-inf.0" }.to_string()
+    } else if n != 0.0 && !(1e-7..1e21).contains(&n.abs()) {
+        format!("{:e}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// Escapes the characters of the contents of a string literal or piped
+/// symbol that the reader would otherwise misread, so that written strings
+/// and symbols can be read back. `delimiter` is the `"` or `|` around them.
+fn escape(s: &str, delimiter: char) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            c if c == delimiter => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Expr {
+    /// Returns the empty list, `()`, which builtins return when they have no
+    /// useful value.
+    pub fn empty_list() -> Expr {
+        Expr::List(Shared::default())
+    }
+
+    /// Renders the expression for humans, the way `display` does.
+    ///
+    /// This differs from `Display`, which gives the written (re-readable) form,
+    /// only in that strings and symbols are shown without quotes or pipes.
+    pub fn to_display_string(&self) -> String {
+        match self {
+            Expr::String(s) => s.to_string(),
+            Expr::Symbol(s) => s.to_string(),
+            Expr::List(list) => {
+                let xs: Vec<String> = list.iter().map(|x| x.to_display_string()).collect();
+                format!("({})", xs.join(" "))
+            }
+            Expr::Map(map) => {
+                let xs: Vec<String> = map
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", k, v.to_display_string()))
+                    .collect();
+                format!("{{{}}}", xs.join(" "))
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl Expr {
+    /// Compares two values structurally, the way `equal?` does.
+    ///
+    /// Lists and maps are equal when their elements are pairwise `equal?`,
+    /// functions when they have the same parameters and body and were created
+    /// in the same environment, conditions when their kind, message and data
+    /// are, and everything else follows [`Expr::is_eqv`].
+    pub fn is_equal(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::List(xs), Expr::List(ys)) => {
+                xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(x, y)| x.is_equal(y))
+            }
+            (
+                Expr::Func {
+                    params: p1,
+                    body: b1,
+                    env: e1,
+                },
+                Expr::Func {
+                    params: p2,
+                    body: b2,
+                    env: e2,
+                },
+            ) => p1 == p2 && b1.is_equal(b2) && e1 == e2,
+            (Expr::Map(m1), Expr::Map(m2)) => {
+                m1.len() == m2.len()
+                    && m1
+                        .iter()
+                        .zip(m2)
+                        .all(|((k1, v1), (k2, v2))| k1 == k2 && v1.is_equal(v2))
+            }
+            (
+                Expr::Condition {
+                    kind: k1,
+                    message: m1,
+                    data: d1,
+                },
+                Expr::Condition {
+                    kind: k2,
+                    message: m2,
+                    data: d2,
+                },
+            ) => k1 == k2 && m1 == m2 && d1.is_equal(d2),
+            _ => self.is_eqv(other),
+        }
+    }
+
+    /// Compares two values the way `eqv?` does.
+    ///
+    /// Numbers are compared by representation, so `nan.0` is `eqv?` to itself
+    /// while `0` and `-0` are not; use `=` for numeric comparison. Symbols,
+    /// booleans and strings compare by value. Lists and maps are only `eqv?`
+    /// when both are empty. Functions never are, except that copies of one
+    /// memoized function are, since they share a cache, and copies of one
+    /// native function.
+    pub fn is_eqv(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::Number(x), Expr::Number(y)) => {
+                x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan())
+            }
+            (Expr::Bool(x), Expr::Bool(y)) => x == y,
+            (Expr::Symbol(x), Expr::Symbol(y)) => x == y,
+            (Expr::String(x), Expr::String(y)) => x == y,
+            (Expr::List(xs), Expr::List(ys)) => xs.is_empty() && ys.is_empty(),
+            (Expr::Map(m1), Expr::Map(m2)) => m1.is_empty() && m2.is_empty(),
+            (Expr::Memoized { cache: c1, .. }, Expr::Memoized { cache: c2, .. }) => {
+                Shared::ptr_eq(c1, c2)
+            }
+            (Expr::NativeFn(f1), Expr::NativeFn(f2)) => f1 == f2,
+            _ => false,
+        }
+    }
+}
+
+/// An `Expr` that hashes and compares the way `equal?` does.
+///
+/// `Expr` itself is not `Eq` or `Hash` because it contains `f64`; wrap values
+/// in this to use them as `HashMap` keys or `HashSet` members.
+#[derive(Clone, Debug)]
+pub struct EqualKey(pub Expr);
+
+impl PartialEq for EqualKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.is_equal(&other.0)
+    }
+}
+
+impl Eq for EqualKey {}
+
+impl Hash for EqualKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        fn hash_expr<H: Hasher>(expr: &Expr, state: &mut H) {
+            std::mem::discriminant(expr).hash(state);
+            match expr {
+                // All NaNs are `equal?`, so they must hash alike.
+                Expr::Number(n) if n.is_nan() => f64::NAN.to_bits().hash(state),
+                Expr::Number(n) => n.to_bits().hash(state),
+                Expr::Bool(b) => b.hash(state),
+                Expr::Symbol(s) => s.hash(state),
+                Expr::String(s) => s.hash(state),
+                Expr::List(items) => {
+                    items.len().hash(state);
+                    items.iter().for_each(|item| hash_expr(item, state));
+                }
+                Expr::Map(map) => {
+                    map.len().hash(state);
+                    for (k, v) in map {
+                        k.hash(state);
+                        hash_expr(v, state);
+                    }
+                }
+                Expr::Func { params, body, env } => {
+                    params.hash(state);
+                    hash_expr(body, state);
+                    Shared::as_ptr(&env.0).hash(state);
+                }
+                Expr::NativeFn(func) => Shared::as_ptr(&func.0).cast::<()>().hash(state),
+                Expr::Memoized { cache, .. } => Shared::as_ptr(cache).hash(state),
+                Expr::Condition {
+                    kind,
+                    message,
+                    data,
+                } => {
+                    kind.hash(state);
+                    message.hash(state);
+                    hash_expr(data, state);
+                }
+            }
+        }
+        hash_expr(&self.0, state);
+    }
+}
+
+/// The variables defined in one scope, and the scope it is nested in.
+pub(crate) struct Frame {
+    pub(crate) vars: HashMap<Symbol, Expr>,
+    pub(crate) parent: Option<Env>,
+}
+
+/// Represents the evaluation environment: a chain of scopes mapping variable
+/// names to expressions, from the innermost scope out to the global one.
+///
+/// Cloning an `Env` is cheap and gives another handle on the same scopes, so
+/// a function shares the scope it was created in instead of copying it, and
+/// later definitions there are visible to it.
+///
+/// A scope and the functions defined in it refer to each other, so dropping
+/// the last handle does not free them by itself; [`cycles`]
+/// frees such scopes once nothing else refers to them.
+///
+/// With the `sync` feature each scope is behind a read-write lock, so
+/// threads can evaluate in scopes of the same environment at once, as
+/// [`Interpreter::with_env`](crate::Interpreter::with_env) describes.
+#[derive(Clone)]
+pub struct Env(pub(crate) Shared<Lock<Frame>>);
+
+impl Env {
+    /// Creates an empty global environment.
+    pub fn new() -> Self {
+        Env::default()
+    }
+
+    /// Creates an empty scope nested in this one, as for a function call.
+    pub fn child(&self) -> Env {
+        Env::scope(Some(self.clone()))
+    }
+
+    /// Returns whether other handles or functions refer to this scope, so
+    /// that it may be part of a cycle.
+    pub(crate) fn is_shared(&self) -> bool {
+        Shared::strong_count(&self.0) > 1
+    }
+
+    /// Creates an empty scope in `parent`, registering it to be freed if it
+    /// ends up in a cycle.
+    fn scope(parent: Option<Env>) -> Env {
+        let frame = Shared::new(Lock::new(Frame {
+            vars: HashMap::new(),
+            parent,
+        }));
+        cycles::register(&frame);
+        Env(frame)
+    }
+
+    /// Looks up `name`, starting in the innermost scope.
+    pub fn get(&self, name: &str) -> Option<Expr> {
+        self.lookup(&Symbol::new(name))
+    }
+
+    /// Like [`get`](Self::get), for a name that is already a symbol, as
+    /// the evaluator has.
+    pub fn lookup(&self, name: &Symbol) -> Option<Expr> {
+        let frame = self.0.borrow();
+        match frame.vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => frame.parent.as_ref()?.lookup(name),
+        }
+    }
+
+    /// Binds `name` in the innermost scope, shadowing any binding of it in
+    /// the scopes outside.
+    pub fn insert(&mut self, name: impl Into<Symbol>, value: Expr) {
+        self.0.borrow_mut().vars.insert(name.into(), value);
+    }
+
+    /// Changes the value of the innermost existing binding of `name`,
+    /// returning false if it is not bound in any scope.
+    pub fn set(&mut self, name: &str, value: Expr) -> bool {
+        self.set_symbol(&Symbol::new(name), value)
+    }
+
+    fn set_symbol(&mut self, name: &Symbol, value: Expr) -> bool {
+        let mut frame = self.0.borrow_mut();
+        if let Some(slot) = frame.vars.get_mut(name) {
+            *slot = value;
+            return true;
+        }
+        match &mut frame.parent {
+            Some(parent) => parent.set_symbol(name, value),
+            None => false,
+        }
+    }
+
+    /// Returns the bindings visible here from every scope but the global
+    /// one, sorted by name, as a debugger shows a function's locals.
+    /// Bindings in inner scopes hide those they shadow.
+    pub fn locals(&self) -> Vec<(Symbol, Expr)> {
+        let mut locals = BTreeMap::new();
+        let mut scope = self.clone();
+        loop {
+            let parent = scope.0.borrow().parent.clone();
+            let Some(parent) = parent else { break };
+            for (name, value) in &scope.0.borrow().vars {
+                locals.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+            scope = parent;
+        }
+        locals.into_iter().collect()
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::scope(None)
+    }
+}
+
+/// Two handles are equal when they refer to the same scope.
+impl PartialEq for Env {
+    fn eq(&self, other: &Self) -> bool {
+        Shared::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Shows only the names bound in each scope, since the values may be
+/// functions that refer back to the environment.
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.0.borrow();
+        let mut names: Vec<&Symbol> = frame.vars.keys().collect();
+        names.sort();
+        f.debug_struct("Env")
+            .field("names", &names)
+            .field("parent", &frame.parent)
+            .finish()
+    }
+}
+
+/// Creates an environment with the predefined constants `pi` and `e` bound.
+pub fn default_env() -> Env {
+    let mut env = Env::new();
+    define_constants(&mut env);
+    env
+}
+
+/// Binds the predefined constants `pi` and `e` in `env`.
+pub(crate) fn define_constants(env: &mut Env) {
+    env.insert("pi".to_string(), Expr::Number(std::f64::consts::PI));
+    env.insert("e".to_string(), Expr::Number(std::f64::consts::E));
+}
@@ -0,0 +1,306 @@
+//! The errors reported when reading and evaluating code.
+//!
+//! Both error types display as the messages the interpreter has always
+//! shown, so printing them gives the same text as before, while embedders
+//! can match on the variants to handle particular failures.
+
+use crate::data::Expr;
+use crate::parser::Position;
+use std::fmt;
+
+/// A failure to parse source text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The text is not valid, however it might continue.
+    Syntax {
+        /// What is wrong, such as `Unexpected closing parenthesis.`.
+        message: String,
+        /// Where the problem was found.
+        position: Position,
+        /// The source line that `position` is on, without its line ending.
+        line: String,
+    },
+    /// The text ends inside a list, string or comment, so more text could
+    /// complete it.
+    Incomplete {
+        /// What is missing, such as `Missing closing parenthesis.`.
+        message: String,
+        /// Where the unfinished list, string or comment starts, or the end of
+        /// the text.
+        position: Position,
+        /// The source line that `position` is on, without its line ending.
+        line: String,
+    },
+    /// Reading the text from a stream failed.
+    Io(String),
+}
+
+impl ParseError {
+    /// Returns what went wrong, without the position or source line.
+    pub fn message(&self) -> &str {
+        match self {
+            ParseError::Syntax { message, .. }
+            | ParseError::Incomplete { message, .. }
+            | ParseError::Io(message) => message,
+        }
+    }
+
+    /// Returns where the error was found, unless reading the input failed.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            ParseError::Syntax { position, .. } | ParseError::Incomplete { position, .. } => {
+                Some(*position)
+            }
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+/// Renders the error as `Parse error at line:column: message`, followed by
+/// the offending source line with a caret under the position.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax {
+                message,
+                position,
+                line,
+            }
+            | ParseError::Incomplete {
+                message,
+                position,
+                line,
+            } => {
+                // Copy tabs so that the caret lines up however tabs are
+                // displayed.
+                let indent: String = line
+                    .chars()
+                    .take(position.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(
+                    f,
+                    "Parse error at {}:{}: {}\n{}\n{}^",
+                    position.line, position.column, message, line, indent
+                )
+            }
+            ParseError::Io(message) => write!(f, "Failed to read input: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A failure to evaluate an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    /// A variable was used that is not defined.
+    UnboundVariable(String),
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch {
+        /// How many arguments the function takes.
+        expected: usize,
+        /// How many it was given.
+        got: usize,
+    },
+    /// A builtin was given a value of the wrong type.
+    TypeMismatch {
+        /// The builtin, such as `substring`.
+        op: String,
+        /// What it requires, such as `a string argument`.
+        expected: String,
+        /// The value it was given instead.
+        got: Expr,
+    },
+    /// Something that is not a function was called.
+    NotAFunction(Expr),
+    /// A number was divided by zero.
+    DivisionByZero,
+    /// Lisp code signalled an error with `raise`.
+    User {
+        /// The kind given to `raise`.
+        kind: String,
+        /// The message given to `raise`.
+        message: String,
+        /// The data given to `raise`, or `()`.
+        data: Expr,
+    },
+    /// Text given to `read-string` could not be parsed.
+    Parse(ParseError),
+    /// Evaluation was stopped through an
+    /// [`InterruptHandle`](crate::runtime::InterruptHandle).
+    Interrupted,
+    /// `exit` was called while the exit policy is
+    /// [`ExitPolicy::Error`](crate::runtime::ExitPolicy::Error).
+    Exit(i32),
+    /// Function calls nested deeper than the limit set with
+    /// [`set_max_call_depth`](crate::runtime::set_max_call_depth), or
+    /// evaluations deeper than
+    /// [`MAX_NESTED_EVALUATIONS`](crate::runtime::MAX_NESTED_EVALUATIONS).
+    /// The limit is given.
+    RecursionLimit(usize),
+    /// Evaluation took more steps than allowed by
+    /// [`set_fuel`](crate::runtime::set_fuel).
+    FuelExhausted,
+    /// Evaluation took longer than allowed by
+    /// [`eval_with_timeout`](crate::eval::eval_with_timeout).
+    Timeout,
+    /// Builtins constructed more bytes of values than the limit set with
+    /// [`set_memory_limit`](crate::runtime::set_memory_limit), which is
+    /// given.
+    MemoryLimit(usize),
+    /// Any other error, described by its message.
+    Other(String),
+    /// An error raised inside calls to user-defined functions.
+    Backtrace {
+        /// The names of the functions that were being called, innermost
+        /// first, the order the error passed out of them in.
+        calls: Vec<String>,
+        /// The error itself, which is never another `Backtrace`.
+        error: Box<EvalError>,
+    },
+}
+
+impl EvalError {
+    /// Returns the error without the calls it was raised in.
+    pub fn root(&self) -> &EvalError {
+        match self {
+            EvalError::Backtrace { error, .. } => error,
+            e => e,
+        }
+    }
+
+    /// Like [`root`](Self::root), but takes ownership.
+    pub fn into_root(self) -> EvalError {
+        match self {
+            EvalError::Backtrace { error, .. } => *error,
+            e => e,
+        }
+    }
+
+    /// Returns the names of the functions the error was raised in, innermost
+    /// first.
+    pub fn backtrace(&self) -> &[String] {
+        match self {
+            EvalError::Backtrace { calls, .. } => calls,
+            _ => &[],
+        }
+    }
+
+    /// Returns the kind of condition `try` describes the error as: the kind
+    /// given to `raise`, or one naming the variant, such as `type-mismatch`.
+    pub fn kind(&self) -> &str {
+        match self.root() {
+            EvalError::UnboundVariable(_) => "unbound-variable",
+            EvalError::ArityMismatch { .. } => "arity-mismatch",
+            EvalError::TypeMismatch { .. } => "type-mismatch",
+            EvalError::NotAFunction(_) => "not-a-function",
+            EvalError::DivisionByZero => "division-by-zero",
+            EvalError::User { kind, .. } => kind,
+            EvalError::Parse(_) => "parse-error",
+            EvalError::Interrupted => "interrupted",
+            EvalError::Exit(_) => "exit",
+            EvalError::RecursionLimit(_) => "recursion-limit",
+            EvalError::FuelExhausted => "fuel-exhausted",
+            EvalError::Timeout => "timeout",
+            EvalError::MemoryLimit(_) => "memory-limit",
+            EvalError::Other(_) => "error",
+            EvalError::Backtrace { .. } => unreachable!("the root is never a backtrace"),
+        }
+    }
+
+    /// Records that the error passed out of a call to the function `name`.
+    pub(crate) fn in_call(self, name: &str) -> EvalError {
+        match self {
+            EvalError::Backtrace { mut calls, error } => {
+                calls.push(name.to_string());
+                EvalError::Backtrace { calls, error }
+            }
+            e => EvalError::Backtrace {
+                calls: vec![name.to_string()],
+                error: Box::new(e),
+            },
+        }
+    }
+
+    /// Renders the error with the calls it was raised in, as
+    /// `in fib → in helper → message`. Runs of recursive calls are shown
+    /// once with a count, as `in loop (×1000)`.
+    pub fn describe(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        let calls: Vec<&String> = self.backtrace().iter().rev().collect();
+        let mut i = 0;
+        while i < calls.len() {
+            let run = calls[i..].iter().take_while(|&c| *c == calls[i]).count();
+            parts.push(if run == 1 {
+                format!("in {}", calls[i])
+            } else {
+                format!("in {} (×{})", calls[i], run)
+            });
+            i += run;
+        }
+        parts.push(self.root().to_string());
+        parts.join(" → ")
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::UnboundVariable(name) => write!(f, "Variable '{}' not found.", name),
+            EvalError::ArityMismatch { expected, got } => write!(
+                f,
+                "Function expects {} arguments, but received {}.",
+                expected, got
+            ),
+            // Arithmetic operators keep their historical "Operator" wording.
+            EvalError::TypeMismatch { op, expected, .. }
+                if matches!(op.as_str(), "+" | "-" | "*" | "/") =>
+            {
+                write!(f, "Operator '{}' requires {}.", op, expected)
+            }
+            EvalError::TypeMismatch { op, expected, .. } => {
+                write!(f, "'{}' requires {}.", op, expected)
+            }
+            EvalError::NotAFunction(expr) => write!(f, "Not a function: {}", expr),
+            EvalError::DivisionByZero => write!(f, "Division by zero."),
+            EvalError::User { message, .. } => write!(f, "{}", message),
+            EvalError::Parse(e) => write!(f, "{}", e),
+            EvalError::Interrupted => write!(f, "Evaluation interrupted."),
+            EvalError::Exit(status) => write!(f, "Exit requested with status {}.", status),
+            EvalError::RecursionLimit(_) => write!(f, "Maximum recursion depth exceeded."),
+            EvalError::FuelExhausted => write!(f, "Evaluation ran out of fuel."),
+            EvalError::Timeout => write!(f, "Evaluation timed out."),
+            EvalError::MemoryLimit(_) => write!(f, "Memory limit exceeded."),
+            EvalError::Other(message) => write!(f, "{}", message),
+            EvalError::Backtrace { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.root() {
+            EvalError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for EvalError {
+    fn from(e: ParseError) -> Self {
+        EvalError::Parse(e)
+    }
+}
+
+impl From<String> for EvalError {
+    fn from(message: String) -> Self {
+        EvalError::Other(message)
+    }
+}
+
+impl From<&str> for EvalError {
+    fn from(message: &str) -> Self {
+        EvalError::Other(message.to_string())
+    }
+}
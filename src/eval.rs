@@ -1,9 +1,29 @@
-use crate::data::{Env, Expr};
+use crate::codec;
+use crate::csv;
+use crate::data::{Env, EqualKey, Expr, format_number};
+use crate::error::EvalError;
+use crate::json;
+use crate::parser::{parse, parse_number};
+use crate::runtime;
+use crate::shared::{Lock, Shared};
+use crate::symbol::Symbol;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 /// Evaluates a Lisp expression within a given environment.
 ///
-/// This function recursively evaluates an expression, handling symbols,
-/// special forms (`define`, `lambda`, `if`), and function applications.
+/// This function evaluates an expression, handling symbols, special forms
+/// (`define`, `lambda`, `if`, `try`, `trace`, `untrace`, `deftest`,
+/// `check-error`), and function applications.
+///
+/// Evaluation does not recurse on the Rust stack: work waiting for a value
+/// is kept on a stack of its own, so deeply nested expressions and calls are
+/// limited by memory and [`runtime::max_call_depth`] rather than by the
+/// thread's stack size. Calls in tail position replace the caller's call
+/// instead of nesting inside it, so loops written as tail recursion run in
+/// constant space. Such a loop is not stopped by the call depth limit
+/// either: one that never ends runs until fuel, a timeout or an interrupt
+/// stops it, so hosts running untrusted code should set one of them.
 ///
 /// # Arguments
 ///
@@ -14,126 +34,899 @@ use crate::data::{Env, Expr};
 ///
 /// A `Result` which is:
 /// - `Ok(Expr)`: If the evaluation is successful, containing the resulting expression.
-/// - `Err(String)`: If an error occurs during evaluation, containing an error message.
-pub fn eval(expr: &Expr, env: &mut Env) -> Result<Expr, String> {
-    match expr {
-        Expr::Symbol(s) => env
-            .get(s)
-            .cloned()
-            .ok_or_else(|| format!("Variable '{}' not found.", s)),
-        Expr::Number(_) | Expr::Bool(_) | Expr::String(_) | Expr::Func { .. } => Ok(expr.clone()),
-        Expr::List(list) => {
-            if list.is_empty() {
-                return Ok(Expr::List(Vec::new()));
-            }
-            let first = &list[0];
-            let args = &list[1..];
+/// - `Err(EvalError)`: If an error occurs during evaluation, describing what went wrong.
+pub fn eval(expr: &Expr, env: &mut Env) -> Result<Expr, EvalError> {
+    let code = Code {
+        root: Root::Borrowed(expr),
+        path: Vec::new(),
+    };
+    run(Step::Eval(code, env.clone()))
+}
+
+/// Evaluates an expression like [`eval`], but fails with
+/// [`EvalError::Timeout`] if it takes longer than `timeout`.
+///
+/// The deadline is checked every few hundred evaluation steps, so a single
+/// slow builtin, such as sorting a huge list, can overrun it. Timed
+/// evaluations may nest, and the inner one also stops at the outer deadline.
+pub fn eval_with_timeout(expr: &Expr, env: &mut Env, timeout: Duration) -> Result<Expr, EvalError> {
+    let outer = runtime::set_deadline(None);
+    let deadline = match (Instant::now().checked_add(timeout), outer) {
+        (Some(deadline), Some(outer)) => Some(deadline.min(outer)),
+        (deadline, outer) => deadline.or(outer),
+    };
+    runtime::set_deadline(deadline);
+    let result = eval(expr, env);
+    runtime::set_deadline(outer);
+    result
+}
+
+/// The expression that a piece of code is found in: borrowed from the caller
+/// of [`eval`], or the shared body of a function.
+#[derive(Clone)]
+enum Root<'a> {
+    Borrowed(&'a Expr),
+    Shared(Shared<Expr>),
+}
+
+/// An expression to evaluate, found by following `path` through the nested
+/// lists of `root`.
+///
+/// Holding the root keeps a function body alive while it runs, even if the
+/// function is redefined meanwhile, without copying any of it.
+#[derive(Clone)]
+struct Code<'a> {
+    root: Root<'a>,
+    path: Vec<usize>,
+}
+
+impl<'a> Code<'a> {
+    fn expr(&self) -> &Expr {
+        let mut expr = match &self.root {
+            Root::Borrowed(expr) => expr,
+            Root::Shared(expr) => &**expr,
+        };
+        for &i in &self.path {
+            let Expr::List(items) = expr else {
+                unreachable!("code paths only lead through lists");
+            };
+            expr = &items[i];
+        }
+        expr
+    }
+
+    /// Returns the items of this code, which must be a list.
+    fn items(&self) -> &[Expr] {
+        match self.expr() {
+            Expr::List(items) => items,
+            _ => unreachable!("only lists have items"),
+        }
+    }
+
+    /// Returns the code for item `i` of this list.
+    fn item(&self, i: usize) -> Code<'a> {
+        let mut path = Vec::with_capacity(self.path.len() + 1);
+        path.extend_from_slice(&self.path);
+        path.push(i);
+        Code {
+            root: self.root.clone(),
+            path,
+        }
+    }
+}
+
+/// What the evaluator does next.
+enum Step<'a> {
+    /// Evaluate the code in the environment.
+    Eval(Code<'a>, Env),
+    /// Call a function value. `caller` is the call, if the function was
+    /// called by name, for backtraces.
+    Call {
+        func: Expr,
+        args: Vec<Expr>,
+        caller: Option<Code<'a>>,
+    },
+    /// Call the function of a builtin such as `partition` on its next item,
+    /// or finish the builtin.
+    Iterate(Iteration),
+    /// Hand a value or error to the innermost pending work.
+    Return(Result<Expr, EvalError>),
+}
+
+pub(crate) type Cache = Shared<Lock<HashMap<EqualKey, Expr>>>;
+
+/// Work waiting for the value being computed, in place of a Rust stack frame.
+enum Pending<'a> {
+    /// The arguments of the call `code` are being evaluated; `values` holds
+    /// those done so far.
+    Args {
+        code: Code<'a>,
+        values: Vec<Expr>,
+        env: Env,
+    },
+    /// The operator of the call `code` is being evaluated, after its
+    /// arguments.
+    Operator { code: Code<'a>, args: Vec<Expr> },
+    /// The condition of the `if` form `code` is being evaluated.
+    If { code: Code<'a>, env: Env },
+    /// The value to bind to `name` is being evaluated.
+    Define { name: Symbol, env: Env },
+    /// The body of the `try` form `code` is being evaluated.
+    Try { code: Code<'a>, env: Env },
+    /// The message of the `check-error` form `code` is being evaluated.
+    CheckErrorMessage { code: Code<'a>, env: Env },
+    /// The expression of the `check-error` form `code` is being evaluated,
+    /// and should fail with a message containing `expected`.
+    CheckError {
+        code: Code<'a>,
+        expected: Option<String>,
+    },
+    /// The body of a function is running. `caller` is the last call made
+    /// through this frame, whether directly or by tail calls, and
+    /// `profiled` whether that call is being profiled.
+    Return {
+        caller: Option<Code<'a>>,
+        profiled: bool,
+    },
+    /// A traced call is running; its result is written indented by `indent`.
+    Traced { indent: String },
+    /// A memoized function is running; its result is cached under `key`.
+    Memoize { cache: Cache, key: EqualKey },
+    /// A builtin such as `partition` is waiting for its function to return.
+    Iterate(Iteration),
+    /// An expression is being evaluated while hooks are installed or the
+    /// debugger is stepping.
+    Exit,
+}
+
+/// Runs the evaluator from `step` until no work is pending.
+///
+/// Builtins that evaluate code, such as `eval`, and host functions that do,
+/// run it nested inside their own call on the Rust stack. Such nesting is
+/// limited by [`runtime::MAX_NESTED_EVALUATIONS`], so that it fails with
+/// [`EvalError::RecursionLimit`] instead of overflowing the stack.
+fn run(step: Step<'_>) -> Result<Expr, EvalError> {
+    runtime::enter_evaluation()?;
+    let result = run_steps(step);
+    runtime::exit_evaluation();
+    result
+}
 
-            if let Expr::Symbol(s) = first {
-                match s.as_str() {
-                    "define" => eval_define(args, env),
-                    "lambda" => eval_lambda(args),
-                    "if" => eval_if(args, env),
-                    _ => apply_procedure(first, args, env),
+fn run_steps(mut step: Step<'_>) -> Result<Expr, EvalError> {
+    let mut stack = Vec::new();
+    loop {
+        step = match step {
+            Step::Eval(code, env) => {
+                if runtime::observing() {
+                    stack.push(Pending::Exit);
+                    if let Err(e) = runtime::enter_eval(code.expr(), &env) {
+                        step = Step::Return(Err(e));
+                        continue;
+                    }
                 }
-            } else {
-                apply_procedure(first, args, env)
+                eval_code(code, env, &mut stack)
+            }
+            Step::Call { func, args, caller } => call(func, args, caller, &mut stack),
+            Step::Iterate(iteration) => iteration.next(&mut stack),
+            Step::Return(result) => match stack.pop() {
+                Some(pending) => resume(pending, result, &mut stack),
+                None => return result,
+            },
+        };
+    }
+}
+
+fn eval_code<'a>(code: Code<'a>, env: Env, stack: &mut Vec<Pending<'a>>) -> Step<'a> {
+    if let Err(e) = runtime::take_step() {
+        return Step::Return(Err(e));
+    }
+    let list = match code.expr() {
+        Expr::Symbol(s) => return Step::Return(lookup(s, &env)),
+        Expr::List(list) if !list.is_empty() => list,
+        Expr::List(_) => return Step::Return(Ok(Expr::empty_list())),
+        expr => return Step::Return(Ok(expr.clone())),
+    };
+    let args = &list[1..];
+    let Expr::Symbol(s) = &list[0] else {
+        let values = Vec::with_capacity(args.len());
+        return next_arg(code, values, env, stack);
+    };
+    match s.as_str() {
+        "define" => match args {
+            [Expr::Symbol(name), _] => {
+                stack.push(Pending::Define {
+                    name: name.clone(),
+                    env: env.clone(),
+                });
+                Step::Eval(code.item(2), env)
+            }
+            [_, _] => Step::Return(Err(
+                "The first argument to 'define' must be a symbol.".into()
+            )),
+            _ => Step::Return(Err("'define' requires a symbol and a value.".into())),
+        },
+        "lambda" => Step::Return(eval_lambda(args, &env)),
+        "if" => {
+            if args.len() != 3 {
+                return Step::Return(Err(
+                    "'if' requires a condition, a then branch, and an else branch.".into(),
+                ));
+            }
+            stack.push(Pending::If {
+                code: code.clone(),
+                env: env.clone(),
+            });
+            Step::Eval(code.item(1), env)
+        }
+        "try" => {
+            if let Err(e) = try_clause(args) {
+                return Step::Return(Err(e));
             }
+            stack.push(Pending::Try {
+                code: code.clone(),
+                env: env.clone(),
+            });
+            Step::Eval(code.item(1), env)
+        }
+        "trace" => Step::Return(eval_trace(args, true)),
+        "untrace" => Step::Return(eval_trace(args, false)),
+        "deftest" => Step::Return(eval_deftest(args)),
+        "check-error" => match args.len() {
+            1 => {
+                stack.push(Pending::CheckError {
+                    code: code.clone(),
+                    expected: None,
+                });
+                Step::Eval(code.item(1), env)
+            }
+            2 => {
+                stack.push(Pending::CheckErrorMessage {
+                    code: code.clone(),
+                    env: env.clone(),
+                });
+                Step::Eval(code.item(2), env)
+            }
+            _ => Step::Return(Err(
+                "'check-error' requires an expression and an optional message.".into(),
+            )),
+        },
+        _ => {
+            let values = Vec::with_capacity(args.len());
+            next_arg(code, values, env, stack)
         }
     }
 }
 
-fn eval_define(args: &[Expr], env: &mut Env) -> Result<Expr, String> {
-    if args.len() != 2 {
-        return Err("'define' requires a symbol and a value.".to_string());
+pub(crate) fn lookup(name: &Symbol, env: &Env) -> Result<Expr, EvalError> {
+    env.lookup(name)
+        .ok_or_else(|| EvalError::UnboundVariable(name.to_string()))
+}
+
+/// Evaluates the next argument of the call `code`, or makes the call once
+/// all of them are in `values`. Arguments that are not lists are evaluated
+/// on the spot rather than as steps of their own.
+fn next_arg<'a>(
+    code: Code<'a>,
+    mut values: Vec<Expr>,
+    env: Env,
+    stack: &mut Vec<Pending<'a>>,
+) -> Step<'a> {
+    loop {
+        let items = code.items();
+        let i = values.len() + 1;
+        let value = match items.get(i) {
+            None => return apply_call(code, values, env, stack),
+            Some(Expr::Symbol(s)) => lookup(s, &env),
+            Some(Expr::List(list)) if !list.is_empty() => break,
+            Some(Expr::List(_)) => Ok(Expr::empty_list()),
+            Some(arg) => Ok(arg.clone()),
+        };
+        let value = if runtime::observing() {
+            let value = runtime::enter_eval(&items[i], &env).and(value);
+            runtime::exit_eval(&value);
+            value
+        } else {
+            value
+        };
+        match value {
+            Ok(value) => values.push(value),
+            Err(e) => return Step::Return(Err(e)),
+        }
     }
-    if let Expr::Symbol(name) = &args[0] {
-        let value = eval(&args[1], env)?;
-        env.insert(name.clone(), value);
-        Ok(Expr::Symbol(name.clone()))
-    } else {
-        Err("The first argument to 'define' must be a symbol.".to_string())
+    let arg = code.item(values.len() + 1);
+    stack.push(Pending::Args {
+        code,
+        values,
+        env: env.clone(),
+    });
+    Step::Eval(arg, env)
+}
+
+/// Applies the operator of the call `code` to its evaluated arguments.
+///
+/// A symbol naming a builtin in the current registry calls the builtin, even
+/// if a variable of the same name is defined. Calls through a traced name are written to the
+/// output sink, indented by the nesting depth of traced calls.
+fn apply_call<'a>(
+    code: Code<'a>,
+    args: Vec<Expr>,
+    mut env: Env,
+    stack: &mut Vec<Pending<'a>>,
+) -> Step<'a> {
+    let Expr::Symbol(name) = &code.items()[0] else {
+        stack.push(Pending::Operator {
+            code: code.clone(),
+            args,
+        });
+        return Step::Eval(code.item(0), env);
+    };
+
+    if runtime::is_traced(name) {
+        let mut call = vec![Expr::Symbol(name.clone())];
+        call.extend(args.iter().cloned());
+        let indent = "  ".repeat(runtime::enter_trace());
+        if let Err(e) = runtime::write_output(&format!("{}> {}\n", indent, Expr::List(call.into())))
+        {
+            runtime::exit_trace();
+            return Step::Return(Err(e.into()));
+        }
+        stack.push(Pending::Traced { indent });
+    }
+
+    if let Some(builtin) = runtime::builtins().get(name) {
+        let profiled = runtime::profile_enter(name);
+        // Builtins that call a function on each item of a list wait on the
+        // stack for it rather than nesting the calls.
+        if let Some(op) = builtin.standard_name().filter(|op| Iteration::handles(op)) {
+            return Iteration::start(op, &args, true, profiled);
+        }
+        let result = builtin.call(&args, &mut env);
+        if profiled {
+            runtime::profile_exit();
+        }
+        return Step::Return(track_allocation(result));
+    }
+    match lookup(name, &env) {
+        Ok(func @ (Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. })) => Step::Call {
+            func,
+            args,
+            caller: Some(code),
+        },
+        Ok(_) => Step::Return(Err(EvalError::NotAFunction(code.items()[0].clone()))),
+        Err(e) => Step::Return(Err(e)),
     }
 }
 
-fn eval_lambda(args: &[Expr]) -> Result<Expr, String> {
+/// Counts a value returned by a builtin against the memory limit.
+pub(crate) fn track_allocation(result: Result<Expr, EvalError>) -> Result<Expr, EvalError> {
+    let value = result?;
+    runtime::track_allocation(&value)?;
+    Ok(value)
+}
+
+/// Calls a function value with already-evaluated arguments.
+fn call<'a>(
+    func: Expr,
+    args: Vec<Expr>,
+    caller: Option<Code<'a>>,
+    stack: &mut Vec<Pending<'a>>,
+) -> Step<'a> {
+    let (params, body, env) = match func {
+        Expr::Memoized { func, cache } => {
+            let key = EqualKey(Expr::List(args.clone().into()));
+            if let Some(result) = cache.borrow().get(&key) {
+                return Step::Return(Ok(result.clone()));
+            }
+            // The cache is not borrowed during the call, so recursive calls
+            // through the memoized function can fill it in.
+            stack.push(Pending::Memoize { cache, key });
+            return Step::Call {
+                func: *func,
+                args,
+                caller,
+            };
+        }
+        Expr::NativeFn(func) => {
+            let profiled = caller_name(&caller).is_some_and(runtime::profile_enter);
+            let result = runtime::take_step().and_then(|_| func.call(&args));
+            if profiled {
+                runtime::profile_exit();
+            }
+            return Step::Return(track_allocation(result));
+        }
+        Expr::Func { params, body, env } => (params, body, env),
+        func => return Step::Return(Err(EvalError::NotAFunction(func))),
+    };
+    if params.len() != args.len() {
+        let e = EvalError::ArityMismatch {
+            expected: params.len(),
+            got: args.len(),
+        };
+        return Step::Return(Err(in_call(e, &caller)));
+    }
+    if let Err(e) = runtime::take_step().and_then(|_| runtime::check_interrupt()) {
+        return Step::Return(Err(in_call(e, &caller)));
+    }
+    let name = caller_name(&caller);
+    let breakpoint = name
+        .filter(|name| runtime::has_breakpoint(name))
+        .map(str::to_string);
+    match stack.last_mut() {
+        // A call in tail position takes over its caller's frame, ending the
+        // caller's profiled call.
+        Some(Pending::Return {
+            caller: frame,
+            profiled,
+        }) => {
+            if *profiled {
+                runtime::profile_exit();
+            }
+            *profiled = name.is_some_and(runtime::profile_enter);
+            *frame = caller;
+        }
+        _ => {
+            if let Err(e) = runtime::enter_call() {
+                return Step::Return(Err(in_call(e, &caller)));
+            }
+            let profiled = name.is_some_and(runtime::profile_enter);
+            stack.push(Pending::Return { caller, profiled });
+        }
+    }
+    if let Some(name) = breakpoint {
+        runtime::break_on_call(name);
+    }
+    let mut func_env = env.child();
+    for (param_name, arg_value) in params.iter().zip(args) {
+        func_env.insert(param_name.clone(), arg_value);
+    }
+    let code = Code {
+        root: Root::Shared(body),
+        path: Vec::new(),
+    };
+    Step::Eval(code, func_env)
+}
+
+/// Returns the name the call `caller` was made by, if any.
+fn caller_name<'c>(caller: &'c Option<Code>) -> Option<&'c str> {
+    match caller.as_ref().map(|code| &code.items()[0]) {
+        Some(Expr::Symbol(name)) => Some(name),
+        _ => None,
+    }
+}
+
+/// Records that `e` passed out of the call `caller`, if it was made by name.
+fn in_call(e: EvalError, caller: &Option<Code>) -> EvalError {
+    match caller_name(caller) {
+        Some(name) => e.in_call(name),
+        None => e,
+    }
+}
+
+/// Continues `pending` with the value or error it was waiting for.
+fn resume<'a>(
+    pending: Pending<'a>,
+    result: Result<Expr, EvalError>,
+    stack: &mut Vec<Pending<'a>>,
+) -> Step<'a> {
+    match pending {
+        Pending::Return { caller, profiled } => {
+            if profiled {
+                runtime::profile_exit();
+            }
+            runtime::exit_call();
+            Step::Return(result.map_err(|e| in_call(e, &caller)))
+        }
+        Pending::Traced { indent } => {
+            runtime::exit_trace();
+            Step::Return(result.and_then(|result| {
+                runtime::write_output(&format!("{}< {}\n", indent, result))?;
+                Ok(result)
+            }))
+        }
+        Pending::Memoize { cache, key } => {
+            if let Ok(result) = &result {
+                cache.borrow_mut().insert(key, result.clone());
+            }
+            Step::Return(result)
+        }
+        Pending::Exit => {
+            runtime::exit_eval(&result);
+            Step::Return(result)
+        }
+        Pending::Iterate(iteration) => match result {
+            Ok(value) => iteration.gather(value, stack),
+            Err(e) => iteration.end(Err(e)),
+        },
+        Pending::Try { code, env } => match result {
+            Err(e) if !is_fatal(&e) => {
+                let (var, _) = try_clause(&code.items()[1..]).expect("checked before evaluating");
+                let mut handler_env = env.child();
+                handler_env.insert(var.clone(), condition(e));
+                Step::Eval(code.item(2).item(2), handler_env)
+            }
+            result => Step::Return(result),
+        },
+        Pending::CheckError { code, expected } => {
+            let expr = &code.items()[1];
+            Step::Return(match result {
+                Ok(value) => Err(format!(
+                    "check-error failed: {} returned {} instead of failing.",
+                    expr, value
+                )
+                .into()),
+                Err(e) if is_fatal(&e) => Err(e),
+                Err(e) => match expected {
+                    Some(expected) if !e.to_string().contains(&expected) => Err(format!(
+                        "check-error failed: expected an error containing \"{}\", got \"{}\".",
+                        expected, e
+                    )
+                    .into()),
+                    _ => Ok(Expr::Bool(true)),
+                },
+            })
+        }
+        // The remaining work only continues with a value.
+        pending => match result {
+            Ok(value) => resume_with(pending, value, stack),
+            Err(e) => Step::Return(Err(e)),
+        },
+    }
+}
+
+fn resume_with<'a>(pending: Pending<'a>, value: Expr, stack: &mut Vec<Pending<'a>>) -> Step<'a> {
+    match pending {
+        Pending::Args {
+            code,
+            mut values,
+            env,
+        } => {
+            values.push(value);
+            next_arg(code, values, env, stack)
+        }
+        Pending::Operator { code, args } => match value {
+            func @ (Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. }) => Step::Call {
+                func,
+                args,
+                caller: None,
+            },
+            _ => Step::Return(Err(EvalError::NotAFunction(code.items()[0].clone()))),
+        },
+        Pending::If { code, env } => match value {
+            Expr::Bool(b) => Step::Eval(code.item(if b { 2 } else { 3 }), env),
+            _ => Step::Return(Err(
+                "The condition for 'if' must evaluate to a boolean.".into()
+            )),
+        },
+        Pending::Define { name, mut env } => {
+            env.insert(name.clone(), value);
+            Step::Return(Ok(Expr::Symbol(name)))
+        }
+        Pending::CheckErrorMessage { code, env } => match expect_string("check-error", &value) {
+            Ok(expected) => {
+                stack.push(Pending::CheckError {
+                    code: code.clone(),
+                    expected: Some(expected.to_string()),
+                });
+                Step::Eval(code.item(1), env)
+            }
+            Err(e) => Step::Return(Err(e)),
+        },
+        Pending::Return { .. }
+        | Pending::Traced { .. }
+        | Pending::Memoize { .. }
+        | Pending::Iterate(_)
+        | Pending::Exit { .. }
+        | Pending::Try { .. }
+        | Pending::CheckError { .. } => unreachable!("handled by resume"),
+    }
+}
+
+/// A builtin that calls a function on each item of a list, such as
+/// `partition`. It waits on the evaluator's stack while the function runs,
+/// so recursion through it is limited like any other recursion rather than
+/// by the size of the Rust stack.
+struct Iteration {
+    op: &'static str,
+    func: Expr,
+    items: Shared<Vec<Expr>>,
+    /// How many items the function has returned for.
+    done: usize,
+    gathered: Gathered,
+    /// Whether the evaluator suspended the builtin itself, rather than
+    /// running it nested for [`apply_builtin_op`]. It then also counts the
+    /// result against the memory limit, as other builtin calls are.
+    suspended: bool,
+    /// Whether the call is being profiled.
+    profiled: bool,
+}
+
+/// What an [`Iteration`] has made of the results so far.
+enum Gathered {
+    /// `flatmap` and `append-map`: the items of the lists returned.
+    Items(Vec<Expr>),
+    /// `partition`: the items that matched, and the rest.
+    Partition(Vec<Expr>, Vec<Expr>),
+    /// `group-by`: the groups in first-seen key order, and where the group
+    /// of each key is.
+    Groups(Vec<(Expr, Vec<Expr>)>, HashMap<EqualKey, usize>),
+    /// `count`: how many items matched.
+    Count(usize),
+    /// `any?`, `every?`, `find` and `position`, which stop at the first item
+    /// that settles the result.
+    Search,
+}
+
+impl Iteration {
+    /// Returns whether `op` is one of the builtins run this way.
+    fn handles(op: &str) -> bool {
+        matches!(
+            op,
+            "flatmap"
+                | "append-map"
+                | "partition"
+                | "group-by"
+                | "any?"
+                | "every?"
+                | "count"
+                | "find"
+                | "position"
+        )
+    }
+
+    /// Checks the arguments of a call to `op` and starts calling its
+    /// function.
+    fn start<'a>(op: &'static str, args: &[Expr], suspended: bool, profiled: bool) -> Step<'a> {
+        let gathered = match op {
+            "flatmap" | "append-map" => Gathered::Items(Vec::new()),
+            "partition" => Gathered::Partition(Vec::new(), Vec::new()),
+            "group-by" => Gathered::Groups(Vec::new(), HashMap::new()),
+            "count" => Gathered::Count(0),
+            _ => Gathered::Search,
+        };
+        let iteration = Iteration {
+            op,
+            func: Expr::empty_list(),
+            items: Shared::new(Vec::new()),
+            done: 0,
+            gathered,
+            suspended,
+            profiled,
+        };
+        let [func, list] = args else {
+            let func = match op {
+                "flatmap" | "append-map" => "a function",
+                "group-by" => "a key function",
+                _ => "a predicate",
+            };
+            let e = format!("'{}' requires {} and a list.", op, func);
+            return iteration.end(Err(e.into()));
+        };
+        let Expr::List(items) = list else {
+            return iteration.end(Err(type_mismatch(op, "a list argument", list)));
+        };
+        Step::Iterate(Iteration {
+            func: func.clone(),
+            items: items.clone(),
+            ..iteration
+        })
+    }
+
+    /// Calls the function on the next item, or finishes once every item has
+    /// been through it.
+    fn next<'a>(self, stack: &mut Vec<Pending<'a>>) -> Step<'a> {
+        let Some(item) = self.items.get(self.done) else {
+            let result = match self.gathered {
+                Gathered::Items(items) => Expr::List(items.into()),
+                Gathered::Partition(matching, rest) => {
+                    Expr::List(vec![Expr::List(matching.into()), Expr::List(rest.into())].into())
+                }
+                // An association list of (key items) pairs.
+                Gathered::Groups(groups, _) => Expr::List(Shared::new(
+                    groups
+                        .into_iter()
+                        .map(|(key, items)| Expr::List(vec![key, Expr::List(items.into())].into()))
+                        .collect(),
+                )),
+                Gathered::Count(n) => Expr::Number(n as f64),
+                // `find` and `position` return false when nothing matches.
+                Gathered::Search => Expr::Bool(self.op == "every?"),
+            };
+            let end = Iteration {
+                gathered: Gathered::Search,
+                ..self
+            };
+            return end.end(Ok(result));
+        };
+        let step = Step::Call {
+            func: self.func.clone(),
+            args: vec![item.clone()],
+            caller: None,
+        };
+        stack.push(Pending::Iterate(self));
+        step
+    }
+
+    /// Takes in what the function returned for the current item.
+    fn gather<'a>(mut self, value: Expr, stack: &mut Vec<Pending<'a>>) -> Step<'a> {
+        let item = &self.items[self.done];
+        let settled = match (&mut self.gathered, value) {
+            (Gathered::Items(items), Expr::List(list)) => {
+                items.extend(Shared::unwrap_or_clone(list));
+                None
+            }
+            (Gathered::Items(_), _) => Some(Err(format!(
+                "The function passed to '{}' must return a list.",
+                self.op
+            )
+            .into())),
+            (Gathered::Groups(groups, index), key) => {
+                match index.get(&EqualKey(key.clone())) {
+                    Some(&i) => groups[i].1.push(item.clone()),
+                    None => {
+                        index.insert(EqualKey(key.clone()), groups.len());
+                        groups.push((key, vec![item.clone()]));
+                    }
+                }
+                None
+            }
+            (gathered, Expr::Bool(matched)) => match gathered {
+                Gathered::Partition(matching, rest) => {
+                    if matched { matching } else { rest }.push(item.clone());
+                    None
+                }
+                Gathered::Count(n) => {
+                    *n += matched as usize;
+                    None
+                }
+                _ => match (self.op, matched) {
+                    ("any?", true) => Some(Ok(Expr::Bool(true))),
+                    ("every?", false) => Some(Ok(Expr::Bool(false))),
+                    ("find", true) => Some(Ok(item.clone())),
+                    ("position", true) => Some(Ok(Expr::Number(self.done as f64))),
+                    _ => None,
+                },
+            },
+            (_, _) => Some(Err(format!(
+                "The predicate passed to '{}' must return a boolean.",
+                self.op
+            )
+            .into())),
+        };
+        match settled {
+            Some(result) => self.end(result),
+            None => {
+                self.done += 1;
+                self.next(stack)
+            }
+        }
+    }
+
+    /// Ends the builtin's call with `result`.
+    fn end<'a>(self, result: Result<Expr, EvalError>) -> Step<'a> {
+        if self.profiled {
+            runtime::profile_exit();
+        }
+        if self.suspended {
+            return Step::Return(track_allocation(result));
+        }
+        Step::Return(result)
+    }
+}
+
+pub(crate) fn eval_lambda(args: &[Expr], env: &Env) -> Result<Expr, EvalError> {
     if args.len() != 2 {
-        return Err("'lambda' requires a list of parameters and a body.".to_string());
+        return Err("'lambda' requires a list of parameters and a body.".into());
     }
     let params_list = match &args[0] {
         Expr::List(p) => p,
-        _ => return Err("The first argument to 'lambda' must be a list of symbols.".to_string()),
+        _ => {
+            return Err("The first argument to 'lambda' must be a list of symbols.".into());
+        }
     };
     let params = params_list
         .iter()
         .map(|p| match p {
             Expr::Symbol(s) => Ok(s.clone()),
-            _ => Err("Lambda parameters must be symbols.".to_string()),
+            _ => Err("Lambda parameters must be symbols.".into()),
         })
-        .collect::<Result<Vec<String>, String>>()?;
-    let body = Box::new(args[1].clone());
-    Ok(Expr::Func { params, body })
+        .collect::<Result<Shared<[Symbol]>, EvalError>>()?;
+    Ok(Expr::Func {
+        params,
+        body: Shared::new(args[1].clone()),
+        env: env.clone(),
+    })
 }
 
-fn eval_if(args: &[Expr], env: &mut Env) -> Result<Expr, String> {
-    if args.len() != 3 {
-        return Err("'if' requires a condition, a then branch, and an else branch.".to_string());
-    }
-    let cond = eval(&args[0], env)?;
-    match cond {
-        Expr::Bool(b) => eval(if b { &args[1] } else { &args[2] }, env),
-        _ => Err("The condition for 'if' must evaluate to a boolean.".to_string()),
+/// Checks the arguments of `(try expr (catch name handler))`, returning
+/// `name` and `handler`.
+///
+/// If evaluating `expr` fails, `handler` is evaluated instead with `name`
+/// bound to a condition describing the error. Interrupts, timeouts and
+/// running out of fuel are not caught.
+fn try_clause(args: &[Expr]) -> Result<(&Symbol, &Expr), EvalError> {
+    match args {
+        [_, Expr::List(clause)] => match clause.as_slice() {
+            [Expr::Symbol(catch), Expr::Symbol(var), handler] if catch == "catch" => {
+                Ok((var, handler))
+            }
+            _ => Err("'try' requires a (catch name handler) clause.".into()),
+        },
+        _ => Err("'try' requires an expression and a catch clause.".into()),
     }
 }
 
-fn apply_procedure(op_expr: &Expr, args: &[Expr], env: &mut Env) -> Result<Expr, String> {
-    let evaluated_args = args
-        .iter()
-        .map(|arg| eval(arg, env))
-        .collect::<Result<Vec<Expr>, String>>()?;
+/// Returns whether `e` must end evaluation, so that neither `try` nor
+/// `check-error` may catch it.
+fn is_fatal(e: &EvalError) -> bool {
+    matches!(
+        e.root(),
+        EvalError::Interrupted | EvalError::FuelExhausted | EvalError::Timeout
+    )
+}
 
-    if let Expr::Symbol(s) = op_expr {
-        match apply_builtin_op(s, &evaluated_args) {
-            Ok(result) => return Ok(result),
-            Err(e) => {
-                if e != "Not a built-in operator" {
-                    return Err(e);
-                }
-                // If it's "Not a built-in operator", fall through to evaluate it as a function
-            }
-        }
+/// Describes an error caught by `try` as a condition.
+fn condition(e: EvalError) -> Expr {
+    match e.into_root() {
+        EvalError::User {
+            kind,
+            message,
+            data,
+        } => Expr::Condition {
+            kind,
+            message,
+            data: Box::new(data),
+        },
+        e => Expr::Condition {
+            kind: e.kind().to_string(),
+            message: e.to_string(),
+            data: Box::new(Expr::empty_list()),
+        },
     }
+}
 
-    let evaluated_op = eval(op_expr, env)?;
-    if let Expr::Func { params, body } = evaluated_op {
-        if params.len() != evaluated_args.len() {
-            return Err(format!(
-                "Function expects {} arguments, but received {}.",
-                params.len(),
-                evaluated_args.len()
-            ));
+/// Implements `trace` and `untrace`, which take the names of the functions
+/// to (un)trace unevaluated and return them as a list.
+fn eval_trace(args: &[Expr], enable: bool) -> Result<Expr, EvalError> {
+    let form = if enable { "trace" } else { "untrace" };
+    for arg in args {
+        match arg {
+            Expr::Symbol(name) => runtime::set_traced(name, enable),
+            _ => return Err(format!("'{}' requires function names.", form).into()),
         }
-        let mut func_env = env.clone();
-        for (param_name, arg_value) in params.iter().zip(evaluated_args) {
-            func_env.insert(param_name.clone(), arg_value);
+    }
+    Ok(Expr::List(args.to_vec().into()))
+}
+
+/// Implements `(deftest name body...)`, which registers the body forms to be
+/// evaluated later by `run-tests`.
+fn eval_deftest(args: &[Expr]) -> Result<Expr, EvalError> {
+    match args {
+        [Expr::Symbol(name), body @ ..] if !body.is_empty() => {
+            runtime::register_test(name, body.to_vec());
+            Ok(Expr::Symbol(name.clone()))
         }
-        eval(&body, &mut func_env)
-    } else {
-        Err(format!("Not a function: {}", op_expr))
+        _ => Err("'deftest' requires a name and at least one body expression.".into()),
     }
 }
 
-fn apply_builtin_op(op: &str, args: &[Expr]) -> Result<Expr, String> {
-    let numeric_op = |f: fn(f64, f64) -> f64, initial: f64| -> Result<Expr, String> {
+/// The most decimals `format-number` writes, which is more than a number
+/// has and keeps the result to a sensible size.
+const MAX_DECIMALS: usize = 100;
+
+/// Runs the standard builtin `op`. Only the registry in
+/// [`builtins`](crate::builtins) calls this, with the names it lists.
+pub(crate) fn apply_builtin_op(
+    op: &'static str,
+    args: &[Expr],
+    env: &mut Env,
+) -> Result<Expr, EvalError> {
+    let numeric_op = |f: fn(f64, f64) -> f64, initial: f64| -> Result<Expr, EvalError> {
         let nums = args
             .iter()
-            .map(|arg| match arg {
-                Expr::Number(n) => Ok(*n),
-                _ => Err(format!("Operator '{}' requires number arguments.", op)),
-            })
-            .collect::<Result<Vec<f64>, String>>()?;
+            .map(|arg| expect_number(op, arg))
+            .collect::<Result<Vec<f64>, EvalError>>()?;
         if op != "+" && op != "*" && nums.is_empty() {
-            return Err(format!("Operator '{}' requires at least one argument.", op));
+            return Err(format!("Operator '{}' requires at least one argument.", op).into());
         }
         let result = nums.iter().fold(initial, |acc, &x| f(acc, x));
         Ok(Expr::Number(result))
@@ -145,13 +938,10 @@ fn apply_builtin_op(op: &str, args: &[Expr]) -> Result<Expr, String> {
         "-" => {
             let nums = args
                 .iter()
-                .map(|arg| match arg {
-                    Expr::Number(n) => Ok(*n),
-                    _ => Err(format!("Operator '{}' requires number arguments.", op)),
-                })
-                .collect::<Result<Vec<f64>, String>>()?;
+                .map(|arg| expect_number(op, arg))
+                .collect::<Result<Vec<f64>, EvalError>>()?;
             if nums.is_empty() {
-                return Err("Operator '-' requires at least one argument.".to_string());
+                return Err("Operator '-' requires at least one argument.".into());
             }
             let first = nums[0];
             if nums.len() == 1 {
@@ -170,43 +960,1546 @@ fn apply_builtin_op(op: &str, args: &[Expr]) -> Result<Expr, String> {
                     false
                 }
             }) {
-                return Err("Division by zero.".to_string());
+                return Err(EvalError::DivisionByZero);
             }
             let nums = args
                 .iter()
-                .map(|arg| match arg {
-                    Expr::Number(n) => Ok(*n),
-                    _ => Err(format!("Operator '{}' requires number arguments.", op)),
-                })
-                .collect::<Result<Vec<f64>, String>>()?;
+                .map(|arg| expect_number(op, arg))
+                .collect::<Result<Vec<f64>, EvalError>>()?;
             if nums.is_empty() {
-                return Err("Operator '/' requires at least one argument.".to_string());
+                return Err("Operator '/' requires at least one argument.".into());
             }
             let first = nums[0];
             Ok(Expr::Number(
                 nums[1..].iter().fold(first, |acc, &x| acc / x),
             ))
         }
-        ">" => {
-            if args.len() != 2 {
-                return Err("'>' requires two arguments.".to_string());
-            }
-            if let (Expr::Number(n1), Expr::Number(n2)) = (&args[0], &args[1]) {
-                Ok(Expr::Bool(n1 > n2))
-            } else {
-                Err("'>' requires number arguments.".to_string())
+        ">" | "<" | ">=" | "<=" | "=" => {
+            if args.len() < 2 {
+                return Err(format!("'{}' requires at least two arguments.", op).into());
             }
+            let nums = args
+                .iter()
+                .map(|arg| expect_number(op, arg))
+                .collect::<Result<Vec<f64>, EvalError>>()?;
+            // Chained comparisons hold when every adjacent pair satisfies the operator.
+            let result = nums.windows(2).all(|pair| {
+                let (n1, n2) = (pair[0], pair[1]);
+                match op {
+                    ">" => n1 > n2,
+                    "<" => n1 < n2,
+                    ">=" => n1 >= n2,
+                    "<=" => n1 <= n2,
+                    // Numeric equality only; structural comparison is `equal?`.
+                    _ => n1 == n2,
+                }
+            });
+            Ok(Expr::Bool(result))
         }
         "concat" => {
             let strings = args
                 .iter()
                 .map(|arg| match arg {
-                    Expr::String(s) => Ok(s.as_str()),
-                    _ => Err("'concat' requires string arguments.".to_string()),
+                    Expr::String(s) => Ok(&**s),
+                    _ => Err("'concat' requires string arguments.".into()),
+                })
+                .collect::<Result<Vec<&str>, EvalError>>()?;
+            runtime::check_allocation(strings.iter().map(|s| s.len()).sum())?;
+            Ok(Expr::String(strings.concat().into()))
+        }
+        "list" => Ok(Expr::List(args.to_vec().into())),
+        "flatten" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("'flatten' requires a list and an optional depth.".into());
+            }
+            let list = expect_list("flatten", &args[0])?;
+            let depth = match args.get(1) {
+                Some(Expr::Number(n)) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+                Some(_) => {
+                    return Err("The depth for 'flatten' must be a non-negative integer.".into());
+                }
+                None => None,
+            };
+            let mut result = Vec::new();
+            flatten_into(list, depth, &mut result);
+            Ok(Expr::List(result.into()))
+        }
+        "flatmap" | "append-map" | "partition" | "group-by" | "any?" | "every?" | "count"
+        | "find" | "position" => run(Iteration::start(op, args, false, false)),
+        "distinct" | "remove-duplicates" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let list = expect_list(op, &args[0])?;
+            let mut seen = HashSet::new();
+            let result = list
+                .iter()
+                .filter(|item| seen.insert(EqualKey((*item).clone())))
+                .cloned()
+                .collect();
+            Ok(Expr::List(Shared::new(result)))
+        }
+        "butlast" => {
+            if args.len() != 1 {
+                return Err("'butlast' requires one argument.".into());
+            }
+            let list = expect_list(op, &args[0])?;
+            if list.is_empty() {
+                return Err("'butlast' requires a non-empty list.".into());
+            }
+            Ok(Expr::List(list[..list.len() - 1].to_vec().into()))
+        }
+        "list-tail" | "split-at" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires a list and an index.", op).into());
+            }
+            let list = expect_list(op, &args[0])?;
+            let k = expect_index(op, &args[1])?;
+            if k > list.len() {
+                return Err(format!(
+                    "'{}' index {} exceeds the list length {}.",
+                    op,
+                    k,
+                    list.len()
+                )
+                .into());
+            }
+            let (prefix, suffix) = list.split_at(k);
+            if op == "list-tail" {
+                Ok(Expr::List(suffix.to_vec().into()))
+            } else {
+                Ok(Expr::List(
+                    vec![
+                        Expr::List(prefix.to_vec().into()),
+                        Expr::List(suffix.to_vec().into()),
+                    ]
+                    .into(),
+                ))
+            }
+        }
+        "string-length" => {
+            if args.len() != 1 {
+                return Err("'string-length' requires one argument.".into());
+            }
+            let s = expect_string(op, &args[0])?;
+            Ok(Expr::Number(s.chars().count() as f64))
+        }
+        "substring" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err("'substring' requires a string, a start and an optional end.".into());
+            }
+            // Indices count characters, not bytes, so slicing never splits a code point.
+            let chars: Vec<char> = expect_string(op, &args[0])?.chars().collect();
+            let start = expect_index(op, &args[1])?;
+            let end = match args.get(2) {
+                Some(arg) => expect_index(op, arg)?,
+                None => chars.len(),
+            };
+            if start > end || end > chars.len() {
+                return Err(format!(
+                    "'substring' range {}..{} is out of bounds for a string of length {}.",
+                    start,
+                    end,
+                    chars.len()
+                )
+                .into());
+            }
+            Ok(Expr::String(
+                chars[start..end].iter().collect::<String>().into(),
+            ))
+        }
+        "string-ref" => {
+            if args.len() != 2 {
+                return Err("'string-ref' requires a string and an index.".into());
+            }
+            let s = expect_string(op, &args[0])?;
+            let k = expect_index(op, &args[1])?;
+            // There is no character type, so the character comes back as a
+            // one-character string.
+            match s.chars().nth(k) {
+                Some(c) => Ok(Expr::String(c.to_string().into())),
+                None => Err(format!(
+                    "'string-ref' index {} is out of bounds for a string of length {}.",
+                    k,
+                    s.chars().count()
+                )
+                .into()),
+            }
+        }
+        "string->list" => {
+            if args.len() != 1 {
+                return Err("'string->list' requires one argument.".into());
+            }
+            // One element per Unicode scalar value, each a one-character string.
+            let s = expect_string(op, &args[0])?;
+            runtime::check_allocation(s.chars().count() * size_of::<Expr>() + s.len())?;
+            Ok(Expr::List(Shared::new(
+                s.chars()
+                    .map(|c| Expr::String(c.to_string().into()))
+                    .collect(),
+            )))
+        }
+        "list->string" => {
+            if args.len() != 1 {
+                return Err("'list->string' requires one argument.".into());
+            }
+            let chars = expect_list(op, &args[0])?
+                .iter()
+                .map(|c| expect_char(op, c))
+                .collect::<Result<String, EvalError>>()?;
+            Ok(Expr::String(chars.into()))
+        }
+        "string-split" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("'string-split' requires a string and an optional separator.".into());
+            }
+            let s = expect_string(op, &args[0])?;
+            let parts: Vec<&str> = match args.get(1) {
+                Some(sep) => {
+                    let sep = expect_string(op, sep)?;
+                    if sep.is_empty() {
+                        return Err("The separator for 'string-split' must not be empty.".into());
+                    }
+                    s.split(sep).collect()
+                }
+                None => s.split_whitespace().collect(),
+            };
+            Ok(Expr::List(Shared::new(
+                parts
+                    .into_iter()
+                    .map(|part| Expr::String(part.into()))
+                    .collect(),
+            )))
+        }
+        "string-join" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(
+                    "'string-join' requires a list of strings and an optional delimiter.".into(),
+                );
+            }
+            let strings = expect_list(op, &args[0])?
+                .iter()
+                .map(|item| expect_string(op, item))
+                .collect::<Result<Vec<&str>, EvalError>>()?;
+            let delimiter = match args.get(1) {
+                Some(d) => expect_string(op, d)?,
+                None => "",
+            };
+            let len = strings.iter().map(|s| s.len()).sum::<usize>()
+                + strings.len().saturating_sub(1) * delimiter.len();
+            runtime::check_allocation(len)?;
+            Ok(Expr::String(strings.join(delimiter).into()))
+        }
+        "upcase" | "downcase" | "trim" | "trim-left" | "trim-right" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let s = expect_string(op, &args[0])?;
+            let result = match op {
+                "upcase" => s.to_uppercase(),
+                "downcase" => s.to_lowercase(),
+                "trim" => s.trim().to_string(),
+                "trim-left" => s.trim_start().to_string(),
+                _ => s.trim_end().to_string(),
+            };
+            Ok(Expr::String(result.into()))
+        }
+        "string->number" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("'string->number' requires a string and an optional radix.".into());
+            }
+            let s = expect_string(op, &args[0])?;
+            let radix = match args.get(1) {
+                Some(radix) => {
+                    let radix = expect_index(op, radix)?;
+                    if !(2..=36).contains(&radix) {
+                        return Err(
+                            "The radix for 'string->number' must be between 2 and 36.".into()
+                        );
+                    }
+                    Some(radix as u32)
+                }
+                None => None,
+            };
+            // Anything that is not a number in the requested radix is false.
+            Ok(parse_number(s, radix)
+                .map(Expr::Number)
+                .unwrap_or(Expr::Bool(false)))
+        }
+        "number->string" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("'number->string' requires a number and an optional radix.".into());
+            }
+            let n = match &args[0] {
+                Expr::Number(n) => *n,
+                _ => {
+                    return Err("'number->string' requires a number argument.".into());
+                }
+            };
+            match args.get(1) {
+                None => Ok(Expr::String(format_number(n).into())),
+                Some(radix) => {
+                    let radix = expect_index(op, radix)?;
+                    if !(2..=36).contains(&radix) {
+                        return Err(
+                            "The radix for 'number->string' must be between 2 and 36.".into()
+                        );
+                    }
+                    if radix == 10 {
+                        return Ok(Expr::String(format_number(n).into()));
+                    }
+                    let n = expect_bits(op, &args[0])?;
+                    Ok(Expr::String(integer_to_radix(n, radix as u32).into()))
+                }
+            }
+        }
+        "format-number" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err(
+                    "'format-number' requires a number, a number of decimals and an optional separator.".into(),
+                );
+            }
+            let n = expect_number(op, &args[0])?;
+            let decimals = expect_index(op, &args[1])?;
+            if decimals > MAX_DECIMALS {
+                return Err(
+                    format!("'format-number' allows at most {} decimals.", MAX_DECIMALS).into(),
+                );
+            }
+            let fixed = format!("{:.*}", decimals, n);
+            match args.get(2) {
+                Some(sep) => Ok(Expr::String(
+                    group_thousands(&fixed, expect_string(op, sep)?).into(),
+                )),
+                None => Ok(Expr::String(fixed.into())),
+            }
+        }
+        "string->symbol" => {
+            if args.len() != 1 {
+                return Err("'string->symbol' requires one argument.".into());
+            }
+            let name = expect_string(op, &args[0])?;
+            if name.is_empty() {
+                return Err("'string->symbol' requires a non-empty string.".into());
+            }
+            Ok(Expr::Symbol(name.into()))
+        }
+        "symbol->string" => {
+            if args.len() != 1 {
+                return Err("'symbol->string' requires one argument.".into());
+            }
+            match &args[0] {
+                Expr::Symbol(name) => Ok(Expr::String(name.as_str().into())),
+                _ => Err("'symbol->string' requires a symbol argument.".into()),
+            }
+        }
+        "string-contains?" | "starts-with?" | "ends-with?" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires two string arguments.", op).into());
+            }
+            let s = expect_string(op, &args[0])?;
+            let needle = expect_string(op, &args[1])?;
+            let result = match op {
+                "string-contains?" => s.contains(needle),
+                "starts-with?" => s.starts_with(needle),
+                _ => s.ends_with(needle),
+            };
+            Ok(Expr::Bool(result))
+        }
+        "string-replace" => {
+            if args.len() != 3 && args.len() != 4 {
+                return Err(
+                    "'string-replace' requires a string, a pattern, a replacement and an optional count.".into(),
+                );
+            }
+            let s = expect_string(op, &args[0])?;
+            let from = expect_string(op, &args[1])?;
+            let to = expect_string(op, &args[2])?;
+            if from.is_empty() {
+                return Err("The pattern for 'string-replace' must not be empty.".into());
+            }
+            let count = match args.get(3) {
+                Some(count) => expect_index(op, count)?,
+                None => usize::MAX,
+            };
+            if to.len() > from.len() {
+                let matches = s.matches(from).take(count).count();
+                runtime::check_allocation(s.len() + matches * (to.len() - from.len()))?;
+            }
+            let result = s.replacen(from, to, count);
+            Ok(Expr::String(result.into()))
+        }
+        "format" => {
+            if args.is_empty() {
+                return Err("'format' requires a format string.".into());
+            }
+            let template = expect_string(op, &args[0])?;
+            format_directives(template, &args[1..]).map(Expr::from)
+        }
+        "char->integer" => {
+            if args.len() != 1 {
+                return Err("'char->integer' requires one argument.".into());
+            }
+            Ok(Expr::Number(expect_char(op, &args[0])? as u32 as f64))
+        }
+        "integer->char" => {
+            if args.len() != 1 {
+                return Err("'integer->char' requires one argument.".into());
+            }
+            let code = expect_index(op, &args[0])?;
+            u32::try_from(code)
+                .ok()
+                .and_then(char::from_u32)
+                .map(|c| Expr::String(c.to_string().into()))
+                .ok_or_else(|| format!("{} is not a valid Unicode scalar value.", code).into())
+        }
+        "char-upcase" | "char-downcase" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let c = expect_char(op, &args[0])?;
+            // Characters whose case mapping expands (like 'ß') are left unchanged.
+            let mut mapped: Vec<char> = if op == "char-upcase" {
+                c.to_uppercase().collect()
+            } else {
+                c.to_lowercase().collect()
+            };
+            if mapped.len() != 1 {
+                mapped = vec![c];
+            }
+            Ok(Expr::String(mapped[0].to_string().into()))
+        }
+        "char-alphabetic?" | "char-numeric?" | "char-whitespace?" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let c = expect_char(op, &args[0])?;
+            let result = match op {
+                "char-alphabetic?" => c.is_alphabetic(),
+                "char-numeric?" => c.is_numeric(),
+                _ => c.is_whitespace(),
+            };
+            Ok(Expr::Bool(result))
+        }
+        // Lists and strings share their storage, but `eq?` deliberately keeps
+        // value semantics instead of comparing pointers, and so behaves
+        // exactly like `eqv?`.
+        "eq?" | "eqv?" | "equal?" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires two arguments.", op).into());
+            }
+            let result = if op == "equal?" {
+                args[0].is_equal(&args[1])
+            } else {
+                args[0].is_eqv(&args[1])
+            };
+            Ok(Expr::Bool(result))
+        }
+        "number?" | "string?" | "symbol?" | "list?" | "map?" | "procedure?" | "bool?"
+        | "boolean?" | "condition?" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let result = matches!(
+                (op, &args[0]),
+                ("number?", Expr::Number(_))
+                    | ("string?", Expr::String(_))
+                    | ("symbol?", Expr::Symbol(_))
+                    | ("list?", Expr::List(_))
+                    | ("map?", Expr::Map(_))
+                    | (
+                        "procedure?",
+                        Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. }
+                    )
+                    | ("bool?" | "boolean?", Expr::Bool(_))
+                    | ("condition?", Expr::Condition { .. })
+            );
+            Ok(Expr::Bool(result))
+        }
+        "modulo" | "remainder" | "quotient" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires two arguments.", op).into());
+            }
+            let a = expect_integer(op, &args[0])?;
+            let b = expect_integer(op, &args[1])?;
+            if b == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            let result = match op {
+                // `%` truncates, so the remainder takes the sign of the dividend...
+                "remainder" => a % b,
+                // ...and `modulo` shifts it to take the sign of the divisor.
+                "modulo" => {
+                    let r = a % b;
+                    if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                        r + b
+                    } else {
+                        r
+                    }
+                }
+                _ => (a / b).trunc(),
+            };
+            Ok(Expr::Number(result))
+        }
+        "abs" => {
+            if args.len() != 1 {
+                return Err("'abs' requires one argument.".into());
+            }
+            Ok(Expr::Number(expect_number(op, &args[0])?.abs()))
+        }
+        "min" | "max" => {
+            // A single list argument is treated as the list of operands.
+            let operands = match args {
+                [Expr::List(list)] => list.as_slice(),
+                _ => args,
+            };
+            if operands.is_empty() {
+                return Err(format!("'{}' requires at least one argument.", op).into());
+            }
+            let nums = operands
+                .iter()
+                .map(|arg| expect_number(op, arg))
+                .collect::<Result<Vec<f64>, EvalError>>()?;
+            let result =
+                nums[1..].iter().fold(
+                    nums[0],
+                    |acc, &x| {
+                        if op == "min" { acc.min(x) } else { acc.max(x) }
+                    },
+                );
+            Ok(Expr::Number(result))
+        }
+        "floor" | "ceiling" | "round" | "truncate" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let n = expect_number(op, &args[0])?;
+            let result = match op {
+                "floor" => n.floor(),
+                "ceiling" => n.ceil(),
+                // As in Scheme, halfway cases round to the nearest even integer.
+                "round" => n.round_ties_even(),
+                _ => n.trunc(),
+            };
+            Ok(Expr::Number(result))
+        }
+        "sqrt" | "exp" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let n = expect_number(op, &args[0])?;
+            if op == "sqrt" {
+                if n < 0.0 {
+                    return Err(format!("Domain error: 'sqrt' is undefined for {}.", n).into());
+                }
+                Ok(Expr::Number(n.sqrt()))
+            } else {
+                Ok(Expr::Number(n.exp()))
+            }
+        }
+        "expt" => {
+            if args.len() != 2 {
+                return Err("'expt' requires a base and an exponent.".into());
+            }
+            let base = expect_number(op, &args[0])?;
+            let exponent = expect_number(op, &args[1])?;
+            if base == 0.0 && exponent < 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            let result = base.powf(exponent);
+            if result.is_nan() && !base.is_nan() && !exponent.is_nan() {
+                return Err(format!(
+                    "Domain error: 'expt' is undefined for base {} and exponent {}.",
+                    base, exponent
+                )
+                .into());
+            }
+            Ok(Expr::Number(result))
+        }
+        "log" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("'log' requires a number and an optional base.".into());
+            }
+            let n = expect_number(op, &args[0])?;
+            if n <= 0.0 {
+                return Err(format!("Domain error: 'log' is undefined for {}.", n).into());
+            }
+            match args.get(1) {
+                Some(base) => {
+                    let base = expect_number(op, base)?;
+                    if base <= 0.0 || base == 1.0 {
+                        return Err(
+                            format!("Domain error: 'log' is undefined for base {}.", base).into(),
+                        );
+                    }
+                    Ok(Expr::Number(n.log(base)))
+                }
+                None => Ok(Expr::Number(n.ln())),
+            }
+        }
+        "sin" | "cos" | "tan" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let n = expect_number(op, &args[0])?;
+            let result = match op {
+                "sin" => n.sin(),
+                "cos" => n.cos(),
+                _ => n.tan(),
+            };
+            Ok(Expr::Number(result))
+        }
+        "atan" => match args {
+            [y] => Ok(Expr::Number(expect_number(op, y)?.atan())),
+            [y, x] => Ok(Expr::Number(
+                expect_number(op, y)?.atan2(expect_number(op, x)?),
+            )),
+            _ => Err("'atan' requires one or two arguments.".into()),
+        },
+        "random" => match args {
+            // 53 random bits give every representable multiple of 2^-53 in [0, 1).
+            [] => Ok(Expr::Number(
+                (runtime::with_rng(|rng| rng.next_u64()) >> 11) as f64 / (1u64 << 53) as f64,
+            )),
+            [Expr::Number(n)] if *n >= 1.0 && n.fract() == 0.0 && *n <= (1u64 << 53) as f64 => {
+                let bits = runtime::with_rng(|rng| rng.next_u64());
+                // Multiply-shift maps the bits onto [0, n) without a modulo.
+                Ok(Expr::Number(((bits as u128 * *n as u128) >> 64) as f64))
+            }
+            [_] => Err("'random' requires a positive integer bound.".into()),
+            _ => Err("'random' requires zero or one argument.".into()),
+        },
+        "random-seed" => {
+            if args.len() != 1 {
+                return Err("'random-seed' requires one argument.".into());
+            }
+            let seed = expect_integer(op, &args[0])?;
+            runtime::with_rng(|rng| rng.reseed(seed as i64 as u64));
+            Ok(Expr::empty_list())
+        }
+        "gcd" | "lcm" => {
+            let nums = args
+                .iter()
+                .map(|arg| expect_integer(op, arg).map(f64::abs))
+                .collect::<Result<Vec<f64>, EvalError>>()?;
+            let gcd = |mut a: f64, mut b: f64| {
+                while b != 0.0 {
+                    (a, b) = (b, a % b);
+                }
+                a
+            };
+            // With no arguments these return their identities, 0 and 1.
+            let result = if op == "gcd" {
+                nums.into_iter().fold(0.0, gcd)
+            } else {
+                nums.into_iter().fold(1.0, |acc, x| {
+                    if acc == 0.0 || x == 0.0 {
+                        0.0
+                    } else {
+                        acc / gcd(acc, x) * x
+                    }
                 })
-                .collect::<Result<Vec<&str>, String>>()?;
-            Ok(Expr::String(strings.concat()))
+            };
+            Ok(Expr::Number(result))
+        }
+        "bit-and" | "bit-or" | "bit-xor" => {
+            let ints = args
+                .iter()
+                .map(|arg| expect_bits(op, arg))
+                .collect::<Result<Vec<i64>, EvalError>>()?;
+            let result = match op {
+                "bit-and" => ints.into_iter().fold(-1, |acc, x| acc & x),
+                "bit-or" => ints.into_iter().fold(0, |acc, x| acc | x),
+                _ => ints.into_iter().fold(0, |acc, x| acc ^ x),
+            };
+            Ok(Expr::Number(result as f64))
+        }
+        "bit-not" => {
+            if args.len() != 1 {
+                return Err("'bit-not' requires one argument.".into());
+            }
+            Ok(Expr::Number(!expect_bits(op, &args[0])? as f64))
+        }
+        "shift-left" | "shift-right" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires a number and a shift amount.", op).into());
+            }
+            let n = expect_bits(op, &args[0])?;
+            let amount = expect_index(op, &args[1])?;
+            if amount > 63 {
+                return Err(format!("'{}' amount must be between 0 and 63.", op).into());
+            }
+            // `shift-right` is arithmetic, so negative numbers stay negative.
+            let result = if op == "shift-left" {
+                n << amount
+            } else {
+                n >> amount
+            };
+            Ok(Expr::Number(result as f64))
+        }
+        "print" | "display" | "write" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            // `write` produces the re-readable form, with strings quoted.
+            let mut text = if op == "write" {
+                args[0].to_string()
+            } else {
+                args[0].to_display_string()
+            };
+            if op == "print" {
+                text.push('\n');
+            }
+            runtime::write_output(&text)?;
+            Ok(Expr::empty_list())
+        }
+        "newline" => {
+            if !args.is_empty() {
+                return Err("'newline' takes no arguments.".into());
+            }
+            runtime::write_output("\n")?;
+            Ok(Expr::empty_list())
+        }
+        "read" | "read-string" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            Ok(parse(expect_string(op, &args[0])?)?)
+        }
+        "eval" => {
+            if args.len() != 1 {
+                return Err("'eval' requires one argument.".into());
+            }
+            eval(&args[0], env)
+        }
+        "break" => {
+            if !args.is_empty() {
+                return Err("'break' takes no arguments.".into());
+            }
+            runtime::break_here(env)?;
+            Ok(Expr::empty_list())
+        }
+        "profile-start" | "profile-stop" | "profile-data" | "profile-report" => {
+            if !args.is_empty() {
+                return Err(format!("'{}' takes no arguments.", op).into());
+            }
+            match op {
+                "profile-start" => runtime::start_profiling(),
+                "profile-stop" => runtime::stop_profiling(),
+                "profile-data" => return Ok(runtime::profile().to_expr()),
+                _ => runtime::write_output(&runtime::profile().report())?,
+            }
+            Ok(Expr::empty_list())
+        }
+        "read-line" => {
+            if !args.is_empty() {
+                return Err("'read-line' takes no arguments.".into());
+            }
+            // End of input is reported as false, which no line can be.
+            Ok(runtime::read_input_line()?
+                .map(Expr::from)
+                .unwrap_or(Expr::Bool(false)))
+        }
+        "read-file" => {
+            if args.len() != 1 {
+                return Err("'read-file' requires a path.".into());
+            }
+            require_filesystem(op)?;
+            let path = expect_string(op, &args[0])?;
+            if let Ok(metadata) = std::fs::metadata(path) {
+                runtime::check_allocation(metadata.len().try_into().unwrap_or(usize::MAX))?;
+            }
+            std::fs::read_to_string(path)
+                .map(Expr::from)
+                .map_err(|e| format!("'{}' failed for '{}': {}", op, path, e).into())
+        }
+        "write-file" | "append-file" => {
+            if args.len() != 2 {
+                return Err(format!("'{}' requires a path and a string.", op).into());
+            }
+            require_filesystem(op)?;
+            let path = expect_string(op, &args[0])?;
+            let contents = expect_string(op, &args[1])?;
+            let result = if op == "write-file" {
+                std::fs::write(path, contents)
+            } else {
+                std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))
+            };
+            result
+                .map(|_| Expr::empty_list())
+                .map_err(|e| format!("'{}' failed for '{}': {}", op, path, e).into())
+        }
+        "file-exists?" | "directory?" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires a path.", op).into());
+            }
+            require_filesystem(op)?;
+            let path = std::path::Path::new(expect_string(op, &args[0])?);
+            let result = if op == "file-exists?" {
+                path.exists()
+            } else {
+                path.is_dir()
+            };
+            Ok(Expr::Bool(result))
+        }
+        "list-dir" => {
+            if args.len() != 1 {
+                return Err("'list-dir' requires a path.".into());
+            }
+            require_filesystem(op)?;
+            let path = expect_string(op, &args[0])?;
+            let fail = |e: std::io::Error| format!("'{}' failed for '{}': {}", op, path, e);
+            let mut names = Vec::new();
+            for entry in std::fs::read_dir(path).map_err(fail)? {
+                names.push(
+                    entry
+                        .map_err(fail)?
+                        .file_name()
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            }
+            // Directory order is platform-dependent, so sort for reproducible scripts.
+            names.sort();
+            Ok(Expr::List(Shared::new(
+                names.into_iter().map(Expr::from).collect(),
+            )))
+        }
+        "delete-file" => {
+            if args.len() != 1 {
+                return Err("'delete-file' requires a path.".into());
+            }
+            require_filesystem(op)?;
+            let path = expect_string(op, &args[0])?;
+            std::fs::remove_file(path)
+                .map(|_| Expr::empty_list())
+                .map_err(|e| format!("'{}' failed for '{}': {}", op, path, e).into())
+        }
+        "getenv" => {
+            if args.len() != 1 {
+                return Err("'getenv' requires a variable name.".into());
+            }
+            require_capability(op, runtime::capabilities().environment)?;
+            let name = expect_string(op, &args[0])?;
+            Ok(runtime::get_env_var(name)
+                .map(Expr::from)
+                .unwrap_or(Expr::Bool(false)))
+        }
+        "setenv" => {
+            if args.len() != 2 {
+                return Err("'setenv' requires a variable name and a value.".into());
+            }
+            require_capability(op, runtime::capabilities().environment)?;
+            let name = expect_string(op, &args[0])?;
+            let value = expect_string(op, &args[1])?;
+            if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0')
+            {
+                return Err(
+                    format!("Invalid environment variable name or value for '{}'.", name).into(),
+                );
+            }
+            runtime::set_env_var(name, value);
+            Ok(Expr::empty_list())
+        }
+        "shell" | "exec" => {
+            require_capability(op, runtime::capabilities().process)?;
+            let mut command = if op == "shell" {
+                if args.len() != 1 {
+                    return Err("'shell' requires a command string.".into());
+                }
+                let mut command = std::process::Command::new("sh");
+                command.arg("-c").arg(expect_string(op, &args[0])?);
+                command
+            } else {
+                if args.is_empty() {
+                    return Err("'exec' requires a program and its arguments.".into());
+                }
+                let argv = args
+                    .iter()
+                    .map(|arg| expect_string(op, arg))
+                    .collect::<Result<Vec<&str>, EvalError>>()?;
+                let mut command = std::process::Command::new(argv[0]);
+                command.args(&argv[1..]);
+                command
+            };
+            let output = command
+                .envs(runtime::env_vars())
+                .output()
+                .map_err(|e| format!("'{}' failed to start: {}", op, e))?;
+            // The result is a list of (exit-code stdout stderr); the exit
+            // code is false if the process was killed by a signal.
+            let code = output
+                .status
+                .code()
+                .map(|c| Expr::Number(c as f64))
+                .unwrap_or(Expr::Bool(false));
+            Ok(Expr::List(
+                vec![
+                    code,
+                    Expr::String(String::from_utf8_lossy(&output.stdout).into_owned().into()),
+                    Expr::String(String::from_utf8_lossy(&output.stderr).into_owned().into()),
+                ]
+                .into(),
+            ))
+        }
+        "current-time" => {
+            if !args.is_empty() {
+                return Err("'current-time' takes no arguments.".into());
+            }
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|e| format!("System clock is before the Unix epoch: {}", e))?;
+            Ok(Expr::Number(now.as_secs_f64()))
+        }
+        "monotonic-ms" => {
+            if !args.is_empty() {
+                return Err("'monotonic-ms' takes no arguments.".into());
+            }
+            // Only differences between readings are meaningful.
+            static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+            let start = START.get_or_init(std::time::Instant::now);
+            Ok(Expr::Number(start.elapsed().as_secs_f64() * 1000.0))
+        }
+        "sleep" => {
+            if args.len() != 1 {
+                return Err("'sleep' requires a number of seconds.".into());
+            }
+            let seconds = expect_number(op, &args[0])?;
+            if !(0.0..=u32::MAX as f64).contains(&seconds) {
+                return Err("'sleep' requires a non-negative number of seconds.".into());
+            }
+            // Sleep in short slices so that an interrupt is noticed promptly,
+            // and no further than the deadline of a timed evaluation.
+            const SLICE: Duration = Duration::from_millis(10);
+            let deadline = Instant::now() + Duration::from_secs_f64(seconds);
+            let timeout = runtime::deadline();
+            loop {
+                runtime::check_interrupt()?;
+                let now = Instant::now();
+                if timeout.is_some_and(|timeout| now >= timeout) {
+                    return Err(EvalError::Timeout);
+                }
+                if now >= deadline {
+                    break;
+                }
+                let until = timeout.map_or(deadline, |timeout| timeout.min(deadline));
+                std::thread::sleep(SLICE.min(until - now));
+            }
+            Ok(Expr::empty_list())
+        }
+        "hash-map" => {
+            if !args.len().is_multiple_of(2) {
+                return Err("'hash-map' requires an even number of arguments.".into());
+            }
+            let mut map = std::collections::BTreeMap::new();
+            for pair in args.chunks(2) {
+                map.insert(expect_string(op, &pair[0])?.to_string(), pair[1].clone());
+            }
+            Ok(Expr::Map(map))
+        }
+        "map-get" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err("'map-get' requires a map, a key and an optional default.".into());
+            }
+            let map = expect_map(op, &args[0])?;
+            let key = expect_string(op, &args[1])?;
+            match (map.get(key), args.get(2)) {
+                (Some(value), _) => Ok(value.clone()),
+                (None, Some(default)) => Ok(default.clone()),
+                (None, None) => Err(format!("Key \"{}\" not found in map.", key).into()),
+            }
+        }
+        "map-keys" => {
+            if args.len() != 1 {
+                return Err("'map-keys' requires one argument.".into());
+            }
+            let map = expect_map(op, &args[0])?;
+            Ok(Expr::List(Shared::new(
+                map.keys().cloned().map(Expr::from).collect(),
+            )))
+        }
+        "json-parse" => {
+            if args.len() != 1 {
+                return Err("'json-parse' requires one argument.".into());
+            }
+            Ok(json::parse(expect_string(op, &args[0])?)?)
+        }
+        "json-stringify" => {
+            let pretty = match args {
+                [_] => false,
+                [_, Expr::Bool(pretty)] => *pretty,
+                _ => {
+                    return Err(
+                        "'json-stringify' requires a value and an optional pretty flag.".into(),
+                    );
+                }
+            };
+            Ok(Expr::String(json::stringify(&args[0], pretty)?.into()))
+        }
+        "base64-encode" | "hex-encode" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let bytes = expect_bytes(op, &args[0])?;
+            let encoded = if op == "base64-encode" {
+                codec::base64_encode(&bytes)
+            } else {
+                codec::hex_encode(&bytes)
+            };
+            Ok(Expr::String(encoded.into()))
+        }
+        "base64-decode" | "hex-decode" | "base64-decode-bytes" | "hex-decode-bytes" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let text = expect_string(op, &args[0])?;
+            let bytes = if op.starts_with("base64") {
+                codec::base64_decode(text)?
+            } else {
+                codec::hex_decode(text)?
+            };
+            if op.ends_with("-bytes") {
+                // A list of byte numbers, the form `expect_bytes` accepts.
+                return Ok(Expr::List(Shared::new(
+                    bytes.into_iter().map(|b| Expr::Number(b as f64)).collect(),
+                )));
+            }
+            String::from_utf8(bytes).map(Expr::from).map_err(|_| {
+                format!(
+                    "'{}' decoded bytes that are not UTF-8 text; use '{}-bytes'.",
+                    op, op
+                )
+                .into()
+            })
+        }
+        #[cfg(feature = "hash")]
+        "sha256" | "sha1" | "md5" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            let bytes = expect_bytes(op, &args[0])?;
+            let digest = match op {
+                "sha256" => crate::hash::sha256(&bytes).to_vec(),
+                "sha1" => crate::hash::sha1(&bytes).to_vec(),
+                _ => crate::hash::md5(&bytes).to_vec(),
+            };
+            Ok(Expr::String(codec::hex_encode(&digest).into()))
+        }
+        "uuid" => {
+            if !args.is_empty() {
+                return Err("'uuid' takes no arguments.".into());
+            }
+            // Draws from the runtime random source, so `random-seed` or an
+            // injected source makes the result reproducible.
+            let (hi, lo) = runtime::with_rng(|rng| (rng.next_u64(), rng.next_u64()));
+            let mut bytes = [0u8; 16];
+            bytes[..8].copy_from_slice(&hi.to_be_bytes());
+            bytes[8..].copy_from_slice(&lo.to_be_bytes());
+            bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+            bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+            let hex = codec::hex_encode(&bytes);
+            Ok(Expr::String(
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+                .into(),
+            ))
+        }
+        "csv-parse" => {
+            let header_mode = match args {
+                [_] => false,
+                [_, Expr::Bool(header_mode)] => *header_mode,
+                _ => {
+                    return Err("'csv-parse' requires a string and an optional header flag.".into());
+                }
+            };
+            let rows = csv::parse(expect_string(op, &args[0])?)?;
+            let to_strings = |row: Vec<String>| {
+                Expr::List(Shared::new(row.into_iter().map(Expr::from).collect()))
+            };
+            if !header_mode {
+                return Ok(Expr::List(Shared::new(
+                    rows.into_iter().map(to_strings).collect(),
+                )));
+            }
+            // In header mode the first row names the fields and each later
+            // row becomes a map from those names to its values.
+            let mut rows = rows.into_iter();
+            let header = rows.next().unwrap_or_default();
+            let mut records = Vec::new();
+            for (i, row) in rows.enumerate() {
+                if row.len() != header.len() {
+                    return Err(format!(
+                        "CSV record {} has {} fields, but the header has {}.",
+                        i + 1,
+                        row.len(),
+                        header.len()
+                    )
+                    .into());
+                }
+                let record = header
+                    .iter()
+                    .cloned()
+                    .zip(row.into_iter().map(Expr::from))
+                    .collect();
+                records.push(Expr::Map(record));
+            }
+            Ok(Expr::List(records.into()))
+        }
+        "csv-write" => {
+            if args.len() != 1 {
+                return Err("'csv-write' requires a list of rows.".into());
+            }
+            let rows = expect_list(op, &args[0])?
+                .iter()
+                .map(|row| {
+                    expect_list(op, row)?
+                        .iter()
+                        .map(|field| match field {
+                            Expr::String(s) => Ok(s.to_string()),
+                            Expr::Number(_) | Expr::Bool(_) | Expr::Symbol(_) => {
+                                Ok(field.to_string())
+                            }
+                            _ => Err(
+                                "'csv-write' fields must be strings, numbers, booleans or symbols."
+                                    .into(),
+                            ),
+                        })
+                        .collect::<Result<Vec<String>, EvalError>>()
+                })
+                .collect::<Result<Vec<Vec<String>>, EvalError>>()?;
+            Ok(Expr::String(csv::write(&rows).into()))
+        }
+        "exit" => {
+            let status = match args {
+                [] => 0,
+                [code] => match code {
+                    Expr::Number(n) if n.fract() == 0.0 && i32::try_from(*n as i64).is_ok() => {
+                        *n as i32
+                    }
+                    _ => return Err("'exit' requires an integer status.".into()),
+                },
+                _ => return Err("'exit' requires zero or one argument.".into()),
+            };
+            match runtime::exit_policy() {
+                runtime::ExitPolicy::Terminate => std::process::exit(status),
+                runtime::ExitPolicy::Error => Err(EvalError::Exit(status)),
+                runtime::ExitPolicy::Ignore => Ok(Expr::empty_list()),
+            }
+        }
+        "memoize" => {
+            if args.len() != 1 {
+                return Err("'memoize' requires one argument.".into());
+            }
+            match &args[0] {
+                Expr::Func { .. } | Expr::NativeFn(_) => Ok(Expr::Memoized {
+                    func: Box::new(args[0].clone()),
+                    cache: Shared::new(Lock::new(HashMap::new())),
+                }),
+                // Memoizing twice would only add a second, redundant cache.
+                Expr::Memoized { .. } => Ok(args[0].clone()),
+                _ => Err("'memoize' requires a function argument.".into()),
+            }
+        }
+        "check-equal?" => {
+            if args.len() != 2 {
+                return Err("'check-equal?' requires an actual and an expected value.".into());
+            }
+            if args[0].is_equal(&args[1]) {
+                Ok(Expr::Bool(true))
+            } else {
+                Err(format!(
+                    "check-equal? failed: expected {}, got {}.",
+                    args[1], args[0]
+                )
+                .into())
+            }
+        }
+        "run-tests" => {
+            if !args.is_empty() {
+                return Err("'run-tests' takes no arguments.".into());
+            }
+            // Each test runs in its own scope, so definitions made by one
+            // test cannot leak into the next.
+            let (mut passed, mut failed) = (0, 0);
+            for (name, body) in runtime::tests() {
+                let mut test_env = env.child();
+                match body
+                    .iter()
+                    .try_for_each(|expr| eval(expr, &mut test_env).map(|_| ()))
+                {
+                    Ok(()) => {
+                        passed += 1;
+                        runtime::write_output(&format!("PASS {}\n", name))?;
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        runtime::write_output(&format!("FAIL {}: {}\n", name, e))?;
+                    }
+                }
+            }
+            runtime::write_output(&format!("{} passed, {} failed\n", passed, failed))?;
+            Ok(Expr::List(
+                vec![Expr::Number(passed as f64), Expr::Number(failed as f64)].into(),
+            ))
+        }
+        "alist->map" => {
+            if args.len() != 1 {
+                return Err("'alist->map' requires one argument.".into());
+            }
+            let mut map = std::collections::BTreeMap::new();
+            for entry in expect_list(op, &args[0])? {
+                let (key, value) = expect_alist_entry(op, entry)?;
+                // As with `alist-get`, the first entry for a key wins.
+                map.entry(expect_string(op, key)?.to_string())
+                    .or_insert_with(|| value.clone());
+            }
+            Ok(Expr::Map(map))
+        }
+        "map->alist" => {
+            if args.len() != 1 {
+                return Err("'map->alist' requires one argument.".into());
+            }
+            let map = expect_map(op, &args[0])?;
+            Ok(Expr::List(Shared::new(
+                map.iter()
+                    .map(|(k, v)| {
+                        Expr::List(vec![Expr::String(k.clone().into()), v.clone()].into())
+                    })
+                    .collect(),
+            )))
+        }
+        "alist-get" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err("'alist-get' requires an alist, a key and an optional default.".into());
+            }
+            for entry in expect_list(op, &args[0])? {
+                let (key, value) = expect_alist_entry(op, entry)?;
+                if key.is_equal(&args[1]) {
+                    return Ok(value.clone());
+                }
+            }
+            match args.get(2) {
+                Some(default) => Ok(default.clone()),
+                None => Err(format!("Key {} not found in alist.", args[1]).into()),
+            }
+        }
+        "alist-update" => {
+            if args.len() != 3 {
+                return Err("'alist-update' requires an alist, a key and a value.".into());
+            }
+            // Replaces the first entry for the key in place, or appends one.
+            let mut entries = expect_list(op, &args[0])?.to_vec();
+            let new_entry = Expr::List(vec![args[1].clone(), args[2].clone()].into());
+            let mut found = false;
+            for entry in entries.iter_mut() {
+                if expect_alist_entry(op, entry)?.0.is_equal(&args[1]) {
+                    *entry = new_entry.clone();
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                entries.push(new_entry);
+            }
+            Ok(Expr::List(entries.into()))
+        }
+        "alist-remove" => {
+            if args.len() != 2 {
+                return Err("'alist-remove' requires an alist and a key.".into());
+            }
+            let mut kept = Vec::new();
+            for entry in expect_list(op, &args[0])? {
+                if !expect_alist_entry(op, entry)?.0.is_equal(&args[1]) {
+                    kept.push(entry.clone());
+                }
+            }
+            Ok(Expr::List(kept.into()))
+        }
+        "raise" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err("'raise' requires a kind, a message and optional data.".into());
+            }
+            let kind = match &args[0] {
+                Expr::Symbol(kind) => kind.to_string(),
+                _ => return Err("'raise' requires a symbol as the kind.".into()),
+            };
+            let message = expect_string(op, &args[1])?.to_string();
+            let data = args.get(2).cloned().unwrap_or(Expr::empty_list());
+            Err(EvalError::User {
+                kind,
+                message,
+                data,
+            })
+        }
+        "condition-message" | "condition-kind" | "condition-data" => {
+            if args.len() != 1 {
+                return Err(format!("'{}' requires one argument.", op).into());
+            }
+            match &args[0] {
+                Expr::Condition {
+                    kind,
+                    message,
+                    data,
+                } => Ok(match op {
+                    "condition-message" => Expr::String(message.clone().into()),
+                    "condition-kind" => Expr::Symbol(kind.into()),
+                    _ => (**data).clone(),
+                }),
+                _ => Err(format!("'{}' requires a condition.", op).into()),
+            }
+        }
+        _ => unreachable!("'{}' is not a standard builtin", op),
+    }
+}
+
+/// Splits an association list entry, a two-element `(key value)` list.
+fn expect_alist_entry<'a>(op: &str, entry: &'a Expr) -> Result<(&'a Expr, &'a Expr), EvalError> {
+    match entry {
+        Expr::List(pair) if pair.len() == 2 => Ok((&pair[0], &pair[1])),
+        _ => Err(format!("'{}' requires an alist of (key value) entries.", op).into()),
+    }
+}
+
+/// Returns the bytes of `arg`, which is either a string (its UTF-8 encoding)
+/// or a list of integers from 0 to 255. There is no separate bytevector type.
+fn expect_bytes(op: &str, arg: &Expr) -> Result<Vec<u8>, EvalError> {
+    match arg {
+        Expr::String(s) => Ok(s.as_bytes().to_vec()),
+        Expr::List(items) => items
+            .iter()
+            .map(|item| match item {
+                Expr::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => Ok(*n as u8),
+                _ => Err(format!("'{}' requires bytes between 0 and 255.", op).into()),
+            })
+            .collect(),
+        _ => Err(type_mismatch(op, "a string or a list of bytes", arg)),
+    }
+}
+
+/// Returns the entries of `arg` if it is a map, or a type error naming `op`.
+fn expect_map<'a>(
+    op: &str,
+    arg: &'a Expr,
+) -> Result<&'a std::collections::BTreeMap<String, Expr>, EvalError> {
+    match arg {
+        Expr::Map(map) => Ok(map),
+        _ => Err(type_mismatch(op, "a map argument", arg)),
+    }
+}
+
+/// Fails unless filesystem access is enabled in the runtime capabilities.
+fn require_filesystem(op: &str) -> Result<(), EvalError> {
+    require_capability(op, runtime::capabilities().filesystem)
+}
+
+/// Fails with a sandbox error naming `op` unless `enabled` is set.
+fn require_capability(op: &str, enabled: bool) -> Result<(), EvalError> {
+    if enabled {
+        Ok(())
+    } else {
+        Err(format!("'{}' is disabled in sandbox mode.", op).into())
+    }
+}
+
+/// Writes an integer in the given radix, using lowercase letters past 9.
+fn integer_to_radix(n: i64, radix: u32) -> String {
+    let mut magnitude = n.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        let digit = (magnitude % radix as u64) as u32;
+        digits.push(char::from_digit(digit, radix).unwrap());
+        magnitude /= radix as u64;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+/// Inserts `sep` between each group of three digits in the integer part of
+/// an already formatted number, such as `-1234.50`.
+fn group_thousands(formatted: &str, sep: &str) -> String {
+    let (sign, rest) = match formatted.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", formatted),
+    };
+    let (int_part, frac_part) = match rest.find('.') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    // Leave non-finite values such as "inf" alone.
+    if !int_part.bytes().all(|b| b.is_ascii_digit()) {
+        return formatted.to_string();
+    }
+    let mut grouped = String::new();
+    for (i, digit) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            grouped.push_str(sep);
+        }
+        grouped.push(digit);
+    }
+    format!("{}{}{}", sign, grouped, frac_part)
+}
+
+/// Returns `arg` as a two's-complement integer for the bitwise operators.
+///
+/// Numbers are `f64`, so only integers of magnitude up to 2^53 are accepted;
+/// beyond that the low bits are no longer exact.
+fn expect_bits(op: &str, arg: &Expr) -> Result<i64, EvalError> {
+    const MAX_EXACT: f64 = (1u64 << 53) as f64;
+    let n = expect_integer(op, arg)?;
+    if n.abs() > MAX_EXACT {
+        return Err(format!("'{}' requires integers between -2^53 and 2^53.", op).into());
+    }
+    Ok(n as i64)
+}
+
+/// Builds the error for `op` being given `got` when it requires `expected`,
+/// a phrase such as `a string argument`.
+fn type_mismatch(op: &str, expected: &str, got: &Expr) -> EvalError {
+    EvalError::TypeMismatch {
+        op: op.to_string(),
+        expected: expected.to_string(),
+        got: got.clone(),
+    }
+}
+
+/// Returns the value of `arg` if it is a number, or a type error naming `op`.
+fn expect_number(op: &str, arg: &Expr) -> Result<f64, EvalError> {
+    match arg {
+        Expr::Number(n) => Ok(*n),
+        _ => Err(type_mismatch(op, "number arguments", arg)),
+    }
+}
+
+/// Returns `arg` if it is a number with no fractional part.
+fn expect_integer(op: &str, arg: &Expr) -> Result<f64, EvalError> {
+    match arg {
+        Expr::Number(n) if n.fract() == 0.0 => Ok(*n),
+        _ => Err(type_mismatch(op, "integer arguments", arg)),
+    }
+}
+
+/// Returns the character held by a one-character string.
+///
+/// There is no separate character type; characters are represented as
+/// strings of length one, which is also what `string-ref` returns.
+fn expect_char(op: &str, arg: &Expr) -> Result<char, EvalError> {
+    if let Expr::String(s) = arg {
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(c);
+        }
+    }
+    Err(type_mismatch(op, "a one-character string", arg))
+}
+
+/// Expands the `~` directives of a `format` template.
+///
+/// Supported directives are `~a` (display), `~s` (write), `~%` (newline) and
+/// `~~` (a literal tilde). `~a` and `~s` accept a width, as in `~8a`, which
+/// pads on the right; `~8@a` pads on the left instead.
+fn format_directives(template: &str, args: &[Expr]) -> Result<String, EvalError> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+        let mut width = String::new();
+        while let Some(&d) = chars.peek() {
+            if !d.is_ascii_digit() {
+                break;
+            }
+            width.push(d);
+            chars.next();
+        }
+        let pad_left = chars.next_if_eq(&'@').is_some();
+        let directive = chars
+            .next()
+            .ok_or_else(|| "'format' string ends in the middle of a directive.".to_string())?;
+        let text = match directive {
+            'a' | 's' => {
+                let arg = args
+                    .next()
+                    .ok_or_else(|| "'format' has more directives than arguments.".to_string())?;
+                if directive == 'a' {
+                    arg.to_display_string()
+                } else {
+                    arg.to_string()
+                }
+            }
+            '%' => "\n".to_string(),
+            '~' => "~".to_string(),
+            _ => return Err(format!("Unknown 'format' directive '~{}'.", directive).into()),
+        };
+        let width: usize = width.parse().unwrap_or(0);
+        runtime::check_allocation(out.len().saturating_add(text.len()).saturating_add(width))?;
+        let padding = " ".repeat(width.saturating_sub(text.chars().count()));
+        if pad_left {
+            out.push_str(&padding);
+            out.push_str(&text);
+        } else {
+            out.push_str(&text);
+            out.push_str(&padding);
+        }
+    }
+
+    if args.next().is_some() {
+        return Err("'format' has more arguments than directives.".into());
+    }
+    Ok(out)
+}
+
+/// Returns the contents of `arg` if it is a string, or a type error naming `op`.
+fn expect_string<'a>(op: &str, arg: &'a Expr) -> Result<&'a str, EvalError> {
+    match arg {
+        Expr::String(s) => Ok(s),
+        _ => Err(type_mismatch(op, "a string argument", arg)),
+    }
+}
+
+/// Returns `arg` as an index if it is a non-negative integer.
+fn expect_index(op: &str, arg: &Expr) -> Result<usize, EvalError> {
+    match arg {
+        Expr::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err(format!("'{}' requires a non-negative integer index.", op).into()),
+    }
+}
+
+/// Returns the elements of `arg` if it is a list, or a type error naming `op`.
+fn expect_list<'a>(op: &str, arg: &'a Expr) -> Result<&'a [Expr], EvalError> {
+    match arg {
+        Expr::List(list) => Ok(list),
+        _ => Err(type_mismatch(op, "a list argument", arg)),
+    }
+}
+
+/// Appends the elements of `list` to `out`, splicing nested lists up to
+/// `depth` levels deep (or all the way down when `depth` is `None`).
+fn flatten_into(list: &[Expr], depth: Option<usize>, out: &mut Vec<Expr>) {
+    for item in list {
+        match item {
+            Expr::List(inner) if depth != Some(0) => {
+                flatten_into(inner, depth.map(|d| d - 1), out);
+            }
+            _ => out.push(item.clone()),
         }
-        _ => Err("Not a built-in operator".to_string()),
     }
 }
@@ -0,0 +1,219 @@
+//! A convenient front end for embedding the interpreter.
+//!
+//! An [`Interpreter`] owns a global environment and evaluates source text in
+//! it, so hosts do not have to parse, evaluate and keep the environment
+//! themselves.
+
+use crate::builtins::{Builtins, Category};
+use crate::cycles;
+use crate::data::{Env, Expr, NativeFn, define_constants};
+use crate::error::EvalError;
+use crate::eval::eval;
+use crate::parser::parse_program;
+use crate::runtime::{self, Debugger, EvalHooks, SharedDebugger};
+use crate::shared::NativeFunction;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// An interpreter with its own global environment, configured with the
+/// `with_` methods.
+///
+/// Runtime settings such as the output sink apply to the current thread, as
+/// described in [`runtime`], so interpreters on the same thread share them.
+#[derive(Default)]
+pub struct Interpreter {
+    env: Env,
+    builtins: Builtins,
+    fuel: Option<u64>,
+    hooks: Option<EvalHooks>,
+    debugger: Option<SharedDebugger>,
+}
+
+impl Interpreter {
+    /// Creates an interpreter with an empty global environment and the
+    /// standard builtins.
+    pub fn new() -> Self {
+        Interpreter::default()
+    }
+
+    /// Evaluates in `env` instead of a new global environment.
+    ///
+    /// To run scripts against a prelude loaded once, give each interpreter
+    /// a scope inside the prelude's environment with [`Env::child`]: the
+    /// prelude's definitions are visible to it, while its own stay in its
+    /// scope. With the `sync` feature environments can be shared between
+    /// threads, so interpreters on many threads can do this at once; their
+    /// lookups only lock the prelude for reading.
+    ///
+    /// Dropping the interpreter frees its scope, along with the functions
+    /// defined in it, unless something else still refers to them; see
+    /// [`cycles`].
+    pub fn with_env(mut self, env: Env) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Binds the predefined constants `pi` and `e`.
+    pub fn with_stdlib(mut self) -> Self {
+        define_constants(&mut self.env);
+        self
+    }
+
+    /// Like [`register`](Self::register), for use while building the
+    /// interpreter.
+    pub fn with_builtin(mut self, name: &str, builtin: impl NativeFunction) -> Self {
+        self.register(name, builtin);
+        self
+    }
+
+    /// Replaces the builtins, for example with [`Builtins::empty`] to start
+    /// from none.
+    pub fn with_builtins(mut self, builtins: Builtins) -> Self {
+        self.builtins = builtins;
+        self
+    }
+
+    /// Removes every builtin in `category`, such as
+    /// [`Category::Filesystem`] to keep untrusted code away from files.
+    /// Calls to them then fail as calls to undefined functions do.
+    pub fn without_category(mut self, category: Category) -> Self {
+        self.builtins.remove_category(category);
+        self
+    }
+
+    /// Limits each call to [`eval`](Self::eval) or
+    /// [`eval_str`](Self::eval_str) to `fuel` evaluation steps, after which
+    /// it fails with [`EvalError::FuelExhausted`]. See [`runtime::set_fuel`].
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Calls `hook` before each expression is evaluated, with the
+    /// expression and how many evaluations enclose it. See
+    /// [`EvalHooks`].
+    pub fn on_enter(mut self, hook: impl Fn(&Expr, usize) + 'static) -> Self {
+        self.hooks = Some(self.hooks.take().unwrap_or_default().on_enter(hook));
+        self
+    }
+
+    /// Calls `hook` with the value or error of each expression evaluated.
+    /// See [`EvalHooks`].
+    pub fn on_exit(mut self, hook: impl Fn(&Result<Expr, EvalError>) + 'static) -> Self {
+        self.hooks = Some(self.hooks.take().unwrap_or_default().on_exit(hook));
+        self
+    }
+
+    /// Pauses evaluation in `debugger` at `(break)` and at breakpoints set
+    /// with [`runtime::set_breakpoint`]. See [`Debugger`].
+    pub fn with_debugger(mut self, debugger: impl Debugger + 'static) -> Self {
+        self.debugger = Some(Rc::new(RefCell::new(debugger)));
+        self
+    }
+
+    /// Binds `name` to a native function that runs `builtin` on the
+    /// evaluated arguments of each call.
+    ///
+    /// The function is a value like any other, so Lisp code can pass it
+    /// around or rebind it. As with variables, a builtin of the same name
+    /// takes precedence in calls; to override a builtin, replace it in
+    /// [`builtins_mut`](Self::builtins_mut) instead.
+    pub fn register(&mut self, name: &str, builtin: impl NativeFunction) {
+        self.env
+            .insert(name.to_string(), Expr::NativeFn(NativeFn::new(builtin)));
+    }
+
+    /// Returns the builtins.
+    pub fn builtins(&self) -> &Builtins {
+        &self.builtins
+    }
+
+    /// Returns the builtins, for example to add, override or remove one.
+    pub fn builtins_mut(&mut self) -> &mut Builtins {
+        &mut self.builtins
+    }
+
+    /// Returns the global environment.
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Returns the global environment, for example to define variables.
+    pub fn env_mut(&mut self) -> &mut Env {
+        &mut self.env
+    }
+
+    /// Evaluates an expression in the global environment.
+    pub fn eval(&mut self, expr: &Expr) -> Result<Expr, EvalError> {
+        self.run(|env| eval(expr, env))
+    }
+
+    /// Parses `source` and evaluates its forms in order, returning the value
+    /// of the last one, or `()` if there are none. Evaluation stops at the
+    /// first error.
+    pub fn eval_str(&mut self, source: &str) -> Result<Expr, EvalError> {
+        let exprs = parse_program(source)?;
+        self.run(|env| {
+            let mut value = Expr::empty_list();
+            for expr in &exprs {
+                value = eval(expr, env)?;
+            }
+            Ok(value)
+        })
+    }
+
+    /// Runs `f` on the global environment with this interpreter's builtins,
+    /// hooks, debugger and limits in force, restoring the thread's own afterwards.
+    fn run(
+        &mut self,
+        f: impl FnOnce(&mut Env) -> Result<Expr, EvalError>,
+    ) -> Result<Expr, EvalError> {
+        let outer = runtime::fuel();
+        if self.fuel.is_some() {
+            runtime::set_fuel(self.fuel);
+        }
+        let outer_builtins = runtime::set_builtins(self.builtins.clone());
+        let outer_hooks = self
+            .hooks
+            .as_ref()
+            .map(|hooks| runtime::set_eval_hooks(Some(hooks.clone())));
+        let outer_debugger = self
+            .debugger
+            .as_ref()
+            .map(|debugger| runtime::set_debugger(Some(debugger.clone())));
+        let result = f(&mut self.env);
+        if let Some(outer_debugger) = outer_debugger {
+            runtime::set_debugger(outer_debugger);
+        }
+        if let Some(outer_hooks) = outer_hooks {
+            runtime::set_eval_hooks(outer_hooks);
+        }
+        runtime::set_builtins(outer_builtins);
+        runtime::set_fuel(outer);
+        result
+    }
+}
+
+/// Frees the global environment even if functions defined in it refer back
+/// to it, as long as nothing else does.
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        if self.env.is_shared() {
+            drop(std::mem::replace(&mut self.env, Env::new()));
+            cycles::collect();
+        }
+    }
+}
+
+impl fmt::Debug for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("env", &self.env)
+            .field("builtins", &self.builtins)
+            .field("fuel", &self.fuel)
+            .field("hooks", &self.hooks)
+            .field("debugger", &self.debugger.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,404 @@
+//! Conversion between JSON text and Lisp values.
+//!
+//! JSON objects become maps, arrays become lists, and `null` becomes the
+//! empty list. Going the other way, the empty list is written as `[]`, so
+//! `null` does not survive a round trip.
+//!
+//! With the `serde_json` feature, [`Expr::to_json`] and [`Expr::from_json`]
+//! convert to and from `serde_json::Value` in the same way.
+
+use crate::data::Expr;
+use crate::runtime;
+use std::collections::BTreeMap;
+
+/// Parses a JSON document into a Lisp value.
+///
+/// Arrays and objects may nest as deeply as Lisp expressions, as set with
+/// [`runtime::set_max_parse_depth`].
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        depth: 0,
+        max_depth: runtime::max_parse_depth(),
+    };
+    parser.skip_whitespace();
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Serializes a Lisp value as JSON, indenting nested values by two spaces
+/// when `pretty` is set.
+///
+/// Symbols are written as strings. Functions and non-finite numbers have no
+/// JSON representation and are an error.
+pub fn stringify(expr: &Expr, pretty: bool) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(expr, pretty, 0, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(feature = "serde_json")]
+impl Expr {
+    /// Converts the value to JSON as `json-stringify` would write it.
+    ///
+    /// Symbols become strings, and integral numbers are written without a
+    /// fractional part. Functions, conditions and non-finite numbers have no
+    /// JSON representation and are an error.
+    pub fn to_json(&self) -> Result<serde_json::Value, String> {
+        use serde_json::Value;
+        Ok(match self {
+            // Within this range every integer is exactly representable.
+            Expr::Number(n) if n.fract() == 0.0 && n.abs() < (1u64 << 53) as f64 => {
+                Value::from(*n as i64)
+            }
+            Expr::Number(n) => serde_json::Number::from_f64(*n)
+                .map(Value::Number)
+                .ok_or_else(|| format!("Cannot represent {} in JSON.", n))?,
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::String(s) => Value::String(s.to_string()),
+            Expr::Symbol(s) => Value::String(s.to_string()),
+            Expr::List(items) => {
+                Value::Array(items.iter().map(Expr::to_json).collect::<Result<_, _>>()?)
+            }
+            Expr::Map(map) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| Ok((k.clone(), v.to_json()?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. } => {
+                return Err("Cannot represent a function in JSON.".to_string());
+            }
+            Expr::Condition { .. } => {
+                return Err("Cannot represent a condition in JSON.".to_string());
+            }
+        })
+    }
+
+    /// Converts JSON to a value as `json-parse` would read it, with `null`
+    /// becoming the empty list.
+    pub fn from_json(value: serde_json::Value) -> Expr {
+        use serde_json::Value;
+        match value {
+            Value::Null => Expr::empty_list(),
+            Value::Bool(b) => Expr::Bool(b),
+            Value::Number(n) => Expr::Number(n.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => Expr::String(s.into()),
+            Value::Array(items) => Expr::List(
+                items
+                    .into_iter()
+                    .map(Expr::from_json)
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            Value::Object(map) => Expr::Map(
+                map.into_iter()
+                    .map(|(k, v)| (k, Expr::from_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// How many arrays and objects enclose the current position.
+    depth: usize,
+    /// The greatest depth allowed, so that hostile input cannot overflow the
+    /// stack.
+    max_depth: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON at offset {}: {}.", self.pos, message)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Expr) -> Result<Expr, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('{') => self.nested(Parser::object),
+            Some('[') => self.nested(Parser::array),
+            Some('"') => self.string().map(Expr::from),
+            Some('t') => self.literal("true", Expr::Bool(true)),
+            Some('f') => self.literal("false", Expr::Bool(false)),
+            Some('n') => self.literal("null", Expr::empty_list()),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    /// Parses an array or object with `parse`, one level deeper.
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<Expr, String>) -> Result<Expr, String> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(&format!("nested more than {} deep", self.max_depth)));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<Expr, String> {
+        self.expect('{')?;
+        let mut map = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Expr::Map(map));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            map.insert(key, self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Expr::Map(map));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Expr, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Expr::List(items.into()));
+        }
+        loop {
+            self.skip_whitespace();
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Expr::List(items.into()));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    /// Parses a number as JSON writes them: no leading zeros, and digits on
+    /// both sides of a decimal point.
+    fn number(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        self.skip('-');
+        let mut well_formed = if self.skip('0') {
+            !self.digits()
+        } else {
+            self.digits()
+        };
+        if self.skip('.') {
+            well_formed &= self.digits();
+        }
+        if self.skip('e') || self.skip('E') {
+            let _ = self.skip('+') || self.skip('-');
+            well_formed &= self.digits();
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse::<f64>() {
+            Ok(n) if well_formed => Ok(Expr::Number(n)),
+            _ => {
+                self.pos = start;
+                Err(self.error("malformed number"))
+            }
+        }
+    }
+
+    /// Skips `c` if it comes next, returning whether it did.
+    fn skip(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Skips a run of digits, returning whether there was at least one.
+    fn digits(&mut self) -> bool {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escape {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        '/' => s.push('/'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => s.push(self.unicode_escape()?),
+                        _ => return Err(self.error("invalid escape sequence")),
+                    }
+                }
+                _ => s.push(c),
+            }
+        }
+    }
+
+    /// Decodes the digits of a `\u` escape, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("invalid unicode escape"));
+        }
+        if self.peek() != Some('\\') || self.chars.get(self.pos + 1) != Some(&'u') {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        self.pos += 2;
+        let low = self.hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = self.chars.iter().skip(self.pos).take(4).collect();
+        if digits.len() != 4 {
+            return Err(self.error("invalid unicode escape"));
+        }
+        let code =
+            u32::from_str_radix(&digits, 16).map_err(|_| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+fn write_value(expr: &Expr, pretty: bool, depth: usize, out: &mut String) -> Result<(), String> {
+    match expr {
+        Expr::Number(n) if n.is_finite() => out.push_str(&n.to_string()),
+        Expr::Number(n) => return Err(format!("Cannot represent {} in JSON.", n)),
+        Expr::Bool(b) => out.push_str(&b.to_string()),
+        Expr::String(s) => write_string(s, out),
+        Expr::Symbol(s) => write_string(s, out),
+        Expr::List(items) => {
+            write_container('[', ']', items.iter(), pretty, depth, out, |item, out| {
+                write_value(item, pretty, depth + 1, out)
+            })?;
+        }
+        Expr::Map(map) => {
+            let separator = if pretty { ": " } else { ":" };
+            write_container('{', '}', map.iter(), pretty, depth, out, |(k, v), out| {
+                write_string(k, out);
+                out.push_str(separator);
+                write_value(v, pretty, depth + 1, out)
+            })?;
+        }
+        Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. } => {
+            return Err("Cannot represent a function in JSON.".to_string());
+        }
+        Expr::Condition { .. } => {
+            return Err("Cannot represent a condition in JSON.".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn write_container<T>(
+    open: char,
+    close: char,
+    items: impl ExactSizeIterator<Item = T>,
+    pretty: bool,
+    depth: usize,
+    out: &mut String,
+    mut write_item: impl FnMut(T, &mut String) -> Result<(), String>,
+) -> Result<(), String> {
+    out.push(open);
+    let empty = items.len() == 0;
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if pretty {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth + 1));
+        }
+        write_item(item, out)?;
+    }
+    if pretty && !empty {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    }
+    out.push(close);
+    Ok(())
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
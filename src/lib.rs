@@ -3,10 +3,33 @@
 //! This library provides a parser and an evaluator for a small subset of the Lisp language.
 //! It supports basic arithmetic, variables, functions (lambdas), and conditional logic.
 
+pub mod builtins;
+pub mod codec;
+pub mod compile;
+pub mod convert;
+pub mod csv;
+pub mod cycles;
 pub mod data;
+pub mod error;
 pub mod eval;
+#[cfg(feature = "hash")]
+pub mod hash;
+pub mod interpreter;
+pub mod json;
+pub mod optimize;
 pub mod parser;
+pub mod pretty;
+pub mod profile;
+pub mod runtime;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod shared;
+pub mod symbol;
 
-pub use data::{Env, Expr};
-pub use eval::eval;
-pub use parser::parse;
+pub use builtins::{Builtin, Builtins};
+pub use data::{Env, Expr, NativeFn, default_env};
+pub use error::{EvalError, ParseError};
+pub use eval::{eval, eval_with_timeout};
+pub use interpreter::Interpreter;
+pub use parser::{parse, parse_program};
+pub use symbol::Symbol;
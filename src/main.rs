@@ -1,13 +1,81 @@
-use minilisp_rust::{Env, eval, parse};
-use std::collections::HashMap;
+use minilisp_rust::parser::{ParseOutcome, parse_incremental};
+use minilisp_rust::pretty::{PrettyOptions, pretty};
+use minilisp_rust::runtime::{self, Debugger, ExitPolicy, Pause, PauseReason, Resume};
+use minilisp_rust::{Expr, Interpreter, eval, parse};
 use std::io::{self, Write};
+use std::process::ExitCode;
 
-fn main() {
-    let mut env: Env = HashMap::new();
+/// The stack size of the thread the interpreter runs on. Evaluation keeps
+/// its own stack, but calls made by builtins such as `map` still nest on the
+/// thread's, taking a few kilobytes each in unoptimized builds, so this
+/// leaves room for `runtime::DEFAULT_MAX_CALL_DEPTH` of them.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() -> ExitCode {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("failed to start the interpreter thread")
+        .join()
+        .unwrap_or(ExitCode::FAILURE)
+}
+
+fn run() -> ExitCode {
+    runtime::set_exit_policy(ExitPolicy::Terminate);
+    let mut args = std::env::args().skip(1);
+    match args.next() {
+        Some(path) => {
+            // Arguments after the script path are passed on to the script,
+            // optionally separated from it by `--`.
+            let mut script_args: Vec<String> = args.collect();
+            if script_args.first().map(String::as_str) == Some("--") {
+                script_args.remove(0);
+            }
+            run_script(&path, script_args)
+        }
+        None => {
+            repl();
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Evaluates the forms in the file at `path` in order, binding
+/// `*script-path*` and `*args*`.
+///
+/// The process fails if the script cannot be read or raises an error;
+/// `(exit n)` ends it earlier with status `n`.
+fn run_script(path: &str, script_args: Vec<String>) -> ExitCode {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error: cannot read '{}': {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut interp = Interpreter::new().with_stdlib();
+    let env = interp.env_mut();
+    env.insert("*script-path*".to_string(), Expr::String(path.into()));
+    env.insert("*args*".to_string(), Expr::from(script_args));
+
+    match interp.eval_str(&source) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e.describe());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn repl() {
+    let mut interp = Interpreter::new().with_stdlib().with_debugger(ReplDebugger);
     println!("Welcome to minilisp-rust!");
 
+    // Lines are collected here until they form complete expressions.
+    let mut pending = String::new();
     loop {
-        print!("> ");
+        print!("{}", if pending.is_empty() { "> " } else { "... " });
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
@@ -17,17 +85,103 @@ fn main() {
             break;
         }
 
-        let input = input.trim();
-        if input.is_empty() {
+        if pending.is_empty() && input.trim().is_empty() {
+            continue;
+        }
+        if pending.is_empty() && input.trim_start().starts_with(':') {
+            repl_command(input.trim());
             continue;
         }
+        pending.push_str(&input);
+
+        // Pasted input may hold several forms; each result is printed, and
+        // an error skips the forms after it.
+        match parse_incremental(&pending) {
+            ParseOutcome::Incomplete => continue,
+            ParseOutcome::Complete(exprs) => {
+                for expr in exprs {
+                    match interp.eval(&expr) {
+                        Ok(result) => println!("{}", pretty(&result, &PrettyOptions::default())),
+                        Err(e) => {
+                            eprintln!("Error: {}", e.describe());
+                            break;
+                        }
+                    }
+                }
+            }
+            ParseOutcome::Error(e) => eprintln!("Error: {}", e),
+        }
+        pending.clear();
+    }
+}
+
+/// Runs a REPL command, a line starting with `:` rather than a form.
+fn repl_command(line: &str) {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(":break"), None) => {
+            for name in runtime::breakpoints() {
+                println!("{}", name);
+            }
+        }
+        (Some(":break"), Some(name)) => runtime::set_breakpoint(name, true),
+        (Some(":unbreak"), Some(name)) => runtime::set_breakpoint(name, false),
+        _ => {
+            println!(":break          list the functions with breakpoints");
+            println!(":break NAME     pause in the debugger when NAME is called");
+            println!(":unbreak NAME   remove the breakpoint on NAME");
+        }
+    }
+}
+
+/// Prompts on the terminal each time evaluation pauses.
+struct ReplDebugger;
 
-        match parse(input) {
-            Ok(expr) => match eval(&expr, &mut env) {
-                Ok(result) => println!("{}", result),
-                Err(e) => eprintln!("Error: {}", e),
-            },
-            Err(e) => eprintln!("Error: {}", e),
+impl Debugger for ReplDebugger {
+    fn pause(&mut self, pause: &Pause) -> Resume {
+        match &pause.reason {
+            PauseReason::Break => println!("Paused at (break)."),
+            PauseReason::Breakpoint(name) => println!("Paused in '{}'.", name),
+            PauseReason::Step => {}
+        }
+        println!("[{}] {}", pause.depth, pause.expr);
+        loop {
+            print!("debug> ");
+            io::stdout().flush().unwrap();
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).unwrap() == 0 {
+                return Resume::Abort;
+            }
+            let input = input.trim();
+            let (command, rest) = input.split_once(' ').unwrap_or((input, ""));
+            match command {
+                "c" | "continue" => return Resume::Continue,
+                "s" | "step" => return Resume::Step,
+                "n" | "next" => return Resume::Next,
+                "f" | "finish" => return Resume::Finish,
+                "q" | "quit" => return Resume::Abort,
+                "l" | "locals" => {
+                    for (name, value) in pause.env.locals() {
+                        println!("{} = {}", name, value);
+                    }
+                }
+                "p" | "print" => match parse(rest) {
+                    Ok(expr) => match eval(&expr, &mut pause.env.clone()) {
+                        Ok(value) => println!("{}", pretty(&value, &PrettyOptions::default())),
+                        Err(e) => eprintln!("Error: {}", e.describe()),
+                    },
+                    Err(e) => eprintln!("Error: {}", e),
+                },
+                _ => {
+                    println!("c, continue   run to the next breakpoint");
+                    println!("s, step       pause at the next expression");
+                    println!("n, next       pause at the next expression not inside this one");
+                    println!("f, finish     pause once the enclosing expression is done");
+                    println!("l, locals     show the local variables");
+                    println!("p, print EXPR evaluate EXPR here");
+                    println!("q, quit       abandon the evaluation");
+                }
+            }
         }
     }
 }
@@ -1,91 +1,986 @@
+//! Reading source text into expressions.
+//!
+//! # Symbols
+//!
+//! A bare token that is not a number, `true` or `false` is a symbol, and must
+//! follow these rules:
+//!
+//! - It starts with a letter, any non-ASCII character, or one of
+//!   `! $ % & * / : < = > ? ^ _ ~ @`.
+//! - After that it may also contain ASCII digits and `+ - . #`.
+//! - Alternatively it may start with `+`, `-` or `.`, as in `+`, `-`, `->x`
+//!   and `...`, provided what follows does not start like a number.
+//!
+//! So `1+` and `+1x` are errors rather than symbols, and `#` is reserved for
+//! reader syntax at the start of a token. Any other name can be written
+//! between pipes, as in `|a symbol with spaces|`, where `\|` and `\\` stand
+//! for a pipe and a backslash.
+
 use crate::data::Expr;
+use crate::error::ParseError;
+use crate::runtime;
+use crate::shared::Shared;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::BufRead;
+use std::iter::Peekable;
+use std::str::Chars;
 
-/// Splits the input string into a vector of tokens.
-fn tokenize(input: &str) -> Vec<String> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+/// A location in source text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    /// The byte offset from the start of the input.
+    pub offset: usize,
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The column number in characters, starting at 1.
+    pub column: usize,
+}
 
-    while let Some(&c) = chars.peek() {
-        match c {
-            '(' | ')' => {
-                tokens.push(c.to_string());
-                chars.next();
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+/// The stretch of source text that a token or expression was read from,
+/// from `start` up to but not including `end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// Where the text begins.
+    pub start: Position,
+    /// Just past where the text ends.
+    pub end: Position,
+}
+
+/// An expression read from source text, together with its location and the
+/// locations of its subexpressions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spanned {
+    /// The expression itself.
+    pub node: Node,
+    /// Where the whole expression was read from.
+    pub span: Span,
+}
+
+/// The shape of a [`Spanned`] expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    /// A symbol, number, boolean or string, or what a reader macro or an
+    /// interpolated string expanded to.
+    Atom(Expr),
+    /// A list, whose elements carry their own spans.
+    List(Vec<Spanned>),
+}
+
+impl Spanned {
+    /// Converts the tree into a plain expression, dropping the spans.
+    pub fn to_expr(&self) -> Expr {
+        match &self.node {
+            Node::Atom(expr) => expr.clone(),
+            Node::List(items) => {
+                Expr::List(Shared::new(items.iter().map(Spanned::to_expr).collect()))
             }
+        }
+    }
+}
 
-            '"' => {
-                chars.next(); // consume opening quote
-                let mut s = String::new();
-                while let Some(&next_c) = chars.peek() {
-                    if next_c == '"' {
-                        break;
+/// A parse failure and the position it is reported at.
+struct SyntaxError {
+    message: String,
+    pos: Position,
+    /// Whether the input ended too early, so that more input could fix it.
+    incomplete: bool,
+    /// Whether expressions were nested deeper than the limit.
+    too_deep: bool,
+}
+
+impl SyntaxError {
+    fn new(message: impl Into<String>, pos: Position) -> Self {
+        SyntaxError {
+            message: message.into(),
+            pos,
+            incomplete: false,
+            too_deep: false,
+        }
+    }
+
+    fn incomplete(message: impl Into<String>, pos: Position) -> Self {
+        SyntaxError {
+            incomplete: true,
+            ..SyntaxError::new(message, pos)
+        }
+    }
+
+    /// Converts the error into a [`ParseError`] that shows the line of
+    /// `input` it is on.
+    fn locate(self, input: &str) -> ParseError {
+        self.locate_from(input, 1)
+    }
+
+    /// Like [`locate`](Self::locate), for `input` that begins on line
+    /// `first_line` of the source.
+    fn locate_from(self, input: &str, first_line: usize) -> ParseError {
+        let line = input
+            .split('\n')
+            .nth(self.pos.line - first_line)
+            .unwrap_or("");
+        let line = line.strip_suffix('\r').unwrap_or(line).to_string();
+        let (message, position) = (self.message, self.pos);
+        if self.incomplete {
+            ParseError::Incomplete {
+                message,
+                position,
+                line,
+            }
+        } else {
+            ParseError::Syntax {
+                message,
+                position,
+                line,
+            }
+        }
+    }
+}
+
+/// What a [`Token`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// `(` or `[`.
+    Open,
+    /// `)` or `]`.
+    Close,
+    /// `#;`, which comments out the datum after it.
+    DatumComment,
+    /// A `;` line comment, a `#| |#` block comment or a leading `#!` line.
+    Comment,
+    /// A string literal, quotes and escapes included, a raw `#"..."#`
+    /// string literal or an interpolated `#f"..."` string literal.
+    String,
+    /// A symbol, number or boolean.
+    Atom,
+}
+
+/// A token, borrowing its text from the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token<'a> {
+    /// What the token is.
+    pub kind: TokenKind,
+    /// The token exactly as written.
+    pub text: &'a str,
+    /// Where the token was read from.
+    pub span: Span,
+}
+
+/// A failure to split input into tokens. Since every token error is an
+/// unterminated literal or comment, more input may fix it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenError {
+    /// What went wrong.
+    pub message: String,
+    /// Where the offending token starts.
+    pub position: Position,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.position.line, self.position.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<TokenError> for SyntaxError {
+    fn from(e: TokenError) -> Self {
+        SyntaxError::incomplete(e.message, e.position)
+    }
+}
+
+/// Walks the input one character at a time, keeping track of the position.
+struct Lexer<'a> {
+    input: &'a str,
+    /// The offset of the start of `input` in the whole source, which is more
+    /// than zero when reading a stream a piece at a time.
+    base: usize,
+    pos: Position,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Lexer {
+            input,
+            base: 0,
+            pos: Position::default(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos.offset - self.base..]
+    }
+
+    /// Returns the input from byte offset `offset` up to the current
+    /// position.
+    fn text_from(&self, offset: usize) -> &'a str {
+        &self.input[offset - self.base..self.pos.offset - self.base]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos.offset += c.len_utf8();
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Moves forward to byte offset `offset`, which must lie on a character
+    /// boundary after the current position.
+    fn advance_to(&mut self, offset: usize) {
+        while self.pos.offset < offset && self.bump().is_some() {}
+    }
+
+    fn bump_while(&mut self, mut f: impl FnMut(char) -> bool) {
+        while self.peek().is_some_and(&mut f) {
+            self.bump();
+        }
+    }
+}
+
+/// Splits `input` into tokens, without copying it.
+///
+/// Whitespace is skipped, but comments are returned as [`TokenKind::Comment`]
+/// tokens, so that tools such as highlighters and formatters can keep them.
+/// The iterator ends after the first error.
+pub fn tokens(input: &str) -> impl Iterator<Item = Result<Token<'_>, TokenError>> {
+    Tokens {
+        lexer: Lexer::new(input),
+        failed: false,
+    }
+}
+
+struct Tokens<'a> {
+    lexer: Lexer<'a>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<'a>, TokenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_token();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+impl<'a> Tokens<'a> {
+    fn next_token(&mut self) -> Option<Result<Token<'a>, TokenError>> {
+        let lexer = &mut self.lexer;
+        lexer.bump_while(char::is_whitespace);
+        let start = lexer.pos;
+        let error = |message: &str| {
+            Some(Err(TokenError {
+                message: message.to_string(),
+                position: start,
+            }))
+        };
+        let kind = match lexer.peek()? {
+            // A `#!` line at the very start lets scripts be run as executables.
+            '#' if start.offset == 0 && lexer.rest().starts_with("#!") => {
+                lexer.bump_while(|c| c != '\n');
+                TokenKind::Comment
+            }
+
+            ';' => {
+                // A line comment runs to the end of the line.
+                lexer.bump_while(|c| c != '\n');
+                TokenKind::Comment
+            }
+
+            '#' if lexer.rest().starts_with("#|") => {
+                if !skip_block_comment(lexer) {
+                    return error("Unterminated block comment.");
+                }
+                TokenKind::Comment
+            }
+
+            '#' if lexer.rest().starts_with("#f\"") => {
+                // An interpolated string, whose `{...}` parts may themselves
+                // contain string literals.
+                let (_, len) = scan_interpolated(&lexer.rest()[3..]);
+                match len {
+                    Some(len) => lexer.advance_to(start.offset + 3 + len),
+                    None => return error("Unterminated string literal."),
+                }
+                TokenKind::String
+            }
+
+            '#' if lexer.rest().trim_start_matches('#').starts_with('"') => {
+                // A raw string: `#"..."#`, with as many `#`s on each side as
+                // needed to keep `"#` inside it.
+                let hashes = lexer.rest().len() - lexer.rest().trim_start_matches('#').len();
+                let closing = format!("\"{}", "#".repeat(hashes));
+                match lexer.rest()[hashes + 1..].find(&closing) {
+                    Some(len) => {
+                        let token_end = start.offset + hashes + 1 + len + closing.len();
+                        lexer.advance_to(token_end);
+                    }
+                    None => return error("Unterminated raw string literal."),
+                }
+                TokenKind::String
+            }
+
+            '#' if lexer.rest().starts_with("#;") => {
+                // The reader skips the datum that follows.
+                lexer.bump();
+                lexer.bump();
+                TokenKind::DatumComment
+            }
+
+            '|' => {
+                lexer.bump(); // consume opening pipe
+                loop {
+                    match lexer.bump() {
+                        Some('|') => break,
+                        Some('\\') if lexer.bump().is_some() => {}
+                        Some(_) => {}
+                        None => return error("Unterminated symbol."),
                     }
-                    s.push(chars.next().unwrap());
                 }
-                if chars.next().is_none() { /* Unterminated string */ }
-                tokens.push(format!("\"{}\"", s));
+                TokenKind::Atom
+            }
+
+            '(' | '[' => {
+                lexer.bump();
+                TokenKind::Open
+            }
+
+            ')' | ']' => {
+                lexer.bump();
+                TokenKind::Close
             }
 
-            _ if c.is_whitespace() => {
-                chars.next(); // consume whitespace
+            '"' => {
+                lexer.bump(); // consume opening quote
+                loop {
+                    match lexer.bump() {
+                        Some('"') => break,
+                        // The escaped character cannot end the string.
+                        Some('\\') if lexer.bump().is_some() => {}
+                        Some(_) => {}
+                        None => return error("Unterminated string literal."),
+                    }
+                }
+                TokenKind::String
             }
 
             _ => {
-                let mut s = String::new();
-                while let Some(&next_c) = chars.peek() {
-                    if next_c.is_whitespace() || next_c == '(' || next_c == ')' {
-                        break;
+                lexer.bump_while(|c| !is_delimiter(c));
+                TokenKind::Atom
+            }
+        };
+        Some(Ok(Token {
+            kind,
+            text: lexer.text_from(start.offset),
+            span: Span {
+                start,
+                end: lexer.pos,
+            },
+        }))
+    }
+}
+
+/// Returns whether `c` ends an atom.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | ';' | '"')
+}
+
+/// Splits the input string into the tokens the reader needs, dropping
+/// comments.
+fn tokenize(input: &str) -> Result<Vec<Token<'_>>, SyntaxError> {
+    let mut result = Vec::new();
+    for token in tokens(input) {
+        let token = token?;
+        if token.kind != TokenKind::Comment {
+            result.push(token);
+        }
+    }
+    Ok(result)
+}
+
+/// Decodes the contents of a string literal, between its quotes, processing
+/// escapes.
+fn unescape(contents: &str) -> String {
+    let mut chars = contents.chars().peekable();
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some('r') => s.push('\r'),
+            Some('"') => s.push('"'),
+            Some('\\') => s.push('\\'),
+            Some('|') => s.push('|'),
+            Some('u') if chars.peek() == Some(&'{') => match unicode_escape(&mut chars) {
+                Some(c) => s.push(c),
+                None => s.push_str("\\u"),
+            },
+            // Unknown escapes are kept as written.
+            Some(other) => {
+                s.push('\\');
+                s.push(other);
+            }
+            None => s.push('\\'),
+        }
+    }
+    s
+}
+
+/// A part of an interpolated string.
+enum Piece<'a> {
+    /// Literal text, with its escapes still unprocessed.
+    Text(String),
+    /// The source of an interpolated `{...}` expression.
+    Code(&'a str),
+    /// A `}` with no matching `{`.
+    StrayBrace,
+}
+
+/// Splits the contents of an interpolated string, starting just after its
+/// opening quote, into pieces. Also returns the length of the contents and
+/// closing quote, or `None` if the string is not terminated.
+///
+/// `{{` and `}}` stand for literal braces. Inside `{...}`, braces nest and
+/// string literals may contain braces and quotes.
+fn scan_interpolated(s: &str) -> (Vec<Piece<'_>>, Option<usize>) {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                pieces.push(Piece::Text(text));
+                return (pieces, Some(i + 1));
+            }
+            '\\' => {
+                text.push(c);
+                match chars.next() {
+                    Some((_, 'u')) if chars.peek().is_some_and(|&(_, c)| c == '{') => {
+                        // Keep the braces of a `\u{...}` escape out of the way.
+                        text.push('u');
+                        for (_, c) in chars.by_ref() {
+                            text.push(c);
+                            if c == '}' {
+                                break;
+                            }
+                        }
+                    }
+                    Some((_, escaped)) => text.push(escaped),
+                    None => return (pieces, None),
+                }
+            }
+            '{' | '}' if chars.next_if(|&(_, next)| next == c).is_some() => text.push(c),
+            '}' => pieces.push(Piece::StrayBrace),
+            '{' => {
+                let Some(end) = matching_brace(&s[i + 1..]) else {
+                    return (pieces, None);
+                };
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+                pieces.push(Piece::Code(&s[i + 1..i + 1 + end]));
+                while chars.next_if(|&(j, _)| j <= i + 1 + end).is_some() {}
+            }
+            c => text.push(c),
+        }
+    }
+    (pieces, None)
+}
+
+/// Returns the offset of the `}` that closes a `{` just before `s`, skipping
+/// over nested braces and string literals.
+fn matching_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            '"' => loop {
+                match chars.next()? {
+                    (_, '"') => break,
+                    (_, '\\') => {
+                        chars.next()?;
                     }
-                    s.push(chars.next().unwrap());
+                    _ => {}
                 }
-                tokens.push(s);
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expands an interpolated string token such as `#f"x is {x}"` into the
+/// equivalent `(format "x is ~a" x)`.
+///
+/// The expressions in braces are read as nested in the string, at the depth
+/// of `ctx`, so that interpolations nested in each other count against the
+/// depth limit too.
+fn interpolate(token: &Token, ctx: ReadContext) -> Result<Expr, SyntaxError> {
+    let (pieces, _) = scan_interpolated(&token.text[3..]);
+    let mut template = String::new();
+    let mut call = vec![Expr::Symbol("format".into())];
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => template.push_str(&unescape(&text).replace('~', "~~")),
+            Piece::Code(code) => {
+                let ctx = ReadContext {
+                    end: end_of(code),
+                    ..ctx
+                };
+                let expr = read_one_in(code, ctx).map_err(|e| {
+                    // Too deep a nesting is reported as it is, rather than
+                    // once more for every string it is nested in.
+                    if e.too_deep {
+                        return SyntaxError {
+                            pos: token.span.start,
+                            ..e
+                        };
+                    }
+                    SyntaxError::new(
+                        format!(
+                            "Invalid expression in interpolated string: {}",
+                            e.locate(code)
+                        ),
+                        token.span.start,
+                    )
+                })?;
+                let expr = expr.to_expr();
+                template.push_str("~a");
+                call.push(expr);
+            }
+            Piece::StrayBrace => {
+                return Err(SyntaxError::new(
+                    "Unmatched '}' in interpolated string; write '}}' for a literal brace.",
+                    token.span.start,
+                ));
             }
         }
     }
-    tokens
+    call.insert(1, Expr::String(template.into()));
+    Ok(Expr::List(call.into()))
 }
 
-/// Converts a single token into an `Expr`.
-fn atom(token: &str) -> Expr {
-    if token.starts_with('"') && token.ends_with('"') {
-        return Expr::String(token[1..token.len() - 1].to_string());
+/// Decodes the `{1F600}` part of a `\u{1F600}` escape: one to six hex
+/// digits naming a Unicode scalar value. Returns `None`, consuming nothing,
+/// if the escape is malformed.
+fn unicode_escape(chars: &mut Peekable<Chars>) -> Option<char> {
+    let mut ahead = chars.clone();
+    ahead.next(); // consume '{'
+    let digits: String = ahead.by_ref().take_while(|&c| c != '}').collect();
+    if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+    let c = char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?;
+    *chars = ahead;
+    Some(c)
+}
 
+/// Skips a `#| ... |#` block comment, including any comments nested in it.
+/// Returns false if the input ends before the comment does.
+fn skip_block_comment(lexer: &mut Lexer) -> bool {
+    lexer.bump(); // consume '#'
+    lexer.bump(); // consume '|'
+    let mut depth = 1;
+    while depth > 0 {
+        if lexer.rest().starts_with("|#") {
+            depth -= 1;
+        } else if lexer.rest().starts_with("#|") {
+            depth += 1;
+        } else if lexer.bump().is_some() {
+            continue;
+        } else {
+            return false;
+        }
+        lexer.bump();
+        lexer.bump();
+    }
+    true
+}
+
+/// Converts a single string or atom token into an `Expr`.
+fn atom(token: &Token) -> Result<Expr, String> {
+    if token.kind == TokenKind::String {
+        if token.text.starts_with('#') {
+            let hashes = token.text.len() - token.text.trim_start_matches('#').len();
+            let raw = &token.text[hashes + 1..token.text.len() - hashes - 1];
+            return Ok(Expr::String(raw.into()));
+        }
+        return Ok(Expr::String(
+            unescape(&token.text[1..token.text.len() - 1]).into(),
+        ));
+    }
+    if token.text.starts_with('|') {
+        return Ok(Expr::Symbol(
+            unescape(&token.text[1..token.text.len() - 1]).into(),
+        ));
+    }
+    bare_atom(token.text)
+}
+
+/// Converts the text of an atom token into a boolean, number or symbol,
+/// following the rules for symbols in the module documentation.
+fn bare_atom(token: &str) -> Result<Expr, String> {
     match token {
-        "true" => Expr::Bool(true),
-        "false" => Expr::Bool(false),
-        _ => token
-            .parse::<f64>()
-            .map(Expr::Number)
-            .unwrap_or_else(|_| Expr::Symbol(token.to_string())),
+        "true" => return Ok(Expr::Bool(true)),
+        "false" => return Ok(Expr::Bool(false)),
+        _ => {}
+    }
+    if let Some(n) = parse_number(&strip_digit_separators(token), None) {
+        return Ok(Expr::Number(n));
+    }
+
+    let mut chars = token.chars();
+    let first = chars.next().unwrap_or(' ');
+    let rest = chars.as_str();
+    let starts_like_number = |s: &str| {
+        let s = s.strip_prefix('.').unwrap_or(s);
+        s.starts_with(|c: char| c.is_ascii_digit())
+    };
+    if first == '#' {
+        return Err(format!("Unknown reader syntax '{}'.", token));
+    }
+    if first.is_ascii_digit() || (matches!(first, '+' | '-' | '.') && starts_like_number(rest)) {
+        return Err(format!("Invalid number '{}'.", token));
+    }
+    if !matches!(first, '+' | '-' | '.') && !is_symbol_initial(first) {
+        return Err(format!(
+            "Invalid character '{}' in symbol '{}'.",
+            first, token
+        ));
     }
+    if let Some(c) = rest.chars().find(|&c| !is_symbol_subsequent(c)) {
+        return Err(format!("Invalid character '{}' in symbol '{}'.", c, token));
+    }
+    Ok(Expr::Symbol(token.into()))
 }
 
-/// Recursively reads tokens to build an expression tree.
-fn read_from_tokens(tokens: &mut &[String]) -> Result<Expr, String> {
-    if tokens.is_empty() {
-        return Err("Unexpected EOF".to_string());
+/// Returns whether the symbol `name` can be written as it is, without pipes,
+/// and still read back as the same symbol.
+pub(crate) fn is_bare_symbol(name: &str) -> bool {
+    !name.contains(is_delimiter)
+        && runtime::reader_macro(name).is_none()
+        && bare_atom(name).is_ok_and(|expr| expr == Expr::Symbol(name.into()))
+}
+
+/// Returns whether a bare symbol may start with `c`.
+fn is_symbol_initial(c: char) -> bool {
+    c.is_alphabetic() || !c.is_ascii() || "!$%&*/:<=>?^_~@".contains(c)
+}
+
+/// Returns whether `c` may appear in a bare symbol after its first character.
+fn is_symbol_subsequent(c: char) -> bool {
+    is_symbol_initial(c) || c.is_ascii_digit() || "+-.#".contains(c)
+}
+
+/// Removes the underscores from a literal such as `1_000_000` or `0xFF_FF`.
+///
+/// Only underscores between two digits are separators; a token with any
+/// other underscore is returned unchanged, so it will not read as a number.
+fn strip_digit_separators(token: &str) -> std::borrow::Cow<'_, str> {
+    if !token.contains('_') {
+        return token.into();
+    }
+    let chars: Vec<char> = token.chars().collect();
+    let separates_digits = |i: usize| {
+        i > 0
+            && i + 1 < chars.len()
+            && chars[i - 1].is_ascii_hexdigit()
+            && chars[i + 1].is_ascii_hexdigit()
+    };
+    if (0..chars.len()).any(|i| chars[i] == '_' && !separates_digits(i)) {
+        return token.into();
     }
+    token.replace('_', "").into()
+}
 
-    let token = tokens[0].clone();
-    *tokens = &tokens[1..];
+/// Parses the text of a numeric literal or of `string->number`.
+///
+/// Without a radix, decimal spellings (including exponents, as in `6.02e23`)
+/// and `+inf.0`, `-inf.0` and `nan.0` are accepted, as well as integers with
+/// a `0x`, `0o` or `0b` prefix. With a radix, the text must be an integer in
+/// that radix, though a prefix matching the radix is allowed.
+pub(crate) fn parse_number(s: &str, radix: Option<u32>) -> Option<f64> {
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let prefixed = [
+        ("0x", 16),
+        ("0X", 16),
+        ("0o", 8),
+        ("0O", 8),
+        ("0b", 2),
+        ("0B", 2),
+    ]
+    .iter()
+    .find_map(|&(prefix, r)| unsigned.strip_prefix(prefix).map(|digits| (digits, r)));
+    let (digits, radix) = match (prefixed, radix) {
+        (Some((digits, r)), None) => (digits, r),
+        (Some((digits, r)), Some(radix)) if r == radix => (digits, r),
+        (_, None) => {
+            return match unsigned {
+                "inf.0" if s != unsigned => Some(if negative {
+                    f64::NEG_INFINITY
+                } else {
+                    f64::INFINITY
+                }),
+                "nan.0" => Some(f64::NAN),
+                // Rust also accepts spellings such as `inf` and `NaN`, which
+                // should stay symbols.
+                _ if unsigned.starts_with(|c: char| c.is_ascii_alphabetic()) => None,
+                _ => s.parse::<f64>().ok(),
+            };
+        }
+        (_, Some(radix)) => (unsigned, radix),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    // Accumulate in a float so that long inputs lose precision rather than
+    // overflowing, as decimal literals do.
+    let mut value = 0.0;
+    for c in digits.chars() {
+        value = value * radix as f64 + c.to_digit(radix)? as f64;
+    }
+    Some(if negative { -value } else { value })
+}
 
-    match token.as_str() {
-        "(" => {
-            let mut list = Vec::new();
-            while !tokens.is_empty() && tokens[0] != ")" {
-                list.push(read_from_tokens(tokens)?);
+/// What the reader needs to know besides the tokens.
+#[derive(Clone, Copy)]
+struct ReadContext {
+    /// The position just past the input, where running out of tokens is
+    /// reported.
+    end: Position,
+    /// How many expressions enclose the one being read.
+    depth: usize,
+    /// The greatest depth allowed, so that hostile input cannot overflow the
+    /// stack of the recursive reader.
+    max_depth: usize,
+}
+
+impl ReadContext {
+    fn new(end: Position) -> Self {
+        ReadContext {
+            end,
+            depth: 0,
+            max_depth: runtime::max_parse_depth(),
+        }
+    }
+
+    /// Returns the context for reading the next expression in `tokens` one
+    /// level deeper, failing if that is too deep.
+    fn deeper(self, tokens: &[Token]) -> Result<Self, SyntaxError> {
+        if self.depth >= self.max_depth {
+            let pos = tokens.first().map_or(self.end, |t| t.span.start);
+            return Err(SyntaxError {
+                too_deep: true,
+                ..SyntaxError::new(
+                    format!("Expressions are nested more than {} deep.", self.max_depth),
+                    pos,
+                )
+            });
+        }
+        Ok(ReadContext {
+            depth: self.depth + 1,
+            ..self
+        })
+    }
+}
+
+/// Recursively reads tokens to build an expression tree.
+fn read_from_tokens(tokens: &mut &[Token], ctx: ReadContext) -> Result<Spanned, SyntaxError> {
+    let ctx = ctx.deeper(tokens)?;
+    skip_datum_comments(tokens, ctx)?;
+    let Some((token, rest)) = tokens.split_first() else {
+        return Err(SyntaxError::incomplete("Unexpected EOF", ctx.end));
+    };
+    *tokens = rest;
+
+    match token.kind {
+        TokenKind::Open => read_list(token, tokens, ctx),
+        TokenKind::Close if token.text == ")" => Err(SyntaxError::new(
+            "Unexpected closing parenthesis.",
+            token.span.start,
+        )),
+        TokenKind::Close => Err(SyntaxError::new(
+            "Unexpected closing bracket.",
+            token.span.start,
+        )),
+        TokenKind::DatumComment | TokenKind::Comment => {
+            unreachable!("comments are skipped above")
+        }
+        TokenKind::Atom => match runtime::reader_macro(token.text) {
+            Some((prefix_len, handler)) if !token.text.starts_with('|') => {
+                read_macro(token, prefix_len, handler, tokens, ctx)
             }
-            if tokens.is_empty() {
-                return Err("Missing closing parenthesis.".to_string());
+            _ => Ok(Spanned {
+                node: Node::Atom(atom(token).map_err(|e| SyntaxError::new(e, token.span.start))?),
+                span: token.span,
+            }),
+        },
+        TokenKind::String if token.text.starts_with("#f") => Ok(Spanned {
+            node: Node::Atom(interpolate(token, ctx)?),
+            span: token.span,
+        }),
+        TokenKind::String => Ok(Spanned {
+            node: Node::Atom(atom(token).map_err(|e| SyntaxError::new(e, token.span.start))?),
+            span: token.span,
+        }),
+    }
+}
+
+/// Reads the rest of a list whose opening bracket is `open`.
+///
+/// This and [`read_macro`] are kept out of [`read_from_tokens`] so that the
+/// frames of the recursive reader stay small.
+fn read_list(
+    open: &Token,
+    tokens: &mut &[Token],
+    ctx: ReadContext,
+) -> Result<Spanned, SyntaxError> {
+    // A list must be closed by the same kind of bracket that opened it.
+    let (close, name) = if open.text == "(" {
+        (")", "parenthesis")
+    } else {
+        ("]", "bracket")
+    };
+    let mut list = Vec::new();
+    loop {
+        skip_datum_comments(tokens, ctx)?;
+        match tokens.first() {
+            None => {
+                return Err(SyntaxError::incomplete(
+                    format!("Missing closing {}.", name),
+                    open.span.start,
+                ));
             }
-            *tokens = &tokens[1..]; // consume ')'
-            Ok(Expr::List(list))
+            Some(t) if t.kind == TokenKind::Close && t.text == close => break,
+            Some(t) if t.kind == TokenKind::Close => {
+                return Err(SyntaxError::new(
+                    format!("Expected '{}' but found '{}'.", close, t.text),
+                    t.span.start,
+                ));
+            }
+            Some(_) => list.push(read_from_tokens(tokens, ctx)?),
         }
-        ")" => Err("Unexpected closing parenthesis.".to_string()),
-        _ => Ok(atom(&token)),
+    }
+    let list_end = tokens[0].span.end;
+    *tokens = &tokens[1..]; // consume the closing bracket
+    Ok(Spanned {
+        node: Node::List(list),
+        span: Span {
+            start: open.span.start,
+            end: list_end,
+        },
+    })
+}
+
+/// Reads the datum that a reader macro applies to and expands it. `token`
+/// starts with the macro's prefix, which is `prefix_len` bytes long.
+fn read_macro(
+    token: &Token,
+    prefix_len: usize,
+    handler: runtime::ReaderMacro,
+    tokens: &mut &[Token],
+    ctx: ReadContext,
+) -> Result<Spanned, SyntaxError> {
+    // A prefix written on its own applies to the next datum, as in
+    // `@(a b)`; otherwise it applies to the rest of the token.
+    let (datum, datum_end) = if prefix_len == token.text.len() {
+        let datum = read_from_tokens(tokens, ctx)?;
+        (datum.to_expr(), datum.span.end)
+    } else {
+        let datum = bare_atom(&token.text[prefix_len..])
+            .map_err(|e| SyntaxError::new(e, token.span.start))?;
+        (datum, token.span.end)
+    };
+    let value = handler(datum).map_err(|e| SyntaxError::new(e, token.span.start))?;
+    Ok(Spanned {
+        node: Node::Atom(value),
+        span: Span {
+            start: token.span.start,
+            end: datum_end,
+        },
+    })
+}
+
+/// Consumes any `#;` datum comments at the front of `tokens`, along with the
+/// expressions they comment out.
+fn skip_datum_comments(tokens: &mut &[Token], ctx: ReadContext) -> Result<(), SyntaxError> {
+    while tokens
+        .first()
+        .is_some_and(|t| t.kind == TokenKind::DatumComment)
+    {
+        *tokens = &tokens[1..];
+        read_from_tokens(tokens, ctx)?;
+    }
+    Ok(())
+}
+
+/// The result of [`parse_incremental`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseOutcome {
+    /// The input holds these complete expressions.
+    Complete(Vec<Expr>),
+    /// The input is the beginning of valid input but ends too early, for
+    /// example inside a list or string.
+    Incomplete,
+    /// The input is invalid however it continues.
+    Error(ParseError),
+}
+
+/// Parses input that may still be being typed, as [`parse_program`] does,
+/// but tells input that is merely unfinished apart from invalid input.
+///
+/// A REPL can use this to keep reading lines until an expression is complete.
+pub fn parse_incremental(input: &str) -> ParseOutcome {
+    match read_all(input) {
+        Ok(forms) => ParseOutcome::Complete(forms.iter().map(Spanned::to_expr).collect()),
+        Err(e) if e.incomplete => ParseOutcome::Incomplete,
+        Err(e) => ParseOutcome::Error(e.locate(input)),
     }
 }
 
+/// Returns the position just past the end of `input`.
+fn end_of(input: &str) -> Position {
+    let mut lexer = Lexer::new(input);
+    lexer.bump_while(|_| true);
+    lexer.pos
+}
+
 /// Parses a string into a Lisp expression.
 ///
 /// This function takes a string slice representing Lisp code, tokenizes it,
@@ -99,15 +994,322 @@ fn read_from_tokens(tokens: &mut &[String]) -> Result<Expr, String> {
 ///
 /// A `Result` which is:
 /// - `Ok(Expr)`: If parsing is successful, containing the root expression.
-/// - `Err(String)`: If parsing fails, containing an error message.
-pub fn parse(input: &str) -> Result<Expr, String> {
-    let tokens = tokenize(input);
+/// - `Err(ParseError)`: If parsing fails, giving the line and column of the
+///   problem and the offending line.
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    parse_spanned(input).map(|spanned| spanned.to_expr())
+}
+
+/// Parses a string into a Lisp expression, like [`parse`], but keeps the
+/// source location of every subexpression.
+pub fn parse_spanned(input: &str) -> Result<Spanned, ParseError> {
+    read_one(input).map_err(|e| e.locate(input))
+}
+
+fn read_one(input: &str) -> Result<Spanned, SyntaxError> {
+    read_one_in(input, ReadContext::new(end_of(input)))
+}
+
+/// Reads the one expression in `input`, as nested in `ctx`.
+fn read_one_in(input: &str, ctx: ReadContext) -> Result<Spanned, SyntaxError> {
+    let tokens = tokenize(input)?;
     let mut tokens_slice = tokens.as_slice();
-    let result = read_from_tokens(&mut tokens_slice)?;
+    let result = read_from_tokens(&mut tokens_slice, ctx)?;
+    skip_datum_comments(&mut tokens_slice, ctx)?;
 
-    if !tokens_slice.is_empty() {
-        Err("Unexpected tokens after main expression.".to_string())
-    } else {
-        Ok(result)
+    match tokens_slice.first() {
+        Some(extra) => Err(SyntaxError::new(
+            "Unexpected tokens after main expression.",
+            extra.span.start,
+        )),
+        None => Ok(result),
+    }
+}
+
+/// Parses a string containing any number of Lisp expressions, such as the
+/// contents of a source file, returning them in order.
+///
+/// Unlike [`parse`], this accepts empty input and input with several
+/// top-level forms.
+pub fn parse_program(input: &str) -> Result<Vec<Expr>, ParseError> {
+    parse_program_spanned(input).map(|forms| forms.iter().map(Spanned::to_expr).collect())
+}
+
+/// Parses any number of Lisp expressions, like [`parse_program`], but keeps
+/// the source location of every subexpression.
+pub fn parse_program_spanned(input: &str) -> Result<Vec<Spanned>, ParseError> {
+    read_all(input).map_err(|e| e.locate(input))
+}
+
+fn read_all(input: &str) -> Result<Vec<Spanned>, SyntaxError> {
+    let tokens = tokenize(input)?;
+    let ctx = ReadContext::new(end_of(input));
+    let mut tokens_slice = tokens.as_slice();
+    let mut exprs = Vec::new();
+    loop {
+        skip_datum_comments(&mut tokens_slice, ctx)?;
+        if tokens_slice.is_empty() {
+            return Ok(exprs);
+        }
+        exprs.push(read_from_tokens(&mut tokens_slice, ctx)?);
+    }
+}
+
+/// Parses the Lisp expressions in `reader` one by one, as [`parse_program`]
+/// does for a string, without reading all of the input first.
+///
+/// Input is read a line at a time and each top-level form is returned once
+/// it is complete, so only the form being read is held in memory. This suits
+/// large data files and input that arrives over time. The iterator ends after
+/// the first parse or read error.
+pub fn parse_from_reader<R: BufRead>(reader: R) -> FormReader<R> {
+    FormReader {
+        reader,
+        buffer: String::new(),
+        start: Position::default(),
+        scanned: Position::default(),
+        tokens: Vec::new(),
+        depth: 0,
+        forms: VecDeque::new(),
+        done: false,
+    }
+}
+
+/// An iterator over the expressions read from a stream, returned by
+/// [`parse_from_reader`].
+pub struct FormReader<R> {
+    reader: R,
+    /// The input read but not yet parsed, from the start of a line.
+    buffer: String,
+    /// Where `buffer` begins in the whole input.
+    start: Position,
+    /// How far `buffer` has been split into tokens.
+    scanned: Position,
+    /// The tokens not yet parsed into forms, with comments dropped.
+    tokens: Vec<(TokenKind, Span)>,
+    /// How many more lists `tokens` open than close.
+    depth: isize,
+    /// Forms parsed but not yet returned, and then any error that followed
+    /// them.
+    forms: VecDeque<Result<Expr, ParseError>>,
+    done: bool,
+}
+
+impl<R: BufRead> Iterator for FormReader<R> {
+    type Item = Result<Expr, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(form) = self.forms.pop_front() {
+                return Some(form);
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.read_line() {
+                self.forms.push_back(Err(e));
+                self.done = true;
+            }
+        }
+    }
+}
+
+impl<R: BufRead> FormReader<R> {
+    /// Reads another line and parses the forms it completes.
+    fn read_line(&mut self) -> Result<(), ParseError> {
+        let at_eof = self
+            .reader
+            .read_line(&mut self.buffer)
+            .map_err(|e| ParseError::Io(e.to_string()))?
+            == 0;
+        self.done = at_eof;
+        self.scan(at_eof)
+            .and_then(|ready| {
+                if ready || at_eof {
+                    self.read_forms(at_eof)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| e.locate_from(&self.buffer, self.start.line))
+    }
+
+    /// Splits the new input into tokens, returning whether any of them
+    /// closes a top-level form, so that there may be forms to read.
+    ///
+    /// Since the buffer only ever ends at the end of a line or of the input,
+    /// every token found is whole, except that a string or comment may go on
+    /// to later lines; it is scanned again once more input arrives.
+    fn scan(&mut self, at_eof: bool) -> Result<bool, SyntaxError> {
+        let mut tokens = Tokens {
+            lexer: Lexer {
+                input: &self.buffer[self.scanned.offset - self.start.offset..],
+                base: self.scanned.offset,
+                pos: self.scanned,
+            },
+            failed: false,
+        };
+        let mut ready = false;
+        for token in tokens.by_ref() {
+            match token {
+                Ok(token) => {
+                    self.scanned = token.span.end;
+                    match token.kind {
+                        TokenKind::Comment => continue,
+                        TokenKind::Open => self.depth += 1,
+                        TokenKind::Close => self.depth -= 1,
+                        _ => {}
+                    }
+                    self.tokens.push((token.kind, token.span));
+                    ready |= self.depth <= 0;
+                }
+                Err(e) if at_eof => return Err(e.into()),
+                Err(_) => return Ok(ready),
+            }
+        }
+        self.scanned = tokens.lexer.pos;
+        Ok(ready)
+    }
+
+    /// Parses as many forms from the scanned tokens as are complete, then
+    /// drops the input they were read from.
+    fn read_forms(&mut self, at_eof: bool) -> Result<(), SyntaxError> {
+        let base = self.start.offset;
+        let tokens: Vec<Token> = self
+            .tokens
+            .iter()
+            .map(|&(kind, span)| Token {
+                kind,
+                text: &self.buffer[span.start.offset - base..span.end.offset - base],
+                span,
+            })
+            .collect();
+        let ctx = ReadContext::new(self.scanned);
+        let mut rest = tokens.as_slice();
+        loop {
+            let before = rest;
+            match skip_datum_comments(&mut rest, ctx).and_then(|_| {
+                if rest.is_empty() {
+                    Ok(None)
+                } else {
+                    read_from_tokens(&mut rest, ctx).map(Some)
+                }
+            }) {
+                Ok(Some(form)) => self.forms.push_back(Ok(form.to_expr())),
+                Ok(None) => break,
+                // The rest of the form has yet to be read.
+                Err(e) if e.incomplete && !at_eof => {
+                    rest = before;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let consumed = tokens.len() - rest.len();
+        self.tokens.drain(..consumed);
+        self.depth = self
+            .tokens
+            .iter()
+            .map(|(kind, _)| match kind {
+                TokenKind::Open => 1,
+                TokenKind::Close => -1,
+                _ => 0,
+            })
+            .sum();
+        // Keep whole lines, so that errors can show the line they are on.
+        let keep = self
+            .tokens
+            .first()
+            .map_or(self.scanned, |(_, span)| span.start);
+        let cut = self.buffer[..keep.offset - base]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        self.buffer.drain(..cut);
+        self.start = Position {
+            offset: base + cut,
+            line: keep.line,
+            column: 1,
+        };
+        Ok(())
+    }
+}
+
+/// A problem found by [`parse_all_recovering`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// What went wrong, such as `Missing closing parenthesis.`.
+    pub message: String,
+    /// Where the problem was found.
+    pub position: Position,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic the way [`parse`] reports errors, with the
+    /// offending line of `input` and a caret under the position.
+    pub fn render(&self, input: &str) -> String {
+        SyntaxError::new(self.message.clone(), self.position)
+            .locate(input)
+            .to_string()
+    }
+}
+
+/// Parses every top-level form it can, like [`parse_program`], but keeps
+/// going after errors, returning the forms that were read and a diagnostic
+/// for each error.
+///
+/// After an error, reading resumes at the next `(` or `[` at the start of a
+/// line, which is where top-level forms usually begin. An unterminated string
+/// or block comment swallows the rest of the input, so nothing after it is
+/// read.
+pub fn parse_all_recovering(input: &str) -> (Vec<Expr>, Vec<Diagnostic>) {
+    let mut diagnostics = Vec::new();
+    let mut all_tokens = Vec::new();
+    for token in tokens(input) {
+        match token {
+            Ok(token) if token.kind == TokenKind::Comment => {}
+            Ok(token) => all_tokens.push(token),
+            Err(e) => diagnostics.push(Diagnostic {
+                message: e.message,
+                position: e.position,
+            }),
+        }
+    }
+
+    let tokenizer_failed = !diagnostics.is_empty();
+    let ctx = ReadContext::new(end_of(input));
+    let mut exprs = Vec::new();
+    let mut next = 0;
+    while next < all_tokens.len() {
+        let mut rest = &all_tokens[next..];
+        match skip_datum_comments(&mut rest, ctx).and_then(|_| {
+            if rest.is_empty() {
+                Ok(None)
+            } else {
+                read_from_tokens(&mut rest, ctx).map(Some)
+            }
+        }) {
+            Ok(expr) => {
+                exprs.extend(expr.map(|e| e.to_expr()));
+                next = all_tokens.len() - rest.len();
+            }
+            Err(e) => {
+                // The tokenizer error, if any, already explains running out.
+                if !(e.incomplete && tokenizer_failed) {
+                    diagnostics.push(Diagnostic {
+                        message: e.message,
+                        position: e.pos,
+                    });
+                }
+                next = (next + 1..all_tokens.len())
+                    .find(|&i| {
+                        all_tokens[i].kind == TokenKind::Open
+                            && all_tokens[i].span.start.column == 1
+                    })
+                    .unwrap_or(all_tokens.len());
+            }
+        }
     }
+    diagnostics.sort_by_key(|d| d.position.offset);
+    (exprs, diagnostics)
 }
@@ -0,0 +1,152 @@
+//! Multi-line rendering of expressions for people to read.
+//!
+//! `Display` always writes an expression on one line. The pretty-printer
+//! keeps anything that fits within the configured width on one line too, and
+//! otherwise breaks lists across lines the way Lisp code is usually laid out:
+//! arguments aligned under the first one, and the bodies of `define`,
+//! `lambda`, `deftest` and `try` indented under the form.
+
+use crate::data::Expr;
+
+/// Settings for [`pretty`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrettyOptions {
+    /// The column that lines should not extend past, where possible.
+    pub width: usize,
+    /// How far the body of a form is indented from its opening parenthesis.
+    pub indent: usize,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        PrettyOptions {
+            width: 80,
+            indent: 2,
+        }
+    }
+}
+
+/// Renders `expr` in written form, like `Display`, breaking it across lines
+/// to fit within `options.width` columns.
+///
+/// Atoms are never broken, so a single long string or symbol may still
+/// overflow the width.
+pub fn pretty(expr: &Expr, options: &PrettyOptions) -> String {
+    let mut out = String::new();
+    write_expr(&measure(expr), 0, options, &mut out);
+    out
+}
+
+/// Returns how many leading arguments of a form named `head` stay on the
+/// form's first line, with the rest treated as an indented body, or `None`
+/// if the form has no body.
+fn body_start(head: &str) -> Option<usize> {
+    match head {
+        "define" | "lambda" | "deftest" => Some(1),
+        "try" => Some(0),
+        _ => None,
+    }
+}
+
+/// An expression together with the width it takes on one line, measured
+/// once up front so that deciding where to break stays linear.
+struct Measured<'a> {
+    expr: &'a Expr,
+    width: usize,
+    /// The measured elements, if `expr` is a non-empty list.
+    items: Vec<Measured<'a>>,
+}
+
+fn measure(expr: &Expr) -> Measured<'_> {
+    match expr {
+        Expr::List(list) if !list.is_empty() => {
+            let items: Vec<Measured> = list.iter().map(measure).collect();
+            // The parentheses, plus a space between each pair of items.
+            let width = items.iter().map(|item| item.width).sum::<usize>() + items.len() + 1;
+            Measured { expr, width, items }
+        }
+        _ => Measured {
+            expr,
+            width: width(&expr.to_string()),
+            items: Vec::new(),
+        },
+    }
+}
+
+/// Writes `measured` to `out`, where the cursor is at column `col`.
+fn write_expr(measured: &Measured, col: usize, options: &PrettyOptions, out: &mut String) {
+    let items = &measured.items;
+    if items.is_empty() || col + measured.width <= options.width {
+        out.push_str(&measured.expr.to_string());
+        return;
+    }
+
+    out.push('(');
+    let head = match items[0].expr {
+        Expr::Symbol(head) => head,
+        // A list that does not start with a name, such as a table of rows,
+        // is data: one element per line, aligned.
+        _ => {
+            write_aligned(items, col + 1, options, out);
+            out.push(')');
+            return;
+        }
+    };
+    out.push_str(&items[0].expr.to_string());
+    let head_width = items[0].width;
+    let args = &items[1..];
+
+    match body_start(head) {
+        Some(n) => {
+            let (header, body) = args.split_at(n.min(args.len()));
+            let mut header_col = col + 1 + head_width;
+            for arg in header {
+                out.push(' ');
+                write_expr(arg, header_col + 1, options, out);
+                header_col = last_line_width(out);
+            }
+            for arg in body {
+                newline(col + options.indent, out);
+                write_expr(arg, col + options.indent, options, out);
+            }
+        }
+        None => {
+            let arg_col = col + 2 + head_width;
+            // Aligning under the first argument only helps if it leaves room.
+            if !args.is_empty() && arg_col < options.width / 2 {
+                out.push(' ');
+                write_aligned(args, arg_col, options, out);
+            } else {
+                for arg in args {
+                    newline(col + options.indent, out);
+                    write_expr(arg, col + options.indent, options, out);
+                }
+            }
+        }
+    }
+    out.push(')');
+}
+
+/// Writes `items` one per line, each starting at column `col`, with the
+/// cursor already at `col` for the first.
+fn write_aligned(items: &[Measured], col: usize, options: &PrettyOptions, out: &mut String) {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            newline(col, out);
+        }
+        write_expr(item, col, options, out);
+    }
+}
+
+fn newline(col: usize, out: &mut String) {
+    out.push('\n');
+    out.extend(std::iter::repeat_n(' ', col));
+}
+
+fn width(s: &str) -> usize {
+    s.chars().count()
+}
+
+fn last_line_width(out: &str) -> usize {
+    width(out.rsplit('\n').next().unwrap_or(out))
+}
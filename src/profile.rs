@@ -0,0 +1,156 @@
+//! Counting and timing function calls, to find where evaluation spends its
+//! time.
+//!
+//! While profiling is on, each call made by name, whether to a builtin or to
+//! a function, is counted and timed under that name. Start and stop it with
+//! [`runtime::start_profiling`](crate::runtime::start_profiling) and
+//! [`runtime::stop_profiling`](crate::runtime::stop_profiling), or from Lisp
+//! with `(profile-start)` and `(profile-stop)`, and read the results with
+//! [`runtime::profile`](crate::runtime::profile), `(profile-data)` or
+//! `(profile-report)`.
+
+use crate::data::Expr;
+use crate::shared::Shared;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// The calls recorded for one function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// How many times it was called.
+    pub calls: u64,
+    /// The time spent in its outermost calls, including the functions they
+    /// called. Recursive calls are not counted twice.
+    pub total: Duration,
+    /// The time spent in the function itself, not counting the functions it
+    /// called by name.
+    pub self_time: Duration,
+}
+
+/// The calls recorded while profiling, by function name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    entries: HashMap<String, ProfileEntry>,
+}
+
+impl Profile {
+    /// Returns what was recorded for the function `name`, if it was called.
+    pub fn get(&self, name: &str) -> Option<&ProfileEntry> {
+        self.entries.get(name)
+    }
+
+    /// Returns whether no calls were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the functions called and what was recorded for each, those
+    /// with the most self time first.
+    pub fn entries(&self) -> Vec<(&str, &ProfileEntry)> {
+        let mut entries: Vec<(&str, &ProfileEntry)> = self
+            .entries
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry))
+            .collect();
+        entries.sort_by(|(a, x), (b, y)| y.self_time.cmp(&x.self_time).then(a.cmp(b)));
+        entries
+    }
+
+    /// Returns the profile as a list of maps in the order of
+    /// [`entries`](Self::entries), with the keys `name`, `calls`,
+    /// `total-ms` and `self-ms`, as `(profile-data)` does.
+    pub fn to_expr(&self) -> Expr {
+        let entries = self.entries().into_iter().map(|(name, entry)| {
+            Expr::Map(BTreeMap::from([
+                ("name".to_string(), Expr::String(name.to_string().into())),
+                ("calls".to_string(), Expr::Number(entry.calls as f64)),
+                ("total-ms".to_string(), Expr::Number(millis(entry.total))),
+                ("self-ms".to_string(), Expr::Number(millis(entry.self_time))),
+            ]))
+        });
+        Expr::List(Shared::new(entries.collect()))
+    }
+
+    /// Formats the profile as a table in the order of
+    /// [`entries`](Self::entries), as `(profile-report)` prints it.
+    pub fn report(&self) -> String {
+        let entries = self.entries();
+        let width = entries
+            .iter()
+            .map(|(name, _)| name.chars().count())
+            .chain(["function".len()])
+            .max()
+            .unwrap_or_default();
+        let mut report = format!(
+            "{:<width$} {:>10} {:>12} {:>12}\n",
+            "function", "calls", "total ms", "self ms"
+        );
+        for (name, entry) in entries {
+            // Writing to a string cannot fail.
+            let _ = writeln!(
+                report,
+                "{:<width$} {:>10} {:>12.3} {:>12.3}",
+                name,
+                entry.calls,
+                millis(entry.total),
+                millis(entry.self_time)
+            );
+        }
+        report
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A call in progress while profiling.
+struct Call {
+    name: String,
+    start: Instant,
+    /// The time spent so far in the calls it made by name.
+    children: Duration,
+}
+
+/// Records calls into a [`Profile`] while profiling is on.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    pub(crate) profile: Profile,
+    calls: Vec<Call>,
+    /// How many calls to each function are in progress, so that only the
+    /// outermost adds to its total.
+    active: HashMap<String, usize>,
+}
+
+impl Profiler {
+    /// Records the start of a call to `name`.
+    pub(crate) fn enter(&mut self, name: &str) {
+        *self.active.entry(name.to_string()).or_default() += 1;
+        self.calls.push(Call {
+            name: name.to_string(),
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    /// Records the end of the innermost call in progress.
+    pub(crate) fn exit(&mut self) {
+        let Some(call) = self.calls.pop() else {
+            return;
+        };
+        let elapsed = call.start.elapsed();
+        if let Some(parent) = self.calls.last_mut() {
+            parent.children += elapsed;
+        }
+        let active = self.active.entry(call.name.clone()).or_default();
+        *active = active.saturating_sub(1);
+        let outermost = *active == 0;
+        let entry = self.profile.entries.entry(call.name).or_default();
+        entry.calls += 1;
+        entry.self_time += elapsed.saturating_sub(call.children);
+        if outermost {
+            entry.total += elapsed;
+        }
+    }
+}
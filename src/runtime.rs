@@ -0,0 +1,1040 @@
+//! Interpreter-wide state used by builtins.
+//!
+//! Builtins only receive their arguments and the environment, so services
+//! such as the random number generator and the input and output ports live
+//! here instead, one instance per thread. Embedders can replace them, for
+//! example to make evaluation deterministic or to capture printed output.
+
+use crate::builtins::Builtins;
+use crate::data::{Env, Expr};
+use crate::error::EvalError;
+use crate::profile::{Profile, Profiler};
+use crate::shared::Shared;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of random numbers for the `random` builtins.
+pub trait RandomSource {
+    /// Returns the next 64 random bits.
+    fn next_u64(&mut self) -> u64;
+    /// Resets the generator to a reproducible state, as `random-seed` does.
+    fn reseed(&mut self, seed: u64);
+}
+
+/// The default random source, a SplitMix64 generator.
+///
+/// It is fast and statistically decent, but not suitable for cryptography.
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Creates a generator with the given seed.
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    /// Creates a generator seeded from the system clock.
+    pub fn from_clock() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        SplitMix64::new(nanos)
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+}
+
+/// An output sink that keeps everything written to it in memory.
+///
+/// Clones share the same buffer, so one clone can be installed with
+/// [`set_output`] while another is kept to read the captured text.
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        OutputBuffer::default()
+    }
+
+    /// Returns everything written so far, replacing invalid UTF-8.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader macro handler, which turns the datum after its prefix into the
+/// value that is read in its place.
+pub type ReaderMacro = Rc<dyn Fn(Expr) -> Result<Expr, String>>;
+
+/// Callbacks that observe evaluation, for tracing, coverage and other
+/// instrumentation. Install them with [`set_eval_hooks`] or
+/// [`Interpreter::on_enter`](crate::Interpreter::on_enter).
+///
+/// `on_enter` is called before each expression is evaluated, with the
+/// expression and how many evaluations enclose it, and `on_exit` with the
+/// value or error of each, in last-in first-out order. While hooks are
+/// installed, calls in tail position nest like any other call. Code run by a
+/// [`Vm`](crate::compile::Vm) only calls them for the forms it hands to the
+/// evaluator.
+#[derive(Clone, Default)]
+pub struct EvalHooks {
+    on_enter: Option<EnterHook>,
+    on_exit: Option<ExitHook>,
+}
+
+type EnterHook = Rc<dyn Fn(&Expr, usize)>;
+type ExitHook = Rc<dyn Fn(&Result<Expr, EvalError>)>;
+
+impl EvalHooks {
+    /// Creates hooks that do nothing.
+    pub fn new() -> Self {
+        EvalHooks::default()
+    }
+
+    /// Sets the callback run before each expression is evaluated.
+    pub fn on_enter(mut self, hook: impl Fn(&Expr, usize) + 'static) -> Self {
+        self.on_enter = Some(Rc::new(hook));
+        self
+    }
+
+    /// Sets the callback run with the result of each expression.
+    pub fn on_exit(mut self, hook: impl Fn(&Result<Expr, EvalError>) + 'static) -> Self {
+        self.on_exit = Some(Rc::new(hook));
+        self
+    }
+}
+
+impl fmt::Debug for EvalHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EvalHooks")
+            .field("on_enter", &self.on_enter.is_some())
+            .field("on_exit", &self.on_exit.is_some())
+            .finish()
+    }
+}
+
+/// An interactive debugger, which decides how evaluation goes on each time
+/// it pauses. Install one with [`set_debugger`] or
+/// [`Interpreter::with_debugger`](crate::Interpreter::with_debugger).
+///
+/// Evaluation pauses at `(break)`, on entry to a function named with
+/// [`set_breakpoint`], and, after [`Resume::Step`] and the like, before the
+/// next expression that is evaluated. While the debugger runs, hooks and
+/// stepping are off and further pauses are skipped, so it can evaluate code
+/// in the paused environment freely. Code run by a
+/// [`Vm`](crate::compile::Vm) only pauses at `(break)` and in the forms it
+/// hands to the evaluator.
+pub trait Debugger {
+    /// Called when evaluation pauses, to inspect it and choose how to go on.
+    fn pause(&mut self, pause: &Pause) -> Resume;
+}
+
+/// A debugger shared between the runtime and whoever installed it.
+pub type SharedDebugger = Rc<RefCell<dyn Debugger>>;
+
+/// Where evaluation has paused.
+#[derive(Debug)]
+pub struct Pause<'a> {
+    /// Why evaluation paused.
+    pub reason: PauseReason,
+    /// The expression about to be evaluated, such as the body of the
+    /// function at a breakpoint, or `(break)` itself.
+    pub expr: &'a Expr,
+    /// The environment the expression is evaluated in, whose
+    /// [`locals`](Env::locals) are the paused function's.
+    pub env: &'a Env,
+    /// How many evaluations enclose the expression, counting only those
+    /// begun while hooks were installed or stepping was on.
+    pub depth: usize,
+}
+
+/// Why evaluation paused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PauseReason {
+    /// `(break)` was called.
+    Break,
+    /// The function with a breakpoint of this name was called.
+    Breakpoint(String),
+    /// Stepping reached the next expression.
+    Step,
+}
+
+/// How evaluation goes on after a pause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next breakpoint or `(break)`.
+    Continue,
+    /// Pause before the next expression evaluated, however deep.
+    Step,
+    /// Pause before the next expression that is not part of this one.
+    Next,
+    /// Pause once the expression enclosing this one is done, before the
+    /// next expression after it.
+    Finish,
+    /// Stop evaluating, failing with [`EvalError::Interrupted`].
+    Abort,
+}
+
+/// Groups of builtins that reach outside the interpreter.
+///
+/// Each group can be switched off to sandbox untrusted code; calling a
+/// builtin from a disabled group is an evaluation error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Reading and writing files.
+    pub filesystem: bool,
+    /// Reading and setting environment variables.
+    pub environment: bool,
+    /// Running other programs.
+    pub process: bool,
+}
+
+impl Capabilities {
+    /// Every group enabled. This is the default.
+    pub fn all() -> Self {
+        Capabilities {
+            filesystem: true,
+            environment: true,
+            process: true,
+        }
+    }
+
+    /// Every group disabled, for running untrusted code.
+    pub fn sandboxed() -> Self {
+        Capabilities {
+            filesystem: false,
+            environment: false,
+            process: false,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::all()
+    }
+}
+
+/// A handle for interrupting evaluation from another thread.
+///
+/// Obtain one with [`interrupt_handle`] on the thread that evaluates, then
+/// call [`InterruptHandle::interrupt`] from anywhere, such as a Ctrl-C
+/// handler. The running evaluation stops at its next function call, or
+/// within a few milliseconds if it is in `sleep`, with an error.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Requests that the current evaluation stop.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What the `exit` builtin does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ExitPolicy {
+    /// Terminate the process with the requested status, as scripts expect.
+    Terminate,
+    /// Fail the evaluation with an error, which the host can inspect. This
+    /// is the default, so that embedded code cannot end its host process.
+    #[default]
+    Error,
+    /// Do nothing and return normally.
+    Ignore,
+}
+
+/// How deeply expressions may nest in parsed text unless
+/// [`set_max_parse_depth`] says otherwise. This leaves room on even a small
+/// thread stack for the reader and for evaluating what it reads.
+pub const DEFAULT_MAX_PARSE_DEPTH: usize = 256;
+
+/// How deeply function calls may nest unless [`set_max_call_depth`] says
+/// otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// How deeply evaluations may nest inside builtins that evaluate code, such
+/// as `eval`, and host functions that do. Each nested evaluation takes some
+/// of the thread's stack, so this leaves room for them on a small one.
+pub const MAX_NESTED_EVALUATIONS: usize = 64;
+
+struct Runtime {
+    /// The builtins that calls look up first.
+    builtins: Builtins,
+    capabilities: Capabilities,
+    exit_policy: ExitPolicy,
+    interrupt: InterruptHandle,
+    rng: Box<dyn RandomSource>,
+    output: Box<dyn Write>,
+    /// `None` reads from standard input, which is locked only per line so
+    /// that the REPL can keep reading from it too.
+    input: Option<Box<dyn BufRead>>,
+    /// Variables set with `setenv`. They are kept here rather than in the
+    /// process environment, which cannot be changed safely while other
+    /// threads may be reading it, and are passed on to child processes.
+    env_vars: HashMap<String, String>,
+    /// Names of the functions whose calls `trace` reports.
+    traced: HashSet<String>,
+    /// How many traced calls are currently in progress.
+    trace_depth: usize,
+    /// Callbacks run around each evaluation, if any.
+    eval_hooks: Option<EvalHooks>,
+    /// How many evaluations are in progress that began while hooks were
+    /// installed or stepping was on.
+    eval_depth: usize,
+    /// The debugger that pauses evaluation, if any.
+    debugger: Option<SharedDebugger>,
+    /// Names of the functions whose calls pause in the debugger.
+    breakpoints: HashSet<String>,
+    /// While stepping, the deepest evaluation at which to pause next.
+    stop_depth: Option<usize>,
+    /// Why the next step pauses, if not for stepping.
+    pause_reason: Option<PauseReason>,
+    /// Records calls while profiling is on.
+    profiler: Option<Profiler>,
+    /// What the last profiling run recorded, once it is stopped.
+    last_profile: Profile,
+    /// Tests registered with `deftest`, in definition order.
+    tests: Vec<(String, Vec<Expr>)>,
+    /// Reader macros by prefix.
+    reader_macros: HashMap<String, ReaderMacro>,
+    /// How deeply the reader lets expressions nest.
+    max_parse_depth: usize,
+    /// How many function calls are currently in progress.
+    call_depth: usize,
+    /// How many evaluations are running, each nested in the last.
+    evaluation_depth: usize,
+    /// How deeply function calls may nest.
+    max_call_depth: usize,
+    /// How many more evaluation steps may be taken, if limited.
+    fuel: Option<u64>,
+    /// When evaluation must stop, if it is timed.
+    deadline: Option<Instant>,
+    /// Steps taken since the deadline was last checked.
+    steps_since_check: u32,
+    /// How many bytes of values builtins may construct, if limited.
+    memory_limit: Option<usize>,
+    /// How many bytes of values builtins have constructed since the limit
+    /// was set.
+    memory_used: usize,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime {
+            builtins: Builtins::standard(),
+            capabilities: Capabilities::default(),
+            exit_policy: ExitPolicy::default(),
+            interrupt: InterruptHandle::default(),
+            rng: Box::new(SplitMix64::from_clock()),
+            output: Box::new(io::stdout()),
+            input: None,
+            env_vars: HashMap::new(),
+            traced: HashSet::new(),
+            trace_depth: 0,
+            eval_hooks: None,
+            eval_depth: 0,
+            debugger: None,
+            breakpoints: HashSet::new(),
+            stop_depth: None,
+            pause_reason: None,
+            profiler: None,
+            last_profile: Profile::default(),
+            tests: Vec::new(),
+            reader_macros: HashMap::new(),
+            max_parse_depth: DEFAULT_MAX_PARSE_DEPTH,
+            call_depth: 0,
+            evaluation_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            fuel: None,
+            deadline: None,
+            steps_since_check: 0,
+            memory_limit: None,
+            memory_used: 0,
+        }
+    }
+}
+
+thread_local! {
+    static RUNTIME: RefCell<Runtime> = RefCell::new(Runtime::default());
+}
+
+/// Replaces the builtins of the current thread, returning the previous
+/// ones. The default is [`Builtins::standard`].
+pub fn set_builtins(builtins: Builtins) -> Builtins {
+    RUNTIME.with(|rt| std::mem::replace(&mut rt.borrow_mut().builtins, builtins))
+}
+
+/// Returns the builtins of the current thread.
+pub fn builtins() -> Builtins {
+    RUNTIME.with(|rt| rt.borrow().builtins.clone())
+}
+
+/// Sets which groups of builtins are available on the current thread.
+pub fn set_capabilities(capabilities: Capabilities) {
+    RUNTIME.with(|rt| rt.borrow_mut().capabilities = capabilities);
+}
+
+/// Returns the capabilities in effect on the current thread.
+pub fn capabilities() -> Capabilities {
+    RUNTIME.with(|rt| rt.borrow().capabilities)
+}
+
+/// Sets what `exit` does on the current thread.
+pub fn set_exit_policy(policy: ExitPolicy) {
+    RUNTIME.with(|rt| rt.borrow_mut().exit_policy = policy);
+}
+
+/// Returns the exit policy in effect on the current thread.
+pub fn exit_policy() -> ExitPolicy {
+    RUNTIME.with(|rt| rt.borrow().exit_policy)
+}
+
+/// Returns a handle that interrupts evaluation on the current thread.
+pub fn interrupt_handle() -> InterruptHandle {
+    RUNTIME.with(|rt| rt.borrow().interrupt.clone())
+}
+
+/// Fails if an interrupt was requested, clearing the request so that the
+/// next evaluation can run.
+pub(crate) fn check_interrupt() -> Result<(), EvalError> {
+    let requested = RUNTIME.with(|rt| rt.borrow().interrupt.0.swap(false, Ordering::SeqCst));
+    if requested {
+        Err(EvalError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Replaces the random source used by `random` on the current thread.
+pub fn set_random_source(source: impl RandomSource + 'static) {
+    RUNTIME.with(|rt| rt.borrow_mut().rng = Box::new(source));
+}
+
+/// Runs `f` with the current thread's random source.
+pub(crate) fn with_rng<T>(f: impl FnOnce(&mut dyn RandomSource) -> T) -> T {
+    RUNTIME.with(|rt| f(rt.borrow_mut().rng.as_mut()))
+}
+
+/// Replaces the sink that `print`, `display`, `write` and `newline` write to
+/// on the current thread. The default is standard output.
+pub fn set_output(output: impl Write + 'static) {
+    RUNTIME.with(|rt| rt.borrow_mut().output = Box::new(output));
+}
+
+/// Writes `text` to the current thread's output sink.
+pub(crate) fn write_output(text: &str) -> Result<(), String> {
+    RUNTIME.with(|rt| {
+        let output = &mut rt.borrow_mut().output;
+        output
+            .write_all(text.as_bytes())
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("Failed to write output: {}", e))
+    })
+}
+
+/// Replaces the source that `read-line` reads from on the current thread.
+/// The default is standard input.
+pub fn set_input(input: impl BufRead + 'static) {
+    RUNTIME.with(|rt| rt.borrow_mut().input = Some(Box::new(input)));
+}
+
+/// Reads one line from the current thread's input, without its line ending.
+/// Returns `None` at end of input.
+pub(crate) fn read_input_line() -> Result<Option<String>, String> {
+    RUNTIME.with(|rt| {
+        let mut line = String::new();
+        let read = match &mut rt.borrow_mut().input {
+            Some(input) => input.read_line(&mut line),
+            None => io::stdin().read_line(&mut line),
+        }
+        .map_err(|e| format!("Failed to read input: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    })
+}
+
+/// Looks up an environment variable, preferring values set with `setenv`.
+pub(crate) fn get_env_var(name: &str) -> Option<String> {
+    RUNTIME
+        .with(|rt| rt.borrow().env_vars.get(name).cloned())
+        .or_else(|| std::env::var(name).ok())
+}
+
+/// Sets an environment variable for `getenv` and for child processes.
+pub(crate) fn set_env_var(name: &str, value: &str) {
+    RUNTIME.with(|rt| {
+        rt.borrow_mut()
+            .env_vars
+            .insert(name.to_string(), value.to_string())
+    });
+}
+
+/// Returns the variables set with `setenv`, to pass on to a child process.
+pub(crate) fn env_vars() -> HashMap<String, String> {
+    RUNTIME.with(|rt| rt.borrow().env_vars.clone())
+}
+
+/// Starts or stops tracing calls to the function named `name`.
+pub(crate) fn set_traced(name: &str, traced: bool) {
+    RUNTIME.with(|rt| {
+        let traced_names = &mut rt.borrow_mut().traced;
+        if traced {
+            traced_names.insert(name.to_string());
+        } else {
+            traced_names.remove(name);
+        }
+    });
+}
+
+/// Returns whether calls to the function named `name` are traced.
+pub(crate) fn is_traced(name: &str) -> bool {
+    RUNTIME.with(|rt| {
+        let rt = rt.borrow();
+        !rt.traced.is_empty() && rt.traced.contains(name)
+    })
+}
+
+/// Records the start of a traced call, returning the depth of traced calls
+/// that enclose it.
+pub(crate) fn enter_trace() -> usize {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.trace_depth += 1;
+        rt.trace_depth - 1
+    })
+}
+
+/// Records the end of a traced call.
+pub(crate) fn exit_trace() {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.trace_depth = rt.trace_depth.saturating_sub(1);
+    });
+}
+
+/// Installs hooks that observe evaluation on the current thread, or removes
+/// them, returning the previous ones.
+pub fn set_eval_hooks(hooks: Option<EvalHooks>) -> Option<EvalHooks> {
+    RUNTIME.with(|rt| std::mem::replace(&mut rt.borrow_mut().eval_hooks, hooks))
+}
+
+/// Returns whether evaluations must be recorded, because hooks are
+/// installed or the debugger is stepping.
+pub(crate) fn observing() -> bool {
+    RUNTIME.with(|rt| {
+        let rt = rt.borrow();
+        rt.eval_hooks.is_some() || rt.stop_depth.is_some()
+    })
+}
+
+/// Records the start of evaluating `expr` in `env`, running the `on_enter`
+/// hook and pausing if the debugger is stepping. Fails if the debugger
+/// aborts; the evaluation must still be ended with [`exit_eval`].
+pub(crate) fn enter_eval(expr: &Expr, env: &Env) -> Result<(), EvalError> {
+    let (hook, depth, stepping) = RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        let depth = rt.eval_depth;
+        rt.eval_depth += 1;
+        let hook = rt
+            .eval_hooks
+            .as_ref()
+            .and_then(|hooks| hooks.on_enter.clone());
+        let stepping = rt.stop_depth.is_some_and(|stop| depth <= stop);
+        (hook, depth, stepping.then(|| rt.pause_reason.take()))
+    });
+    // The runtime is not borrowed while the hook runs, so it may evaluate.
+    if let Some(hook) = hook {
+        hook(expr, depth);
+    }
+    match stepping {
+        Some(reason) => pause(reason.unwrap_or(PauseReason::Step), expr, env, depth),
+        None => Ok(()),
+    }
+}
+
+/// Records the end of an evaluation, running the `on_exit` hook.
+pub(crate) fn exit_eval(result: &Result<Expr, EvalError>) {
+    let hook = RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.eval_depth = rt.eval_depth.saturating_sub(1);
+        rt.eval_hooks
+            .as_ref()
+            .and_then(|hooks| hooks.on_exit.clone())
+    });
+    if let Some(hook) = hook {
+        hook(result);
+    }
+}
+
+/// Installs a debugger on the current thread, or removes it, returning the
+/// previous one. Removing it also stops any stepping in progress.
+pub fn set_debugger(debugger: Option<SharedDebugger>) -> Option<SharedDebugger> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        if debugger.is_none() {
+            rt.stop_depth = None;
+            rt.pause_reason = None;
+        }
+        std::mem::replace(&mut rt.debugger, debugger)
+    })
+}
+
+/// Sets or clears a breakpoint on calls to the function named `name`, on
+/// the current thread. Breakpoints only pause while a debugger is installed.
+pub fn set_breakpoint(name: &str, enabled: bool) {
+    RUNTIME.with(|rt| {
+        let breakpoints = &mut rt.borrow_mut().breakpoints;
+        if enabled {
+            breakpoints.insert(name.to_string());
+        } else {
+            breakpoints.remove(name);
+        }
+    });
+}
+
+/// Returns the names of the functions with breakpoints on the current
+/// thread, sorted.
+pub fn breakpoints() -> Vec<String> {
+    let mut names: Vec<String> =
+        RUNTIME.with(|rt| rt.borrow().breakpoints.iter().cloned().collect());
+    names.sort();
+    names
+}
+
+/// Returns whether calls to the function named `name` pause in the
+/// debugger.
+pub(crate) fn has_breakpoint(name: &str) -> bool {
+    RUNTIME.with(|rt| {
+        let rt = rt.borrow();
+        rt.debugger.is_some() && !rt.breakpoints.is_empty() && rt.breakpoints.contains(name)
+    })
+}
+
+/// Arranges to pause in the debugger before the next expression, the body
+/// of the function `name`, which has a breakpoint.
+pub(crate) fn break_on_call(name: String) {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.stop_depth = Some(usize::MAX);
+        rt.pause_reason = Some(PauseReason::Breakpoint(name));
+    });
+}
+
+/// Pauses in the debugger, if one is installed, for `(break)` in `env`.
+pub(crate) fn break_here(env: &Env) -> Result<(), EvalError> {
+    let depth = RUNTIME.with(|rt| rt.borrow().eval_depth);
+    let expr = Expr::List(vec![Expr::Symbol("break".into())].into());
+    pause(PauseReason::Break, &expr, env, depth)
+}
+
+/// Hands a pause to the debugger and sets up stepping as it asks.
+fn pause(reason: PauseReason, expr: &Expr, env: &Env, depth: usize) -> Result<(), EvalError> {
+    let Some(debugger) = RUNTIME.with(|rt| rt.borrow().debugger.clone()) else {
+        return Ok(());
+    };
+    // Code the debugger evaluates while paused runs to completion.
+    let Ok(mut debugger) = debugger.try_borrow_mut() else {
+        return Ok(());
+    };
+    let hooks = RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.stop_depth = None;
+        rt.eval_hooks.take()
+    });
+    let resume = debugger.pause(&Pause {
+        reason,
+        expr,
+        env,
+        depth,
+    });
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.eval_hooks = hooks;
+        rt.stop_depth = match resume {
+            Resume::Step => Some(usize::MAX),
+            Resume::Next => Some(depth),
+            Resume::Finish => depth.checked_sub(1),
+            Resume::Continue | Resume::Abort => None,
+        };
+    });
+    match resume {
+        Resume::Abort => Err(EvalError::Interrupted),
+        _ => Ok(()),
+    }
+}
+
+/// Starts recording the calls made on the current thread, discarding what
+/// was recorded before. See [`profile`](crate::profile).
+pub fn start_profiling() {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.profiler = Some(Profiler::default());
+        rt.last_profile = Profile::default();
+    });
+}
+
+/// Stops recording calls on the current thread, keeping what was recorded
+/// for [`profile`].
+pub fn stop_profiling() {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        if let Some(profiler) = rt.profiler.take() {
+            rt.last_profile = profiler.profile;
+        }
+    });
+}
+
+/// Returns what has been recorded on the current thread since profiling
+/// was last started, whether or not it has been stopped since.
+pub fn profile() -> Profile {
+    RUNTIME.with(|rt| {
+        let rt = rt.borrow();
+        match &rt.profiler {
+            Some(profiler) => profiler.profile.clone(),
+            None => rt.last_profile.clone(),
+        }
+    })
+}
+
+/// Records the start of a call to `name` if profiling is on, returning
+/// whether it did; if so, the call must be ended with [`profile_exit`].
+pub(crate) fn profile_enter(name: &str) -> bool {
+    RUNTIME.with(|rt| match &mut rt.borrow_mut().profiler {
+        Some(profiler) => {
+            profiler.enter(name);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Records the end of the innermost call begun with [`profile_enter`].
+pub(crate) fn profile_exit() {
+    RUNTIME.with(|rt| {
+        if let Some(profiler) = &mut rt.borrow_mut().profiler {
+            profiler.exit();
+        }
+    });
+}
+
+/// Registers a test, replacing any earlier test with the same name.
+pub(crate) fn register_test(name: &str, body: Vec<Expr>) {
+    RUNTIME.with(|rt| {
+        let tests = &mut rt.borrow_mut().tests;
+        match tests.iter_mut().find(|(n, _)| n == name) {
+            Some(test) => test.1 = body,
+            None => tests.push((name.to_string(), body)),
+        }
+    });
+}
+
+/// Returns the registered tests.
+pub(crate) fn tests() -> Vec<(String, Vec<Expr>)> {
+    RUNTIME.with(|rt| rt.borrow().tests.clone())
+}
+
+/// Registers a reader macro for the current thread, replacing any earlier
+/// one with the same prefix.
+///
+/// When the reader meets an atom starting with `prefix`, it passes the rest
+/// of the atom, read as a symbol, number or boolean, to `handler`; if the
+/// prefix stands alone, as in `@(a b)`, it passes the next datum instead.
+/// Whatever `handler` returns is read in place of both, and an error it
+/// returns becomes a parse error. The longest matching prefix wins.
+///
+/// Prefixes must not be empty or contain whitespace, brackets, `;` or `"`.
+pub fn register_reader_macro(
+    prefix: &str,
+    handler: impl Fn(Expr) -> Result<Expr, String> + 'static,
+) -> Result<(), String> {
+    if prefix.is_empty()
+        || prefix
+            .chars()
+            .any(|c| c.is_whitespace() || "()[];\"".contains(c))
+    {
+        return Err(format!("Invalid reader macro prefix '{}'.", prefix));
+    }
+    RUNTIME.with(|rt| {
+        rt.borrow_mut()
+            .reader_macros
+            .insert(prefix.to_string(), Rc::new(handler))
+    });
+    Ok(())
+}
+
+/// Removes the reader macro for `prefix` on the current thread.
+pub fn unregister_reader_macro(prefix: &str) {
+    RUNTIME.with(|rt| rt.borrow_mut().reader_macros.remove(prefix));
+}
+
+/// Finds the reader macro with the longest prefix of `text`, returning the
+/// prefix length and the handler.
+pub(crate) fn reader_macro(text: &str) -> Option<(usize, ReaderMacro)> {
+    RUNTIME.with(|rt| {
+        rt.borrow()
+            .reader_macros
+            .iter()
+            .filter(|(prefix, _)| text.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, handler)| (prefix.len(), handler.clone()))
+    })
+}
+
+/// Sets how deeply expressions may nest in text parsed on the current
+/// thread. Deeper input is a parse error rather than a stack overflow.
+pub fn set_max_parse_depth(depth: usize) {
+    RUNTIME.with(|rt| rt.borrow_mut().max_parse_depth = depth);
+}
+
+/// Returns how deeply expressions may nest in text parsed on the current
+/// thread.
+pub fn max_parse_depth() -> usize {
+    RUNTIME.with(|rt| rt.borrow().max_parse_depth)
+}
+
+/// Sets how deeply function calls may nest on the current thread. Deeper
+/// recursion fails with [`EvalError::RecursionLimit`] rather than using up
+/// memory. Tail calls do not nest, so they are not limited: a loop of tail
+/// calls that never ends, such as `((lambda (f) (f f)) (lambda (f) (f f)))`,
+/// runs until [`set_fuel`], a timeout or an [`InterruptHandle`] stops it, and
+/// forever if none is in force.
+pub fn set_max_call_depth(depth: usize) {
+    RUNTIME.with(|rt| rt.borrow_mut().max_call_depth = depth);
+}
+
+/// Returns how deeply function calls may nest on the current thread.
+pub fn max_call_depth() -> usize {
+    RUNTIME.with(|rt| rt.borrow().max_call_depth)
+}
+
+/// Records the start of a function call, failing if it would nest deeper
+/// than the limit.
+pub(crate) fn enter_call() -> Result<(), EvalError> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        if rt.call_depth >= rt.max_call_depth {
+            return Err(EvalError::RecursionLimit(rt.max_call_depth));
+        }
+        rt.call_depth += 1;
+        Ok(())
+    })
+}
+
+/// Records the end of a function call.
+pub(crate) fn exit_call() {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.call_depth = rt.call_depth.saturating_sub(1);
+    });
+}
+
+/// Records the start of an evaluation, failing if it would nest deeper
+/// than [`MAX_NESTED_EVALUATIONS`].
+pub(crate) fn enter_evaluation() -> Result<(), EvalError> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        if rt.evaluation_depth >= MAX_NESTED_EVALUATIONS {
+            return Err(EvalError::RecursionLimit(MAX_NESTED_EVALUATIONS));
+        }
+        rt.evaluation_depth += 1;
+        Ok(())
+    })
+}
+
+/// Records the end of an evaluation.
+pub(crate) fn exit_evaluation() {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.evaluation_depth = rt.evaluation_depth.saturating_sub(1);
+    });
+}
+
+/// Limits how many evaluation steps code on the current thread may take
+/// before failing with [`EvalError::FuelExhausted`], or lifts the limit
+/// with `None`, the default.
+///
+/// Evaluating an expression and calling a function each take a step. The
+/// budget is shared by all evaluations until it is set again, so hosts
+/// running untrusted code typically set it before each evaluation.
+pub fn set_fuel(fuel: Option<u64>) {
+    RUNTIME.with(|rt| rt.borrow_mut().fuel = fuel);
+}
+
+/// Returns how many evaluation steps remain on the current thread, or
+/// `None` if they are not limited.
+pub fn fuel() -> Option<u64> {
+    RUNTIME.with(|rt| rt.borrow().fuel)
+}
+
+/// How many steps are taken between checks of the deadline, since reading
+/// the clock costs more than a step.
+const STEPS_PER_DEADLINE_CHECK: u32 = 256;
+
+/// Records an evaluation step, failing if it would exceed the fuel budget
+/// or the deadline has passed.
+pub(crate) fn take_step() -> Result<(), EvalError> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        match &mut rt.fuel {
+            Some(0) => return Err(EvalError::FuelExhausted),
+            Some(fuel) => *fuel -= 1,
+            None => {}
+        }
+        if let Some(deadline) = rt.deadline {
+            rt.steps_since_check += 1;
+            if rt.steps_since_check >= STEPS_PER_DEADLINE_CHECK {
+                rt.steps_since_check = 0;
+                if Instant::now() >= deadline {
+                    return Err(EvalError::Timeout);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Returns when evaluation on the current thread must stop, if it must.
+pub(crate) fn deadline() -> Option<Instant> {
+    RUNTIME.with(|rt| rt.borrow().deadline)
+}
+
+/// Sets when evaluation on the current thread must stop, returning the
+/// deadline it replaces.
+pub(crate) fn set_deadline(deadline: Option<Instant>) -> Option<Instant> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.steps_since_check = 0;
+        std::mem::replace(&mut rt.deadline, deadline)
+    })
+}
+
+/// Limits how many bytes of values builtins on the current thread may
+/// construct before failing with [`EvalError::MemoryLimit`], or lifts the
+/// limit with `None`, the default. This also resets the count.
+///
+/// The size of each value a builtin returns is estimated and added up,
+/// whether or not the value is still in use later, so this bounds the
+/// memory an evaluation can take rather than measuring what it holds.
+/// Only what the builtin allocated counts: a list or string it shares with
+/// its arguments counts as one handle. Builtins that may build a large value
+/// from small arguments, such as `concat` or `format`, check its size before
+/// building it. Like [`set_fuel`], it is typically set before each
+/// evaluation of untrusted code.
+pub fn set_memory_limit(limit: Option<usize>) {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        rt.memory_limit = limit;
+        rt.memory_used = 0;
+    });
+}
+
+/// Returns how many bytes of values builtins on the current thread have
+/// constructed since the memory limit was set, while one is set.
+pub fn memory_used() -> usize {
+    RUNTIME.with(|rt| rt.borrow().memory_used)
+}
+
+/// Fails if allocating `bytes` more would exceed the memory limit, without
+/// counting them. Builtins call this before building a value whose size they
+/// know in advance, which is counted once it is returned.
+pub(crate) fn check_allocation(bytes: usize) -> Result<(), EvalError> {
+    RUNTIME.with(|rt| {
+        let rt = rt.borrow();
+        match rt.memory_limit {
+            Some(limit) if rt.memory_used.saturating_add(bytes) > limit => {
+                Err(EvalError::MemoryLimit(limit))
+            }
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Counts `value`, just returned by a builtin, against the memory limit.
+///
+/// A value that would exceed the limit is not counted, since it is
+/// discarded, so that code catching the error can still run.
+pub(crate) fn track_allocation(value: &Expr) -> Result<(), EvalError> {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        let Some(limit) = rt.memory_limit else {
+            return Ok(());
+        };
+        let used = rt.memory_used.saturating_add(approximate_size(value));
+        if used > limit {
+            return Err(EvalError::MemoryLimit(limit));
+        }
+        rt.memory_used = used;
+        Ok(())
+    })
+}
+
+/// Estimates how many new bytes `value` takes. Lists and strings that are
+/// also held elsewhere count only their handle, as they were allocated
+/// before, and so do symbols, functions and `memoize` caches.
+fn approximate_size(value: &Expr) -> usize {
+    let own = match value {
+        Expr::String(s) if Shared::strong_count(s) == 1 => s.len(),
+        Expr::List(items) if Shared::strong_count(items) == 1 => {
+            items.iter().map(approximate_size).sum()
+        }
+        Expr::Map(map) => map
+            .iter()
+            .map(|(k, v)| size_of::<String>() + k.len() + approximate_size(v))
+            .sum(),
+        Expr::Condition {
+            message,
+            kind,
+            data,
+        } => kind.len() + message.len() + approximate_size(data),
+        Expr::Symbol(_)
+        | Expr::String(_)
+        | Expr::List(_)
+        | Expr::Number(_)
+        | Expr::Bool(_)
+        | Expr::Func { .. }
+        | Expr::NativeFn(_)
+        | Expr::Memoized { .. } => 0,
+    };
+    size_of::<Expr>() + own
+}
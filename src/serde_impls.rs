@@ -0,0 +1,94 @@
+//! `Serialize` and `Deserialize` for [`Expr`], enabled by the `serde`
+//! feature.
+//!
+//! Each value is written as a variant named after its `Expr` variant, such
+//! as `{"Symbol": "x"}` in JSON, so that symbols and strings stay apart and
+//! every data value reads back as it was written. Functions cannot be
+//! serialized, since their code and environment are not data.
+
+use crate::data::Expr;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Error, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// The serialized form of an [`Expr`], borrowing from it.
+#[derive(serde::Serialize)]
+#[serde(rename = "Expr")]
+enum ExprRef<'a> {
+    Symbol(&'a str),
+    Number(f64),
+    Bool(bool),
+    String(&'a str),
+    List(&'a [Expr]),
+    Map(&'a BTreeMap<String, Expr>),
+    Condition {
+        kind: &'a str,
+        message: &'a str,
+        data: &'a Expr,
+    },
+}
+
+/// The serialized form of an [`Expr`], as read back.
+#[derive(serde::Deserialize)]
+#[serde(rename = "Expr")]
+enum ExprData {
+    Symbol(String),
+    Number(f64),
+    Bool(bool),
+    String(String),
+    List(Vec<Expr>),
+    Map(BTreeMap<String, Expr>),
+    Condition {
+        kind: String,
+        message: String,
+        data: Box<Expr>,
+    },
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let data = match self {
+            Expr::Symbol(s) => ExprRef::Symbol(s),
+            Expr::Number(n) => ExprRef::Number(*n),
+            Expr::Bool(b) => ExprRef::Bool(*b),
+            Expr::String(s) => ExprRef::String(s),
+            Expr::List(items) => ExprRef::List(items),
+            Expr::Map(map) => ExprRef::Map(map),
+            Expr::Condition {
+                kind,
+                message,
+                data,
+            } => ExprRef::Condition {
+                kind,
+                message,
+                data,
+            },
+            Expr::Func { .. } | Expr::NativeFn(_) | Expr::Memoized { .. } => {
+                return Err(S::Error::custom("functions cannot be serialized"));
+            }
+        };
+        data.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match ExprData::deserialize(deserializer)? {
+            ExprData::Symbol(s) => Expr::Symbol(s.into()),
+            ExprData::Number(n) => Expr::Number(n),
+            ExprData::Bool(b) => Expr::Bool(b),
+            ExprData::String(s) => Expr::String(s.into()),
+            ExprData::List(items) => Expr::List(items.into()),
+            ExprData::Map(map) => Expr::Map(map),
+            ExprData::Condition {
+                kind,
+                message,
+                data,
+            } => Expr::Condition {
+                kind,
+                message,
+                data,
+            },
+        })
+    }
+}
@@ -0,0 +1,116 @@
+//! The pointer and lock types that values are built from.
+//!
+//! By default values share their parts through [`Rc`](std::rc::Rc) and
+//! guard what changes in place with [`RefCell`](std::cell::RefCell), which
+//! is cheapest when everything stays on one thread. With the `sync` feature
+//! they use [`Arc`](std::sync::Arc) and [`RwLock`](std::sync::RwLock)
+//! instead, so that [`Expr`] and [`Env`](crate::Env) are
+//! `Send` and `Sync`: results can be handed to other threads, and an
+//! environment can be moved to a worker thread and evaluated in there.
+//!
+//! Runtime settings such as the builtins and the output sink still belong
+//! to each thread, as described in [`runtime`](crate::runtime), so a worker
+//! thread evaluates with its own.
+
+use crate::data::Expr;
+use crate::error::EvalError;
+
+/// The reference-counted pointer values share their parts through:
+/// [`Rc`](std::rc::Rc), or [`Arc`](std::sync::Arc) with the `sync` feature.
+#[cfg(not(feature = "sync"))]
+pub use std::rc::Rc as Shared;
+/// The reference-counted pointer values share their parts through:
+/// [`Rc`](std::rc::Rc), or [`Arc`](std::sync::Arc) with the `sync` feature.
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Shared;
+
+/// A weak handle on a [`Shared`] value.
+#[cfg(not(feature = "sync"))]
+pub(crate) use std::rc::Weak;
+/// A weak handle on a [`Shared`] value.
+#[cfg(feature = "sync")]
+pub(crate) use std::sync::Weak;
+
+/// The cell guarding the parts of values that change in place, such as the
+/// variables of an environment: [`RefCell`](std::cell::RefCell), or a
+/// [`RwLock`](std::sync::RwLock) with the same methods with the `sync`
+/// feature.
+#[cfg(not(feature = "sync"))]
+pub use std::cell::RefCell as Lock;
+
+/// The cell guarding the parts of values that change in place, such as the
+/// variables of an environment: [`RefCell`](std::cell::RefCell), or a
+/// [`RwLock`](std::sync::RwLock) with the same methods with the `sync`
+/// feature.
+#[cfg(feature = "sync")]
+#[derive(Debug, Default)]
+pub struct Lock<T: ?Sized>(std::sync::RwLock<T>);
+
+#[cfg(feature = "sync")]
+impl<T> Lock<T> {
+    /// Creates a lock holding `value`.
+    pub fn new(value: T) -> Self {
+        Lock(std::sync::RwLock::new(value))
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: ?Sized> Lock<T> {
+    /// Locks the value for reading, waiting for any writer to finish.
+    ///
+    /// A lock whose writer panicked is still usable, as the values kept in
+    /// one are left whole between changes.
+    pub fn borrow(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        self.0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Locks the value for reading if no writer holds it, as
+    /// [`RefCell::try_borrow`](std::cell::RefCell::try_borrow) does.
+    #[allow(clippy::type_complexity)]
+    pub fn try_borrow(
+        &self,
+    ) -> Result<
+        std::sync::RwLockReadGuard<'_, T>,
+        std::sync::TryLockError<std::sync::RwLockReadGuard<'_, T>>,
+    > {
+        match self.0.try_read() {
+            Err(std::sync::TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+            result => result,
+        }
+    }
+
+    /// Locks the value for writing, waiting for other readers and writers
+    /// to finish.
+    pub fn borrow_mut(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        self.0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: PartialEq + ?Sized> PartialEq for Lock<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other) || *self.borrow() == *other.borrow()
+    }
+}
+
+/// A Rust function that [`NativeFn`](crate::NativeFn) can wrap: any
+/// `Fn(&[Expr]) -> Result<Expr, EvalError>`, which must also be `Send` and
+/// `Sync` with the `sync` feature.
+#[cfg(not(feature = "sync"))]
+pub trait NativeFunction: Fn(&[Expr]) -> Result<Expr, EvalError> + 'static {}
+
+#[cfg(not(feature = "sync"))]
+impl<F: Fn(&[Expr]) -> Result<Expr, EvalError> + 'static> NativeFunction for F {}
+
+/// A Rust function that [`NativeFn`](crate::NativeFn) can wrap: any
+/// `Fn(&[Expr]) -> Result<Expr, EvalError>`, which must also be `Send` and
+/// `Sync` with the `sync` feature.
+#[cfg(feature = "sync")]
+pub trait NativeFunction: Fn(&[Expr]) -> Result<Expr, EvalError> + Send + Sync + 'static {}
+
+#[cfg(feature = "sync")]
+impl<F: Fn(&[Expr]) -> Result<Expr, EvalError> + Send + Sync + 'static> NativeFunction for F {}
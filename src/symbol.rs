@@ -0,0 +1,189 @@
+//! Interned symbols.
+//!
+//! Each distinct name is stored once per thread, or once per process with
+//! the `sync` feature so that symbols can move between threads, and symbols
+//! compare and hash by address instead of by their text. The parser interns
+//! the symbols it reads, and the evaluator looks variables up by symbol, so
+//! evaluating a variable does not hash its name again.
+//!
+//! Names that are no longer used by any symbol are dropped from the table
+//! whenever it has doubled in size, so code making up new names, as with
+//! `string->symbol` on untrusted input, does not grow it without end.
+
+use crate::shared::Shared;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// How many names the table holds before it is first pruned.
+const MIN_THRESHOLD: usize = 1024;
+
+/// The interned names, some of which may no longer be used.
+struct Symbols {
+    names: HashSet<Shared<str>>,
+    /// How many names may be held before the unused ones are dropped.
+    threshold: usize,
+}
+
+impl Symbols {
+    fn new() -> Self {
+        Symbols {
+            names: HashSet::new(),
+            threshold: MIN_THRESHOLD,
+        }
+    }
+
+    /// Adds `name`, first dropping the names only the table holds if it is
+    /// full.
+    fn insert(&mut self, name: Shared<str>) {
+        if self.names.len() >= self.threshold {
+            self.names.retain(|name| Shared::strong_count(name) > 1);
+            self.threshold = MIN_THRESHOLD.max(2 * self.names.len());
+        }
+        self.names.insert(name);
+    }
+}
+
+#[cfg(not(feature = "sync"))]
+thread_local! {
+    /// Every symbol interned on this thread.
+    static SYMBOLS: std::cell::RefCell<Symbols> = std::cell::RefCell::new(Symbols::new());
+}
+
+/// Runs `f` on the symbols interned on this thread.
+#[cfg(not(feature = "sync"))]
+fn with_symbols<T>(f: impl FnOnce(&mut Symbols) -> T) -> T {
+    SYMBOLS.with(|symbols| f(&mut symbols.borrow_mut()))
+}
+
+/// Every symbol interned by any thread.
+#[cfg(feature = "sync")]
+static SYMBOLS: std::sync::Mutex<Option<Symbols>> = std::sync::Mutex::new(None);
+
+/// Runs `f` on the symbols interned by any thread.
+#[cfg(feature = "sync")]
+fn with_symbols<T>(f: impl FnOnce(&mut Symbols) -> T) -> T {
+    let mut symbols = SYMBOLS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    f(symbols.get_or_insert_with(Symbols::new))
+}
+
+/// Returns how many names are interned on this thread, or by any thread with
+/// the `sync` feature, including unused ones not yet dropped.
+pub fn interned() -> usize {
+    with_symbols(|symbols| symbols.names.len())
+}
+
+/// An interned name, as held by [`Expr::Symbol`](crate::Expr::Symbol) and
+/// used as the key of an [`Env`](crate::Env).
+///
+/// Two symbols with the same name made on the same thread, or anywhere with
+/// the `sync` feature, are the same symbol, so comparing and hashing them is
+/// as cheap as for a pointer. Symbols dereference to their name and order by
+/// it.
+#[derive(Clone)]
+pub struct Symbol(Shared<str>);
+
+impl Symbol {
+    /// Returns the symbol named `name`, interning it if it is new.
+    pub fn new(name: &str) -> Self {
+        with_symbols(|symbols| match symbols.names.get(name) {
+            Some(symbol) => Symbol(symbol.clone()),
+            None => {
+                let symbol: Shared<str> = Shared::from(name);
+                symbols.insert(symbol.clone());
+                Symbol(symbol)
+            }
+        })
+    }
+
+    /// Returns the name.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::new(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Shared::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Shared::as_ptr(&self.0).cast::<u8>().hash(state);
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
@@ -1,10 +1,9 @@
-use minilisp_rust::{Env, Expr, eval, parse};
-use std::collections::HashMap;
+use minilisp_rust::{Env, Expr, default_env, eval, parse};
 
 // --- Helper functions for tests ---
 
 fn run_eval_test(input: &str, expected: Result<Expr, String>) {
-    let mut env: Env = HashMap::new();
+    let mut env = Env::new();
     let parsed = parse(input);
     assert!(
         parsed.is_ok(),
@@ -13,12 +12,17 @@ fn run_eval_test(input: &str, expected: Result<Expr, String>) {
         parsed.err().unwrap()
     );
     let result = eval(&parsed.unwrap(), &mut env);
-    assert_eq!(result, expected);
+    assert_eq!(result.map_err(|e| e.to_string()), expected);
+}
+
+/// Reads the expected value of a test from Lisp syntax.
+fn datum(input: &str) -> Result<Expr, String> {
+    Ok(parse(input).unwrap())
 }
 
 /// Helper to test for specific evaluation errors.
 fn run_eval_error_test(input: &str, expected_error: &str) {
-    let mut env: Env = HashMap::new();
+    let mut env = Env::new();
     let parsed = parse(input).unwrap();
     let result = eval(&parsed, &mut env);
     assert!(
@@ -26,7 +30,7 @@ fn run_eval_error_test(input: &str, expected_error: &str) {
         "Expected an error for '{}', but got Ok.",
         input
     );
-    assert_eq!(result.err().unwrap(), expected_error);
+    assert_eq!(result.err().unwrap().to_string(), expected_error);
 }
 
 /// Helper to test for specific parsing errors.
@@ -37,7 +41,7 @@ fn run_parse_error_test(input: &str, expected_error: &str) {
         "Expected a parse error for '{}', but got Ok.",
         input
     );
-    assert_eq!(result.err().unwrap(), expected_error);
+    assert_eq!(result.err().unwrap().to_string(), expected_error);
 }
 
 // --- Success Tests ---
@@ -54,14 +58,14 @@ fn test_eval_nested_expression() {
 
 #[test]
 fn test_define_variable() {
-    let mut env: Env = HashMap::new();
+    let mut env = Env::new();
     eval(&parse("(define x 3)").unwrap(), &mut env).unwrap();
-    assert_eq!(env.get("x"), Some(&Expr::Number(3.0)));
+    assert_eq!(env.get("x"), Some(Expr::Number(3.0)));
 }
 
 #[test]
 fn test_use_defined_variable() {
-    let mut env: Env = HashMap::new();
+    let mut env = Env::new();
     eval(&parse("(define x 5)").unwrap(), &mut env).unwrap();
     let result = eval(&parse("(+ x 2)").unwrap(), &mut env);
     assert_eq!(result, Ok(Expr::Number(7.0)));
@@ -74,7 +78,7 @@ fn test_lambda_application() {
 
 #[test]
 fn test_define_lambda_and_apply() {
-    let mut env: Env = HashMap::new();
+    let mut env = Env::new();
     eval(
         &parse("(define square (lambda (x) (* x x)))").unwrap(),
         &mut env,
@@ -84,40 +88,73 @@ fn test_define_lambda_and_apply() {
     assert_eq!(result, Ok(Expr::Number(9.0)));
 }
 
+#[test]
+fn test_lambdas_close_over_their_scope() {
+    let mut env = Env::new();
+    for form in [
+        "(define make-adder (lambda (n) (lambda (x) (+ x n))))",
+        "(define add2 (make-adder 2))",
+        "(define n 100)",
+        // Later global definitions are visible, so functions can refer to
+        // each other in either order.
+        "(define even? (lambda (k) (if (= k 0) true (odd? (- k 1)))))",
+        "(define odd? (lambda (k) (if (= k 0) false (even? (- k 1)))))",
+        "(define peek (lambda () y))",
+        "(define f (lambda (y) (peek)))",
+        "(define g (lambda (x) (define local x)))",
+    ] {
+        eval(&parse(form).unwrap(), &mut env).unwrap();
+    }
+    let mut run = |input: &str| eval(&parse(input).unwrap(), &mut env).map_err(|e| e.to_string());
+    assert_eq!(run("(add2 5)"), Ok(Expr::Number(7.0)));
+    assert_eq!(run("((make-adder 10) 1)"), Ok(Expr::Number(11.0)));
+    assert_eq!(run("(even? 10)"), Ok(Expr::Bool(true)));
+    // Scoping is lexical: a function does not see its caller's variables,
+    // and definitions in its body stay local to the call.
+    assert_eq!(run("(f 1)"), Err("Variable 'y' not found.".to_string()));
+    assert_eq!(run("(g 1)"), Ok(Expr::Symbol("local".into())));
+    assert_eq!(env.get("local"), None);
+
+    let mut inner = env.child();
+    inner.insert("n".to_string(), Expr::Number(1.0));
+    assert_eq!(inner.get("n"), Some(Expr::Number(1.0)));
+    assert_eq!(env.get("n"), Some(Expr::Number(100.0)));
+    assert!(inner.set("add2", Expr::Bool(false)));
+    assert_eq!(env.get("add2"), Some(Expr::Bool(false)));
+    assert!(!inner.set("missing", Expr::Bool(false)));
+}
+
 #[test]
 fn test_if_true() {
     run_eval_test(
         "(if (> 5 2) \"yes\" \"no\")",
-        Ok(Expr::String("yes".to_string())),
+        Ok(Expr::String("yes".into())),
     );
 }
 
 #[test]
 fn test_if_false() {
-    run_eval_test(
-        "(if (> 2 5) \"yes\" \"no\")",
-        Ok(Expr::String("no".to_string())),
-    );
+    run_eval_test("(if (> 2 5) \"yes\" \"no\")", Ok(Expr::String("no".into())));
 }
 
 #[test]
 fn test_parse_string() {
     assert_eq!(
         parse("\"hello world\"").unwrap(),
-        Expr::String("hello world".to_string())
+        Expr::String("hello world".into())
     );
 }
 
 #[test]
 fn test_parse_empty_string() {
-    assert_eq!(parse("\"\"").unwrap(), Expr::String("".to_string()));
+    assert_eq!(parse("\"\"").unwrap(), Expr::String("".into()));
 }
 
 #[test]
 fn test_eval_concat() {
     run_eval_test(
         "(concat \"hello\" \" \" \"world\")",
-        Ok(Expr::String("hello world".to_string())),
+        Ok(Expr::String("hello world".into())),
     );
 }
 
@@ -125,7 +162,7 @@ fn test_eval_concat() {
 fn test_string_in_if() {
     run_eval_test(
         "(if (> 1 0) \"greater\" \"smaller\")",
-        Ok(Expr::String("greater".to_string())),
+        Ok(Expr::String("greater".into())),
     );
 }
 
@@ -148,15 +185,3241 @@ fn test_eval_invalid_define_syntax() {
 
 #[test]
 fn test_parse_unclosed_parenthesis() {
-    run_parse_error_test("(+ 1 2", "Missing closing parenthesis.");
+    run_parse_error_test(
+        "(+ 1 2",
+        "Parse error at 1:1: Missing closing parenthesis.\n(+ 1 2\n^",
+    );
 }
 
 #[test]
 fn test_parse_unexpected_closing_parenthesis() {
-    run_parse_error_test(")", "Unexpected closing parenthesis.");
+    run_parse_error_test(
+        ")",
+        "Parse error at 1:1: Unexpected closing parenthesis.\n)\n^",
+    );
 }
 
 #[test]
 fn test_parse_extra_tokens() {
-    run_parse_error_test("(+ 1 2) 3", "Unexpected tokens after main expression.");
+    run_parse_error_test(
+        "(+ 1 2) 3",
+        "Parse error at 1:9: Unexpected tokens after main expression.\n(+ 1 2) 3\n        ^",
+    );
+}
+
+// --- Reader Tests ---
+
+#[test]
+fn test_parse_line_comments() {
+    run_eval_test(
+        "; add two numbers\n(+ 1 ; the first\n 2) ; trailing",
+        Ok(Expr::Number(3.0)),
+    );
+    assert_eq!(parse("(a;comment\nb)"), parse("(a b)"));
+    run_eval_test("(string-length \"a;b\")", Ok(Expr::Number(3.0)));
+}
+
+#[test]
+fn test_parse_nested_block_comments() {
+    run_eval_test(
+        "(+ 1 #| disabled #| nested (+ 100) |# still disabled |# 2)",
+        Ok(Expr::Number(3.0)),
+    );
+    assert_eq!(parse("#|\n(a b)\n|#(c)"), parse("(c)"));
+    // A `#` that does not start a comment is still part of a symbol.
+    assert_eq!(parse("a#"), Ok(Expr::Symbol("a#".into())));
+}
+
+#[test]
+fn test_parse_datum_comments() {
+    run_eval_test("(+ 1 #;(* 100 100) 2)", Ok(Expr::Number(3.0)));
+    assert_eq!(parse("(a #;b)"), parse("(a)"));
+    assert_eq!(parse("#; #; a b (c)"), parse("(c)"));
+    assert_eq!(parse("(c) #;(d)"), parse("(c)"));
+    run_parse_error_test(
+        "(a #;)",
+        "Parse error at 1:6: Unexpected closing parenthesis.\n(a #;)\n     ^",
+    );
+}
+
+#[test]
+fn test_parse_string_escapes() {
+    assert_eq!(
+        parse(r#""say \"hi\"\n\tback\\slash""#),
+        Ok(Expr::String("say \"hi\"\n\tback\\slash".to_string().into()))
+    );
+    run_eval_test(r#"(string-length "\"\\")"#, Ok(Expr::Number(2.0)));
+}
+
+#[test]
+fn test_display_reescapes_strings() {
+    let s = Expr::String("a \"quoted\"\nline\\".to_string().into());
+    assert_eq!(s.to_string(), r#""a \"quoted\"\nline\\""#);
+    assert_eq!(parse(&s.to_string()), Ok(s));
+}
+
+#[test]
+fn test_display_round_trips_through_parse() {
+    let sym = |s: &str| Expr::Symbol(s.into());
+    let atoms = vec![
+        sym("x"),
+        sym("->x"),
+        sym("..."),
+        sym("λ"),
+        sym(""),
+        sym("a b"),
+        sym("1+"),
+        sym("42"),
+        sym("true"),
+        sym("#x"),
+        sym("a|b\\c"),
+        sym("(\"\n\u{7}\u{a0})"),
+        Expr::Number(-0.0),
+        Expr::Number(1e300),
+        Expr::Number(f64::NAN),
+        Expr::Number(f64::NEG_INFINITY),
+        Expr::Bool(false),
+        Expr::String("|\"\\\r\u{0}".to_string().into()),
+        Expr::empty_list(),
+    ];
+    let mut values = atoms.clone();
+    values.push(Expr::List(
+        vec![
+            Expr::List(atoms.clone().into()),
+            Expr::List(vec![Expr::List(atoms.into())].into()),
+        ]
+        .into(),
+    ));
+    for value in values {
+        let written = value.to_string();
+        let read = parse(&written).unwrap_or_else(|e| panic!("{}: {}", written, e));
+        assert!(read.is_equal(&value), "{} read back as {:?}", written, read);
+    }
+
+    assert_eq!(sym("a b").to_string(), "|a b|");
+    assert_eq!(sym("a|b").to_string(), r"|a\|b|");
+    assert_eq!(sym("").to_string(), "||");
+    assert_eq!(sym("a b").to_display_string(), "a b");
+}
+
+#[test]
+fn test_parse_unicode_escapes() {
+    assert_eq!(
+        parse(r#""\u{1F600} \u{e9}""#),
+        Ok(Expr::String("😀 é".to_string().into()))
+    );
+    // Malformed escapes, such as surrogates, are kept as written.
+    assert_eq!(
+        parse(r#""\u{D800}""#),
+        Ok(Expr::String("\\u{D800}".to_string().into()))
+    );
+    let bell = Expr::String("\u{7}".to_string().into());
+    assert_eq!(bell.to_string(), r#""\u{7}""#);
+    assert_eq!(parse(&bell.to_string()), Ok(bell));
+}
+
+#[test]
+fn test_parse_unterminated_literals() {
+    run_parse_error_test(
+        "(display \"oops)",
+        "Parse error at 1:10: Unterminated string literal.\n(display \"oops)\n         ^",
+    );
+    run_parse_error_test(
+        "(list 1\n   \"a\\\" b",
+        "Parse error at 2:4: Unterminated string literal.\n   \"a\\\" b\n   ^",
+    );
+    run_parse_error_test(
+        "(+ 1 #| #| |# 2)",
+        "Parse error at 1:6: Unterminated block comment.\n(+ 1 #| #| |# 2)\n     ^",
+    );
+}
+
+#[test]
+fn test_parse_program_reads_every_form() {
+    use minilisp_rust::parse_program;
+
+    assert_eq!(
+        parse_program("(define x 1) #;(skipped) x ; done\n\"s\""),
+        Ok(vec![
+            parse("(define x 1)").unwrap(),
+            Expr::Symbol("x".into()),
+            Expr::String("s".into()),
+        ])
+    );
+    assert_eq!(parse_program("  ; nothing\n"), Ok(vec![]));
+    assert_eq!(
+        parse_program("(a) (b").map_err(|e| e.to_string()),
+        Err("Parse error at 1:5: Missing closing parenthesis.\n(a) (b\n    ^".to_string())
+    );
+}
+
+#[test]
+fn test_parse_square_brackets() {
+    assert_eq!(parse("[a (b [c])]"), parse("(a (b (c)))"));
+    run_eval_test("[+ 1 [* 2 3]]", Ok(Expr::Number(7.0)));
+    run_parse_error_test(
+        "(a b]",
+        "Parse error at 1:5: Expected ')' but found ']'.\n(a b]\n    ^",
+    );
+    run_parse_error_test(
+        "[a (b]",
+        "Parse error at 1:6: Expected ')' but found ']'.\n[a (b]\n     ^",
+    );
+    run_parse_error_test(
+        "[a b",
+        "Parse error at 1:1: Missing closing bracket.\n[a b\n^",
+    );
+    run_parse_error_test("]", "Parse error at 1:1: Unexpected closing bracket.\n]\n^");
+}
+
+#[test]
+fn test_parse_radix_literals() {
+    run_eval_test(
+        "(+ 0xFF 0o755 0b1010)",
+        Ok(Expr::Number(255.0 + 493.0 + 10.0)),
+    );
+    assert_eq!(parse("-0x10"), Ok(Expr::Number(-16.0)));
+    // Invalid digits make the literal an error rather than a symbol.
+    run_parse_error_test(
+        "0b102",
+        "Parse error at 1:1: Invalid number '0b102'.\n0b102\n^",
+    );
+}
+
+#[test]
+fn test_parse_scientific_and_special_floats() {
+    assert_eq!(parse("1e-3"), Ok(Expr::Number(0.001)));
+    assert_eq!(parse("6.02e23"), Ok(Expr::Number(6.02e23)));
+    assert_eq!(parse("+inf.0"), Ok(Expr::Number(f64::INFINITY)));
+    assert_eq!(parse("-inf.0"), Ok(Expr::Number(f64::NEG_INFINITY)));
+    run_eval_test("(= nan.0 nan.0)", Ok(Expr::Bool(false)));
+    assert_eq!(parse("inf"), Ok(Expr::Symbol("inf".into())));
+    for n in [
+        6.02e23,
+        1e-10,
+        -1.5e300,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        0.1,
+        -0.0,
+    ] {
+        let written = Expr::Number(n).to_string();
+        assert_eq!(parse(&written), Ok(Expr::Number(n)), "{}", written);
+    }
+    assert_eq!(Expr::Number(6.02e23).to_string(), "6.02e23");
+    assert_eq!(Expr::Number(f64::NAN).to_string(), "nan.0");
+}
+
+#[test]
+fn test_parse_digit_separators() {
+    assert_eq!(parse("1_000_000"), Ok(Expr::Number(1_000_000.0)));
+    assert_eq!(parse("0xFF_FF"), Ok(Expr::Number(65535.0)));
+    assert_eq!(parse("1_000.000_1"), Ok(Expr::Number(1000.0001)));
+    for symbol in ["_1", "a_b"] {
+        assert_eq!(parse(symbol), Ok(Expr::Symbol(symbol.into())));
+    }
+    for invalid in ["1_", "1__0"] {
+        assert!(parse(invalid).is_err(), "{}", invalid);
+    }
+    run_eval_test("(string->number \"1_000\")", Ok(Expr::Bool(false)));
+}
+
+#[test]
+fn test_parse_spanned_records_locations() {
+    use minilisp_rust::parser::{Node, Position, parse_spanned};
+
+    let spanned = parse_spanned("(list 1\n  \"é\" [x])").unwrap();
+    assert_eq!(spanned.to_expr(), parse("(list 1 \"é\" (x))").unwrap());
+    assert_eq!(
+        spanned.span.start,
+        Position {
+            offset: 0,
+            line: 1,
+            column: 1
+        }
+    );
+    assert_eq!(
+        spanned.span.end,
+        Position {
+            offset: 19,
+            line: 2,
+            column: 11
+        }
+    );
+    let Node::List(items) = &spanned.node else {
+        panic!("expected a list");
+    };
+    let string = &items[2].span;
+    assert_eq!((string.start.line, string.start.column), (2, 3));
+    assert_eq!(
+        (string.end.offset - string.start.offset, string.end.column),
+        (4, 6)
+    );
+    assert_eq!(
+        (items[3].span.start.column, items[3].span.end.column),
+        (7, 10)
+    );
+}
+
+#[test]
+fn test_parse_error_points_into_source() {
+    use minilisp_rust::parse_program;
+
+    let source = "(define x 1)\n\n(define (foo x)\n\t(+ x 1)";
+    assert_eq!(
+        parse_program(source).map_err(|e| e.to_string()),
+        Err("Parse error at 3:1: Missing closing parenthesis.\n(define (foo x)\n^".to_string())
+    );
+    assert_eq!(
+        parse_program("(list\n\t1 2))").map_err(|e| e.to_string()),
+        Err("Parse error at 2:6: Unexpected closing parenthesis.\n\t1 2))\n\t    ^".to_string())
+    );
+    run_parse_error_test("", "Parse error at 1:1: Unexpected EOF\n\n^");
+}
+
+#[test]
+fn test_parse_incremental_detects_unfinished_input() {
+    use minilisp_rust::parser::{ParseOutcome, parse_incremental};
+
+    for unfinished in [
+        "(define (f x)",
+        "[1 2",
+        "(display \"abc",
+        "#| comment",
+        "(a) #;",
+        "",
+    ] {
+        let expected = if unfinished.is_empty() {
+            ParseOutcome::Complete(vec![])
+        } else {
+            ParseOutcome::Incomplete
+        };
+        assert_eq!(parse_incremental(unfinished), expected, "{}", unfinished);
+    }
+    assert_eq!(
+        parse_incremental("(+ 1\n 2)"),
+        ParseOutcome::Complete(vec![parse("(+ 1 2)").unwrap()])
+    );
+    assert!(matches!(parse_incremental("(a ]"), ParseOutcome::Error(_)));
+    assert!(matches!(parse_incremental(") (a"), ParseOutcome::Error(_)));
+}
+
+#[test]
+fn test_public_tokenizer() {
+    use minilisp_rust::parser::{TokenKind, tokens};
+
+    let input = "(say \"a b\") ; hi\n#;[x]";
+    let found: Vec<(TokenKind, &str, usize)> = tokens(input)
+        .map(|t| t.unwrap())
+        .map(|t| (t.kind, t.text, t.span.start.offset))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (TokenKind::Open, "(", 0),
+            (TokenKind::Atom, "say", 1),
+            (TokenKind::String, "\"a b\"", 5),
+            (TokenKind::Close, ")", 10),
+            (TokenKind::Comment, "; hi", 12),
+            (TokenKind::DatumComment, "#;", 17),
+            (TokenKind::Open, "[", 19),
+            (TokenKind::Atom, "x", 20),
+            (TokenKind::Close, "]", 21),
+        ]
+    );
+    // Each text is a slice of the input rather than a copy.
+    let first = tokens(input).next().unwrap().unwrap();
+    assert!(std::ptr::eq(first.text.as_ptr(), input.as_ptr()));
+
+    let results: Vec<_> = tokens("a \"b").collect();
+    assert_eq!(results.len(), 2);
+    let error = results[1].clone().unwrap_err();
+    assert_eq!(error.to_string(), "1:3: Unterminated string literal.");
+}
+
+#[test]
+fn test_reader_macros() {
+    use minilisp_rust::runtime::{register_reader_macro, unregister_reader_macro};
+
+    register_reader_macro("@", |datum| {
+        Ok(Expr::List(vec![Expr::Symbol("deref".into()), datum].into()))
+    })
+    .unwrap();
+    register_reader_macro("#upper", |datum| match datum {
+        Expr::String(s) => Ok(Expr::String(s.to_uppercase().into())),
+        _ => Err("#upper requires a string.".to_string()),
+    })
+    .unwrap();
+    assert_eq!(
+        parse("(f @x @ (g) @7)"),
+        parse("(f (deref x) (deref (g)) (deref 7))")
+    );
+    assert_eq!(parse("#upper\"abc\""), Ok(Expr::String("ABC".into())));
+    run_parse_error_test(
+        "#upper 1",
+        "Parse error at 1:1: #upper requires a string.\n#upper 1\n^",
+    );
+    assert!(register_reader_macro("a b", Ok).is_err());
+    // Symbols that would read as a reader macro are written with pipes.
+    assert_eq!(Expr::Symbol("@x".into()).to_string(), "|@x|");
+    unregister_reader_macro("@");
+    assert_eq!(parse("@x"), Ok(Expr::Symbol("@x".into())));
+    assert_eq!(Expr::Symbol("@x".into()).to_string(), "@x");
+}
+
+#[test]
+fn test_parse_raw_strings() {
+    assert_eq!(
+        parse(r##"#"C:\path\n "quoted""#"##),
+        Ok(Expr::String(r#"C:\path\n "quoted""#.to_string().into()))
+    );
+    assert_eq!(
+        parse("##\"contains \"# inside\n line two\"##"),
+        Ok(Expr::String(
+            "contains \"# inside\n line two".to_string().into()
+        ))
+    );
+    assert_eq!(parse("(a #\"\"# b)"), parse("(a \"\" b)"));
+    run_parse_error_test(
+        "#\"open",
+        "Parse error at 1:1: Unterminated raw string literal.\n#\"open\n^",
+    );
+}
+
+#[test]
+fn test_string_interpolation() {
+    let mut env = Env::new();
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define name \"Ada\")").unwrap();
+    run("(define age 36)").unwrap();
+    assert_eq!(
+        run("#f\"hello {name}, you are {(+ age 1)}\""),
+        Ok(Expr::String("hello Ada, you are 37".to_string().into()))
+    );
+    assert_eq!(
+        run("#f\"{{literal}} ~ {(concat \"}\" \"\\\"\")}\\t\\u{41}\""),
+        Ok(Expr::String("{literal} ~ }\"\tA".to_string().into()))
+    );
+    assert_eq!(parse("#f\"x={x}\""), parse("(format \"x=~a\" x)"));
+    run_parse_error_test(
+        "#f\"a } b\"",
+        "Parse error at 1:1: Unmatched '}' in interpolated string; write '}}' for a literal brace.\n#f\"a } b\"\n^",
+    );
+}
+
+#[test]
+fn test_parse_all_recovering_reports_every_error() {
+    use minilisp_rust::parser::parse_all_recovering;
+
+    let source =
+        "(define a 1)\n(define b (+ 1 2]\n(define c 3)\n)\n(define d (list 4\n(define e 5)\n";
+    let (exprs, diagnostics) = parse_all_recovering(source);
+    assert_eq!(
+        exprs,
+        vec![
+            parse("(define a 1)").unwrap(),
+            parse("(define c 3)").unwrap(),
+            parse("(define e 5)").unwrap(),
+        ]
+    );
+    let found: Vec<(&str, usize, usize)> = diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.position.line, d.position.column))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("Expected ')' but found ']'.", 2, 17),
+            ("Unexpected closing parenthesis.", 4, 1),
+            ("Missing closing parenthesis.", 5, 11),
+        ]
+    );
+    assert_eq!(
+        diagnostics[1].render(source),
+        "Parse error at 4:1: Unexpected closing parenthesis.\n)\n^"
+    );
+
+    let (exprs, diagnostics) = parse_all_recovering("(ok)\n(bad \"open\n(lost)");
+    assert_eq!(exprs, vec![parse("(ok)").unwrap()]);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Unterminated string literal.");
+}
+
+#[test]
+fn test_pretty_printer_breaks_long_forms() {
+    use minilisp_rust::pretty::{PrettyOptions, pretty};
+
+    let options = PrettyOptions {
+        width: 30,
+        indent: 2,
+    };
+    let short = parse("(+ 1 2)").unwrap();
+    assert_eq!(pretty(&short, &options), "(+ 1 2)");
+
+    let define = parse("(define fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))").unwrap();
+    assert_eq!(
+        pretty(&define, &options),
+        "(define fact\n  (lambda (n)\n    (if (= n 0)\n        1\n        (* n (fact (- n 1))))))"
+    );
+
+    let guarded = parse("(try (risky-operation 1 2) (catch e (condition-kind e)))").unwrap();
+    assert_eq!(
+        pretty(&guarded, &options),
+        "(try\n  (risky-operation 1 2)\n  (catch e (condition-kind e)))"
+    );
+
+    let table = parse("((alpha 1) (beta 2) (gamma 3) (delta 4))").unwrap();
+    assert_eq!(
+        pretty(&table, &options),
+        "((alpha 1)\n (beta 2)\n (gamma 3)\n (delta 4))"
+    );
+
+    // Only forms the language has get body indentation.
+    let call = parse("(let (alpha beta gamma) (+ alpha beta gamma))").unwrap();
+    assert_eq!(
+        pretty(&call, &options),
+        "(let (alpha beta gamma)\n     (+ alpha beta gamma))"
+    );
+    for expr in [define, guarded, table, call] {
+        assert_eq!(parse(&pretty(&expr, &options)), Ok(expr));
+    }
+}
+
+#[test]
+fn test_unused_symbols_are_dropped() {
+    use minilisp_rust::Symbol;
+    use minilisp_rust::symbol::interned;
+
+    let kept = Symbol::new("kept-while-others-come-and-go");
+    for i in 0..100_000 {
+        Symbol::new(&format!("made-up-{}", i));
+    }
+    assert!(interned() < 50_000, "{}", interned());
+    // A symbol still in use stays the same symbol.
+    assert_eq!(Symbol::new("kept-while-others-come-and-go"), kept);
+}
+
+#[test]
+fn test_parse_pipe_symbols_and_identifier_rules() {
+    assert_eq!(
+        parse("|a symbol with spaces|"),
+        Ok(Expr::Symbol("a symbol with spaces".into()))
+    );
+    assert_eq!(
+        parse(r"|pipe\|and\\slash|"),
+        Ok(Expr::Symbol(r"pipe|and\slash".into()))
+    );
+    assert_eq!(parse("|42|"), Ok(Expr::Symbol("42".into())));
+    assert_eq!(parse("||"), Ok(Expr::Symbol("".into())));
+    for symbol in [
+        "+", "-", "...", "->x", "-x", "<=?", "*args*", "λ", "a.b", "x+1", "@at",
+    ] {
+        assert_eq!(parse(symbol), Ok(Expr::Symbol(symbol.into())), "{}", symbol);
+    }
+    assert_eq!(parse("+1"), Ok(Expr::Number(1.0)));
+    for (invalid, message) in [
+        ("1+", "Invalid number '1+'."),
+        ("+1x", "Invalid number '+1x'."),
+        ("-.5x", "Invalid number '-.5x'."),
+        ("#foo", "Unknown reader syntax '#foo'."),
+        ("a,b", "Invalid character ',' in symbol 'a,b'."),
+        ("'x", "Invalid character ''' in symbol ''x'."),
+    ] {
+        let error = parse(invalid).unwrap_err().to_string();
+        assert!(
+            error.starts_with(&format!("Parse error at 1:1: {}", message)),
+            "{}",
+            error
+        );
+    }
+    run_parse_error_test(
+        "|open",
+        "Parse error at 1:1: Unterminated symbol.\n|open\n^",
+    );
+}
+
+#[test]
+fn test_parse_depth_guard() {
+    use minilisp_rust::runtime::{DEFAULT_MAX_PARSE_DEPTH, set_max_parse_depth};
+
+    let nested = |depth: usize| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(parse(&nested(DEFAULT_MAX_PARSE_DEPTH - 1)).is_ok());
+    let error = parse(&nested(100_000)).unwrap_err().to_string();
+    assert!(
+        error.starts_with("Parse error at 1:257: Expressions are nested more than 256 deep."),
+        "{}",
+        error
+    );
+
+    // Each interpolated string nested in another counts as a level.
+    let interpolated =
+        |depth: usize| (0..depth).fold("x".to_string(), |inner, _| format!("#f\"{{{}}}\"", inner));
+    assert!(parse(&interpolated(DEFAULT_MAX_PARSE_DEPTH - 1)).is_ok());
+    for depth in [300, 2000] {
+        let input = interpolated(depth);
+        let error = std::thread::Builder::new()
+            .stack_size(2 << 20)
+            .spawn(move || parse(&input).unwrap_err().to_string())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(
+            error.starts_with("Parse error at 1:1: Expressions are nested more than 256 deep."),
+            "{}",
+            error
+        );
+    }
+
+    set_max_parse_depth(3);
+    assert!(parse("((x))").is_ok());
+    assert!(parse("#f\"{#f\"{x}\"}\"").is_ok());
+    assert!(parse("#f\"{#f\"{#f\"{x}\"}\"}\"").is_err());
+    run_parse_error_test(
+        "(((x)))",
+        "Parse error at 1:4: Expressions are nested more than 3 deep.\n(((x)))\n   ^",
+    );
+    run_parse_error_test(
+        "#;#;#;a",
+        "Parse error at 1:7: Expressions are nested more than 3 deep.\n#;#;#;a\n      ^",
+    );
+}
+
+#[test]
+fn test_parse_from_reader_streams_forms() {
+    use minilisp_rust::parse_program;
+    use minilisp_rust::parser::parse_from_reader;
+    use std::io::{BufReader, Cursor, Read};
+
+    let source = "#!/usr/bin/env minilisp\n(a 1) b ; comment\n(c\n \"two\nlines\" #| x |#\n d) e\n#;(skipped)";
+    let forms: Result<Vec<Expr>, _> = parse_from_reader(Cursor::new(source)).collect();
+    assert_eq!(forms, parse_program(source));
+    assert_eq!(forms.unwrap().len(), 4);
+
+    // Errors give their line in the whole input, after the forms before them.
+    let mut forms = parse_from_reader(Cursor::new("(a)\n\n(b\n  c))\n(d)"));
+    assert_eq!(forms.next(), Some(Ok(parse("(a)").unwrap())));
+    assert_eq!(forms.next(), Some(Ok(parse("(b c)").unwrap())));
+    assert_eq!(
+        forms.next().map(|form| form.map_err(|e| e.to_string())),
+        Some(Err(
+            "Parse error at 4:5: Unexpected closing parenthesis.\n  c))\n    ^".to_string()
+        ))
+    );
+    assert_eq!(forms.next(), None);
+    let mut forms = parse_from_reader(Cursor::new("(a)\n(b \"open\n"));
+    assert!(forms.next().unwrap().is_ok());
+    assert_eq!(
+        forms.next().map(|form| form.map_err(|e| e.to_string())),
+        Some(Err(
+            "Parse error at 2:4: Unterminated string literal.\n(b \"open\n   ^".to_string()
+        ))
+    );
+
+    // Forms are returned before the rest of the input has been read.
+    struct NoMoreInput;
+    impl Read for NoMoreInput {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("read past the first form");
+        }
+    }
+    let reader = BufReader::new(Cursor::new("(a\n b)\n").chain(NoMoreInput));
+    assert_eq!(
+        parse_from_reader(reader).next(),
+        Some(Ok(parse("(a b)").unwrap()))
+    );
+}
+
+// --- List Tests ---
+
+#[test]
+fn test_flatten_deep_and_one_level() {
+    run_eval_test("(flatten (list 1 (list 2 (list 3)) 4))", datum("(1 2 3 4)"));
+    run_eval_test(
+        "(flatten (list 1 (list 2 (list 3)) 4) 1)",
+        datum("(1 2 (3) 4)"),
+    );
+}
+
+#[test]
+fn test_flatmap() {
+    run_eval_test(
+        "(flatmap (lambda (x) (list x x)) (list 1 2))",
+        datum("(1 1 2 2)"),
+    );
+    run_eval_error_test(
+        "(append-map (lambda (x) x) (list 1))",
+        "The function passed to 'append-map' must return a list.",
+    );
+}
+
+#[test]
+fn test_distinct_preserves_first_occurrence() {
+    run_eval_test(
+        "(distinct (list 3 1 3 (list 1) 2 1 (list 1)))",
+        datum("(3 1 (1) 2)"),
+    );
+    run_eval_test(
+        "(remove-duplicates (list \"a\" \"b\" \"a\"))",
+        Ok(Expr::List(
+            vec![Expr::String("a".into()), Expr::String("b".into())].into(),
+        )),
+    );
+}
+
+#[test]
+fn test_partition() {
+    run_eval_test(
+        "(partition (lambda (x) (> x 2)) (list 1 4 2 5))",
+        datum("((4 5) (1 2))"),
+    );
+    run_eval_error_test(
+        "(partition (lambda (x) x) (list 1))",
+        "The predicate passed to 'partition' must return a boolean.",
+    );
+}
+
+#[test]
+fn test_group_by_returns_alist_in_key_order() {
+    run_eval_test(
+        "(group-by (lambda (x) (> x 2)) (list 1 4 2 5))",
+        datum("((false (1 2)) (true (4 5)))"),
+    );
+}
+
+#[test]
+fn test_any_and_every_short_circuit() {
+    // The second element would make the predicate fail if it were evaluated.
+    run_eval_test(
+        "(any? (lambda (x) (> x 2)) (list 3 \"oops\"))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test(
+        "(every? (lambda (x) (> x 2)) (list 1 \"oops\"))",
+        Ok(Expr::Bool(false)),
+    );
+    run_eval_test("(every? (lambda (x) (> x 2)) (list))", Ok(Expr::Bool(true)));
+}
+
+#[test]
+fn test_count_find_position() {
+    run_eval_test(
+        "(count (lambda (x) (> x 2)) (list 1 3 5))",
+        Ok(Expr::Number(2.0)),
+    );
+    run_eval_test(
+        "(find (lambda (x) (> x 2)) (list 1 3 5))",
+        Ok(Expr::Number(3.0)),
+    );
+    run_eval_test(
+        "(position (lambda (x) (> x 2)) (list 1 3 5))",
+        Ok(Expr::Number(1.0)),
+    );
+    run_eval_test(
+        "(find (lambda (x) (> x 9)) (list 1 3 5))",
+        Ok(Expr::Bool(false)),
+    );
+}
+
+#[test]
+fn test_butlast_list_tail_split_at() {
+    run_eval_test("(butlast (list 1 2 3))", datum("(1 2)"));
+    run_eval_test("(list-tail (list 1 2 3) 1)", datum("(2 3)"));
+    run_eval_test("(split-at (list 1 2 3) 2)", datum("((1 2) (3))"));
+    run_eval_test("(split-at (list 1 2 3) 3)", datum("((1 2 3) ())"));
+}
+
+#[test]
+fn test_list_slicing_errors() {
+    run_eval_error_test(
+        "(list-tail (list 1 2 3) 4)",
+        "'list-tail' index 4 exceeds the list length 3.",
+    );
+    run_eval_error_test("(butlast (list))", "'butlast' requires a non-empty list.");
+}
+
+// --- String Tests ---
+
+#[test]
+fn test_string_length_counts_chars() {
+    run_eval_test("(string-length \"héllo\")", Ok(Expr::Number(5.0)));
+    run_eval_test("(string-length \"\")", Ok(Expr::Number(0.0)));
+}
+
+#[test]
+fn test_substring_and_string_ref() {
+    run_eval_test("(substring \"héllo\" 1 3)", Ok(Expr::String("él".into())));
+    run_eval_test("(substring \"héllo\" 2)", Ok(Expr::String("llo".into())));
+    run_eval_test("(string-ref \"héllo\" 1)", Ok(Expr::String("é".into())));
+    run_eval_error_test(
+        "(string-ref \"abc\" 3)",
+        "'string-ref' index 3 is out of bounds for a string of length 3.",
+    );
+    run_eval_error_test(
+        "(substring \"abc\" 2 1)",
+        "'substring' range 2..1 is out of bounds for a string of length 3.",
+    );
+}
+
+#[test]
+fn test_string_split() {
+    run_eval_test(
+        "(string-split \"a,b,,c\" \",\")",
+        datum("(\"a\" \"b\" \"\" \"c\")"),
+    );
+    run_eval_test(
+        "(string-split \"  one two\tthree \")",
+        datum("(\"one\" \"two\" \"three\")"),
+    );
+}
+
+#[test]
+fn test_string_join() {
+    run_eval_test(
+        "(string-join (list \"a\" \"b\" \"c\") \", \")",
+        Ok(Expr::String("a, b, c".to_string().into())),
+    );
+    run_eval_test(
+        "(string-join (string-split \"x y\"))",
+        Ok(Expr::String("xy".into())),
+    );
+    run_eval_error_test(
+        "(string-join (list \"a\" 1) \"-\")",
+        "'string-join' requires a string argument.",
+    );
+}
+
+#[test]
+fn test_string_case_is_unicode_aware() {
+    run_eval_test("(upcase \"straße\")", Ok(Expr::String("STRASSE".into())));
+    run_eval_test("(downcase \"ÀÉÎ\")", Ok(Expr::String("àéî".into())));
+}
+
+#[test]
+fn test_string_trimming() {
+    run_eval_test("(trim \"  hi \")", Ok(Expr::String("hi".into())));
+    run_eval_test("(trim-left \"  hi \")", Ok(Expr::String("hi ".into())));
+    run_eval_test("(trim-right \"  hi \")", Ok(Expr::String("  hi".into())));
+}
+
+#[test]
+fn test_string_to_number_radix() {
+    run_eval_test("(string->number \"ff\" 16)", Ok(Expr::Number(255.0)));
+    run_eval_test("(string->number \"1010\" 2)", Ok(Expr::Number(10.0)));
+    run_eval_test("(string->number \"0xff\" 16)", Ok(Expr::Number(255.0)));
+    run_eval_test("(string->number \"-0x1F\")", Ok(Expr::Number(-31.0)));
+    run_eval_test("(string->number \"0b101\")", Ok(Expr::Number(5.0)));
+    run_eval_test("(string->number \"0o17\")", Ok(Expr::Number(15.0)));
+    run_eval_test("(string->number \"12\" 2)", Ok(Expr::Bool(false)));
+    run_eval_test("(string->number \"0x\")", Ok(Expr::Bool(false)));
+    run_eval_error_test(
+        "(string->number \"1\" 37)",
+        "The radix for 'string->number' must be between 2 and 36.",
+    );
+}
+
+#[test]
+fn test_string_number_conversions() {
+    run_eval_test("(+ 1 (string->number \"41\"))", Ok(Expr::Number(42.0)));
+    run_eval_test("(string->number \"-2.5\")", Ok(Expr::Number(-2.5)));
+    run_eval_test("(string->number \"forty\")", Ok(Expr::Bool(false)));
+    run_eval_test("(number->string (* 2 21))", Ok(Expr::String("42".into())));
+    run_eval_error_test(
+        "(number->string \"42\")",
+        "'number->string' requires a number argument.",
+    );
+}
+
+#[test]
+fn test_string_symbol_conversions() {
+    run_eval_test("(string->symbol \"foo\")", Ok(Expr::Symbol("foo".into())));
+    run_eval_test(
+        "(symbol->string (string->symbol (concat \"make-\" \"point\")))",
+        Ok(Expr::String("make-point".to_string().into())),
+    );
+    run_eval_error_test(
+        "(symbol->string \"foo\")",
+        "'symbol->string' requires a symbol argument.",
+    );
+}
+
+#[test]
+fn test_string_list_conversions() {
+    run_eval_test(
+        "(string->list \"hé😀\")",
+        Ok(Expr::List(
+            vec![
+                Expr::String("h".into()),
+                Expr::String("é".into()),
+                Expr::String("😀".to_string().into()),
+            ]
+            .into(),
+        )),
+    );
+    run_eval_test(
+        "(list->string (list-tail (string->list \"abc\") 1))",
+        Ok(Expr::String("bc".into())),
+    );
+    run_eval_test(
+        "(list->string (list))",
+        Ok(Expr::String(String::new().into())),
+    );
+    run_eval_error_test(
+        "(list->string (list \"ab\"))",
+        "'list->string' requires a one-character string.",
+    );
+}
+
+#[test]
+fn test_substring_predicates() {
+    run_eval_test(
+        "(string-contains? \"error: disk full\" \"disk\")",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test("(starts-with? \"main.rs\" \"main\")", Ok(Expr::Bool(true)));
+    run_eval_test("(ends-with? \"main.rs\" \".lisp\")", Ok(Expr::Bool(false)));
+    run_eval_test("(ends-with? \"main.rs\" \"\")", Ok(Expr::Bool(true)));
+}
+
+#[test]
+fn test_string_replace() {
+    run_eval_test(
+        "(string-replace \"a-b-c\" \"-\" \"+\")",
+        Ok(Expr::String("a+b+c".to_string().into())),
+    );
+    run_eval_test(
+        "(string-replace \"a-b-c\" \"-\" \"\" 1)",
+        Ok(Expr::String("ab-c".to_string().into())),
+    );
+    run_eval_error_test(
+        "(string-replace \"abc\" \"\" \"x\")",
+        "The pattern for 'string-replace' must not be empty.",
+    );
+}
+
+#[test]
+fn test_format_display_write_and_newline() {
+    run_eval_test(
+        "(format \"x=~a y=~s~%\" \"one\" \"two\")",
+        Ok(Expr::String("x=one y=\"two\"\n".to_string().into())),
+    );
+    run_eval_test(
+        "(format \"~a items, 100~~\" (list 1 \"a\"))",
+        Ok(Expr::String("(1 a) items, 100~".to_string().into())),
+    );
+}
+
+#[test]
+fn test_format_width() {
+    run_eval_test(
+        "(format \"[~5a][~5@a]\" 42 \"ab\")",
+        Ok(Expr::String("[42   ][   ab]".into())),
+    );
+}
+
+#[test]
+fn test_format_errors() {
+    run_eval_error_test(
+        "(format \"~a ~a\" 1)",
+        "'format' has more directives than arguments.",
+    );
+    run_eval_error_test("(format \"~q\" 1)", "Unknown 'format' directive '~q'.");
+}
+
+#[test]
+fn test_char_integer_conversions() {
+    run_eval_test("(char->integer \"A\")", Ok(Expr::Number(65.0)));
+    run_eval_test("(integer->char 233)", Ok(Expr::String("é".into())));
+    run_eval_error_test(
+        "(char->integer \"AB\")",
+        "'char->integer' requires a one-character string.",
+    );
+    run_eval_error_test(
+        "(integer->char 55296)",
+        "55296 is not a valid Unicode scalar value.",
+    );
+}
+
+#[test]
+fn test_char_case_and_predicates() {
+    run_eval_test("(char-upcase \"é\")", Ok(Expr::String("É".into())));
+    run_eval_test("(char-upcase \"ß\")", Ok(Expr::String("ß".into())));
+    run_eval_test(
+        "(char-alphabetic? (string-ref \"a1 \" 0))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test(
+        "(char-numeric? (string-ref \"a1 \" 1))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test(
+        "(char-whitespace? (string-ref \"a1 \" 2))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test("(char-numeric? \"x\")", Ok(Expr::Bool(false)));
+}
+
+// --- Comparison Tests ---
+
+#[test]
+fn test_comparison_operators() {
+    run_eval_test("(< 1 2)", Ok(Expr::Bool(true)));
+    run_eval_test("(<= 2 2)", Ok(Expr::Bool(true)));
+    run_eval_test("(>= 1 2)", Ok(Expr::Bool(false)));
+    run_eval_test("(= 2 2.0)", Ok(Expr::Bool(true)));
+    run_eval_test("(= 2 3)", Ok(Expr::Bool(false)));
+}
+
+#[test]
+fn test_numeric_equality_rejects_non_numbers() {
+    run_eval_error_test("(= \"a\" \"a\")", "'=' requires number arguments.");
+    run_eval_error_test("(< 1)", "'<' requires at least two arguments.");
+}
+
+#[test]
+fn test_chained_comparisons() {
+    run_eval_test("(< 1 2 3 4)", Ok(Expr::Bool(true)));
+    run_eval_test("(< 1 3 2)", Ok(Expr::Bool(false)));
+    run_eval_test("(>= 3 3 1)", Ok(Expr::Bool(true)));
+    run_eval_test("(= 1 1 1.0)", Ok(Expr::Bool(true)));
+    run_eval_error_test("(< 1 2 \"3\")", "'<' requires number arguments.");
+}
+
+#[test]
+fn test_equality_predicates_on_atoms() {
+    run_eval_test("(eqv? 1 1)", Ok(Expr::Bool(true)));
+    run_eval_test("(eq? \"a\" \"a\")", Ok(Expr::Bool(true)));
+    run_eval_test("(eqv? 1 \"1\")", Ok(Expr::Bool(false)));
+    run_eval_test("(eqv? 0 (- 0))", Ok(Expr::Bool(false)));
+    run_eval_test("(= 0 (- 0))", Ok(Expr::Bool(true)));
+    // inf - inf is NaN, which is `eqv?` to itself but not `=`.
+    let nan = "(- (* 1e308 10) (* 1e308 10))";
+    run_eval_test(&format!("(eqv? {0} {0})", nan), Ok(Expr::Bool(true)));
+    run_eval_test(&format!("(= {0} {0})", nan), Ok(Expr::Bool(false)));
+}
+
+#[test]
+fn test_equal_is_structural() {
+    run_eval_test(
+        "(equal? (list 1 (list 2)) (list 1 (list 2)))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test("(eqv? (list 1) (list 1))", Ok(Expr::Bool(false)));
+    run_eval_test("(eqv? (list) (list))", Ok(Expr::Bool(true)));
+    run_eval_test(
+        "(equal? (lambda (x) x) (lambda (x) x))",
+        Ok(Expr::Bool(true)),
+    );
+    run_eval_test(
+        "(eqv? (lambda (x) x) (lambda (x) x))",
+        Ok(Expr::Bool(false)),
+    );
+}
+
+// --- Type Predicate Tests ---
+
+#[test]
+fn test_type_predicates_accept_their_variant() {
+    run_eval_test("(number? 1.5)", Ok(Expr::Bool(true)));
+    run_eval_test("(string? \"s\")", Ok(Expr::Bool(true)));
+    run_eval_test("(symbol? (string->symbol \"s\"))", Ok(Expr::Bool(true)));
+    run_eval_test("(list? (list))", Ok(Expr::Bool(true)));
+    run_eval_test("(procedure? (lambda (x) x))", Ok(Expr::Bool(true)));
+    run_eval_test("(bool? false)", Ok(Expr::Bool(true)));
+}
+
+#[test]
+fn test_type_predicates_reject_other_variants() {
+    run_eval_test("(number? \"1\")", Ok(Expr::Bool(false)));
+    run_eval_test("(string? (string->symbol \"s\"))", Ok(Expr::Bool(false)));
+    run_eval_test("(list? 1)", Ok(Expr::Bool(false)));
+    run_eval_test("(procedure? (list))", Ok(Expr::Bool(false)));
+    run_eval_test("(boolean? 0)", Ok(Expr::Bool(false)));
+}
+
+// --- Math Tests ---
+
+#[test]
+fn test_integer_division_signs() {
+    run_eval_test("(modulo 7 3)", Ok(Expr::Number(1.0)));
+    run_eval_test("(modulo (- 7) 3)", Ok(Expr::Number(2.0)));
+    run_eval_test("(modulo 7 (- 3))", Ok(Expr::Number(-2.0)));
+    run_eval_test("(remainder (- 7) 3)", Ok(Expr::Number(-1.0)));
+    run_eval_test("(remainder 7 (- 3))", Ok(Expr::Number(1.0)));
+    run_eval_test("(quotient (- 7) 2)", Ok(Expr::Number(-3.0)));
+}
+
+#[test]
+fn test_integer_division_errors() {
+    run_eval_error_test("(modulo 7 0)", "Division by zero.");
+    run_eval_error_test("(quotient 7.5 2)", "'quotient' requires integer arguments.");
+}
+
+#[test]
+fn test_abs_min_max() {
+    run_eval_test("(abs (- 3))", Ok(Expr::Number(3.0)));
+    run_eval_test("(min 3 1 2)", Ok(Expr::Number(1.0)));
+    run_eval_test("(max 3 1 2)", Ok(Expr::Number(3.0)));
+    run_eval_test("(max (list 4 9 2))", Ok(Expr::Number(9.0)));
+    run_eval_error_test("(min)", "'min' requires at least one argument.");
+    run_eval_error_test("(max 1 \"2\")", "'max' requires number arguments.");
+}
+
+#[test]
+fn test_rounding_family() {
+    run_eval_test("(floor (- 1.5))", Ok(Expr::Number(-2.0)));
+    run_eval_test("(ceiling 1.2)", Ok(Expr::Number(2.0)));
+    run_eval_test("(truncate (- 1.7))", Ok(Expr::Number(-1.0)));
+    run_eval_test("(round 1.7)", Ok(Expr::Number(2.0)));
+}
+
+#[test]
+fn test_round_uses_bankers_rounding() {
+    run_eval_test("(round 0.5)", Ok(Expr::Number(0.0)));
+    run_eval_test("(round 1.5)", Ok(Expr::Number(2.0)));
+    run_eval_test("(round 2.5)", Ok(Expr::Number(2.0)));
+    run_eval_test("(round (- 2.5))", Ok(Expr::Number(-2.0)));
+}
+
+#[test]
+fn test_expt_sqrt_exp_log() {
+    run_eval_test("(expt 2 10)", Ok(Expr::Number(1024.0)));
+    run_eval_test("(expt 4 0.5)", Ok(Expr::Number(2.0)));
+    run_eval_test("(sqrt 9)", Ok(Expr::Number(3.0)));
+    run_eval_test("(exp 0)", Ok(Expr::Number(1.0)));
+    run_eval_test("(log 1)", Ok(Expr::Number(0.0)));
+    run_eval_test("(log 8 2)", Ok(Expr::Number(3.0)));
+}
+
+#[test]
+fn test_math_domain_errors() {
+    run_eval_error_test("(sqrt (- 4))", "Domain error: 'sqrt' is undefined for -4.");
+    run_eval_error_test("(log 0)", "Domain error: 'log' is undefined for 0.");
+    run_eval_error_test("(log 8 1)", "Domain error: 'log' is undefined for base 1.");
+    run_eval_error_test(
+        "(expt (- 8) 0.5)",
+        "Domain error: 'expt' is undefined for base -8 and exponent 0.5.",
+    );
+    run_eval_error_test("(expt 0 (- 1))", "Division by zero.");
+}
+
+#[test]
+fn test_trigonometry() {
+    run_eval_test("(sin 0)", Ok(Expr::Number(0.0)));
+    run_eval_test("(cos 0)", Ok(Expr::Number(1.0)));
+    run_eval_test("(atan 1 1)", Ok(Expr::Number(std::f64::consts::FRAC_PI_4)));
+    run_eval_test("(atan 0)", Ok(Expr::Number(0.0)));
+}
+
+#[test]
+fn test_default_env_defines_pi_and_e() {
+    let mut env = default_env();
+    let result = eval(&parse("(cos pi)").unwrap(), &mut env);
+    assert_eq!(result, Ok(Expr::Number(-1.0)));
+    let result = eval(&parse("(log e)").unwrap(), &mut env);
+    assert_eq!(result, Ok(Expr::Number(1.0)));
+}
+
+#[test]
+fn test_random_ranges() {
+    let mut env = Env::new();
+    for _ in 0..100 {
+        let x = eval(&parse("(random)").unwrap(), &mut env).unwrap();
+        assert!(matches!(x, Expr::Number(n) if (0.0..1.0).contains(&n)));
+        let k = eval(&parse("(random 6)").unwrap(), &mut env).unwrap();
+        assert!(matches!(k, Expr::Number(n) if (0.0..6.0).contains(&n) && n.fract() == 0.0));
+    }
+    run_eval_error_test("(random 0)", "'random' requires a positive integer bound.");
+}
+
+#[test]
+fn test_random_seed_is_reproducible() {
+    let mut env = Env::new();
+    let mut draw = |src: &str| eval(&parse(src).unwrap(), &mut env).unwrap();
+    draw("(random-seed 42)");
+    let first = (draw("(random)"), draw("(random 1000)"));
+    draw("(random-seed 42)");
+    let second = (draw("(random)"), draw("(random 1000)"));
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_random_source_is_injectable() {
+    struct Fixed;
+    impl minilisp_rust::runtime::RandomSource for Fixed {
+        fn next_u64(&mut self) -> u64 {
+            u64::MAX / 2
+        }
+        fn reseed(&mut self, _seed: u64) {}
+    }
+    minilisp_rust::runtime::set_random_source(Fixed);
+    run_eval_test("(random 10)", Ok(Expr::Number(4.0)));
+    run_eval_test(
+        "(random)",
+        Ok(Expr::Number(0.5 - 1.0 / (1u64 << 53) as f64)),
+    );
+}
+
+#[test]
+fn test_gcd_and_lcm() {
+    run_eval_test("(gcd 12 18 (- 30))", Ok(Expr::Number(6.0)));
+    run_eval_test("(lcm 4 6 10)", Ok(Expr::Number(60.0)));
+    run_eval_test("(gcd)", Ok(Expr::Number(0.0)));
+    run_eval_test("(lcm)", Ok(Expr::Number(1.0)));
+    run_eval_test("(lcm 3 0)", Ok(Expr::Number(0.0)));
+    run_eval_error_test("(gcd 4 1.5)", "'gcd' requires integer arguments.");
+}
+
+#[test]
+fn test_bitwise_operators() {
+    run_eval_test("(bit-and 12 10)", Ok(Expr::Number(8.0)));
+    run_eval_test("(bit-or 12 10 1)", Ok(Expr::Number(15.0)));
+    run_eval_test("(bit-xor 12 10)", Ok(Expr::Number(6.0)));
+    run_eval_test("(bit-not 0)", Ok(Expr::Number(-1.0)));
+    run_eval_test("(shift-left 1 10)", Ok(Expr::Number(1024.0)));
+    run_eval_test("(shift-right (- 16) 2)", Ok(Expr::Number(-4.0)));
+}
+
+#[test]
+fn test_bitwise_operator_errors() {
+    run_eval_error_test("(bit-and 1.5 1)", "'bit-and' requires integer arguments.");
+    run_eval_error_test(
+        "(shift-left 1 64)",
+        "'shift-left' amount must be between 0 and 63.",
+    );
+}
+
+#[test]
+fn test_number_to_string_radix() {
+    run_eval_test("(number->string 255 16)", Ok(Expr::String("ff".into())));
+    run_eval_test(
+        "(number->string (- 10) 2)",
+        Ok(Expr::String("-1010".to_string().into())),
+    );
+    run_eval_error_test(
+        "(number->string 1.5 2)",
+        "'number->string' requires integer arguments.",
+    );
+}
+
+#[test]
+fn test_format_number() {
+    run_eval_test("(format-number 3.14159 2)", Ok(Expr::String("3.14".into())));
+    run_eval_test(
+        "(format-number (- 1234567.891) 1 \",\")",
+        Ok(Expr::String("-1,234,567.9".to_string().into())),
+    );
+    run_eval_test(
+        "(format-number 999 0 \"_\")",
+        Ok(Expr::String("999".into())),
+    );
+    // Up to 100 decimals are written; more is an error, not a panic.
+    run_eval_test(
+        "(string-length (format-number 1 100))",
+        Ok(Expr::Number(102.0)),
+    );
+    for decimals in ["101", "65535", "100000000"] {
+        run_eval_error_test(
+            &format!("(format-number 1 {})", decimals),
+            "'format-number' allows at most 100 decimals.",
+        );
+    }
+}
+
+// --- I/O Tests ---
+
+#[test]
+fn test_output_builtins_write_to_sink() {
+    use minilisp_rust::runtime::{OutputBuffer, set_output};
+
+    let buffer = OutputBuffer::new();
+    set_output(buffer.clone());
+    let mut env = Env::new();
+    for src in [
+        "(display \"a b\")",
+        "(newline)",
+        "(write \"a b\")",
+        "(newline)",
+        "(print (list 1 \"x\"))",
+    ] {
+        let result = eval(&parse(src).unwrap(), &mut env);
+        assert_eq!(result, Ok(Expr::empty_list()));
+    }
+    assert_eq!(buffer.contents(), "a b\n\"a b\"\n(1 x)\n");
+}
+
+#[test]
+fn test_read_string_returns_datum() {
+    run_eval_test("(read-string \"(1 (2) x)\")", datum("(1 (2) x)"));
+    run_eval_test("(read \"42\")", Ok(Expr::Number(42.0)));
+    run_eval_error_test(
+        "(read-string \"(1 2\")",
+        "Parse error at 1:1: Missing closing parenthesis.\n(1 2\n^",
+    );
+}
+
+#[test]
+fn test_eval_of_read_datum() {
+    let mut env = Env::new();
+    eval(&parse("(define x 4)").unwrap(), &mut env).unwrap();
+    let result = eval(
+        &parse("(eval (read-string \"(* x 10)\"))").unwrap(),
+        &mut env,
+    );
+    assert_eq!(result, Ok(Expr::Number(40.0)));
+}
+
+#[test]
+fn test_read_line_from_input_source() {
+    minilisp_rust::runtime::set_input(std::io::Cursor::new("first\r\nsecond"));
+    run_eval_test("(read-line)", Ok(Expr::String("first".into())));
+    run_eval_test("(read-line)", Ok(Expr::String("second".into())));
+    run_eval_test("(read-line)", Ok(Expr::Bool(false)));
+}
+
+/// Returns a path in the system temp directory that is unique to this test.
+fn temp_path(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("minilisp-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name).to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_file_io_round_trip() {
+    let path = temp_path("file_io_round_trip.txt");
+    let mut env = Env::new();
+    env.insert("path".to_string(), Expr::String(path.as_str().into()));
+    for src in ["(write-file path \"one\n\")", "(append-file path \"two\")"] {
+        eval(&parse(src).unwrap(), &mut env).unwrap();
+    }
+    let result = eval(&parse("(read-file path)").unwrap(), &mut env);
+    assert_eq!(result, Ok(Expr::String("one\ntwo".to_string().into())));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_read_missing_file_is_an_error() {
+    let mut env = Env::new();
+    let path = temp_path("does-not-exist.txt");
+    env.insert("path".to_string(), Expr::String(path.as_str().into()));
+    let result = eval(&parse("(read-file path)").unwrap(), &mut env);
+    assert!(
+        result
+            .unwrap_err()
+            .to_string()
+            .starts_with(&format!("'read-file' failed for '{}'", path))
+    );
+}
+
+#[test]
+fn test_file_io_disabled_in_sandbox() {
+    use minilisp_rust::runtime::{Capabilities, set_capabilities};
+
+    set_capabilities(Capabilities::sandboxed());
+    run_eval_error_test(
+        "(read-file \"/etc/passwd\")",
+        "'read-file' is disabled in sandbox mode.",
+    );
+    run_eval_error_test(
+        "(write-file \"x.txt\" \"\")",
+        "'write-file' is disabled in sandbox mode.",
+    );
+    run_eval_error_test(
+        "(list-dir \".\")",
+        "'list-dir' is disabled in sandbox mode.",
+    );
+}
+
+#[test]
+fn test_filesystem_queries() {
+    let dir = temp_path("fs_queries");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/b.txt", dir), "").unwrap();
+    std::fs::write(format!("{}/a.txt", dir), "").unwrap();
+    let mut env = Env::new();
+    env.insert("dir".to_string(), Expr::String(dir.clone().into()));
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+
+    assert_eq!(run("(directory? dir)"), Ok(Expr::Bool(true)));
+    assert_eq!(run("(list-dir dir)"), datum("(\"a.txt\" \"b.txt\")"));
+    assert_eq!(
+        run("(file-exists? (concat dir \"/a.txt\"))"),
+        Ok(Expr::Bool(true))
+    );
+    run("(delete-file (concat dir \"/a.txt\"))").unwrap();
+    assert_eq!(
+        run("(file-exists? (concat dir \"/a.txt\"))"),
+        Ok(Expr::Bool(false))
+    );
+    assert_eq!(
+        run("(directory? (concat dir \"/b.txt\"))"),
+        Ok(Expr::Bool(false))
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_getenv_and_setenv() {
+    run_eval_test(
+        "(getenv \"MINILISP_SURELY_UNSET_VARIABLE\")",
+        Ok(Expr::Bool(false)),
+    );
+    run_eval_test("(string? (getenv \"PATH\"))", Ok(Expr::Bool(true)));
+    let mut env = Env::new();
+    eval(
+        &parse("(setenv \"MINILISP_TEST_VAR\" \"42\")").unwrap(),
+        &mut env,
+    )
+    .unwrap();
+    let result = eval(&parse("(getenv \"MINILISP_TEST_VAR\")").unwrap(), &mut env);
+    assert_eq!(result, Ok(Expr::String("42".into())));
+}
+
+#[test]
+fn test_environment_disabled_in_sandbox() {
+    use minilisp_rust::runtime::{Capabilities, set_capabilities};
+
+    set_capabilities(Capabilities::sandboxed());
+    run_eval_error_test("(getenv \"HOME\")", "'getenv' is disabled in sandbox mode.");
+}
+
+// --- Script Runner Tests ---
+
+/// Runs the interpreter binary on a script with the given arguments.
+fn run_script(name: &str, source: &str, args: &[&str]) -> std::process::Output {
+    let path = temp_path(name);
+    std::fs::write(&path, source).unwrap();
+    std::process::Command::new(env!("CARGO_BIN_EXE_minilisp-rust"))
+        .arg(&path)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_script_receives_arguments() {
+    let output = run_script(
+        "args.lisp",
+        "(print (list (ends-with? *script-path* \"args.lisp\") *args*))",
+        &["--", "one", "two words"],
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "(true (one two words))\n"
+    );
+}
+
+#[test]
+fn test_script_runs_every_form() {
+    let output = run_script(
+        "forms.lisp",
+        "; a script\n(define double (lambda (x) (* x 2)))\n(print (double 21))\n(print (double 1))\n",
+        &[],
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n2\n");
+}
+
+#[test]
+fn test_script_with_shebang_line() {
+    let output = run_script(
+        "shebang.lisp",
+        "#!/usr/bin/env minilisp\n(print (+ 1 2))\n",
+        &[],
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn test_repl_continues_unfinished_input() {
+    use std::io::Write;
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_minilisp-rust"))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"(define add\n  (lambda (a b)\n    (+ a b)))\n(add 1 2)\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Welcome to minilisp-rust!\n> ... ... add\n> 3\n> Goodbye!\n"
+    );
+}
+
+#[test]
+fn test_shell_and_exec() {
+    run_eval_test(
+        "(shell \"echo out; echo err >&2; exit 3\")",
+        datum("(3 \"out\n\" \"err\n\")"),
+    );
+    run_eval_test("(exec \"echo\" \"a  b\")", datum("(0 \"a  b\n\" \"\")"));
+}
+
+#[test]
+fn test_exec_sees_setenv_variables() {
+    let mut env = Env::new();
+    eval(
+        &parse("(setenv \"MINILISP_CHILD_VAR\" \"hi\")").unwrap(),
+        &mut env,
+    )
+    .unwrap();
+    let result = eval(
+        &parse("(shell \"printf $MINILISP_CHILD_VAR\")").unwrap(),
+        &mut env,
+    );
+    assert_eq!(result.map_err(|e| e.to_string()), datum("(0 \"hi\" \"\")"));
+}
+
+#[test]
+fn test_shell_disabled_in_sandbox() {
+    use minilisp_rust::runtime::{Capabilities, set_capabilities};
+
+    set_capabilities(Capabilities::sandboxed());
+    run_eval_error_test("(shell \"ls\")", "'shell' is disabled in sandbox mode.");
+    run_eval_error_test("(exec \"ls\")", "'exec' is disabled in sandbox mode.");
+}
+
+// --- Time Tests ---
+
+#[test]
+fn test_current_time_is_epoch_seconds() {
+    let mut env = Env::new();
+    let result = eval(&parse("(current-time)").unwrap(), &mut env).unwrap();
+    let expected = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    assert!(matches!(result, Expr::Number(n) if (n - expected).abs() < 5.0));
+}
+
+#[test]
+fn test_monotonic_ms_never_decreases() {
+    let mut env = Env::new();
+    let result = eval(
+        &parse("((lambda (t0) (>= (monotonic-ms) t0)) (monotonic-ms))").unwrap(),
+        &mut env,
+    );
+    assert_eq!(result, Ok(Expr::Bool(true)));
+}
+
+#[test]
+fn test_sleep_pauses() {
+    let start = std::time::Instant::now();
+    run_eval_test("(sleep 0.05)", Ok(Expr::empty_list()));
+    assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+    run_eval_error_test(
+        "(sleep (- 1))",
+        "'sleep' requires a non-negative number of seconds.",
+    );
+}
+
+#[test]
+fn test_sleep_is_interruptible() {
+    let handle = minilisp_rust::runtime::interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let start = std::time::Instant::now();
+    run_eval_error_test("(sleep 30)", "Evaluation interrupted.");
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    interrupter.join().unwrap();
+    // The interrupt is consumed, so later evaluations run normally.
+    run_eval_test("(sleep 0)", Ok(Expr::empty_list()));
+}
+
+#[test]
+fn test_sleep_stops_at_the_timeout() {
+    use minilisp_rust::{EvalError, eval_with_timeout};
+    use std::time::{Duration, Instant};
+
+    let mut env = Env::new();
+    let start = Instant::now();
+    let error = eval_with_timeout(
+        &parse("(sleep 3)").unwrap(),
+        &mut env,
+        Duration::from_millis(100),
+    )
+    .unwrap_err();
+    assert_eq!(*error.root(), EvalError::Timeout);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+    // A sleep that ends before the deadline is not cut short.
+    assert_eq!(
+        eval_with_timeout(
+            &parse("(sleep 0.01)").unwrap(),
+            &mut env,
+            Duration::from_secs(5)
+        ),
+        Ok(Expr::empty_list())
+    );
+}
+
+// --- Data Format Tests ---
+
+#[test]
+fn test_json_parse_builds_lisp_data() {
+    let mut env = Env::new();
+    env.insert(
+        "text".to_string(),
+        Expr::String(r#"{"name": "ada", "tags": [1, 2.5, true, null], "nested": {}}"#.into()),
+    );
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define doc (json-parse text))").unwrap();
+    assert_eq!(
+        run("(map-get doc \"name\")"),
+        Ok(Expr::String("ada".into()))
+    );
+    assert_eq!(run("(map-get doc \"tags\")"), datum("(1 2.5 true ())"));
+    assert_eq!(
+        run("(map-keys doc)"),
+        datum("(\"name\" \"nested\" \"tags\")")
+    );
+    assert_eq!(run("(map? (map-get doc \"nested\"))"), Ok(Expr::Bool(true)));
+    assert_eq!(
+        run("(map-get doc \"missing\" false)"),
+        Ok(Expr::Bool(false))
+    );
+}
+
+#[test]
+fn test_json_stringify() {
+    run_eval_test(
+        "(json-stringify (hash-map \"b\" (list 1 \"x\") \"a\" true))",
+        Ok(Expr::String(r#"{"a":true,"b":[1,"x"]}"#.to_string().into())),
+    );
+    run_eval_test(
+        "(json-stringify (hash-map \"a\" (list 1)) true)",
+        Ok(Expr::String(
+            "{\n  \"a\": [\n    1\n  ]\n}".to_string().into(),
+        )),
+    );
+    run_eval_error_test(
+        "(json-stringify (lambda (x) x))",
+        "Cannot represent a function in JSON.",
+    );
+}
+
+#[test]
+fn test_json_escapes_round_trip() {
+    let value = minilisp_rust::json::parse(r#"["tab\tquote\"é😀"]"#).unwrap();
+    assert_eq!(
+        value,
+        Expr::List(vec![Expr::String("tab\tquote\"é😀".to_string().into())].into())
+    );
+    let text = minilisp_rust::json::stringify(&value, false).unwrap();
+    assert_eq!(minilisp_rust::json::parse(&text), Ok(value));
+    assert_eq!(
+        minilisp_rust::json::parse("[1,]"),
+        Err("Invalid JSON at offset 3: unexpected character.".to_string())
+    );
+}
+
+#[test]
+fn test_json_parse_rejects_malformed_numbers_and_deep_nesting() {
+    use minilisp_rust::json;
+
+    assert_eq!(
+        json::parse("[-0, 10, 1.5e-3, 2E+2]"),
+        datum("(0 10 0.0015 200)")
+    );
+    for text in ["01", "-01", "1.", "1e", "-", "1.e5"] {
+        assert_eq!(
+            json::parse(text),
+            Err("Invalid JSON at offset 0: malformed number.".to_string()),
+            "{}",
+            text
+        );
+    }
+
+    // Nesting is limited like the Lisp reader's, so deep input is an error
+    // rather than a stack overflow.
+    let deep = |n| format!("{}{}", "[".repeat(n), "]".repeat(n));
+    assert!(json::parse(&deep(200)).is_ok());
+    let error = json::parse(&deep(200_000)).unwrap_err();
+    assert_eq!(
+        error,
+        "Invalid JSON at offset 256: nested more than 256 deep."
+    );
+    let mut env = Env::new();
+    env.insert("deep", Expr::String(deep(200_000).into()));
+    assert!(eval(&parse("(json-parse deep)").unwrap(), &mut env).is_err());
+}
+
+#[test]
+fn test_base64_round_trip() {
+    run_eval_test(
+        "(base64-encode \"hello!?\")",
+        Ok(Expr::String("aGVsbG8hPw==".to_string().into())),
+    );
+    run_eval_test(
+        "(base64-decode (base64-encode \"héllo\"))",
+        Ok(Expr::String("héllo".into())),
+    );
+    run_eval_test(
+        "(base64-encode (list 0 255 16))",
+        Ok(Expr::String("AP8Q".into())),
+    );
+    run_eval_error_test(
+        "(base64-decode \"a$==\")",
+        "Invalid base64: unexpected character '$'.",
+    );
+}
+
+#[test]
+fn test_hex_round_trip() {
+    run_eval_test(
+        "(hex-encode (list 0 171 255))",
+        Ok(Expr::String("00abff".into())),
+    );
+    run_eval_test("(hex-decode \"6869\")", Ok(Expr::String("hi".into())));
+    run_eval_error_test("(hex-decode \"abc\")", "Invalid hex: odd number of digits.");
+    run_eval_error_test(
+        "(hex-decode \"+f\")",
+        "Invalid hex: unexpected digits '+f'.",
+    );
+    run_eval_error_test(
+        "(hex-decode \"-1\")",
+        "Invalid hex: unexpected digits '-1'.",
+    );
+    // The string decoders refuse binary data; the -bytes decoders always
+    // return byte numbers, which round-trip.
+    run_eval_error_test(
+        "(hex-decode \"ff\")",
+        "'hex-decode' decoded bytes that are not UTF-8 text; use 'hex-decode-bytes'.",
+    );
+    run_eval_test("(hex-decode-bytes \"ff\")", datum("(255)"));
+    run_eval_test("(hex-decode-bytes \"6869\")", datum("(104 105)"));
+    run_eval_test("(hex-decode-bytes \"00c328ff\")", datum("(0 195 40 255)"));
+    run_eval_test(
+        "(hex-encode (hex-decode-bytes \"00c328ff\"))",
+        Ok(Expr::String("00c328ff".into())),
+    );
+    run_eval_test(
+        "(base64-encode (base64-decode-bytes \"/wD+\"))",
+        Ok(Expr::String("/wD+".into())),
+    );
+    run_eval_test("(base64-decode-bytes \"/wD+\")", datum("(255 0 254)"));
+    run_eval_error_test(
+        "(base64-decode \"/wD+\")",
+        "'base64-decode' decoded bytes that are not UTF-8 text; use 'base64-decode-bytes'.",
+    );
+}
+
+#[cfg(feature = "hash")]
+#[test]
+fn test_hash_digests() {
+    run_eval_test(
+        "(sha256 \"abc\")",
+        Ok(Expr::String(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".into(),
+        )),
+    );
+    run_eval_test(
+        "(sha1 \"abc\")",
+        Ok(Expr::String(
+            "a9993e364706816aba3e25717850c26c9cd0d89d".into(),
+        )),
+    );
+    run_eval_test(
+        "(md5 \"\")",
+        Ok(Expr::String("d41d8cd98f00b204e9800998ecf8427e".into())),
+    );
+}
+
+#[cfg(feature = "hash")]
+#[test]
+fn test_hash_multi_block_input() {
+    // 56 bytes forces the padding into a second block.
+    let digest =
+        minilisp_rust::hash::sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq");
+    assert_eq!(
+        minilisp_rust::codec::hex_encode(&digest),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+    assert_eq!(
+        minilisp_rust::codec::hex_encode(&minilisp_rust::hash::md5(
+            b"The quick brown fox jumps over the lazy dog"
+        )),
+        "9e107d9d372bb6826bd81d3542a419d6"
+    );
+}
+
+#[test]
+fn test_uuid_is_version_4() {
+    let mut env = Env::new();
+    let Ok(Expr::String(id)) = eval(&parse("(uuid)").unwrap(), &mut env) else {
+        panic!("uuid should return a string");
+    };
+    let groups: Vec<usize> = id.split('-').map(str::len).collect();
+    assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+    assert_eq!(&id[14..15], "4");
+    assert!("89ab".contains(&id[19..20]));
+}
+
+#[test]
+fn test_uuid_is_deterministic_with_injected_source() {
+    minilisp_rust::runtime::set_random_source(minilisp_rust::runtime::SplitMix64::new(7));
+    let mut env = Env::new();
+    let first = eval(&parse("(uuid)").unwrap(), &mut env);
+    minilisp_rust::runtime::set_random_source(minilisp_rust::runtime::SplitMix64::new(7));
+    let second = eval(&parse("(uuid)").unwrap(), &mut env);
+    assert_eq!(first, second);
+    assert_ne!(first, eval(&parse("(uuid)").unwrap(), &mut env));
+}
+
+#[test]
+fn test_csv_parse_handles_quoting() {
+    let rows =
+        minilisp_rust::csv::parse("a,\"b,c\",\"say \"\"hi\"\"\"\r\n1,\"two\nlines\",\n").unwrap();
+    assert_eq!(
+        rows,
+        vec![vec!["a", "b,c", "say \"hi\""], vec!["1", "two\nlines", ""],]
+    );
+    // A final record holding only an empty quoted field is still a record.
+    assert_eq!(
+        minilisp_rust::csv::parse("a,b\n\"\"").unwrap(),
+        vec![vec!["a", "b"], vec![""]]
+    );
+    assert_eq!(
+        minilisp_rust::csv::parse("a\n\"\"\n").unwrap(),
+        vec![vec!["a"], vec![""]]
+    );
+    assert_eq!(
+        minilisp_rust::csv::parse("\"open"),
+        Err("Invalid CSV: unterminated quoted field starting on line 1.".to_string())
+    );
+}
+
+#[test]
+fn test_csv_builtins() {
+    let mut env = Env::new();
+    env.insert(
+        "text".to_string(),
+        Expr::String("name,age\nada,36\nalan,41\n".to_string().into()),
+    );
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    assert_eq!(
+        run("(csv-parse text)"),
+        datum("((\"name\" \"age\") (\"ada\" \"36\") (\"alan\" \"41\"))")
+    );
+    assert_eq!(
+        run("(map-get (find (lambda (r) true) (csv-parse text true)) \"age\")"),
+        Ok(Expr::String("36".into()))
+    );
+    assert_eq!(
+        run("(csv-parse \"a,b\\n\\\"\\\"\")"),
+        datum("((\"a\" \"b\") (\"\"))")
+    );
+    assert_eq!(
+        run("(csv-write (list (list \"a,b\" 1 true)))"),
+        Ok(Expr::String("\"a,b\",1,true\n".to_string().into()))
+    );
+}
+
+#[test]
+fn test_script_exit_codes() {
+    let output = run_script(
+        "exit.lisp",
+        "(if (> 2 1) (exit 3) (print \"unreachable\"))",
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    let output = run_script("error.lisp", "(+ 1 \"one\")", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("requires number arguments"));
+    let output = run_script("ok.lisp", "(+ 1 1)", &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_exit_policy_in_embedded_mode() {
+    use minilisp_rust::runtime::{ExitPolicy, set_exit_policy};
+
+    run_eval_error_test("(exit 2)", "Exit requested with status 2.");
+    set_exit_policy(ExitPolicy::Ignore);
+    run_eval_test("(exit)", Ok(Expr::empty_list()));
+}
+
+// --- Function Utility Tests ---
+
+#[test]
+fn test_memoize_caches_results() {
+    let mut env = Env::new();
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define counter (lambda (x) (list x (random 1000000000))))").unwrap();
+    run("(define cached (memoize counter))").unwrap();
+    // A fresh random draw would differ; a cached result repeats exactly.
+    assert_eq!(run("(equal? (cached 1) (cached 1))"), Ok(Expr::Bool(true)));
+    assert_eq!(run("(equal? (cached 1) (cached 2))"), Ok(Expr::Bool(false)));
+    assert_eq!(run("(procedure? cached)"), Ok(Expr::Bool(true)));
+}
+
+#[test]
+fn test_memoized_recursion() {
+    let mut env = Env::new();
+    eval(
+        &parse(
+            "(define fib (memoize (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))))",
+        )
+        .unwrap(),
+        &mut env,
+    )
+    .unwrap();
+    // Without the cache this would take on the order of 2^60 calls.
+    let result = eval(&parse("(fib 60)").unwrap(), &mut env);
+    assert_eq!(result, Ok(Expr::Number(1548008755920.0)));
+}
+
+#[test]
+fn test_trace_prints_nested_calls() {
+    use minilisp_rust::runtime::{OutputBuffer, set_output};
+
+    let buffer = OutputBuffer::new();
+    set_output(buffer.clone());
+    let mut env = Env::new();
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define fact (lambda (n) (if (< n 2) 1 (* n (fact (- n 1))))))").unwrap();
+    assert_eq!(run("(trace fact)"), datum("(fact)"));
+    assert_eq!(run("(fact 3)"), Ok(Expr::Number(6.0)));
+    assert_eq!(
+        buffer.contents(),
+        "> (fact 3)\n  > (fact 2)\n    > (fact 1)\n    < 1\n  < 2\n< 6\n"
+    );
+
+    run("(untrace fact)").unwrap();
+    run("(fact 3)").unwrap();
+    assert_eq!(buffer.contents().lines().count(), 6);
+}
+
+#[test]
+fn test_check_forms() {
+    run_eval_test("(check-equal? (list 1 2) (list 1 2))", Ok(Expr::Bool(true)));
+    run_eval_error_test(
+        "(check-equal? (+ 1 1) 3)",
+        "check-equal? failed: expected 3, got 2.",
+    );
+    run_eval_test("(check-error (/ 1 0) \"zero\")", Ok(Expr::Bool(true)));
+    run_eval_error_test(
+        "(check-error (+ 1 1))",
+        "check-error failed: (+ 1 1) returned 2 instead of failing.",
+    );
+}
+
+#[test]
+fn test_deftest_and_run_tests() {
+    use minilisp_rust::runtime::{OutputBuffer, set_output};
+
+    let buffer = OutputBuffer::new();
+    set_output(buffer.clone());
+    let mut env = Env::new();
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define double (lambda (x) (* 2 x)))").unwrap();
+    run("(deftest doubling (check-equal? (double 2) 4) (check-error (double \"a\")))").unwrap();
+    run("(deftest broken (check-equal? (double 2) 5))").unwrap();
+    assert_eq!(run("(run-tests)"), datum("(1 1)"));
+    assert_eq!(
+        buffer.contents(),
+        "PASS doubling\nFAIL broken: check-equal? failed: expected 5, got 4.\n1 passed, 1 failed\n"
+    );
+}
+
+// --- Association List Tests ---
+
+#[test]
+fn test_alist_get_update_remove() {
+    let mut env = Env::new();
+    let mut run = |src: &str| eval(&parse(src).unwrap(), &mut env).map_err(|e| e.to_string());
+    run("(define al (list (list \"a\" 1) (list (string->symbol \"b\") 2)))").unwrap();
+    assert_eq!(run("(alist-get al \"a\")"), Ok(Expr::Number(1.0)));
+    assert_eq!(
+        run("(alist-get al (string->symbol \"b\"))"),
+        Ok(Expr::Number(2.0))
+    );
+    assert_eq!(run("(alist-get al \"z\" 0)"), Ok(Expr::Number(0.0)));
+    assert_eq!(
+        run("(alist-get (alist-update al \"a\" 10) \"a\")"),
+        Ok(Expr::Number(10.0))
+    );
+    assert_eq!(run("(alist-update (list) \"c\" 3)"), datum("((\"c\" 3))"));
+    assert_eq!(run("(alist-remove al \"a\")"), datum("((b 2))"));
+    assert_eq!(
+        run("(alist-get al \"z\")"),
+        Err("Key \"z\" not found in alist.".to_string())
+    );
+}
+
+#[test]
+fn test_alist_map_conversions() {
+    run_eval_test(
+        "(map->alist (alist->map (list (list \"b\" 2) (list \"a\" 1) (list \"b\" 3))))",
+        datum("((\"a\" 1) (\"b\" 2))"),
+    );
+    run_eval_error_test(
+        "(alist->map (list (list \"a\")))",
+        "'alist->map' requires an alist of (key value) entries.",
+    );
+}
+
+// --- Error Handling Tests ---
+
+#[test]
+fn test_try_catches_builtin_errors_as_conditions() {
+    run_eval_test("(try (+ 1 2) (catch e 0))", Ok(Expr::Number(3.0)));
+    run_eval_test(
+        "(try (undefined-var) (catch e (condition-message e)))",
+        Ok(Expr::String(
+            "Variable 'undefined-var' not found.".to_string().into(),
+        )),
+    );
+    run_eval_test(
+        "(try (sqrt \"x\") (catch e (list (condition? e) (condition-kind e) (condition-data e))))",
+        datum("(true type-mismatch ())"),
+    );
+    // Each kind of error is caught as a condition of its own kind.
+    for (input, kind) in [
+        ("(< 1 \"2\")", "type-mismatch"),
+        ("undefined-var", "unbound-variable"),
+        ("((lambda (x) x))", "arity-mismatch"),
+        ("(1 2)", "not-a-function"),
+        ("(/ 1 0)", "division-by-zero"),
+        ("(read-string \"(\")", "parse-error"),
+        ("(raise (string->symbol \"mine\") \"m\")", "mine"),
+        ("(flatten (list) -1)", "error"),
+    ] {
+        run_eval_test(
+            &format!("(try {} (catch e (condition-kind e)))", input),
+            Ok(Expr::Symbol(kind.into())),
+        );
+    }
+    run_eval_error_test(
+        "(try (+ 1 2) (handle e 0))",
+        "'try' requires a (catch name handler) clause.",
+    );
+}
+
+#[test]
+fn test_raise_signals_structured_conditions() {
+    run_eval_test(
+        "(try (raise (string->symbol \"not-found\") \"no such user\" (list 42)) \
+         (catch e (list (condition-kind e) (condition-message e) (condition-data e))))",
+        datum("(not-found \"no such user\" (42))"),
+    );
+    run_eval_error_test("(raise (string->symbol \"oops\") \"boom\")", "boom");
+    run_eval_error_test(
+        "(condition-kind 1)",
+        "'condition-kind' requires a condition.",
+    );
+}
+
+#[test]
+fn test_errors_are_structured() {
+    use minilisp_rust::{EvalError, ParseError};
+
+    let run = |input: &str| eval(&parse(input).unwrap(), &mut Env::new()).unwrap_err();
+    assert_eq!(run("nope"), EvalError::UnboundVariable("nope".to_string()));
+    assert_eq!(
+        run("((lambda (x) x) 1 2)"),
+        EvalError::ArityMismatch {
+            expected: 1,
+            got: 2
+        }
+    );
+    assert_eq!(
+        run("(string-length 5)"),
+        EvalError::TypeMismatch {
+            op: "string-length".to_string(),
+            expected: "a string argument".to_string(),
+            got: Expr::Number(5.0),
+        }
+    );
+    assert_eq!(run("(/ 1 0)"), EvalError::DivisionByZero);
+    assert_eq!(run("(1 2)"), EvalError::NotAFunction(Expr::Number(1.0)));
+    assert_eq!(
+        run("(raise (string->symbol \"oops\") \"boom\" 7)"),
+        EvalError::User {
+            kind: "oops".to_string(),
+            message: "boom".to_string(),
+            data: Expr::Number(7.0),
+        }
+    );
+    assert!(matches!(
+        run("(read-string \"(\")"),
+        EvalError::Parse(ParseError::Incomplete { .. })
+    ));
+    assert_eq!(
+        run("(string-length 5)").to_string(),
+        "'string-length' requires a string argument."
+    );
+
+    let error = parse("(a))").unwrap_err();
+    assert!(matches!(error, ParseError::Syntax { .. }));
+    assert_eq!(error.message(), "Unexpected tokens after main expression.");
+    assert_eq!(error.position().map(|p| p.column), Some(4));
+}
+
+#[test]
+fn test_errors_carry_the_calls_they_passed_through() {
+    use minilisp_rust::EvalError;
+
+    let mut env = Env::new();
+    for input in [
+        "(define helper (lambda (x) (+ x \"one\")))",
+        "(define fib (lambda (n) (+ 1 (helper n))))",
+        "(define countdown (lambda (n) (+ 1 (if (= n 0) (fib n) (countdown (- n 1))))))",
+        "(define tail (lambda (n) (helper n)))",
+    ] {
+        eval(&parse(input).unwrap(), &mut env).unwrap();
+    }
+
+    let error = eval(&parse("(fib 1)").unwrap(), &mut env).unwrap_err();
+    assert_eq!(error.backtrace(), ["helper", "fib"]);
+    assert!(!matches!(error.root(), EvalError::Backtrace { .. }));
+    assert_eq!(
+        *error.root(),
+        EvalError::TypeMismatch {
+            op: "+".to_string(),
+            expected: "number arguments".to_string(),
+            got: Expr::String("one".into()),
+        }
+    );
+    assert_eq!(error.to_string(), "Operator '+' requires number arguments.");
+    assert_eq!(
+        error.describe(),
+        "in fib → in helper → Operator '+' requires number arguments."
+    );
+
+    let error = eval(&parse("(countdown 3)").unwrap(), &mut env).unwrap_err();
+    assert!(
+        error
+            .describe()
+            .starts_with("in countdown (×4) → in fib → in helper → ")
+    );
+
+    // A call in tail position replaces its caller.
+    let error = eval(&parse("(tail 1)").unwrap(), &mut env).unwrap_err();
+    assert_eq!(error.backtrace(), ["helper"]);
+
+    // `try` sees the error itself, not the calls around it.
+    eval(
+        &parse("(define thrower (lambda () (raise (string->symbol \"oops\") \"boom\")))").unwrap(),
+        &mut env,
+    )
+    .unwrap();
+    let caught = eval(&parse("(try (thrower) (catch c c))").unwrap(), &mut env).unwrap();
+    assert!(matches!(caught, Expr::Condition { kind, .. } if kind == "oops"));
+
+    let error = eval(&parse("(+ 1 \"one\")").unwrap(), &mut env).unwrap_err();
+    assert!(error.backtrace().is_empty());
+}
+
+#[test]
+fn test_call_depth_limit() {
+    use minilisp_rust::EvalError;
+    use minilisp_rust::runtime::{DEFAULT_MAX_CALL_DEPTH, max_call_depth, set_max_call_depth};
+
+    assert_eq!(max_call_depth(), DEFAULT_MAX_CALL_DEPTH);
+    set_max_call_depth(50);
+
+    let mut env = Env::new();
+    let omega = "((lambda (f) (+ 1 (f f))) (lambda (f) (+ 1 (f f))))";
+    let error = eval(&parse(omega).unwrap(), &mut env).unwrap_err();
+    assert_eq!(*error.root(), EvalError::RecursionLimit(50));
+    assert_eq!(error.to_string(), "Maximum recursion depth exceeded.");
+
+    let caught = format!("(try {} (catch c (condition-message c)))", omega);
+    assert_eq!(
+        eval(&parse(&caught).unwrap(), &mut env).unwrap(),
+        Expr::String("Maximum recursion depth exceeded.".into())
+    );
+
+    // The depth is back to zero after an error, so the full limit is
+    // available again.
+    let down = "(define down (lambda (n) (if (= n 0) 0 (+ 1 (down (- n 1))))))";
+    eval(&parse(down).unwrap(), &mut env).unwrap();
+    assert_eq!(
+        eval(&parse("(down 49)").unwrap(), &mut env).unwrap(),
+        Expr::Number(49.0)
+    );
+    assert!(eval(&parse("(down 50)").unwrap(), &mut env).is_err());
+}
+
+#[test]
+fn test_endless_tail_calls_are_stopped_by_fuel_or_a_timeout() {
+    use minilisp_rust::EvalError;
+    use minilisp_rust::runtime::{set_fuel, set_max_call_depth};
+    use std::time::Duration;
+
+    // In tail position each call replaces the last, so the depth limit never
+    // trips and only fuel or a timeout ends the loop.
+    set_max_call_depth(50);
+    let mut env = Env::new();
+    let omega = parse("((lambda (f) (f f)) (lambda (f) (f f)))").unwrap();
+    set_fuel(Some(100_000));
+    let error = eval(&omega, &mut env).unwrap_err();
+    set_fuel(None);
+    assert_eq!(*error.root(), EvalError::FuelExhausted);
+
+    let error =
+        minilisp_rust::eval_with_timeout(&omega, &mut env, Duration::from_millis(50)).unwrap_err();
+    assert_eq!(*error.root(), EvalError::Timeout);
+}
+
+#[test]
+fn test_evaluation_does_not_use_the_thread_stack() {
+    use minilisp_rust::runtime::set_max_call_depth;
+
+    let mut env = Env::new();
+    let run = |input: &str, env: &mut Env| eval(&parse(input).unwrap(), env).unwrap();
+    run(
+        "(define down (lambda (n) (if (= n 0) 0 (+ 1 (down (- n 1))))))",
+        &mut env,
+    );
+    run(
+        "(define loop (lambda (n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1)))))",
+        &mut env,
+    );
+
+    // Tail calls do not nest, so they are not limited by the call depth.
+    assert_eq!(run("(loop 100000 0)", &mut env), Expr::Number(100000.0));
+
+    // Far deeper than the test thread's stack would allow for recursive
+    // evaluation.
+    set_max_call_depth(100_000);
+    assert_eq!(run("(down 50000)", &mut env), Expr::Number(50000.0));
+}
+
+#[test]
+fn test_recursion_through_builtins_does_not_use_the_thread_stack() {
+    use minilisp_rust::runtime::{MAX_NESTED_EVALUATIONS, set_builtins, set_max_call_depth};
+    use minilisp_rust::{Builtin, Builtins, EvalError};
+
+    std::thread::Builder::new()
+        .stack_size(2 << 20)
+        .spawn(|| {
+            let mut env = Env::new();
+            let run = |input: &str, env: &mut Env| eval(&parse(input).unwrap(), env);
+            for (name, call) in [
+                (
+                    "p",
+                    "(if (list? (partition (lambda (x) (number? (p (- n 1)))) (list 1))) 1 0)",
+                ),
+                (
+                    "a",
+                    "(if (any? (lambda (x) (number? (a (- n 1)))) (list 1)) 1 0)",
+                ),
+                ("c", "(count (lambda (x) (number? (c (- n 1)))) (list 1))"),
+                (
+                    "g",
+                    "(if (list? (group-by (lambda (x) (g (- n 1))) (list 1))) 1 0)",
+                ),
+                (
+                    "m",
+                    "(if (list? (flatmap (lambda (x) (list (m (- n 1)))) (list 1))) 1 0)",
+                ),
+            ] {
+                let define = format!("(define {} (lambda (n) (if (= n 0) 0 {})))", name, call);
+                run(&define, &mut env).unwrap();
+                set_max_call_depth(100_000);
+                let deep = format!("({} 20000)", name);
+                assert!(run(&deep, &mut env).is_ok(), "{}", name);
+
+                // Past the call depth limit it fails, rather than crashing.
+                set_max_call_depth(1000);
+                let error = run(&deep, &mut env).unwrap_err();
+                assert_eq!(*error.root(), EvalError::RecursionLimit(1000), "{}", name);
+            }
+
+            // Host functions that evaluate code nest on the thread stack, so
+            // they can only nest so deeply.
+            let mut builtins = Builtins::standard();
+            builtins.insert(
+                "nest",
+                Builtin::new(|_| eval(&parse("(nest)").unwrap(), &mut Env::new())),
+            );
+            set_builtins(builtins);
+            let error = run("(nest)", &mut env).unwrap_err();
+            assert_eq!(
+                *error.root(),
+                EvalError::RecursionLimit(MAX_NESTED_EVALUATIONS)
+            );
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_fuel_limits_evaluation_steps() {
+    use minilisp_rust::EvalError;
+    use minilisp_rust::runtime::{fuel, set_fuel};
+
+    let mut env = Env::new();
+    let run = |input: &str, env: &mut Env| eval(&parse(input).unwrap(), env);
+    run("(define spin (lambda () (spin)))", &mut env).unwrap();
+
+    set_fuel(Some(1000));
+    let error = run("(spin)", &mut env).unwrap_err();
+    assert_eq!(*error.root(), EvalError::FuelExhausted);
+    assert_eq!(error.to_string(), "Evaluation ran out of fuel.");
+    assert_eq!(fuel(), Some(0));
+
+    // Running out of fuel cannot be caught.
+    set_fuel(Some(1000));
+    let error = run("(try (spin) (catch c 0))", &mut env).unwrap_err();
+    assert_eq!(*error.root(), EvalError::FuelExhausted);
+    set_fuel(Some(1000));
+    let error = run("(check-error (spin))", &mut env).unwrap_err();
+    assert_eq!(*error.root(), EvalError::FuelExhausted);
+
+    // Each expression evaluated takes a step.
+    set_fuel(Some(2));
+    assert_eq!(run("(+ 1 (* 2 3))", &mut env).unwrap(), Expr::Number(7.0));
+    assert_eq!(fuel(), Some(0));
+
+    set_fuel(None);
+    assert_eq!(run("(+ 1 2)", &mut env).unwrap(), Expr::Number(3.0));
+    assert_eq!(fuel(), None);
+}
+
+#[test]
+fn test_eval_with_timeout() {
+    use minilisp_rust::{EvalError, eval_with_timeout};
+    use std::time::{Duration, Instant};
+
+    let mut env = Env::new();
+    eval(
+        &parse("(define spin (lambda () (spin)))").unwrap(),
+        &mut env,
+    )
+    .unwrap();
+
+    let start = Instant::now();
+    let spin = parse("(spin)").unwrap();
+    let error = eval_with_timeout(&spin, &mut env, Duration::from_millis(50)).unwrap_err();
+    assert_eq!(*error.root(), EvalError::Timeout);
+    assert_eq!(error.to_string(), "Evaluation timed out.");
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // Timeouts cannot be caught.
+    let caught = parse("(try (spin) (catch c 0))").unwrap();
+    let error = eval_with_timeout(&caught, &mut env, Duration::from_millis(50)).unwrap_err();
+    assert_eq!(*error.root(), EvalError::Timeout);
+
+    let quick = parse("(+ 1 2)").unwrap();
+    assert_eq!(
+        eval_with_timeout(&quick, &mut env, Duration::from_secs(60)).unwrap(),
+        Expr::Number(3.0)
+    );
+    // The deadline only applies while the timed evaluation runs.
+    assert_eq!(eval(&quick, &mut env).unwrap(), Expr::Number(3.0));
+}
+
+#[test]
+fn test_memory_limit() {
+    use minilisp_rust::EvalError;
+    use minilisp_rust::runtime::{memory_used, set_memory_limit};
+
+    let mut env = Env::new();
+    let run = |input: &str, env: &mut Env| eval(&parse(input).unwrap(), env);
+    run("(define grow (lambda (s) (grow (concat s s))))", &mut env).unwrap();
+
+    set_memory_limit(Some(1 << 20));
+    let error = run("(grow \"ab\")", &mut env).unwrap_err();
+    assert_eq!(*error.root(), EvalError::MemoryLimit(1 << 20));
+    assert_eq!(error.to_string(), "Memory limit exceeded.");
+    let used = memory_used();
+    assert!(used > 1 << 19 && used <= 1 << 20, "{}", used);
+
+    // The error can be caught, and the value that would have exceeded the
+    // limit is not counted.
+    assert_eq!(
+        run(
+            "(try (grow \"ab\") (catch c (condition-message c)))",
+            &mut env
+        )
+        .unwrap(),
+        Expr::String("Memory limit exceeded.".into())
+    );
+
+    set_memory_limit(None);
+    assert_eq!(memory_used(), 0);
+    assert_eq!(
+        run("(string-length (concat \"ab\" \"cd\"))", &mut env).unwrap(),
+        Expr::Number(4.0)
+    );
+}
+
+#[test]
+fn test_memory_limit_counts_only_new_allocations() {
+    use minilisp_rust::EvalError;
+    use minilisp_rust::runtime::{memory_used, set_memory_limit};
+
+    let mut env = Env::new();
+    let run = |input: &str, env: &mut Env| eval(&parse(input).unwrap(), env);
+    set_memory_limit(Some(10 << 20));
+    run(
+        "(define big (string->list (format \"~100000a\" \"\")))",
+        &mut env,
+    )
+    .unwrap();
+    let before = memory_used();
+
+    // A list sharing a large one counts as a few cells, however often it
+    // is built.
+    run(
+        "(define spin (lambda (i last) (if (= i 0) last (spin (- i 1) (list i big)))))",
+        &mut env,
+    )
+    .unwrap();
+    run("(spin 2000 (list))", &mut env).unwrap();
+    let spun = memory_used() - before;
+    assert!(spun < 1 << 20, "{}", spun);
+
+    // So does each level of a deeply nested list.
+    run(
+        "(define nest (lambda (i acc) (if (= i 0) acc (nest (- i 1) (list i acc)))))",
+        &mut env,
+    )
+    .unwrap();
+    run("(nest 3000 (list))", &mut env).unwrap();
+    assert!(memory_used() - before - spun < 1 << 20, "{}", memory_used());
+
+    // A value too large for the limit is refused before it is built.
+    let used = memory_used();
+    for input in [
+        "(format \"~100000000000a\" 1)",
+        "(concat (format \"~4000000a\" 1) (format \"~4000000a\" 1))",
+        "(string-replace (format \"~1000a\" \"\") \" \" (format \"~100000a\" \"\"))",
+    ] {
+        let error = run(input, &mut env).unwrap_err();
+        assert_eq!(*error.root(), EvalError::MemoryLimit(10 << 20), "{}", input);
+    }
+    assert!(memory_used() - used < 9 << 20, "{}", memory_used());
+    set_memory_limit(None);
+}
+
+#[test]
+fn test_interpreter() {
+    use minilisp_rust::{EvalError, Interpreter};
+
+    let mut interp = Interpreter::new()
+        .with_stdlib()
+        .with_builtin("double", |args| match args {
+            [Expr::Number(n)] => Ok(Expr::Number(n * 2.0)),
+            _ => Err("'double' requires a number.".into()),
+        });
+    assert_eq!(
+        interp.eval_str("(define r 2) (double (* pi r))").unwrap(),
+        Expr::Number(4.0 * std::f64::consts::PI)
+    );
+    assert_eq!(interp.env().get("r"), Some(Expr::Number(2.0)));
+    assert_eq!(interp.eval_str("").unwrap(), Expr::empty_list());
+    assert_eq!(
+        interp.eval_str("(double \"x\")").unwrap_err().to_string(),
+        "'double' requires a number."
+    );
+    assert!(matches!(
+        interp.eval_str("(double").unwrap_err(),
+        EvalError::Parse(_)
+    ));
+    assert_eq!(
+        Interpreter::new().eval_str("pi").unwrap_err(),
+        EvalError::UnboundVariable("pi".to_string())
+    );
+
+    // The fuel budget applies to each evaluation.
+    let mut interp = Interpreter::new().with_fuel(1000);
+    interp.eval_str("(define spin (lambda () (spin)))").unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            *interp.eval_str("(spin)").unwrap_err().root(),
+            EvalError::FuelExhausted
+        );
+        assert_eq!(interp.eval_str("(+ 1 2)").unwrap(), Expr::Number(3.0));
+    }
+    assert_eq!(minilisp_rust::runtime::fuel(), None);
+}
+
+#[test]
+fn test_native_functions_are_values() {
+    use minilisp_rust::{Interpreter, NativeFn};
+
+    let mut interp = Interpreter::new();
+    interp.register("positive", |args| match args {
+        [Expr::Number(n)] => Ok(Expr::Bool(*n > 0.0)),
+        _ => Err("'positive' requires a number.".into()),
+    });
+    let mut run = |input: &str| interp.eval_str(input).unwrap();
+    assert_eq!(run("(positive 2)"), Expr::Bool(true));
+    assert_eq!(run("((lambda (f) (f -1)) positive)"), Expr::Bool(false));
+    assert_eq!(run("(any? positive (list -1 0 3))"), Expr::Bool(true));
+    assert_eq!(run("(procedure? positive)"), Expr::Bool(true));
+    assert_eq!(run("(eqv? positive positive)"), Expr::Bool(true));
+    assert_eq!(run("((memoize positive) 5)"), Expr::Bool(true));
+    assert_eq!(run("positive").to_string(), "<function>");
+
+    let native = NativeFn::new(|args| Ok(Expr::Number(args.len() as f64)));
+    assert_eq!(native.call(&[Expr::Bool(true)]), Ok(Expr::Number(1.0)));
+    assert_eq!(native, native.clone());
+    assert_ne!(native, NativeFn::new(|_| Ok(Expr::Bool(true))));
+}
+
+#[test]
+fn test_native_fn_macro_converts_arguments() {
+    use minilisp_rust::{EvalError, Interpreter, native_fn};
+
+    fn scale(x: f64, unit: &str) -> Result<f64, String> {
+        match unit {
+            "km" => Ok(x * 1000.0),
+            "m" => Ok(x),
+            _ => Err(format!("Unknown unit '{}'.", unit)),
+        }
+    }
+
+    let mut interp = Interpreter::new()
+        .with_builtin("scale", native_fn!(scale(f64, &str)))
+        .with_builtin(
+            "repeat",
+            native_fn!("repeat", |s: &str, times: usize| s.repeat(times)),
+        )
+        .with_builtin(
+            "evens",
+            native_fn!("evens", |items: &[Expr]| items
+                .iter()
+                .step_by(2)
+                .cloned()
+                .collect::<Vec<_>>()),
+        )
+        .with_builtin("nothing", native_fn!("nothing", || ()));
+
+    let mut run = |input: &str| interp.eval_str(input);
+    assert_eq!(run("(scale 2 \"km\")").unwrap(), Expr::Number(2000.0));
+    assert_eq!(
+        run("(repeat \"ab\" 3)").unwrap(),
+        Expr::String("ababab".into())
+    );
+    assert_eq!(
+        run("(evens (list 1 2 3))").unwrap(),
+        Expr::List(vec![Expr::Number(1.0), Expr::Number(3.0)].into())
+    );
+    assert_eq!(run("(nothing)").unwrap(), Expr::empty_list());
+
+    assert_eq!(
+        run("(scale 2 \"mi\")").unwrap_err().to_string(),
+        "Unknown unit 'mi'."
+    );
+    assert_eq!(
+        run("(scale 2)").unwrap_err(),
+        EvalError::ArityMismatch {
+            expected: 2,
+            got: 1
+        }
+    );
+    assert_eq!(
+        run("(scale \"2\" \"km\")").unwrap_err().to_string(),
+        "'scale' requires a number as argument 1."
+    );
+    assert_eq!(
+        run("(repeat \"ab\" 1.5)").unwrap_err().to_string(),
+        "'repeat' requires a non-negative integer as argument 2."
+    );
+}
+
+#[test]
+fn test_builtin_registry() {
+    use minilisp_rust::builtins::{Arity, Category};
+    use minilisp_rust::{Builtin, Builtins, EvalError, Interpreter};
+
+    let standard = Builtins::standard();
+    let substring = standard.get("substring").unwrap();
+    assert_eq!(substring.category(), Category::Strings);
+    assert_eq!(substring.arity(), Arity::range(2, 3));
+    assert!(substring.arity().accepts(3) && !substring.arity().accepts(4));
+    assert!(!substring.doc().is_empty());
+    assert!(standard.names().contains(&"read-file"));
+
+    // Builtins can be added, overridden and removed.
+    let mut interp = Interpreter::new();
+    interp.builtins_mut().insert(
+        "+",
+        Builtin::new(|args| Ok(Expr::Number(args.len() as f64)))
+            .with_arity(Arity::at_least(0))
+            .with_doc("Counts its arguments."),
+    );
+    interp.builtins_mut().remove("upcase");
+    assert_eq!(interp.eval_str("(+ 5 5 5)").unwrap(), Expr::Number(3.0));
+    assert_eq!(
+        interp.eval_str("(upcase \"a\")").unwrap_err(),
+        EvalError::UnboundVariable("upcase".to_string())
+    );
+    assert_eq!(
+        interp.builtins().get("+").unwrap().doc(),
+        "Counts its arguments."
+    );
+    // The thread's own builtins are untouched.
+    assert_eq!(
+        eval(&parse("(+ 5 5 5)").unwrap(), &mut default_env()).unwrap(),
+        Expr::Number(15.0)
+    );
+
+    // Removing a builtin lets a variable of the same name be called.
+    interp.eval_str("(define upcase (lambda (s) s))").unwrap();
+    assert_eq!(
+        interp.eval_str("(upcase \"a\")").unwrap(),
+        Expr::String("a".into())
+    );
+
+    let mut sandboxed = Interpreter::new()
+        .without_category(Category::Filesystem)
+        .without_category(Category::Process);
+    assert_eq!(
+        sandboxed
+            .eval_str("(read-file \"/etc/passwd\")")
+            .unwrap_err(),
+        EvalError::UnboundVariable("read-file".to_string())
+    );
+    assert_eq!(
+        sandboxed.eval_str("(shell \"ls\")").unwrap_err(),
+        EvalError::UnboundVariable("shell".to_string())
+    );
+    assert_eq!(sandboxed.eval_str("(* 6 7)").unwrap(), Expr::Number(42.0));
+
+    let mut bare = Interpreter::new().with_builtins(Builtins::empty());
+    assert_eq!(
+        bare.eval_str("(list 1)").unwrap_err(),
+        EvalError::UnboundVariable("list".to_string())
+    );
+}
+
+#[test]
+fn test_conversions_between_exprs_and_rust_values() {
+    use minilisp_rust::convert::ConversionError;
+    use std::collections::HashMap;
+
+    assert_eq!(Expr::from(1.5), Expr::Number(1.5));
+    assert_eq!(Expr::from("hi"), Expr::String("hi".into()));
+    assert_eq!(Expr::from(true), Expr::Bool(true));
+    assert_eq!(
+        Expr::from(vec![1.0, 2.0]),
+        Expr::List(vec![Expr::Number(1.0), Expr::Number(2.0)].into())
+    );
+    assert_eq!(Expr::from(None::<f64>), Expr::empty_list());
+    let run = |input: &str| eval(&parse(input).unwrap(), &mut default_env()).unwrap();
+    assert_eq!(f64::try_from(run("(+ 1 2)")), Ok(3.0));
+    assert_eq!(
+        String::try_from(run("(concat \"a\" \"b\")")),
+        Ok("ab".to_string())
+    );
+    assert_eq!(bool::try_from(run("(< 1 2)")), Ok(true));
+    assert_eq!(Vec::<i64>::try_from(run("(list 1 2 3)")), Ok(vec![1, 2, 3]));
+    assert_eq!(Option::<f64>::try_from(run("(list)")), Ok(None));
+    let map = HashMap::from([("a".to_string(), vec!["x".to_string()])]);
+    assert_eq!(
+        Expr::from(map.clone()),
+        run("(hash-map \"a\" (list \"x\"))")
+    );
+    assert_eq!(Option::<f64>::try_from(run("7")), Ok(Some(7.0)));
+    assert_eq!(
+        HashMap::<String, Vec<String>>::try_from(run("(hash-map \"a\" (list \"x\"))")),
+        Ok(map)
+    );
+
+    let error = Vec::<f64>::try_from(run("(list 1 \"two\")")).unwrap_err();
+    assert_eq!(
+        error,
+        ConversionError {
+            expected: "a number",
+            got: Expr::String("two".into())
+        }
+    );
+    assert_eq!(error.to_string(), "Expected a number, but got \"two\".");
+    assert_eq!(
+        f64::try_from(Expr::Bool(true)).unwrap_err().expected,
+        "a number"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trips_data_values() {
+    let value = eval(
+        &parse("(list (string->symbol \"sym\") \"str\" 1.5 true (hash-map \"k\" (list)) (try (raise (string->symbol \"oops\") \"bad\" 7) (catch c c)))")
+            .unwrap(),
+        &mut default_env(),
+    )
+    .unwrap();
+    let json = serde_json::to_string(&value).unwrap();
+    assert_eq!(serde_json::from_str::<Expr>(&json).unwrap(), value);
+    assert_eq!(
+        serde_json::to_string(&Expr::Symbol("x".into())).unwrap(),
+        r#"{"Symbol":"x"}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Expr>(r#"{"List":[{"Number":1.0},{"String":"a"}]}"#).unwrap(),
+        Expr::List(vec![Expr::Number(1.0), Expr::String("a".into())].into())
+    );
+
+    let func = eval(&parse("(lambda (x) x)").unwrap(), &mut default_env()).unwrap();
+    assert!(
+        serde_json::to_string(&func)
+            .unwrap_err()
+            .to_string()
+            .contains("functions cannot be serialized")
+    );
+    assert!(serde_json::from_str::<Expr>(r#"{"Func":[]}"#).is_err());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn test_json_value_conversion() {
+    use serde_json::json;
+
+    let value = eval(
+        &parse("(hash-map \"id\" 7 \"tags\" (list \"a\" (string->symbol \"b\")) \"ratio\" 0.5 \"ok\" true)")
+            .unwrap(),
+        &mut default_env(),
+    )
+    .unwrap();
+    assert_eq!(
+        value.to_json().unwrap(),
+        json!({"id": 7, "tags": ["a", "b"], "ratio": 0.5, "ok": true})
+    );
+    assert_eq!(
+        Expr::from_json(json!({"xs": [1, 2.5, null], "name": "n"})),
+        eval(
+            &parse("(hash-map \"xs\" (list 1 2.5 (list)) \"name\" \"n\")").unwrap(),
+            &mut default_env()
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        Expr::Number(f64::INFINITY).to_json().unwrap_err(),
+        "Cannot represent inf in JSON."
+    );
+    let func = eval(&parse("(lambda (x) x)").unwrap(), &mut default_env()).unwrap();
+    assert_eq!(
+        func.to_json().unwrap_err(),
+        "Cannot represent a function in JSON."
+    );
+}
+
+#[test]
+fn test_compiled_code_matches_the_evaluator() {
+    use minilisp_rust::compile::{Vm, compile, eval_compiled};
+    use minilisp_rust::runtime::{OutputBuffer, fuel, set_fuel, set_output};
+
+    let programs = [
+        "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15)",
+        "(define loop (lambda (n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1))))) (loop 5000 0)",
+        "(define make-adder (lambda (n) (lambda (x) (+ x n)))) ((make-adder 3) 4)",
+        "(define f (lambda (x) (* x 2))) (flatmap (lambda (x) (list (f x))) (list 1 2 3))",
+        "(define slow (memoize (lambda (n) (if (< n 2) n (+ (slow (- n 1)) (slow (- n 2))))))) (slow 40)",
+        "(define helper (lambda (x) (string-length x))) (define outer (lambda (x) (+ 1 (helper x)))) (outer 1)",
+        "(define tail (lambda () (helper 1))) (tail)",
+        "(define g (lambda (x) x)) (g 1 2)",
+        "(if 1 2 3)",
+        "(5 1)",
+        "(define v 5) (v 1)",
+        "(try (raise (string->symbol \"oops\") \"bad\") (catch c (condition-message c)))",
+        "(define omega (lambda (f) (+ 1 (f f)))) (omega omega)",
+        "(define (x) 1)",
+        "(define counter (lambda (n) (if (= n 0) 0 (counter (- n 1))))) (trace counter) (counter 3)",
+        "(define id (lambda (x) x)) (trace id) (id (id 2))",
+        "(define x 1) (define y (if (< x 2) x (list x))) ((lambda (a) a) y)",
+    ];
+    for (program, budget) in programs
+        .iter()
+        .map(|program| (program, 10_000_000))
+        .chain([(&programs[0], 2000)])
+    {
+        // Both take the same fuel, and run out of it at the same point.
+        let run = |eval_form: fn(&Expr, &mut Env) -> Result<Expr, minilisp_rust::EvalError>| {
+            let buffer = OutputBuffer::new();
+            set_output(buffer.clone());
+            set_fuel(Some(budget));
+            let mut env = default_env();
+            let mut result = Ok(Expr::empty_list());
+            for form in minilisp_rust::parse_program(program).unwrap() {
+                result = eval_form(&form, &mut env);
+                if result.is_err() {
+                    break;
+                }
+            }
+            let left = fuel();
+            set_fuel(None);
+            (result, buffer.contents(), left)
+        };
+        assert_eq!(run(eval_compiled), run(eval), "for {}", program);
+    }
+
+    // A reused machine drops the bodies of functions that no longer exist.
+    let mut vm = Vm::new();
+    for i in 0..2000 {
+        let chunk = compile(&parse(&format!("((lambda (x) (+ x {})) 1)", i)).unwrap());
+        vm.run(&chunk, &mut default_env()).unwrap();
+    }
+    assert!(vm.compiled_bodies() <= 512, "{}", vm.compiled_bodies());
+
+    // Tail calls run in constant space, well past the call depth limit.
+    let mut env = default_env();
+    for form in minilisp_rust::parse_program(
+        "(define loop (lambda (n) (if (= n 0) 0 (loop (- n 1))))) (loop 100000)",
+    )
+    .unwrap()
+    {
+        eval_compiled(&form, &mut env).unwrap();
+    }
+}
+
+#[test]
+fn test_optimize_folds_constants() {
+    use minilisp_rust::optimize::optimize;
+    use minilisp_rust::{Builtin, Builtins, Interpreter};
+
+    let optimized = |input: &str| optimize(&parse(input).unwrap()).to_string();
+    assert_eq!(optimized("(+ 1 (* 2 3))"), "7");
+    assert_eq!(optimized("(< (- 5) (abs -3))"), "true");
+    assert_eq!(
+        optimized("(lambda (x) (+ x (* 2 3)))"),
+        "(lambda (x) (+ x 6))"
+    );
+    assert_eq!(optimized("(if (< 1 2) yes no)"), "yes");
+    assert_eq!(optimized("(if (= 1 2) (f 1) (g (+ 1 1)))"), "(g 2)");
+    assert_eq!(
+        optimized("(try (f (+ 1 1)) (catch e (- 3 1)))"),
+        "(try (f 2) (catch e 2))"
+    );
+    // Calls that fail, have effects or are not to builtins are kept.
+    assert_eq!(optimized("(/ 1 0)"), "(/ 1 0)");
+    assert_eq!(optimized("(if 1 2 3)"), "(if 1 2 3)");
+    assert_eq!(optimized("(random 10)"), "(random 10)");
+    assert_eq!(optimized("(+ x 1)"), "(+ x 1)");
+    assert_eq!(optimized("(check-error (+ 1 2))"), "(check-error (+ 1 2))");
+
+    // Optimized code gives the same value.
+    let program =
+        "(define fact (lambda (n) (if (= n 0) 1 (* n (fact (- n (- 2 1))))))) (fact (+ 2 3))";
+    let mut plain = Interpreter::new();
+    let mut fast = Interpreter::new();
+    let mut value = Expr::empty_list();
+    for form in minilisp_rust::parse_program(program).unwrap() {
+        value = fast.eval(&optimize(&form)).unwrap();
+    }
+    assert_eq!(plain.eval_str(program).unwrap(), value);
+
+    // Builtins replaced by the host are not folded.
+    let mut builtins = Builtins::standard();
+    builtins.insert("+", Builtin::new(|_| Ok(Expr::Number(0.0))));
+    let outer = minilisp_rust::runtime::set_builtins(builtins);
+    assert_eq!(optimized("(+ 1 2)"), "(+ 1 2)");
+    minilisp_rust::runtime::set_builtins(outer);
+}
+
+#[test]
+fn test_eval_hooks_observe_evaluation() {
+    use minilisp_rust::Interpreter;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let (enter_log, exit_log) = (log.clone(), log.clone());
+    let mut interp = Interpreter::new()
+        .on_enter(move |expr, depth| {
+            enter_log
+                .borrow_mut()
+                .push(format!("{}> {}", "  ".repeat(depth), expr))
+        })
+        .on_exit(move |result| {
+            exit_log.borrow_mut().push(match result {
+                Ok(value) => format!("< {}", value),
+                Err(e) => format!("< error: {}", e),
+            })
+        });
+    interp
+        .eval_str("(define double (lambda (x) (* 2 x)))")
+        .unwrap();
+    log.borrow_mut().clear();
+    assert_eq!(
+        interp.eval_str("(+ 1 (double 3))").unwrap(),
+        Expr::Number(7.0)
+    );
+    assert_eq!(
+        *log.borrow(),
+        [
+            "> (+ 1 (double 3))",
+            "  > 1",
+            "< 1",
+            "  > (double 3)",
+            "    > 3",
+            "< 3",
+            "    > (* 2 x)",
+            "      > 2",
+            "< 2",
+            "      > x",
+            "< 3",
+            "< 6",
+            "< 6",
+            "< 7",
+        ]
+    );
+
+    log.borrow_mut().clear();
+    assert!(interp.eval_str("(double \"a\")").is_err());
+    assert_eq!(
+        log.borrow().last().unwrap(),
+        "< error: Operator '*' requires number arguments."
+    );
+
+    // The hooks belong to the interpreter.
+    log.borrow_mut().clear();
+    eval(&parse("(+ 1 2)").unwrap(), &mut default_env()).unwrap();
+    assert!(log.borrow().is_empty());
+}
+
+#[test]
+fn test_debugger_pauses_and_steps() {
+    use minilisp_rust::runtime::{self, Debugger, Pause, Resume};
+    use minilisp_rust::{EvalError, Interpreter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records each pause and resumes as scripted.
+    struct Scripted {
+        log: Rc<RefCell<Vec<String>>>,
+        script: Vec<Resume>,
+    }
+
+    impl Debugger for Scripted {
+        fn pause(&mut self, pause: &Pause) -> Resume {
+            let locals: Vec<String> = pause
+                .env
+                .locals()
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            self.log.borrow_mut().push(format!(
+                "{:?} [{}] {} {}",
+                pause.reason,
+                pause.depth,
+                pause.expr,
+                locals.join(",")
+            ));
+            // Code evaluated while paused neither pauses nor steps.
+            let mut env = pause.env.clone();
+            eval(&parse("(break)").unwrap(), &mut env).unwrap();
+            if self.script.is_empty() {
+                Resume::Continue
+            } else {
+                self.script.remove(0)
+            }
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut interp = Interpreter::new().with_debugger(Scripted {
+        log: log.clone(),
+        script: vec![
+            Resume::Step,
+            Resume::Next,
+            Resume::Step,
+            Resume::Next,
+            Resume::Finish,
+        ],
+    });
+    interp
+        .eval_str("(define f (lambda (x y) (+ x (* y (g y)))))")
+        .unwrap();
+    interp.eval_str("(define g (lambda (n) (- n 1)))").unwrap();
+    runtime::set_breakpoint("f", true);
+    assert_eq!(runtime::breakpoints(), ["f"]);
+    assert_eq!(interp.eval_str("(f 1 3)").unwrap(), Expr::Number(7.0));
+    assert_eq!(
+        *log.borrow(),
+        [
+            "Breakpoint(\"f\") [0] (+ x (* y (g y))) x=1,y=3",
+            "Step [1] x x=1,y=3",
+            "Step [1] (* y (g y)) x=1,y=3",
+            "Step [2] y x=1,y=3",
+            "Step [2] (g y) x=1,y=3",
+        ]
+    );
+
+    // `(break)` pauses where it is called, and aborting fails the evaluation.
+    runtime::set_breakpoint("f", false);
+    log.borrow_mut().clear();
+    let mut interp = Interpreter::new().with_debugger(Scripted {
+        log: log.clone(),
+        script: vec![Resume::Abort],
+    });
+    interp
+        .eval_str("(define h (lambda (x) (list (break) x)))")
+        .unwrap();
+    assert_eq!(
+        interp.eval_str("(h 5)").unwrap_err().into_root(),
+        EvalError::Interrupted
+    );
+    assert_eq!(*log.borrow(), ["Break [0] (break) x=5"]);
+
+    // Without a debugger, `(break)` does nothing.
+    run_eval_test("(list (break) 1)", datum("(() 1)"));
+}
+
+#[test]
+fn test_profiler_counts_and_times_calls() {
+    use minilisp_rust::compile::eval_compiled;
+    use minilisp_rust::runtime::{self, OutputBuffer};
+
+    let mut env = default_env();
+    let define = "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))";
+    eval(&parse(define).unwrap(), &mut env).unwrap();
+    let loop_ = "(define countdown (lambda (n) (if (= n 0) 0 (countdown (- n 1)))))";
+    eval(&parse(loop_).unwrap(), &mut env).unwrap();
+
+    for run in [eval, eval_compiled] {
+        runtime::start_profiling();
+        run(&parse("(fib 10)").unwrap(), &mut env).unwrap();
+        // Tail calls still run in constant space while profiling.
+        run(&parse("(countdown 5000)").unwrap(), &mut env).unwrap();
+        runtime::stop_profiling();
+
+        let profile = runtime::profile();
+        let fib = profile.get("fib").unwrap();
+        assert_eq!(fib.calls, 177);
+        assert!(fib.self_time <= fib.total);
+        assert_eq!(profile.get("+").unwrap().calls, 88);
+        assert_eq!(profile.get("<").unwrap().calls, 177);
+        assert_eq!(profile.get("countdown").unwrap().calls, 5001);
+        let entries = profile.entries();
+        assert!(
+            entries
+                .windows(2)
+                .all(|pair| pair[0].1.self_time >= pair[1].1.self_time)
+        );
+    }
+
+    // Nothing is recorded once profiling stops.
+    eval(&parse("(fib 3)").unwrap(), &mut env).unwrap();
+    assert_eq!(runtime::profile().get("fib").unwrap().calls, 177);
+
+    let buffer = OutputBuffer::new();
+    runtime::set_output(buffer.clone());
+    let source = "(profile-start) (fib 2) (profile-stop) (profile-report)";
+    let mut result = Expr::empty_list();
+    for expr in minilisp_rust::parse_program(source).unwrap() {
+        result = eval(&expr, &mut env).unwrap();
+    }
+    assert_eq!(result, Expr::empty_list());
+    let report = buffer.contents();
+    assert!(report.starts_with("function "));
+    assert!(
+        report
+            .lines()
+            .any(|line| line.starts_with("fib ") && line.split_whitespace().nth(1) == Some("3"))
+    );
+    runtime::set_output(std::io::stdout());
+
+    let data = eval(&parse("(profile-data)").unwrap(), &mut env).unwrap();
+    let Expr::List(entries) = data else {
+        panic!("profile data is a list");
+    };
+    let fib = entries
+        .iter()
+        .find_map(|entry| match entry {
+            Expr::Map(map) if map["name"] == Expr::String("fib".into()) => Some(map),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(fib["calls"], Expr::Number(3.0));
+    assert!(matches!(fib["self-ms"], Expr::Number(ms) if ms >= 0.0));
+    assert!(fib.contains_key("total-ms"));
+}
+
+#[test]
+fn test_symbols_are_interned() {
+    use minilisp_rust::Symbol;
+
+    let (a, b) = (
+        Symbol::new("count-down"),
+        Symbol::from("count-down".to_string()),
+    );
+    assert_eq!(a, b);
+    assert_eq!(a.as_ptr(), b.as_ptr());
+    assert_ne!(a, Symbol::new("count-up"));
+    assert_eq!(a, "count-down");
+
+    // Parsed symbols are the same symbols the environment is keyed by.
+    let mut env = Env::new();
+    env.insert("x", Expr::Number(1.0));
+    let Ok(Expr::Symbol(x)) = parse("x") else {
+        panic!("x is a symbol");
+    };
+    assert_eq!(env.lookup(&x), Some(Expr::Number(1.0)));
+    assert_eq!(env.get("x"), Some(Expr::Number(1.0)));
+    assert_eq!(
+        eval(
+            &parse("(symbol->string (string->symbol \"x\"))").unwrap(),
+            &mut env
+        ),
+        Ok(Expr::String("x".into()))
+    );
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_values_and_environments_move_between_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Expr>();
+    assert_send_sync::<Env>();
+
+    let mut env = Env::new();
+    let source = "(define square (lambda (x) (* x x))) (define squares (memoize square))";
+    for expr in minilisp_rust::parse_program(source).unwrap() {
+        eval(&expr, &mut env).unwrap();
+    }
+    let native = minilisp_rust::NativeFn::new(|args| Ok(Expr::Number(args.len() as f64)));
+    env.insert("arity", Expr::NativeFn(native));
+
+    // The worker evaluates with the environment built here, and its results
+    // come back with symbols that are the same symbols as this thread's.
+    let worker = std::thread::spawn(move || {
+        let mut env = env;
+        let call =
+            parse("(list (square 4) (squares 5) (arity 1 2 3) (string->symbol \"done\"))").unwrap();
+        (eval(&call, &mut env), env)
+    });
+    let (result, env) = worker.join().unwrap();
+    assert_eq!(result.map_err(|e| e.to_string()), datum("(16 25 3 done)"));
+    assert_eq!(
+        eval(&parse("(squares 5)").unwrap(), &mut env.clone()),
+        Ok(Expr::Number(25.0))
+    );
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_concurrent_evaluation_in_a_shared_prelude() {
+    use minilisp_rust::{Builtins, Interpreter, builtins::Category};
+
+    let mut prelude = Interpreter::new();
+    prelude
+        .eval_str("(define limit 100) (define clamp (lambda (x) (if (> x limit) limit x)))")
+        .unwrap();
+    let prelude = prelude.env().clone();
+    let mut builtins = Builtins::standard();
+    builtins.remove_category(Category::Filesystem);
+
+    let workers: Vec<_> = (0..4)
+        .map(|i| {
+            let (prelude, builtins) = (prelude.clone(), builtins.clone());
+            std::thread::spawn(move || {
+                let mut interpreter = Interpreter::new()
+                    .with_builtins(builtins)
+                    .with_env(prelude.child());
+                let source = format!("(define x {}) (clamp (* x 40))", i);
+                let value = interpreter.eval_str(&source).unwrap();
+                (value, interpreter.eval_str("(read-file \"x\")").is_err())
+            })
+        })
+        .collect();
+    let results: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    assert_eq!(
+        results,
+        [0.0, 40.0, 80.0, 100.0].map(|n| (Expr::Number(n), true))
+    );
+    // Each script's definitions stayed in its own scope.
+    assert_eq!(prelude.get("x"), None);
+    assert_eq!(prelude.get("limit"), Some(Expr::Number(100.0)));
+}
+
+#[test]
+fn test_scopes_in_reference_cycles_are_freed() {
+    use minilisp_rust::{NativeFn, cycles};
+    use std::sync::Arc;
+
+    // Each call of `marker` makes a function holding a clone of `live`, so
+    // the clones count the markers still alive.
+    let live = Arc::new(());
+    let counter = live.clone();
+    let marker = NativeFn::new(move |_| {
+        let held = counter.clone();
+        Ok(Expr::NativeFn(NativeFn::new(move |_| {
+            let _ = &held;
+            Ok(Expr::Bool(true))
+        })))
+    });
+    let markers = || Arc::strong_count(&live) - 2;
+
+    let mut env = Env::new();
+    env.insert("marker", Expr::NativeFn(marker));
+    // Each call's scope holds a marker and a function closing over the
+    // scope, which refers back to it.
+    let source = "(define f (lambda (m) (define inner (lambda () m))))";
+    eval(&parse(source).unwrap(), &mut env).unwrap();
+    let call = parse("(f (marker))").unwrap();
+    for _ in 0..5000 {
+        eval(&call, &mut env).unwrap();
+    }
+    // Scopes are collected as more are created, without being asked to.
+    assert!(markers() < 2500, "{} markers alive", markers());
+    cycles::collect();
+    assert_eq!(markers(), 0);
+
+    // A whole environment, with a function defined in it, is freed too,
+    // and with it `marker` itself.
+    eval(&parse("(define kept (marker))").unwrap(), &mut env).unwrap();
+    assert_eq!(markers(), 1);
+    drop(env);
+    cycles::collect();
+    assert_eq!(Arc::strong_count(&live), 1);
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_request_scopes_defining_functions_are_freed() {
+    use minilisp_rust::{Interpreter, NativeFn};
+    use std::sync::Arc;
+
+    // Each call of `marker` makes a function holding a clone of `live`.
+    let live = Arc::new(());
+    let counter = live.clone();
+    let mut prelude = Env::new();
+    prelude.insert(
+        "marker",
+        Expr::NativeFn(NativeFn::new(move |_| {
+            let held = counter.clone();
+            Ok(Expr::NativeFn(NativeFn::new(move |_| {
+                let _ = &held;
+                Ok(Expr::Bool(true))
+            })))
+        })),
+    );
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let prelude = prelude.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut interpreter = Interpreter::new().with_env(prelude.child());
+                    let source = "(define helper (lambda (x) x)) (define kept (marker)) (helper 1)";
+                    assert_eq!(interpreter.eval_str(source), Ok(Expr::Number(1.0)));
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    // Every request's scope, and the marker in it, went with its
+    // interpreter.
+    assert_eq!(Arc::strong_count(&live), 2);
 }